@@ -34,3 +34,8 @@ else
 endif
 
 QEMU_ARGS += -drive file=$(shell pwd)/deps/ovmf/ovmf-$(TARGET_SIMPLE)-padded.fd,if=pflash,format=raw,readonly=on
+
+# A scratch disk for the virtio-blk driver. Only the modern virtio-mmio interface is supported.
+QEMU_DISK = $(shell pwd)/target/disk.img
+QEMU_ARGS += -global virtio-mmio.force-legacy=false
+QEMU_ARGS += -drive file=$(QEMU_DISK),if=none,format=raw,id=disk -device virtio-blk-device,drive=disk
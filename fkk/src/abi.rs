@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: MIT
+//! The interface between the kernel and user programs: system call numbers, the registers they're
+//! passed in, error codes, and what a process can expect when it starts.
+//!
+//! This is the single source of truth for both sides, so anything added here must be handled by
+//! the kernel's system call dispatcher, and vice versa.
+//!
+//! # Process startup
+//!
+//! A process starts at its ELF entry point in EL0, with `sp` at the top of its stack (16 byte
+//! aligned) and every other register zeroed, except for `x30`, which holds
+//! [`PROCESS_RETURN_ADDRESS`]. Returning from the entry point exits the process, with the value in
+//! `x0` as its exit code.
+//!
+//! # System calls
+//!
+//! A system call is made by placing its number in [`SYSCALL_NUMBER_REGISTER`] and up to
+//! [`SYSCALL_ARG_COUNT`] arguments in [`SYSCALL_ARG_REGISTERS`], then executing `svc #0`. The
+//! result is returned in [`SYSCALL_RETURN_REGISTER`]; negative values are negated error codes.
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// Terminates the calling process. Arguments: exit code.
+pub const SYS_EXIT: usize = 0;
+/// Writes a buffer to a file descriptor. Arguments: fd, buffer address, buffer length.
+pub const SYS_WRITE: usize = 1;
+/// Gives up the rest of the calling process's time slice. No arguments.
+pub const SYS_YIELD: usize = 2;
+/// Suspends the calling process for at least the given time. Arguments: nanoseconds. Sleeping for
+/// 0 nanoseconds is the same as [`SYS_YIELD`]. Returns 0, or the nanoseconds left if the process
+/// was woken early.
+pub const SYS_NANOSLEEP: usize = 3;
+
+/// The number of system calls; every number below this is a valid system call.
+pub const SYSCALL_COUNT: usize = 4;
+/// Every system call number, in order.
+pub const SYSCALLS: [usize; SYSCALL_COUNT] = [SYS_EXIT, SYS_WRITE, SYS_YIELD, SYS_NANOSLEEP];
+
+/// The register holding the system call number.
+pub const SYSCALL_NUMBER_REGISTER: usize = 8;
+/// The maximum number of arguments a system call takes.
+pub const SYSCALL_ARG_COUNT: usize = 6;
+/// The registers holding each system call argument, in order.
+pub const SYSCALL_ARG_REGISTERS: [usize; SYSCALL_ARG_COUNT] = [0, 1, 2, 3, 4, 5];
+/// The register the result of a system call is returned in.
+pub const SYSCALL_RETURN_REGISTER: usize = 0;
+
+/// The address a process returns to from its entry point, which is never mapped.
+pub const PROCESS_RETURN_ADDRESS: usize = 0x0;
+
+/// Standard output file descriptor.
+pub const STDOUT: u64 = 1;
+/// Standard error file descriptor.
+pub const STDERR: u64 = 2;
+
+/// Bad file descriptor.
+pub const EBADF: isize = 9;
+/// Bad address.
+pub const EFAULT: isize = 14;
+/// Invalid argument.
+pub const EINVAL: isize = 22;
+/// Function not implemented.
+pub const ENOSYS: isize = 38;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syscall_numbers_are_dense() {
+        for (i, &nr) in SYSCALLS.iter().enumerate() {
+            assert_eq!(
+                nr, i,
+                "system call numbers must be 0..SYSCALL_COUNT, in order"
+            );
+        }
+    }
+}
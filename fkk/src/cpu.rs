@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: MIT
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Returns the index of the executing core.
+///
+/// Outside of the kernel (e.g. when running on a host), this always returns 0.
+#[inline(always)]
+pub fn core_index() -> usize {
+    #[cfg(all(target_arch = "aarch64", target_os = "none"))]
+    {
+        const CORE_MASK: u64 = 0b11;
+
+        let mpidr: u64;
+        unsafe {
+            core::arch::asm!(
+                "mrs {}, MPIDR_EL1",
+                out(reg) mpidr,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+
+        (mpidr & CORE_MASK) as usize
+    }
+
+    #[cfg(not(all(target_arch = "aarch64", target_os = "none")))]
+    {
+        0
+    }
+}
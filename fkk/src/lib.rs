@@ -2,6 +2,11 @@
 extern crate alloc;
 
 use core::ops;
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub mod abi;
+pub mod cpu;
 
 /// The Flow Kernel Kit, or FKK, is a collection of libraries and utilities
 /// for building components of the Flow kernel. It provides some semi-stable
@@ -38,7 +43,18 @@ macro_rules! println {
 
 /// Add `Sync` to an arbitrary type. This is EXTREMELY, INCREDIBLY unsafe in anything other
 /// than a single-threaded environment!
-pub struct Syncify<T>(T);
+///
+/// In debug builds, the first core to access the wrapped value becomes its owner, and any access
+/// from a different core afterwards panics. Release builds skip the check entirely.
+pub struct Syncify<T> {
+    inner: T,
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+}
+
+/// The owner of a `Syncify` that hasn't been accessed yet.
+#[cfg(debug_assertions)]
+const UNCLAIMED: usize = usize::MAX;
 
 impl<T> Syncify<T> {
     /// Create a new `Syncify` wrapper.
@@ -47,14 +63,38 @@ impl<T> Syncify<T> {
     ///
     /// This is invariant-breaking and thus unsafe.
     pub const unsafe fn new(inner: T) -> Syncify<T> {
-        Syncify(inner)
+        Syncify {
+            inner,
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(UNCLAIMED),
+        }
     }
 
     pub fn with<F, R>(&'static self, f: F) -> R
     where
         F: FnOnce(&T) -> R,
     {
-        f(&self.0)
+        #[cfg(debug_assertions)]
+        self.assert_single_core();
+
+        f(&self.inner)
+    }
+
+    /// Claims this value for the executing core on first access, and panics if it's accessed
+    /// from any other core afterwards.
+    #[cfg(debug_assertions)]
+    fn assert_single_core(&self) {
+        let core = cpu::core_index();
+        if let Err(owner) =
+            self.owner
+                .compare_exchange(UNCLAIMED, core, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            assert_eq!(
+                owner, core,
+                "Syncify value owned by core {} was accessed from core {}",
+                owner, core
+            );
+        }
     }
 }
 
@@ -62,8 +102,30 @@ impl<T> ops::Deref for Syncify<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        &self.0
+        #[cfg(debug_assertions)]
+        self.assert_single_core();
+
+        &self.inner
     }
 }
 
 unsafe impl<T> Sync for Syncify<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syncify_allows_repeated_access_from_one_core() {
+        static VALUE: Syncify<u32> = unsafe { Syncify::new(42) };
+
+        for _ in 0..3 {
+            assert_eq!(*VALUE, 42);
+            assert_eq!(VALUE.with(|value| *value), 42);
+        }
+
+        // the first access claimed it for this core, which every later access matched
+        #[cfg(debug_assertions)]
+        assert_eq!(VALUE.owner.load(Ordering::Relaxed), cpu::core_index());
+    }
+}
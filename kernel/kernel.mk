@@ -12,16 +12,32 @@ include ../common.mk
 
 RUSTFLAGS = $(RUSTC_MISC_ARGS) \
 	-C panic=abort \
+	-C force-frame-pointers=yes \
 	-C link-arg=-Lsrc/arch/$(TARGET_SIMPLE)/ \
 	-C link-arg=--script=src/arch/$(TARGET_SIMPLE)/kernel.ld
 
+FEATURES = bsp_$(BSP)
+
+# Translation granule size, either 4K (default), 16K or 64K
+GRANULE ?= 4K
+ifeq ($(GRANULE),16K)
+	FEATURES += granule_16k
+else ifeq ($(GRANULE),64K)
+	FEATURES += granule_64k
+endif
+
+# Set SELFTEST=1 to run the in-kernel self-tests at boot
+ifeq ($(SELFTEST),1)
+	FEATURES += selftest
+endif
+
 .PHONY: clean build all
 
 all: build
 
 build:
 	$(call color_header, "Building kernel")
-	@RUSTFLAGS="$(RUSTFLAGS)" cargo build --target $(TARGET) --features bsp_$(BSP)
+	@RUSTFLAGS="$(RUSTFLAGS)" cargo build --target $(TARGET) --features "$(FEATURES)"
 
 clean:
 	rm -rf target/
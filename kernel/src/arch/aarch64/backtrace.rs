@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MIT
+//! Stack backtraces, by following the chain of frame records.
+//!
+//! With frame pointers enabled, every function's prologue pushes a frame record holding its
+//! caller's frame pointer (`x29`) and its return address (`x30`), and points `x29` at it. The
+//! records are linked from the innermost frame outwards, up the stack.
+//!
+//! Nothing on the stack is trusted: each frame pointer is checked to be 16-byte aligned, as frame
+//! records always are, within the stack
+//! the walk started on, and mapped, before it's read, so a corrupted stack can't make the walk
+//! fault.
+
+use core::arch::asm;
+
+use crate::mem::vm::paging::{translate_current, VirtualAddress};
+use crate::mem::{kernel_stack_end, kernel_stack_start};
+use crate::println;
+use crate::util::symbolize;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The most frames printed in a single backtrace.
+pub const MAX_BACKTRACE_DEPTH: usize = 32;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Prints the return addresses of the calling function and its callers.
+#[inline(never)]
+pub fn backtrace() {
+    let fp: usize;
+    // Safe because reading x29 has no side effects.
+    unsafe { asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+
+    print_frames(None, fp);
+}
+
+/// Prints a backtrace of a saved context, starting with the address it was executing, `pc`, and
+/// followed by the return addresses in the frame chain starting at `fp`.
+pub fn backtrace_from(pc: usize, fp: usize) {
+    print_frames(Some(pc), fp);
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// The size of the stack Limine boots us on, which is in the direct map rather than the kernel
+/// stack window.
+const BOOT_STACK_SIZE: usize = 64 * 1024;
+
+/// The size of a frame record: the caller's frame pointer, then the return address.
+const FRAME_RECORD_SIZE: usize = 2 * core::mem::size_of::<usize>();
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+fn print_frames(pc: Option<usize>, fp: usize) {
+    println!("Backtrace:");
+
+    let mut depth = 0;
+    if let Some(pc) = pc {
+        print_frame(depth, pc);
+        depth += 1;
+    }
+
+    // frame records are only followed within the stack the walk started on
+    let (stack_start, stack_end) = if (kernel_stack_start()..kernel_stack_end()).contains(&fp) {
+        (kernel_stack_start(), kernel_stack_end())
+    } else {
+        (fp, fp.saturating_add(BOOT_STACK_SIZE))
+    };
+
+    let mut fp = fp;
+    while fp != 0 && depth < MAX_BACKTRACE_DEPTH {
+        let in_stack = fp >= stack_start && fp.saturating_add(FRAME_RECORD_SIZE) <= stack_end;
+        if fp % FRAME_RECORD_SIZE != 0 || !in_stack || !is_readable(fp) {
+            println!("    <invalid frame pointer {:#018x}>", fp);
+            return;
+        }
+
+        // Safe because the record was just checked to be aligned, and mapped.
+        let [next, return_address] = unsafe { (fp as *const [usize; 2]).read() };
+        if return_address == 0 {
+            return;
+        }
+
+        print_frame(depth, return_address);
+        depth += 1;
+
+        // callers' records are always further up the stack, so anything else is a loop
+        if next != 0 && next <= fp {
+            println!("    <invalid frame pointer {:#018x}>", next);
+            return;
+        }
+
+        fp = next;
+    }
+
+    if fp != 0 {
+        println!("    ...");
+    }
+}
+
+fn print_frame(depth: usize, address: usize) {
+    match symbolize(address) {
+        Some((name, offset)) => {
+            println!("    #{:<2} {:#018x} {}+{:#x}", depth, address, name, offset)
+        }
+        None => println!("    #{:<2} {:#018x}", depth, address),
+    }
+}
+
+/// Whether both words of the frame record at `va` can be read without faulting.
+fn is_readable(va: usize) -> bool {
+    // an aligned record never straddles a page, so only its address needs translating
+    translate_current(VirtualAddress(va)).is_some()
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MIT
+//! Cache maintenance, for memory that's about to be executed or that's shared with devices.
+
+use core::arch::asm;
+
+use crate::cpu::barrier;
+use crate::mem::vm::paging::VirtualMemoryRegion;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Makes instructions written to `range` visible to instruction fetches, on every core in the
+/// inner shareable domain.
+///
+/// The data cache is cleaned to the point of unification, then the instruction cache invalidated,
+/// over the whole range. The range may be any mapping of the memory, such as the direct map, since
+/// the caches behave as physically indexed for maintenance by address.
+pub fn sync_icache(range: &VirtualMemoryRegion) {
+    if range.start().0 >= range.end().0 {
+        return;
+    }
+
+    let ctr = read_ctr();
+
+    // Safe because cleaning and invalidating the caches doesn't change the contents of memory.
+    unsafe {
+        let line = dcache_line_size(ctr);
+        for addr in (range.start().0 & !(line - 1)..range.end().0).step_by(line) {
+            asm!("dc cvau, {addr}", addr = in(reg) addr, options(nostack, preserves_flags));
+        }
+        barrier::dsb_ish();
+
+        let line = icache_line_size(ctr);
+        for addr in (range.start().0 & !(line - 1)..range.end().0).step_by(line) {
+            asm!("ic ivau, {addr}", addr = in(reg) addr, options(nostack, preserves_flags));
+        }
+        barrier::dsb_ish();
+        barrier::isb();
+    }
+}
+
+/// Writes any dirty data cache lines covering `range` back to the point of coherency, so a device
+/// reading the memory sees what the CPU wrote to it.
+pub fn clean_dcache(range: &VirtualMemoryRegion) {
+    // Safe because cleaning the cache doesn't change the contents of memory.
+    unsafe { dcache_op_to_poc::<CLEAN>(range) }
+}
+
+/// Discards the data cache lines covering `range`, so the CPU sees what a device wrote to the
+/// memory rather than stale cached data.
+///
+/// Any dirty lines are written back first, since `dc ivac` could otherwise discard data the CPU
+/// wrote to the parts of a line outside the range.
+#[allow(unused)]
+pub fn invalidate_dcache(range: &VirtualMemoryRegion) {
+    // Safe because lines are cleaned as they're invalidated, so no writes are lost.
+    unsafe { dcache_op_to_poc::<CLEAN_INVALIDATE>(range) }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+const CLEAN: u8 = 0;
+const CLEAN_INVALIDATE: u8 = 1;
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+/// Performs a data cache maintenance operation, by address to the point of coherency, on every line
+/// covering `range`, then waits for it to complete.
+unsafe fn dcache_op_to_poc<const OP: u8>(range: &VirtualMemoryRegion) {
+    if range.start().0 >= range.end().0 {
+        return;
+    }
+
+    let line = dcache_line_size(read_ctr());
+    for addr in (range.start().0 & !(line - 1)..range.end().0).step_by(line) {
+        match OP {
+            CLEAN => {
+                asm!("dc cvac, {addr}", addr = in(reg) addr, options(nostack, preserves_flags))
+            }
+            _ => asm!("dc civac, {addr}", addr = in(reg) addr, options(nostack, preserves_flags)),
+        }
+    }
+    barrier::dsb_sy();
+}
+
+#[inline(always)]
+fn read_ctr() -> u64 {
+    let ctr: u64;
+    // Safe because CTR_EL0 is a read-only identification register.
+    unsafe {
+        asm!("mrs {ctr}, ctr_el0", ctr = out(reg) ctr, options(nomem, nostack, preserves_flags));
+    }
+    ctr
+}
+
+/// The smallest data cache line size, in bytes, from `CTR_EL0.DminLine`.
+#[inline(always)]
+fn dcache_line_size(ctr: u64) -> usize {
+    // the field holds log2 of the number of 4-byte words in a line
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// The smallest instruction cache line size, in bytes, from `CTR_EL0.IminLine`.
+#[inline(always)]
+fn icache_line_size(ctr: u64) -> usize {
+    4 << (ctr & 0xf)
+}
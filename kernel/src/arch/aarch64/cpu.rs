@@ -1,15 +1,33 @@
 // SPDX-License-Identifier: MIT
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use crate::mem;
 use aarch64_cpu::asm;
-use aarch64_cpu::registers::{CNTFRQ_EL0, CNTPCT_EL0, MPIDR_EL1};
-use tock_registers::interfaces::Readable;
+#[cfg(debug_assertions)]
+use aarch64_cpu::registers::OSLAR_EL1;
+use aarch64_cpu::registers::{MPIDR_EL1, TPIDR_EL0};
+use tock_registers::interfaces::{Readable, Writeable};
 
 use crate::time::{KernelTimerData, KERNEL_TIMER_DATA};
+use crate::warn;
+
+#[path = "cpu/barrier.rs"]
+pub mod barrier;
+#[path = "cpu/features.rs"]
+mod features;
+#[path = "cpu/percpu.rs"]
+pub mod percpu;
+#[path = "cpu/psci.rs"]
+mod psci;
+
+pub use features::{features, CpuFeatures};
 
 pub static BOOT_CORE_ID: u64 = 0;
 
+/// Bitmask of the cores powered down by `park`.
+static PARKED_CORES: AtomicU64 = AtomicU64::new(0);
+
 /// The entry point for the kernel.
 ///
 /// # Safety
@@ -30,15 +48,16 @@ pub unsafe extern "C" fn _start() -> ! {
         dm_offset = in(reg) mem::direct_map_virt_offset(),
     );
 
+    // find this core's control block, before anything that might look for it
+    percpu::init();
+
     // Only proceed on the boot core for now
-    if core_id::<u64>() != BOOT_CORE_ID {
-        loop {
-            asm::wfe();
-        }
+    if percpu::this_core().id() != BOOT_CORE_ID {
+        park();
     }
 
     // set up some kernel constants
-    KERNEL_TIMER_DATA.set(KernelTimerData::new(CNTFRQ_EL0.get(), CNTPCT_EL0.get()));
+    KERNEL_TIMER_DATA.set(KernelTimerData::now());
 
     // Start the rest of the kernel init process
     crate::boot::kernel_init()
@@ -51,6 +70,104 @@ pub fn wait_forever() -> ! {
     }
 }
 
+/// Waits until an event is signalled, e.g. by another core executing `send_event`.
+#[inline(always)]
+pub fn wait_for_event() {
+    asm::wfe()
+}
+
+/// Puts the core into a low power state until an interrupt is pending. The interrupt is only
+/// taken if it's unmasked, but masked interrupts still wake the core.
+#[inline(always)]
+pub fn wait_for_interrupt() {
+    asm::wfi()
+}
+
+/// Signals an event to all cores, waking any that are waiting in `wait_for_event`.
+#[inline(always)]
+pub fn send_event() {
+    asm::sev()
+}
+
+/// Powers down the calling secondary core, until it's brought back with `unpark`.
+///
+/// If the firmware refuses to power the core down, it waits for events forever instead.
+pub fn park() -> ! {
+    let core = percpu::this_core().id();
+    assert_ne!(core, BOOT_CORE_ID, "the boot core can't be parked");
+
+    PARKED_CORES.fetch_or(1 << core, Ordering::AcqRel);
+    let err = psci::cpu_off();
+
+    PARKED_CORES.fetch_and(!(1 << core), Ordering::AcqRel);
+    warn!("core {}: failed to power down: {}", core, err);
+    wait_forever()
+}
+
+/// Powers a core parked with `park` back on. It starts at `entry_pa`, with the MMU off and
+/// `context_id` in `x0`.
+///
+/// # Safety
+///
+/// - `entry_pa` must be the physical address of code that's safe to run with the MMU off, and
+///   that brings the core up to the point where it can run the kernel.
+#[allow(unused)]
+pub unsafe fn unpark(core: u64, entry_pa: usize, context_id: u64) -> Result<(), &'static str> {
+    if !is_parked(core) {
+        return Err("core is not parked");
+    }
+
+    // on QEMU virt, the core ID is the MPIDR affinity value
+    psci::cpu_on(core, entry_pa, context_id)?;
+    PARKED_CORES.fetch_and(!(1 << core), Ordering::AcqRel);
+
+    Ok(())
+}
+
+/// Whether the given core has been powered down with `park`.
+#[allow(unused)]
+pub fn is_parked(core: u64) -> bool {
+    PARKED_CORES.load(Ordering::Acquire) & (1 << core) != 0
+}
+
+/// Enables or disables software step debug exceptions for lower exception levels.
+///
+/// While enabled, returning to a lower EL with `SPSR_EL1.SS` set traps back after one instruction,
+/// and returning with it clear traps back immediately, so it must be disabled when returning to
+/// code that isn't being stepped.
+#[cfg(debug_assertions)]
+pub fn set_software_step(enabled: bool) {
+    const MDSCR_EL1_SS: u64 = 1 << 0;
+
+    unsafe {
+        // the OS lock is set at reset, and blocks all debug exceptions while it's set
+        if enabled {
+            OSLAR_EL1.write(OSLAR_EL1::OSLK::Unlocked);
+        }
+
+        let mut mdscr: u64;
+        asm!("mrs {}, mdscr_el1", out(reg) mdscr);
+        if enabled {
+            mdscr |= MDSCR_EL1_SS;
+        } else {
+            mdscr &= !MDSCR_EL1_SS;
+        }
+        asm!("msr mdscr_el1, {}", "isb", in(reg) mdscr);
+    }
+}
+
+/// Returns the user thread pointer, `TPIDR_EL0`, of the calling core.
+#[inline(always)]
+pub fn user_thread_pointer() -> usize {
+    TPIDR_EL0.get() as usize
+}
+
+/// Sets the user thread pointer, `TPIDR_EL0`, of the calling core.
+#[inline(always)]
+pub fn set_user_thread_pointer(tp: usize) {
+    TPIDR_EL0.set(tp as u64);
+}
+
 #[inline(always)]
 pub fn nop() {
     asm::nop()
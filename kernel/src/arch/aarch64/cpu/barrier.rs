@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: MIT
+//! Memory barriers.
+//!
+//! - `dmb` orders memory accesses before it against those after it, as seen by other observers.
+//! - `dsb` waits for memory accesses (and cache and TLB maintenance) before it to complete before
+//!   any instruction after it executes.
+//! - `isb` flushes the pipeline, so system register writes before it take effect for the
+//!   instructions after it.
+//!
+//! The `ish` variants cover every core in the inner shareable domain; the `sy` variants also cover
+//! devices and other observers outside it.
+//!
+//! # Resources
+//!
+//! - <https://developer.arm.com/documentation/100941/latest/Barriers>
+
+use core::arch::asm;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Waits for every earlier memory access to complete, across the inner shareable domain.
+#[inline(always)]
+pub fn dsb_ish() {
+    // Safe because barriers only constrain the order of memory accesses.
+    unsafe { asm!("dsb ish", options(nostack, preserves_flags)) };
+}
+
+/// Waits for every earlier store to complete, across the inner shareable domain, e.g. so a
+/// translation table walk on any core sees a descriptor that was just written.
+#[inline(always)]
+pub fn dsb_ishst() {
+    // Safe because barriers only constrain the order of memory accesses.
+    unsafe { asm!("dsb ishst", options(nostack, preserves_flags)) };
+}
+
+/// Waits for every earlier memory access to complete, as seen by the calling core only.
+#[inline(always)]
+pub fn dsb_nsh() {
+    // Safe because barriers only constrain the order of memory accesses.
+    unsafe { asm!("dsb nsh", options(nostack, preserves_flags)) };
+}
+
+/// Waits for every earlier memory access to complete, for every observer in the system, including
+/// devices.
+#[inline(always)]
+pub fn dsb_sy() {
+    // Safe because barriers only constrain the order of memory accesses.
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+/// Orders earlier memory accesses before later ones, as seen by the inner shareable domain.
+#[allow(unused)]
+#[inline(always)]
+pub fn dmb_ish() {
+    // Safe because barriers only constrain the order of memory accesses.
+    unsafe { asm!("dmb ish", options(nostack, preserves_flags)) };
+}
+
+/// Flushes the pipeline, so that earlier system register writes and completed maintenance take
+/// effect for every later instruction.
+#[inline(always)]
+pub fn isb() {
+    // Safe because flushing the pipeline doesn't change any state.
+    unsafe { asm!("isb", options(nostack, preserves_flags)) };
+}
+
+/// Waits for every earlier memory access to complete system-wide, then flushes the pipeline. This
+/// is the big hammer, for when a change to memory or system state must be visible to everything
+/// that follows.
+#[allow(unused)]
+#[inline(always)]
+pub fn data_synchronization() {
+    dsb_sy();
+    isb();
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT
+//! CPU identification and feature discovery, from the ID registers.
+//!
+//! # Resources
+//!
+//! - <https://developer.arm.com/documentation/ddi0601/latest/AArch64-Registers>
+
+use core::arch::asm;
+use core::fmt;
+
+use aarch64_cpu::registers::{ID_AA64MMFR0_EL1, MIDR_EL1};
+use tock_registers::interfaces::Readable;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// What the CPU we're running on is, and what it supports.
+#[derive(Copy, Clone, Debug)]
+pub struct CpuFeatures {
+    /// The implementer code from `MIDR_EL1`, e.g. `0x41` for Arm.
+    pub implementer: u8,
+    /// The implementer's part number from `MIDR_EL1`, e.g. `0xd08` for a Cortex-A72.
+    pub part_number: u16,
+    pub variant: u8,
+    pub revision: u8,
+
+    /// The number of physical address bits supported, from `ID_AA64MMFR0_EL1.PARange`.
+    pub physical_address_bits: u8,
+    pub granule_4k: bool,
+    pub granule_16k: bool,
+    pub granule_64k: bool,
+
+    /// Whether EL2 and EL3 are implemented.
+    pub el2: bool,
+    pub el3: bool,
+    pub floating_point: bool,
+    pub advanced_simd: bool,
+    /// Whether the GIC CPU interface can be accessed through system registers.
+    pub gic_system_registers: bool,
+    pub sve: bool,
+
+    /// The smallest data and instruction cache line sizes, in bytes, from `CTR_EL0`.
+    pub dcache_line_size: usize,
+    pub icache_line_size: usize,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Reads the ID registers of the calling core.
+pub fn features() -> CpuFeatures {
+    let pfr0 = read_pfr0();
+    let ctr = read_ctr();
+
+    CpuFeatures {
+        implementer: MIDR_EL1.read(MIDR_EL1::Implementer) as u8,
+        part_number: MIDR_EL1.read(MIDR_EL1::PartNum) as u16,
+        variant: MIDR_EL1.read(MIDR_EL1::Variant) as u8,
+        revision: MIDR_EL1.read(MIDR_EL1::Revision) as u8,
+
+        physical_address_bits: physical_address_bits(),
+        granule_4k: ID_AA64MMFR0_EL1.matches_all(ID_AA64MMFR0_EL1::TGran4::Supported),
+        granule_16k: ID_AA64MMFR0_EL1.matches_all(ID_AA64MMFR0_EL1::TGran16::Supported),
+        granule_64k: ID_AA64MMFR0_EL1.matches_all(ID_AA64MMFR0_EL1::TGran64::Supported),
+
+        el2: field(pfr0, 8) != 0,
+        el3: field(pfr0, 12) != 0,
+        floating_point: field(pfr0, 16) != 0xf,
+        advanced_simd: field(pfr0, 20) != 0xf,
+        gic_system_registers: field(pfr0, 24) != 0,
+        sve: field(pfr0, 32) != 0,
+
+        // both fields hold log2 of the number of 4-byte words in a line
+        dcache_line_size: 4 << field(ctr, 16),
+        icache_line_size: 4 << field(ctr, 0),
+    }
+}
+
+impl CpuFeatures {
+    /// Whether the CPU supports a translation granule of the given size, in bytes.
+    pub fn supports_granule(&self, size: usize) -> bool {
+        match size {
+            0x1000 => self.granule_4k,
+            0x4000 => self.granule_16k,
+            0x10000 => self.granule_64k,
+            _ => false,
+        }
+    }
+
+    /// The name of the CPU's implementer, if it's one we know of.
+    pub fn implementer_name(&self) -> Option<&'static str> {
+        match self.implementer {
+            0x41 => Some("Arm"),
+            0x42 => Some("Broadcom"),
+            0x43 => Some("Cavium"),
+            0x46 => Some("Fujitsu"),
+            0x4e => Some("NVIDIA"),
+            0x51 => Some("Qualcomm"),
+            0x61 => Some("Apple"),
+            0xc0 => Some("Ampere"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.implementer_name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "implementer {:#04x}", self.implementer)?,
+        }
+
+        write!(
+            f,
+            " part {:#05x} r{}p{}, {}-bit PA, granules:",
+            self.part_number, self.variant, self.revision, self.physical_address_bits
+        )?;
+
+        for (supported, name) in [
+            (self.granule_4k, " 4K"),
+            (self.granule_16k, " 16K"),
+            (self.granule_64k, " 64K"),
+        ] {
+            if supported {
+                f.write_str(name)?;
+            }
+        }
+
+        f.write_str(", features:")?;
+        for (supported, name) in [
+            (self.el2, " el2"),
+            (self.el3, " el3"),
+            (self.floating_point, " fp"),
+            (self.advanced_simd, " asimd"),
+            (self.gic_system_registers, " gic-sysreg"),
+            (self.sve, " sve"),
+        ] {
+            if supported {
+                f.write_str(name)?;
+            }
+        }
+
+        write!(
+            f,
+            ", cache lines: {}B D / {}B I",
+            self.dcache_line_size, self.icache_line_size
+        )
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+fn physical_address_bits() -> u8 {
+    use ID_AA64MMFR0_EL1::PARange::Value;
+
+    match ID_AA64MMFR0_EL1.read_as_enum(ID_AA64MMFR0_EL1::PARange) {
+        Some(Value::Bits_32) => 32,
+        Some(Value::Bits_36) => 36,
+        Some(Value::Bits_40) => 40,
+        Some(Value::Bits_42) => 42,
+        Some(Value::Bits_44) => 44,
+        Some(Value::Bits_48) => 48,
+        Some(Value::Bits_52) => 52,
+        // reserved values; assume the smallest range
+        None => 32,
+    }
+}
+
+/// Returns the 4-bit ID register field starting at bit `offset`.
+#[inline(always)]
+fn field(value: u64, offset: u32) -> u64 {
+    (value >> offset) & 0xf
+}
+
+#[inline(always)]
+fn read_pfr0() -> u64 {
+    let pfr0: u64;
+    // Safe because ID_AA64PFR0_EL1 is a read-only identification register.
+    unsafe {
+        asm!("mrs {pfr0}, id_aa64pfr0_el1", pfr0 = out(reg) pfr0, options(nomem, nostack, preserves_flags));
+    }
+    pfr0
+}
+
+#[inline(always)]
+fn read_ctr() -> u64 {
+    let ctr: u64;
+    // Safe because CTR_EL0 is a read-only identification register.
+    unsafe {
+        asm!("mrs {ctr}, ctr_el0", ctr = out(reg) ctr, options(nomem, nostack, preserves_flags));
+    }
+    ctr
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MIT
+//! Per-core kernel state.
+//!
+//! Each core has a [`CoreLocal`] control block, which `TPIDR_EL1` points at while the kernel runs
+//! on it. User space can't read or write the register, so the calling core's block can be found
+//! from anywhere in the kernel with a single system register read.
+//!
+//! Statics with a separate copy for each core are declared with [`per_core!`](crate::per_core).
+//!
+//! # Resources
+//!
+//! - <https://developer.arm.com/documentation/ddi0601/latest/AArch64-Registers/TPIDR-EL1--EL1-Software-Thread-ID-Register>
+
+use aarch64_cpu::registers::TPIDR_EL1;
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::cpu::core_id;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The most cores the kernel supports, one for each core ID that `MPIDR_EL1` can give.
+pub const MAX_CORES: usize = 4;
+
+/// The kernel's state for one core.
+pub struct CoreLocal {
+    /// The core's ID, from `MPIDR_EL1`.
+    id: u64,
+}
+
+/// A value with a separate copy for each core. Declared with [`per_core!`](crate::per_core).
+pub struct PerCore<T> {
+    values: [T; MAX_CORES],
+}
+
+// Safe because each core only ever gets at its own copy.
+unsafe impl<T: Send> Sync for PerCore<T> {}
+
+/// Declares a static with a separate copy for each core, each starting out as `$init`, which must
+/// be a constant expression. The calling core's copy is returned by
+/// [`PerCore::get`](crate::cpu::percpu::PerCore::get).
+#[macro_export]
+macro_rules! per_core {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::cpu::percpu::PerCore<$ty> = {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const INIT: $ty = $init;
+            $crate::cpu::percpu::PerCore::new([INIT; $crate::cpu::percpu::MAX_CORES])
+        };
+    };
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Points `TPIDR_EL1` at the calling core's control block.
+///
+/// # Safety
+///
+/// Must be called on each core as it enters the kernel, before anything calls [`this_core`].
+pub unsafe fn init() {
+    TPIDR_EL1.set(&CORE_LOCALS[core_id::<usize>()] as *const CoreLocal as u64);
+}
+
+/// Returns the control block of the calling core.
+#[inline(always)]
+pub fn this_core() -> &'static CoreLocal {
+    // Safe because `init` pointed TPIDR_EL1 at one of the static control blocks, and nothing else
+    // writes it.
+    unsafe { &*(TPIDR_EL1.get() as *const CoreLocal) }
+}
+
+impl CoreLocal {
+    /// The ID of the core, from `MPIDR_EL1`.
+    #[inline(always)]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl<T> PerCore<T> {
+    #[doc(hidden)]
+    #[allow(unused)]
+    pub const fn new(values: [T; MAX_CORES]) -> Self {
+        Self { values }
+    }
+
+    /// Returns the calling core's copy.
+    ///
+    /// The reference must not be kept across anything that could move the caller to another
+    /// core.
+    #[allow(unused)]
+    #[inline(always)]
+    pub fn get(&self) -> &T {
+        &self.values[this_core().id as usize]
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+static CORE_LOCALS: [CoreLocal; MAX_CORES] = [
+    CoreLocal::new(0),
+    CoreLocal::new(1),
+    CoreLocal::new(2),
+    CoreLocal::new(3),
+];
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl CoreLocal {
+    const fn new(id: u64) -> Self {
+        Self { id }
+    }
+}
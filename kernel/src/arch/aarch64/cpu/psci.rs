@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: MIT
+//! Power State Coordination Interface (PSCI) calls, used to power cores on and off.
+//!
+//! # Resources
+//!
+//! - <https://developer.arm.com/documentation/den0022/latest>
+
+use core::arch::asm;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// Powers down the calling core. Does not return on success.
+pub const CPU_OFF: u32 = 0x8400_0002;
+
+/// Powers up a core, starting it at a given physical address (SMC64/HVC64 calling convention).
+pub const CPU_ON_64: u32 = 0xC400_0003;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Powers down the calling core. Only returns if the firmware refused to do so.
+pub fn cpu_off() -> &'static str {
+    error_description(call(CPU_OFF, 0, 0, 0))
+}
+
+/// Powers up the core with the given MPIDR affinity value, starting it at `entry_pa` in the
+/// highest implemented EL up to the kernel's, with the MMU off, and `context_id` in `x0`.
+///
+/// # Safety
+///
+/// - `entry_pa` must be the physical address of code that's safe to run with the MMU off.
+pub unsafe fn cpu_on(
+    target_mpidr: u64,
+    entry_pa: usize,
+    context_id: u64,
+) -> Result<(), &'static str> {
+    // a core which has only just called CPU_OFF may still be on its way down
+    for _ in 0..CPU_ON_RETRIES {
+        match call(CPU_ON_64, target_mpidr, entry_pa as u64, context_id) {
+            SUCCESS => return Ok(()),
+            ALREADY_ON => continue,
+            err => return Err(error_description(err)),
+        }
+    }
+
+    Err(error_description(ALREADY_ON))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// How many times to retry `CPU_ON` while the target core is still powering down.
+const CPU_ON_RETRIES: usize = 1000;
+
+const SUCCESS: i64 = 0;
+const NOT_SUPPORTED: i64 = -1;
+const INVALID_PARAMETERS: i64 = -2;
+const DENIED: i64 = -3;
+const ALREADY_ON: i64 = -4;
+const ON_PENDING: i64 = -5;
+const INTERNAL_FAILURE: i64 = -6;
+const NOT_PRESENT: i64 = -7;
+const DISABLED: i64 = -8;
+const INVALID_ADDRESS: i64 = -9;
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+/// Calls into the PSCI firmware. QEMU's virt machine, like most firmware running the kernel at EL1,
+/// expects PSCI calls through `hvc`.
+fn call(function_id: u32, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let ret: i64;
+    unsafe {
+        asm!(
+            "hvc #0",
+            inlateout("x0") function_id as u64 => ret,
+            inlateout("x1") arg0 => _,
+            inlateout("x2") arg1 => _,
+            inlateout("x3") arg2 => _,
+            options(nomem, nostack),
+        );
+    }
+
+    ret
+}
+
+fn error_description(code: i64) -> &'static str {
+    match code {
+        SUCCESS => "success",
+        NOT_SUPPORTED => "not supported",
+        INVALID_PARAMETERS => "invalid parameters",
+        DENIED => "denied",
+        ALREADY_ON => "already on",
+        ON_PENDING => "on pending",
+        INTERNAL_FAILURE => "internal failure",
+        NOT_PRESENT => "not present",
+        DISABLED => "disabled",
+        INVALID_ADDRESS => "invalid address",
+        _ => "unknown error",
+    }
+}
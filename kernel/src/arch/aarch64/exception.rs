@@ -1,13 +1,17 @@
 use core::arch::global_asm;
 use core::cell::UnsafeCell;
 
-use aarch64_cpu::asm::barrier;
 use aarch64_cpu::registers::VBAR_EL1;
 use tock_registers::interfaces::Writeable;
 
-use context::ExceptionContext;
+pub use context::{AbortAccess, ExceptionContext, FaultStatus};
+use fkk::abi::{SYSCALL_NUMBER_REGISTER, SYSCALL_RETURN_REGISTER};
 
-use crate::exception;
+use crate::exec;
+use crate::mem::vm::paging::VirtualAddress;
+use crate::mem::{virtual_memory_manager, MemoryManager};
+use crate::sched::{self, PROCESS_RETURN_ADDRESS};
+use crate::{cpu, exception, syscall, util, warn};
 
 // SPDX-License-Identifier: MIT
 #[path = "exception/context.rs"]
@@ -29,34 +33,103 @@ pub unsafe fn init() {
     }
 
     VBAR_EL1.set(__exception_vector_start.get() as u64);
-    barrier::isb(barrier::SY);
+    cpu::barrier::isb();
 
     exception::asynchronous::setup_critical_section_handler();
 }
 
+/// Restores the given context and returns via `eret`, as if returning from an exception.
+/// The current stack is reused from the context onwards, so the caller never gets control back.
+///
+/// # Safety
+///
+/// - The context must be valid to enter, e.g. by having been created through one of the
+///   `ExceptionContext` constructors.
+pub unsafe fn enter_context(context: ExceptionContext) -> ! {
+    extern "Rust" {
+        // Defined in exception.S
+        fn __exception_enter_context(context: *const ExceptionContext) -> !;
+    }
+
+    exception::asynchronous::local_irq_mask();
+    __exception_enter_context(&context)
+}
+
 fn default_exception_handler(exc: &ExceptionContext) {
+    // the panic's own backtrace starts in the handler, so show where the exception came from too
+    if !exc.is_from_el0() {
+        util::backtrace_from(exc.pc() as usize, exc.frame_pointer());
+    }
+
     panic!("Unhandled CPU exception occurred!\n\n{}", exc);
 }
 
+/// Panics with the decoded cause of a data or instruction abort, if the exception is one.
+fn abort_exception_handler(exc: &ExceptionContext) {
+    if let Some(abort) = exc.abort_info() {
+        panic!("Unhandled {}\n\n{}", abort, exc);
+    }
+}
+
+/// Panics with a clear message if the exception is a kernel stack running into its guard page.
+fn check_kernel_stack_overflow(exc: &ExceptionContext) {
+    if !exc.is_data_abort() {
+        return;
+    }
+
+    if let Some(far) = exc.fault_address() {
+        if virtual_memory_manager().is_kernel_stack_guard(VirtualAddress(far)) {
+            panic!("Kernel stack overflow! (FAR_EL1: {:#018x})\n\n{}", far, exc);
+        }
+    }
+}
+
+/// Kills the current process, which faulted at `far` on a page that there's no memory left to
+/// back, rather than taking the kernel down with it.
+fn kill_out_of_memory(exc: &mut ExceptionContext, far: usize) {
+    warn!("Process out of memory (FAR_EL1: {:#018x}), killing it", far);
+    sched::scheduler().request_exit(-1);
+    sched::scheduler().handle_pending(exc);
+}
+
 // Current, EL0
 #[no_mangle]
-extern "C" fn eh_cel0_sync(_exc: &mut ExceptionContext) {
-    panic!("Use of SP_EL0 in EL1 is not allowed!");
+extern "C" fn eh_cel0_sync(exc: &mut ExceptionContext) {
+    #[cfg(all(feature = "selftest", debug_assertions))]
+    if exception::fault::catch_exception(exc) {
+        return;
+    }
+
+    // only kernel threads run on SP_EL0 in EL1
+    check_kernel_stack_overflow(exc);
+    abort_exception_handler(exc);
+    default_exception_handler(exc);
 }
 
 #[no_mangle]
-extern "C" fn eh_cel0_irq(_exc: &mut ExceptionContext) {
-    panic!("Use of SP_EL0 in EL1 is not allowed!");
+extern "C" fn eh_cel0_irq(exc: &mut ExceptionContext) {
+    let token = unsafe { &exception::asynchronous::CriticalSection::new() };
+    exception::asynchronous::irq_manager().handle_pending_irqs(token);
+
+    // kernel threads are switched the same way as processes
+    sched::scheduler().handle_pending(exc);
 }
 
 #[no_mangle]
-extern "C" fn eh_cel0_serror(_exc: &mut ExceptionContext) {
-    panic!("Use of SP_EL0 in EL1 is not allowed!");
+extern "C" fn eh_cel0_serror(exc: &mut ExceptionContext) {
+    default_exception_handler(exc);
 }
 
 // Current, ELx
 #[no_mangle]
 extern "C" fn eh_celx_sync(exc: &mut ExceptionContext) {
+    #[cfg(all(feature = "selftest", debug_assertions))]
+    if exception::fault::catch_exception(exc) {
+        return;
+    }
+
+    check_kernel_stack_overflow(exc);
+    abort_exception_handler(exc);
     default_exception_handler(exc);
 }
 
@@ -74,12 +147,93 @@ extern "C" fn eh_celx_serror(exc: &mut ExceptionContext) {
 // Lower, AArch64
 #[no_mangle]
 extern "C" fn eh_lower_aa64_sync(exc: &mut ExceptionContext) {
+    if exc.is_svc64() {
+        // the context is restored from the stack on return, so this lands in x0 after `eret`
+        let nr = exc.gpr(SYSCALL_NUMBER_REGISTER) as usize;
+        let ret = syscall::dispatch(nr, exc.syscall_args());
+        exc.set_gpr(SYSCALL_RETURN_REGISTER, ret as u64);
+        sched::scheduler().handle_pending(exc);
+        return;
+    }
+
+    #[cfg(debug_assertions)]
+    if exc.is_lower_el_software_step() {
+        sched::scheduler().handle_single_step(exc);
+        sched::scheduler().handle_pending(exc);
+        return;
+    }
+
+    // a process returning from its entry point lands on this (unmapped) address
+    if exc.is_lower_el_instruction_abort() && exc.pc() as usize == PROCESS_RETURN_ADDRESS {
+        // the return value of the entry point is in x0
+        sched::scheduler().request_exit(exc.gpr(0) as i32);
+        sched::scheduler().handle_pending(exc);
+        return;
+    }
+
+    // a write to a page shared with another process after a fork
+    if let Some(abort) = exc.abort_info() {
+        let is_write_permission_fault = abort.access() == AbortAccess::Write
+            && matches!(abort.status(), FaultStatus::Permission(_));
+        if let Some(far) = abort.address().filter(|_| is_write_permission_fault) {
+            match sched::scheduler().handle_cow_fault(far) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(_) => return kill_out_of_memory(exc, far),
+            }
+        }
+    }
+
+    // the first access to a page reserved for demand paging
+    if let Some(abort) = exc.abort_info() {
+        let is_translation_fault = matches!(abort.status(), FaultStatus::Translation(_));
+        if let Some(far) = abort.address().filter(|_| is_translation_fault) {
+            match sched::scheduler().handle_demand_fault(far) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(_) => return kill_out_of_memory(exc, far),
+            }
+        }
+    }
+
+    if exc.is_data_abort() {
+        if let Some(far) = exc
+            .fault_address()
+            .filter(|&far| exec::is_user_stack_guard(far))
+        {
+            warn!(
+                "Process stack overflow (FAR_EL1: {:#018x}), killing it",
+                far
+            );
+            sched::scheduler().request_exit(-1);
+            sched::scheduler().handle_pending(exc);
+            return;
+        }
+
+        // not anywhere the process is allowed to touch
+        match exc.fault_address() {
+            Some(far) => warn!(
+                "Process segmentation fault (FAR_EL1: {:#018x}), killing it",
+                far
+            ),
+            None => warn!("Process segmentation fault, killing it"),
+        }
+        sched::scheduler().request_exit(-1);
+        sched::scheduler().handle_pending(exc);
+        return;
+    }
+
+    abort_exception_handler(exc);
     default_exception_handler(exc);
 }
 
 #[no_mangle]
 extern "C" fn eh_lower_aa64_irq(exc: &mut ExceptionContext) {
-    default_exception_handler(exc);
+    let token = unsafe { &exception::asynchronous::CriticalSection::new() };
+    exception::asynchronous::irq_manager().handle_pending_irqs(token);
+
+    // the kernel itself isn't preemptible, so processes are only switched on the way back to EL0
+    sched::scheduler().handle_pending(exc);
 }
 
 #[no_mangle]
@@ -3,7 +3,7 @@ use core::arch::asm;
 
 use aarch64_cpu::registers::DAIF;
 use tock_registers::fields::Field;
-use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
 // Public code
 pub fn is_local_irq_masked() -> bool {
@@ -32,21 +32,107 @@ pub fn local_irq_mask() {
     }
 }
 
+#[allow(unused)]
+#[inline(always)]
+pub fn local_fiq_unmask() {
+    unsafe {
+        asm!(
+            "msr DAIFClr, {arg}",
+            arg = const daif_bits::FIQ,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+#[allow(unused)]
+#[inline(always)]
+pub fn local_fiq_mask() {
+    unsafe {
+        asm!(
+            "msr DAIFSet, {arg}",
+            arg = const daif_bits::FIQ,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+#[allow(unused)]
+#[inline(always)]
+pub fn local_serror_unmask() {
+    unsafe {
+        asm!(
+            "msr DAIFClr, {arg}",
+            arg = const daif_bits::SERROR,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+#[allow(unused)]
+#[inline(always)]
+pub fn local_serror_mask() {
+    unsafe {
+        asm!(
+            "msr DAIFSet, {arg}",
+            arg = const daif_bits::SERROR,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+/// Masks IRQs, returning the previous DAIF state, to be passed to [`local_irq_restore`].
+///
+/// Only the IRQ mask is changed; FIQs, SErrors and debug exceptions are left as they were.
 #[inline(always)]
 pub fn local_irq_mask_save() -> u64 {
-    let daif = DAIF.get();
+    let daif = local_daif_save();
     local_irq_mask();
 
     daif
 }
 
+/// Restores the IRQ mask saved by [`local_irq_mask_save`], leaving the other DAIF bits alone.
 #[inline(always)]
 pub fn local_irq_restore(flags: u64) {
+    DAIF.modify(DAIF::I.val(DAIF::I.read(flags)));
+}
+
+/// Masks IRQs, FIQs and SErrors, returning the previous DAIF state, to be passed to
+/// [`local_daif_restore`].
+///
+/// This is for critical sections that mustn't be interrupted by anything asynchronous, e.g. while
+/// switching page tables. Debug exceptions are left as they were.
+#[inline(always)]
+pub fn local_mask_all_save() -> u64 {
+    let daif = local_daif_save();
+    unsafe {
+        asm!(
+            "msr DAIFSet, {arg}",
+            arg = const daif_bits::SERROR | daif_bits::IRQ | daif_bits::FIQ,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+
+    daif
+}
+
+/// Returns the current DAIF state, without changing it.
+#[inline(always)]
+pub fn local_daif_save() -> u64 {
+    DAIF.get()
+}
+
+/// Restores every DAIF bit to the state saved by [`local_daif_save`] or [`local_mask_all_save`].
+#[inline(always)]
+pub fn local_daif_restore(flags: u64) {
     DAIF.set(flags);
 }
 
+/// The bits of the immediate taken by `msr DAIFSet`/`msr DAIFClr`.
 mod daif_bits {
+    pub const SERROR: u8 = 0b0100;
     pub const IRQ: u8 = 0b0010;
+    pub const FIQ: u8 = 0b0001;
 }
 
 trait DaifField {
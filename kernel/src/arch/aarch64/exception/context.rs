@@ -3,7 +3,10 @@ use core::fmt;
 use core::fmt::Formatter;
 
 use aarch64_cpu::registers::{ESR_EL1, FAR_EL1, SPSR_EL1};
-use tock_registers::interfaces::Readable;
+use fkk::abi::{SYSCALL_ARG_COUNT, SYSCALL_ARG_REGISTERS};
+#[cfg(debug_assertions)]
+use tock_registers::interfaces::ReadWriteable;
+use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::registers::InMemoryRegister;
 
 #[repr(transparent)]
@@ -11,7 +14,52 @@ struct SpsrEL1(InMemoryRegister<u64, SPSR_EL1::Register>);
 
 struct EsrEL1(InMemoryRegister<u64, ESR_EL1::Register>);
 
-#[repr(C)]
+// Fields of the ISS of data and instruction aborts.
+const ISS_FSC_MASK: u64 = 0b11_1111;
+const ISS_WNR: u64 = 1 << 6;
+const ISS_FNV: u64 = 1 << 10;
+
+/// The cause of a data or instruction abort, decoded from `ESR_EL1`.
+#[derive(Copy, Clone, Debug)]
+pub struct AbortInfo {
+    /// The kind of access that faulted.
+    access: AbortAccess,
+
+    /// Whether the abort was taken from EL0, rather than from EL1.
+    from_el0: bool,
+
+    /// The data or instruction fault status code.
+    status: FaultStatus,
+
+    /// The faulting virtual address, if `FAR_EL1` holds a valid one.
+    address: Option<usize>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AbortAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A decoded DFSC/IFSC field. Faults that happen during a translation table walk carry the level
+/// they happened at.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultStatus {
+    AddressSize(u8),
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    Alignment,
+    SynchronousExternal,
+    TlbConflict,
+    Other(u8),
+}
+
+/// The state saved on the stack when taking an exception, and restored on `eret`.
+///
+/// The layout must match `CALL_WITH_CONTEXT` and `__exception_restore_context` in `exception.S`.
+#[repr(C, align(16))]
 pub struct ExceptionContext {
     /// General purpose registers
     gpr: [u64; 30],
@@ -27,6 +75,9 @@ pub struct ExceptionContext {
 
     /// Exception syndrome register
     esr_el1: EsrEL1,
+
+    /// EL0 stack pointer
+    sp_el0: u64,
 }
 
 impl fmt::Display for SpsrEL1 {
@@ -80,6 +131,79 @@ impl EsrEL1 {
     fn exception_class(&self) -> Option<ESR_EL1::EC::Value> {
         self.0.read_as_enum(ESR_EL1::EC)
     }
+
+    #[inline(always)]
+    fn iss(&self) -> u64 {
+        self.0.read(ESR_EL1::ISS)
+    }
+}
+
+impl FaultStatus {
+    /// Decodes the 6-bit DFSC/IFSC field of a data or instruction abort ISS.
+    fn from_fsc(fsc: u8) -> Self {
+        let level = fsc & 0b11;
+        match fsc {
+            0b00_0000..=0b00_0011 => Self::AddressSize(level),
+            0b00_0100..=0b00_0111 => Self::Translation(level),
+            0b00_1000..=0b00_1011 => Self::AccessFlag(level),
+            0b00_1100..=0b00_1111 => Self::Permission(level),
+            0b01_0000 => Self::SynchronousExternal,
+            0b10_0001 => Self::Alignment,
+            0b11_0000 => Self::TlbConflict,
+            _ => Self::Other(fsc),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddressSize(level) => write!(f, "address size fault, level {}", level),
+            Self::Translation(level) => write!(f, "translation fault, level {}", level),
+            Self::AccessFlag(level) => write!(f, "access flag fault, level {}", level),
+            Self::Permission(level) => write!(f, "permission fault, level {}", level),
+            Self::Alignment => write!(f, "alignment fault"),
+            Self::SynchronousExternal => write!(f, "synchronous external abort"),
+            Self::TlbConflict => write!(f, "TLB conflict abort"),
+            Self::Other(fsc) => write!(f, "unknown fault (status code {:#04x})", fsc),
+        }
+    }
+}
+
+impl AbortInfo {
+    pub fn access(&self) -> AbortAccess {
+        self.access
+    }
+
+    #[allow(unused)]
+    pub fn is_from_el0(&self) -> bool {
+        self.from_el0
+    }
+
+    pub fn status(&self) -> FaultStatus {
+        self.status
+    }
+
+    pub fn address(&self) -> Option<usize> {
+        self.address
+    }
+}
+
+impl fmt::Display for AbortInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (kind, access) = match self.access {
+            AbortAccess::Read => ("Data abort", "read"),
+            AbortAccess::Write => ("Data abort", "write"),
+            AbortAccess::Execute => ("Instruction abort", "instruction fetch"),
+        };
+        let el = if self.from_el0 { "EL0" } else { "EL1" };
+
+        write!(f, "{} from {}: {} on {}", kind, el, self.status, access)?;
+        match self.address {
+            Some(address) => write!(f, " at {:#018x}", address),
+            None => write!(f, " at an unknown address"),
+        }
+    }
 }
 
 impl fmt::Display for EsrEL1 {
@@ -87,6 +211,10 @@ impl fmt::Display for EsrEL1 {
         writeln!(f, "ESR_EL1: {:#010x}", self.0.get())?;
         let ec_desc = match self.exception_class() {
             Some(ESR_EL1::EC::Value::DataAbortCurrentEL) => "Data abort (current EL)",
+            Some(ESR_EL1::EC::Value::DataAbortLowerEL) => "Data abort (lower EL)",
+            Some(ESR_EL1::EC::Value::InstrAbortCurrentEL) => "Instruction abort (current EL)",
+            Some(ESR_EL1::EC::Value::InstrAbortLowerEL) => "Instruction abort (lower EL)",
+            Some(ESR_EL1::EC::Value::SVC64) => "Supervisor call (AArch64)",
             _ => "Unknown",
         };
         writeln!(
@@ -104,11 +232,219 @@ impl fmt::Display for EsrEL1 {
 }
 
 impl ExceptionContext {
+    /// Creates a context which enters EL0 at `entry` with the given stack pointer, and IRQs
+    /// unmasked. Returning from `entry` jumps to `return_address`.
+    pub fn new_user(entry: usize, stack_top: usize, return_address: usize) -> Self {
+        let spsr = SpsrEL1(InMemoryRegister::new(0));
+        spsr.0.write(SPSR_EL1::M::EL0t);
+
+        Self {
+            gpr: [0; 30],
+            lr: return_address as u64,
+            elr_el1: entry as u64,
+            spsr_el1: spsr,
+            esr_el1: EsrEL1(InMemoryRegister::new(0)),
+            sp_el0: stack_top as u64,
+        }
+    }
+
+    /// Creates a context which enters EL1 at `entry` with `arg` in `x0`, and IRQs unmasked.
+    ///
+    /// The context runs on `SP_EL0` (EL1t), using the given stack, so that exceptions taken from it
+    /// are handled on the exception stack and it can be switched like a user process.
+    pub fn new_kernel(entry: usize, arg: usize, stack_top: usize) -> Self {
+        let spsr = SpsrEL1(InMemoryRegister::new(0));
+        spsr.0.write(SPSR_EL1::M::EL1t);
+
+        let mut gpr = [0; 30];
+        gpr[0] = arg as u64;
+
+        Self {
+            gpr,
+            lr: 0,
+            elr_el1: entry as u64,
+            spsr_el1: spsr,
+            esr_el1: EsrEL1(InMemoryRegister::new(0)),
+            sp_el0: stack_top as u64,
+        }
+    }
+
     #[inline(always)]
     fn exception_class(&self) -> Option<ESR_EL1::EC::Value> {
         self.esr_el1.exception_class()
     }
 
+    /// Returns the raw exception class from `ESR_EL1`, including classes with no name in
+    /// [`ESR_EL1::EC`].
+    #[allow(unused)]
+    #[inline(always)]
+    pub fn exception_class_code(&self) -> u8 {
+        self.esr_el1.0.read(ESR_EL1::EC) as u8
+    }
+
+    /// Returns true if the exception was caused by an `svc` instruction executed in AArch64 state.
+    #[inline(always)]
+    pub fn is_svc64(&self) -> bool {
+        matches!(self.exception_class(), Some(ESR_EL1::EC::Value::SVC64))
+    }
+
+    /// Returns the system call arguments, passed in `x0..x5`.
+    #[inline(always)]
+    pub fn syscall_args(&self) -> [u64; SYSCALL_ARG_COUNT] {
+        SYSCALL_ARG_REGISTERS.map(|reg| self.gpr(reg))
+    }
+
+    /// Returns true if the exception was an instruction abort taken from a lower exception level.
+    #[inline(always)]
+    pub fn is_lower_el_instruction_abort(&self) -> bool {
+        matches!(
+            self.exception_class(),
+            Some(ESR_EL1::EC::Value::InstrAbortLowerEL)
+        )
+    }
+
+    /// Returns true if the exception was a software step exception taken from a lower exception
+    /// level, i.e. a single-stepped instruction has completed.
+    #[cfg(debug_assertions)]
+    #[inline(always)]
+    pub fn is_lower_el_software_step(&self) -> bool {
+        matches!(
+            self.exception_class(),
+            Some(ESR_EL1::EC::Value::SoftwareStepLowerEL)
+        )
+    }
+
+    /// Sets whether the software step state machine is active-not-pending on return, so that a
+    /// step exception is taken after exactly one instruction. Only has an effect while software
+    /// step is enabled with `cpu::set_software_step`.
+    #[cfg(debug_assertions)]
+    #[inline(always)]
+    pub fn set_software_step(&mut self, enabled: bool) {
+        if enabled {
+            self.spsr_el1.0.modify(SPSR_EL1::SS::SET);
+        } else {
+            self.spsr_el1.0.modify(SPSR_EL1::SS::CLEAR);
+        }
+    }
+
+    /// Returns the value of general purpose register `xn`, where `x30` is the link register.
+    ///
+    /// Panics if `n` is greater than 30.
+    #[inline(always)]
+    pub fn gpr(&self, n: usize) -> u64 {
+        match n {
+            0..=29 => self.gpr[n],
+            30 => self.lr,
+            _ => panic!("no such register: x{}", n),
+        }
+    }
+
+    /// Sets general purpose register `xn`, which is restored on `eret`. `x30` is the link
+    /// register.
+    ///
+    /// Panics if `n` is greater than 30.
+    #[inline(always)]
+    pub fn set_gpr(&mut self, n: usize, value: u64) {
+        match n {
+            0..=29 => self.gpr[n] = value,
+            30 => self.lr = value,
+            _ => panic!("no such register: x{}", n),
+        }
+    }
+
+    /// Returns the program counter at the time of the exception, which is where `eret` resumes.
+    /// For a system call, this is the instruction after the `svc`.
+    #[inline(always)]
+    pub fn pc(&self) -> u64 {
+        self.elr_el1
+    }
+
+    /// Sets the address that `eret` resumes at.
+    #[allow(unused)]
+    #[inline(always)]
+    pub fn set_pc(&mut self, pc: u64) {
+        self.elr_el1 = pc;
+    }
+
+    /// Returns the stack pointer of the interrupted code, if it was running on `SP_EL0`, as user
+    /// processes and kernel threads do. The exception handlers' own `SP_EL1` isn't saved.
+    #[allow(unused)]
+    #[inline(always)]
+    pub fn stack_pointer(&self) -> Option<u64> {
+        let spsr = &self.spsr_el1.0;
+        let on_sp_el0 = spsr.matches_all(SPSR_EL1::M::EL0t) || spsr.matches_all(SPSR_EL1::M::EL1t);
+        on_sp_el0.then_some(self.sp_el0)
+    }
+
+    /// Returns the value of the frame pointer, `x29`.
+    #[inline(always)]
+    pub fn frame_pointer(&self) -> usize {
+        self.gpr(29) as usize
+    }
+
+    /// Returns true if the exception was taken from EL0.
+    #[inline(always)]
+    pub fn is_from_el0(&self) -> bool {
+        self.spsr_el1.0.matches_all(SPSR_EL1::M::EL0t)
+    }
+
+    /// Returns true if the exception was a data abort, taken from any exception level.
+    #[inline(always)]
+    pub fn is_data_abort(&self) -> bool {
+        matches!(
+            self.exception_class(),
+            Some(ESR_EL1::EC::Value::DataAbortLowerEL | ESR_EL1::EC::Value::DataAbortCurrentEL)
+        )
+    }
+
+    /// Decodes the cause of a data or instruction abort. Returns `None` for any other exception.
+    ///
+    /// `FAR_EL1` is read directly, so this must be called before anything else can fault.
+    pub fn abort_info(&self) -> Option<AbortInfo> {
+        use ESR_EL1::EC::Value::*;
+
+        let (data, from_el0) = match self.exception_class()? {
+            DataAbortLowerEL => (true, true),
+            DataAbortCurrentEL => (true, false),
+            InstrAbortLowerEL => (false, true),
+            InstrAbortCurrentEL => (false, false),
+            _ => return None,
+        };
+
+        let iss = self.esr_el1.iss();
+        let access = match (data, iss & ISS_WNR != 0) {
+            (false, _) => AbortAccess::Execute,
+            (true, false) => AbortAccess::Read,
+            (true, true) => AbortAccess::Write,
+        };
+
+        // FnV is only meaningful for synchronous external aborts, and is zero otherwise
+        let address = if iss & ISS_FNV == 0 {
+            self.fault_address()
+        } else {
+            None
+        };
+
+        Some(AbortInfo {
+            access,
+            from_el0,
+            status: FaultStatus::from_fsc((iss & ISS_FSC_MASK) as u8),
+            address,
+        })
+    }
+
+    /// Returns the faulting virtual address from `FAR_EL1`, if the exception reports one.
+    ///
+    /// `FAR_EL1` is read directly, so this must be called before anything else can fault.
+    #[inline(always)]
+    pub fn fault_address(&self) -> Option<usize> {
+        if self.fault_address_valid() {
+            Some(FAR_EL1.get() as usize)
+        } else {
+            None
+        }
+    }
+
     #[inline(always)]
     fn fault_address_valid(&self) -> bool {
         use ESR_EL1::EC::Value::*;
@@ -153,6 +489,20 @@ impl fmt::Display for ExceptionContext {
         for (i, reg) in self.gpr.iter().enumerate() {
             write!(f, "x{: <2}: {: >#018x}{}", i, reg, alternating(i))?;
         }
-        write!(f, "lr : {:#018x}", self.lr)
+        writeln!(f, "lr : {:#018x}", self.lr)?;
+        write!(f, "sp_el0: {:#018x}", self.sp_el0)
+    }
+}
+
+impl Clone for ExceptionContext {
+    fn clone(&self) -> Self {
+        Self {
+            gpr: self.gpr,
+            lr: self.lr,
+            elr_el1: self.elr_el1,
+            spsr_el1: SpsrEL1(InMemoryRegister::new(self.spsr_el1.0.get())),
+            esr_el1: EsrEL1(InMemoryRegister::new(self.esr_el1.0.get())),
+            sp_el0: self.sp_el0,
+        }
     }
 }
@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: MIT
+//! Architectural fault triggers.
+
+use core::arch::asm;
+
+#[cfg(feature = "selftest")]
+use crate::exception::ExceptionContext;
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// The first page of the lower half is never mapped, by the kernel or by user processes.
+const UNMAPPED_ADDRESS: usize = 0x0;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Loads from an unmapped address, causing a data abort (EC 0x25).
+pub unsafe fn data_abort() {
+    asm!("ldr {tmp}, [{addr}]", addr = in(reg) UNMAPPED_ADDRESS, tmp = out(reg) _);
+}
+
+/// Calls an unmapped address, causing an instruction abort (EC 0x21).
+pub unsafe fn instruction_abort() {
+    asm!("blr {addr}", addr = in(reg) UNMAPPED_ADDRESS, clobber_abi("C"));
+}
+
+/// Performs a misaligned exclusive load, causing an alignment fault (EC 0x25, DFSC 0x21).
+///
+/// Exclusive loads always require natural alignment, regardless of the memory type or
+/// `SCTLR_EL1.A`, so this faults even on normal memory.
+pub unsafe fn alignment_fault() {
+    let value: [u64; 2] = [0; 2];
+    let addr = value.as_ptr() as usize + 1;
+
+    asm!("ldxr {tmp}, [{addr}]", addr = in(reg) addr, tmp = out(reg) _);
+}
+
+/// Executes a permanently undefined instruction (EC 0x00).
+pub unsafe fn undefined_instruction() {
+    asm!("udf #0");
+}
+
+/// Makes a supervisor call from EL1 (EC 0x15).
+pub unsafe fn supervisor_call() {
+    asm!("svc #0");
+}
+
+/// Makes `exc`, taken for one of the faults above, resume in the trigger that raised it: back from
+/// the call for an instruction abort, after the `svc` for a supervisor call (where it already
+/// points), or otherwise after the faulting instruction.
+#[cfg(feature = "selftest")]
+pub fn resume_after(exc: &mut ExceptionContext) {
+    if exc.pc() as usize == UNMAPPED_ADDRESS {
+        exc.set_pc(exc.gpr(30));
+    } else if !exc.is_svc64() {
+        exc.set_pc(exc.pc() + 4);
+    }
+}
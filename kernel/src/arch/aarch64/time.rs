@@ -3,10 +3,24 @@ use core::num::{NonZeroU128, NonZeroU64};
 use core::ops::{Add, Div, Sub};
 use core::time::Duration;
 
-use aarch64_cpu::asm::barrier;
-use aarch64_cpu::registers::CNTPCT_EL0;
-use tock_registers::interfaces::Readable;
+use aarch64_cpu::registers::CNTFRQ_EL0;
+use tock_registers::interfaces::{Readable, Writeable};
 
+// The EL1 physical timer is used by default. Where the kernel doesn't own it, such as when running
+// as a guest under a hypervisor, the virtual timer can be selected with the `virtual_timer` feature
+// instead. Both count at the rate in `CNTFRQ_EL0`.
+#[cfg(not(feature = "virtual_timer"))]
+use aarch64_cpu::registers::{
+    CNTPCT_EL0 as CNTXCT_EL0, CNTP_CTL_EL0 as CNTX_CTL_EL0, CNTP_CVAL_EL0 as CNTX_CVAL_EL0,
+    CNTP_TVAL_EL0 as CNTX_TVAL_EL0,
+};
+#[cfg(feature = "virtual_timer")]
+use aarch64_cpu::registers::{
+    CNTVCT_EL0 as CNTXCT_EL0, CNTV_CTL_EL0 as CNTX_CTL_EL0, CNTV_CVAL_EL0 as CNTX_CVAL_EL0,
+    CNTV_TVAL_EL0 as CNTX_TVAL_EL0,
+};
+
+use crate::cpu;
 use crate::sync::OnceCell;
 use crate::warn;
 
@@ -30,6 +44,12 @@ impl KernelTimerData {
             kernel_boot_time: GenericTimerCounterValue(kernel_boot_time),
         }
     }
+
+    /// Reads the counter frequency, and takes the current value of the selected counter as the
+    /// kernel's boot time.
+    pub fn now() -> Self {
+        Self::new(CNTFRQ_EL0.get(), read_counter().0)
+    }
 }
 
 impl GenericTimerCounterValue {
@@ -106,10 +126,10 @@ impl TryFrom<Duration> for GenericTimerCounterValue {
 }
 
 #[inline(always)]
-fn read_cntpct() -> GenericTimerCounterValue {
+fn read_counter() -> GenericTimerCounterValue {
     // Prevent reordering of instructions from reading the counter ahead of time.
-    barrier::isb(barrier::SY);
-    let cnt = CNTPCT_EL0.get();
+    cpu::barrier::isb();
+    let cnt = CNTXCT_EL0.get();
 
     GenericTimerCounterValue(cnt)
 }
@@ -120,17 +140,59 @@ pub fn resolution() -> Duration {
 }
 
 pub fn uptime_sys() -> Duration {
-    read_cntpct().into()
+    read_counter().into()
+}
+
+/// Converts a duration into a number of architectural timer ticks, saturating at `u64::MAX`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    match GenericTimerCounterValue::try_from(duration) {
+        Ok(ticks) => ticks.0,
+        Err(_) => u64::MAX,
+    }
 }
 
 pub fn uptime_kernel() -> Duration {
-    let uptime = read_cntpct() - KERNEL_TIMER_DATA.kernel_boot_time;
+    let uptime = read_counter() - KERNEL_TIMER_DATA.kernel_boot_time;
 
     uptime.into()
 }
 
+/// The time since the kernel was loaded, in nanoseconds, without going through a `Duration`.
+pub fn uptime_kernel_nanos() -> u64 {
+    ticks_to_nanos((read_counter() - KERNEL_TIMER_DATA.kernel_boot_time).0)
+}
+
+/// The raw value of the counter, for measuring short intervals as cheaply as possible.
+#[inline(always)]
+pub fn counter_ticks() -> u64 {
+    read_counter().0
+}
+
+/// Converts a number of counter ticks into nanoseconds, saturating at `u64::MAX`.
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    let freq: NonZeroU64 = KERNEL_TIMER_DATA.arch_timer_counter_frequency;
+    let secs = ticks.div(freq);
+    let subsec = ticks % freq;
+
+    // As in the conversion to `Duration`, the frequency fits in a u32, so the multiplication of the
+    // sub-second ticks can't overflow.
+    secs.saturating_mul(u64::from(NANOSEC_PER_SEC))
+        .saturating_add((subsec * u64::from(NANOSEC_PER_SEC)).div(freq))
+}
+
+/// Converts a kernel uptime into the counter value it will be reached at.
+fn deadline_counter(deadline: Duration) -> Option<GenericTimerCounterValue> {
+    match GenericTimerCounterValue::try_from(deadline) {
+        Ok(ticks) => Some(KERNEL_TIMER_DATA.kernel_boot_time + ticks),
+        Err(msg) => {
+            warn!("deadline {:?}: {}", deadline, msg);
+            None
+        }
+    }
+}
+
 pub fn spin_for(duration: Duration) {
-    let start = read_cntpct();
+    let start = read_counter();
     let delta: GenericTimerCounterValue = match duration.try_into() {
         Err(msg) => {
             warn!("spin_for: {}", msg);
@@ -140,5 +202,74 @@ pub fn spin_for(duration: Duration) {
     };
     let target = start + delta;
 
-    while GenericTimerCounterValue(CNTPCT_EL0.get()) < target {}
+    while read_counter() < target {}
+}
+
+/// Spins until the kernel uptime reaches `deadline`. Returns `false` if it already had, or if the
+/// deadline is too far away to represent.
+pub fn spin_until(deadline: Duration) -> bool {
+    let Some(target) = deadline_counter(deadline) else {
+        return false;
+    };
+
+    if read_counter() >= target {
+        return false;
+    }
+
+    while read_counter() < target {}
+    true
+}
+
+/// Spins until `condition` returns `true`, or the kernel uptime reaches `deadline`, whichever comes
+/// first. Returns whether the condition became true.
+///
+/// The condition is checked once more after the deadline, so a slow check that straddles it doesn't
+/// cause a spurious timeout.
+pub fn poll_until(deadline: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let Some(target) = deadline_counter(deadline) else {
+        return condition();
+    };
+
+    loop {
+        if condition() {
+            return true;
+        }
+
+        if read_counter() >= target {
+            return condition();
+        }
+    }
+}
+
+/// Arms the EL1 timer to fire its interrupt once `duration` has elapsed.
+pub fn set_timeout(duration: Duration) -> Result<(), &'static str> {
+    let delta: GenericTimerCounterValue = duration.try_into()?;
+
+    // TVAL is a signed 32-bit down-counter.
+    if delta.0 > i32::MAX as u64 {
+        return Err("duration too large");
+    }
+
+    CNTX_TVAL_EL0.set(delta.0);
+    CNTX_CTL_EL0.write(CNTX_CTL_EL0::ENABLE::SET + CNTX_CTL_EL0::IMASK::CLEAR);
+
+    Ok(())
+}
+
+/// Re-arms the EL1 timer to fire `interval` after its previous deadline.
+///
+/// The interval is added to the previous compare value rather than the current time, so periodic
+/// timeouts don't drift by however long it took to handle the interrupt.
+pub fn rearm_timeout(interval: Duration) -> Result<(), &'static str> {
+    let delta: GenericTimerCounterValue = interval.try_into()?;
+    let target = GenericTimerCounterValue(CNTX_CVAL_EL0.get()) + delta;
+
+    CNTX_CVAL_EL0.set(target.0);
+
+    Ok(())
+}
+
+/// Disables the EL1 timer, deasserting its interrupt.
+pub fn cancel_timeout() {
+    CNTX_CTL_EL0.write(CNTX_CTL_EL0::ENABLE::CLEAR + CNTX_CTL_EL0::IMASK::SET);
 }
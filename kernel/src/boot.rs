@@ -2,44 +2,77 @@
 use alloc::boxed::Box;
 use limine::LimineBootInfoRequest;
 
+use crate::boot::milestone::Milestone;
 use crate::mem::{virtual_memory_manager, MemoryManager};
-use crate::{bsp, cpu, driver, exception, exec, info, mem, println, EARLY_INIT_COMPLETE};
+use crate::sync::EarlyInit;
+use crate::util::size_human_readable_ceil;
+use crate::{
+    bsp, console, cpu, driver, dt, exception, exec, info, mem, modules, println, sched, util,
+};
+
+pub mod milestone;
+mod progress;
+
+pub use progress::{current_stage, stage, Stage};
 
 static BOOTLOADER_INFO: LimineBootInfoRequest = LimineBootInfoRequest::new(0);
 
 /// # Safety
 /// - MMU & caching must be initialised first.
 pub unsafe fn kernel_init() -> ! {
+    milestone::record(Milestone::KernelInit);
+
     // set up exception handling, since we're about to invalidate the lower half of the address space
+    stage("exceptions");
     exception::init();
 
+    stage("virtual memory");
     virtual_memory_manager().init();
+    milestone::record(Milestone::VmmReady);
+
+    // copy the device tree out of bootloader memory while it's still around
+    stage("device tree");
+    dt::init();
+
+    // likewise for the kernel's symbols, so backtraces can be symbolized
+    util::symbols::init();
+
+    // and the names of the bootloader's modules, whose contents stay where they are
+    modules::init();
 
     // init the bsp drivers
     if let Err(x) = bsp::driver::init() {
         panic!("Failed to init bsp drivers: {}", x);
     }
 
+    // the token proving we're in early init, which is consumed once early init is complete
+    let early_init = EarlyInit::new();
+
     // init the interrupt controller first, so other drivers can register interrupts
-    driver::driver_manager().init_interrupt_controller();
+    stage("interrupt controller");
+    driver::driver_manager().init_interrupt_controller(&early_init);
 
     // unmask interrupts on the boot core
     exception::asynchronous::local_irq_unmask();
 
     // init early drivers, so we can print debug information
-    driver::driver_manager().init_early();
+    stage("early drivers");
+    driver::driver_manager().init_early(&early_init);
 
     // lock any init state locks
-    EARLY_INIT_COMPLETE.store(true, core::sync::atomic::Ordering::Relaxed);
+    early_init.complete();
 
     // serial out is now usable, load other drivers
+    stage("drivers");
     driver::driver_manager().init_normal();
+    milestone::record(Milestone::DriversReady);
 
     // exiting unsafe code, time to bootstrap the rest of the system
     kernel_main()
 }
 
 fn kernel_main() -> ! {
+    stage("kernel main");
     println!(
         r#"
     ______
@@ -62,8 +95,21 @@ flow v{}, built at {}"#,
 
     println!();
 
+    info!("CPU: {}", cpu::features());
     mem::print_physical_memory_map();
 
+    // all bootloader responses have been consumed by now, so their memory can be reused
+    let reclaimed = unsafe { virtual_memory_manager().reclaim_bootloader_memory() };
+    let (size, unit) = size_human_readable_ceil(reclaimed);
+    info!("Reclaimed {} {} of bootloader memory", size, unit);
+    mem::print_stats();
+    info!("Console: {}", console::console_stats());
+
+    match dt::blob() {
+        Some(blob) => info!("Device tree: {} bytes", blob.len()),
+        None => info!("Device tree: none"),
+    }
+
     info!("Loaded drivers:");
     driver::driver_manager().enumerate();
 
@@ -77,8 +123,12 @@ flow v{}, built at {}"#,
     info!("x = {}", x);
 
     // exec::read_test_executable();
-    exec::load_test_executable();
+    exec::load_test_executable(&["test_executable"]);
+
+    // the self-tests run as a kernel thread, so they can wait for the tasks they spawn
+    #[cfg(feature = "selftest")]
+    sched::kthread::spawn_kthread("selftest", crate::selftest::run_all);
 
-    info!("Entering infinite idle loop.");
-    cpu::wait_forever()
+    progress::finish();
+    sched::scheduler().start()
 }
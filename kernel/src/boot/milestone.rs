@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: MIT
+//! Boot milestone tracking, used to measure how long each phase of the boot process takes.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+use crate::info;
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A key point in the boot process, in the order they're expected to be reached.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Milestone {
+    /// The kernel has started initialising, right after leaving the architectural entry point.
+    KernelInit,
+
+    /// The virtual memory manager has switched to the kernel's own page tables.
+    VmmReady,
+
+    /// All drivers loaded at boot have been initialised.
+    DriversReady,
+
+    /// The first user process is about to be entered.
+    FirstUserProcess,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+impl Milestone {
+    const COUNT: usize = 4;
+
+    const ALL: [Milestone; Self::COUNT] = [
+        Milestone::KernelInit,
+        Milestone::VmmReady,
+        Milestone::DriversReady,
+        Milestone::FirstUserProcess,
+    ];
+
+    /// A short human readable description of the milestone.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Milestone::KernelInit => "kernel init",
+            Milestone::VmmReady => "vmm ready",
+            Milestone::DriversReady => "drivers ready",
+            Milestone::FirstUserProcess => "first user process",
+        }
+    }
+}
+
+/// Records the current kernel uptime as the time at which the given milestone was reached.
+///
+/// If a milestone is reached more than once, only the first time is kept.
+pub fn record(milestone: Milestone) {
+    let timestamp = crate::time::time_manager().uptime_kernel();
+
+    MILESTONES.lock(|milestones| {
+        let slot = &mut milestones[milestone as usize];
+        if slot.is_none() {
+            *slot = Some(timestamp);
+        }
+    });
+}
+
+/// Prints a table of all recorded milestones, with the time since boot at which each was reached,
+/// and the time spent in the phase leading up to it.
+pub fn print_summary() {
+    let milestones = MILESTONES.lock(|milestones| *milestones);
+
+    let mut summary = String::new();
+    format_summary(&milestones, &mut summary).expect("failed to format boot milestones");
+
+    info!("Boot milestones:");
+    for line in summary.lines() {
+        info!("    {}", line);
+    }
+}
+
+/// Writes one line per milestone to `out`, with the time since boot at which it was reached, if it
+/// was, and the time spent in the phase leading up to it. `milestones` is indexed by [`Milestone`].
+///
+/// A milestone reached before the one preceding it is marked as out of order.
+pub fn format_summary(
+    milestones: &[Option<Duration>; Milestone::COUNT],
+    out: &mut impl Write,
+) -> fmt::Result {
+    let mut previous = Duration::ZERO;
+    for milestone in Milestone::ALL {
+        match milestones[milestone as usize] {
+            Some(at) => {
+                let phase = at.saturating_sub(previous);
+                write!(
+                    out,
+                    "{:<20} {:>3}.{:06} (+{}.{:06})",
+                    milestone.name(),
+                    at.as_secs(),
+                    at.subsec_micros(),
+                    phase.as_secs(),
+                    phase.subsec_micros()
+                )?;
+                if at < previous {
+                    write!(out, " out of order")?;
+                }
+                writeln!(out)?;
+                previous = at;
+            }
+            None => writeln!(out, "{:<20} not reached", milestone.name())?,
+        }
+    }
+
+    Ok(())
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+static MILESTONES: IRQSafeNullLock<[Option<Duration>; Milestone::COUNT]> =
+    IRQSafeNullLock::new([None; Milestone::COUNT]);
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::string::String;
+    use core::time::Duration;
+
+    use super::{format_summary, Milestone, MILESTONES};
+    use crate::selftest::SelfTest;
+    use crate::sync::interface::Mutex;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "milestone::format_summary",
+            run: format_known_milestones,
+        },
+        SelfTest {
+            name: "milestone::format_summary out of order",
+            run: format_out_of_order,
+        },
+        SelfTest {
+            name: "milestone::recorded milestones are monotonic",
+            run: recorded_milestones_are_monotonic,
+        },
+    ];
+
+    fn format(milestones: &[Option<Duration>; Milestone::COUNT]) -> String {
+        let mut out = String::new();
+        format_summary(milestones, &mut out).unwrap();
+        out
+    }
+
+    fn format_known_milestones() {
+        let out = format(&[
+            Some(Duration::from_micros(1_500)),
+            Some(Duration::from_micros(2_250_000)),
+            None,
+            Some(Duration::from_secs(12)),
+        ]);
+
+        assert_eq!(
+            out,
+            "kernel init            0.001500 (+0.001500)\n\
+             vmm ready              2.250000 (+2.248500)\n\
+             drivers ready        not reached\n\
+             first user process    12.000000 (+9.750000)\n"
+        );
+    }
+
+    fn format_out_of_order() {
+        let out = format(&[
+            Some(Duration::from_secs(2)),
+            Some(Duration::from_secs(1)),
+            None,
+            None,
+        ]);
+
+        let mut lines = out.lines();
+        assert!(!lines.next().unwrap().ends_with("out of order"));
+        assert!(lines.next().unwrap().ends_with("(+0.000000) out of order"));
+    }
+
+    /// Parses the time each reached milestone was printed with, in microseconds.
+    fn printed_times(summary: &str) -> impl Iterator<Item = u64> + '_ {
+        summary.lines().filter_map(|line| {
+            let at = line.get(20..)?.split_whitespace().next()?;
+            let (secs, micros) = at.split_once('.')?;
+            Some(secs.parse::<u64>().ok()? * 1_000_000 + micros.parse::<u64>().ok()?)
+        })
+    }
+
+    fn recorded_milestones_are_monotonic() {
+        let milestones = MILESTONES.lock(|milestones| *milestones);
+        let summary = format(&milestones);
+
+        // every milestone up to the drivers being loaded has been reached by the time this runs
+        assert!(printed_times(&summary).count() > Milestone::DriversReady as usize);
+        assert!(!summary.contains("out of order"), "{}", summary);
+
+        let mut previous = 0;
+        for at in printed_times(&summary) {
+            assert!(
+                at >= previous,
+                "boot milestones aren't monotonic:\n{}",
+                summary
+            );
+            previous = at;
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MIT
+//! Boot stage tracking, so a hang or panic during boot can be pinned to the step it happened in.
+//!
+//! Unlike [milestones](super::milestone), which summarise the boot once it's done, stages are
+//! logged as they're entered and left, and the current one is always available.
+
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+use crate::{info, time};
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A step of the boot process.
+#[derive(Copy, Clone, Debug)]
+pub struct Stage {
+    /// A short human readable name, e.g. `virtual memory`.
+    pub name: &'static str,
+
+    /// The kernel uptime at which the stage was entered, in nanoseconds.
+    pub started: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Leaves the current boot stage, if any, and enters the one called `name`, logging both along
+/// with how long the stage that was left took.
+pub fn stage(name: &'static str) {
+    let now = time::now_nanos();
+    let previous = CURRENT_STAGE.lock(|current| current.replace(Stage { name, started: now }));
+
+    if let Some(previous) = previous {
+        log_finished(previous, now);
+    }
+    info!("boot: entering {}", name);
+}
+
+/// Leaves the current boot stage, once the kernel has finished booting.
+pub fn finish() {
+    let now = time::now_nanos();
+    if let Some(previous) = CURRENT_STAGE.lock(|current| current.take()) {
+        log_finished(previous, now);
+    }
+}
+
+/// Returns the boot stage the kernel is in, or `None` if it has finished booting.
+pub fn current_stage() -> Option<Stage> {
+    CURRENT_STAGE.lock(|current| *current)
+}
+
+impl Stage {
+    /// Returns how long the kernel has been in this stage, in nanoseconds.
+    pub fn elapsed(&self) -> u64 {
+        time::now_nanos().saturating_sub(self.started)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+static CURRENT_STAGE: IRQSafeNullLock<Option<Stage>> = IRQSafeNullLock::new(None);
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+fn log_finished(stage: Stage, now: u64) {
+    let elapsed = now.saturating_sub(stage.started);
+    info!(
+        "boot: {} done in {}.{:03} ms",
+        stage.name,
+        elapsed / 1_000_000,
+        elapsed % 1_000_000 / 1000
+    );
+}
@@ -1,24 +1,98 @@
 // SPDX-License-Identifier: MIT
+use core::fmt::{self, Write};
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::bsp::exception::asynchronous::irq_map;
-use crate::bsp::mem::map::mmio;
+use crate::driver::block::interface::BlockDevice;
 use crate::driver::interrupt::gicv2::GICv2;
+use crate::driver::timer::ArmGenericTimer;
+use crate::driver::uart::interface::Uart;
+#[cfg(feature = "uart_ns16550")]
+use crate::driver::uart::Ns16550Uart;
+use crate::driver::uart::PL011EarlyWriter;
+#[cfg(not(feature = "uart_ns16550"))]
 use crate::driver::uart::PL011Uart;
+use crate::driver::video::FramebufferConsole;
+use crate::driver::virtio::VirtioBlk;
+use crate::sync::EarlyInit;
 
-use crate::{console, driver};
+use crate::{console, driver, info, mem};
 
-static INTERRUPT_CONTROLLER: GICv2 = unsafe { GICv2::new(mmio::GICD_START, mmio::GICC_START) };
+/// Where the PL011 always is on the QEMU virt machine, for printing before the device tree has
+/// been probed.
+const EARLY_UART_PHYS: usize = 0x0900_0000;
 
-static PL011_UART: PL011Uart = unsafe { PL011Uart::new(mmio::PL011_UART_START) };
+// MMIO addresses are discovered from the device tree during probe
+static INTERRUPT_CONTROLLER: GICv2 = unsafe { GICv2::new(0, 0) };
 
-fn post_init_uart() -> Result<(), &'static str> {
-    console::register_console(&PL011_UART);
+// the console UART is a PL011, unless the `uart_ns16550` feature selects a 16550 compatible one
+#[cfg(not(feature = "uart_ns16550"))]
+static UART: PL011Uart = unsafe { PL011Uart::new(0) };
+#[cfg(feature = "uart_ns16550")]
+static UART: Ns16550Uart = unsafe { Ns16550Uart::new(0, NS16550_REG_STRIDE, NS16550_CLOCK_HZ) };
+
+/// The distance between the 16550's registers, in bytes.
+#[cfg(feature = "uart_ns16550")]
+const NS16550_REG_STRIDE: usize = 1;
+
+/// The 16550's input clock, the classic 1.8432 MHz crystal.
+#[cfg(feature = "uart_ns16550")]
+const NS16550_CLOCK_HZ: u32 = 1_843_200;
+
+static ARCH_TIMER: ArmGenericTimer = ArmGenericTimer::new();
+
+// the framebuffer is found through the bootloader rather than the device tree
+static FRAMEBUFFER_CONSOLE: FramebufferConsole = FramebufferConsole::new();
+
+// the disk is polled, so it has no IRQ
+static VIRTIO_BLK: VirtioBlk = unsafe { VirtioBlk::new(0) };
+
+/// Writes straight to the UART through the direct map, for output before any console has been
+/// registered. Both the bootloader's direct map and the kernel's cover the UART.
+pub fn early_console_write(args: fmt::Arguments) {
+    // Safe because the UART is at this address on every QEMU virt machine.
+    let mut uart =
+        unsafe { PL011EarlyWriter::new(mem::direct_map_virt_offset() + EARLY_UART_PHYS) };
+    uart.write_fmt(args).ok();
+}
+
+/// Switches the console over to the framebuffer, if the bootloader provided one.
+#[allow(unused)]
+pub fn select_framebuffer_console() -> Result<(), &'static str> {
+    if !FRAMEBUFFER_CONSOLE.is_present() {
+        return Err("no framebuffer available");
+    }
+
+    console::register_console(&FRAMEBUFFER_CONSOLE);
     Ok(())
 }
 
-fn post_init_interrupt_controller() -> Result<(), &'static str> {
-    crate::exception::asynchronous::register_irq_manager(&INTERRUPT_CONTROLLER);
+/// The virtio disk, which has to be loaded with [`VirtioBlk::COMPATIBLE`] before it's used.
+#[allow(unused)]
+pub fn disk() -> &'static (dyn BlockDevice + Sync) {
+    &VIRTIO_BLK
+}
+
+/// Switches the console back to the UART.
+#[allow(unused)]
+pub fn select_uart_console() {
+    console::register_console(&UART);
+}
+
+fn post_init_uart(_early_init: Option<&EarlyInit>) -> Result<(), &'static str> {
+    console::register_console(&UART);
+    info!(
+        "console: {} UART at {} baud",
+        UART.chip_name(),
+        UART.baud_rate()
+    );
+    Ok(())
+}
+
+fn post_init_interrupt_controller(early_init: Option<&EarlyInit>) -> Result<(), &'static str> {
+    let early_init =
+        early_init.ok_or("the interrupt controller can only be registered during early init")?;
+    crate::exception::asynchronous::register_irq_manager(early_init, &INTERRUPT_CONTROLLER);
 
     Ok(())
 }
@@ -35,16 +109,36 @@ fn driver_interrupt_controller() -> Result<(), &'static str> {
 }
 
 fn driver_uart() -> Result<(), &'static str> {
-    let uart_descriptor = driver::DeviceDriverDescriptor::new(
-        &PL011_UART,
-        Some(post_init_uart),
-        Some(&irq_map::PL011_UART),
-    );
+    let uart_descriptor =
+        driver::DeviceDriverDescriptor::new(&UART, Some(post_init_uart), Some(&irq_map::UART));
     driver::driver_manager().register(uart_descriptor);
 
     Ok(())
 }
 
+fn driver_timer() -> Result<(), &'static str> {
+    let timer_descriptor =
+        driver::DeviceDriverDescriptor::new(&ARCH_TIMER, None, Some(&irq_map::ARCH_TIMER));
+    driver::driver_manager().register(timer_descriptor);
+
+    Ok(())
+}
+
+fn driver_framebuffer() -> Result<(), &'static str> {
+    let framebuffer_descriptor =
+        driver::DeviceDriverDescriptor::new(&FRAMEBUFFER_CONSOLE, None, None);
+    driver::driver_manager().register(framebuffer_descriptor);
+
+    Ok(())
+}
+
+fn driver_virtio_blk() -> Result<(), &'static str> {
+    let virtio_blk_descriptor = driver::DeviceDriverDescriptor::new(&VIRTIO_BLK, None, None);
+    driver::driver_manager().register(virtio_blk_descriptor);
+
+    Ok(())
+}
+
 // fn driver_fw_cfg() -> Result<(), &'static str> {
 //     let fw_cfg_descriptor = driver::DeviceDriverDescriptor::new(&FW_CFG, None);
 //     driver::driver_manager().register(fw_cfg_descriptor);
@@ -60,6 +154,9 @@ pub unsafe fn init() -> Result<(), &'static str> {
 
     driver_interrupt_controller()?;
     driver_uart()?;
+    driver_timer()?;
+    driver_framebuffer()?;
+    driver_virtio_blk()?;
     // driver_fw_cfg()?;
     INIT_DONE.store(true, Ordering::Relaxed);
     Ok(())
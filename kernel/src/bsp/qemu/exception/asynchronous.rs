@@ -4,5 +4,12 @@ pub use crate::driver::interrupt::gicv2::IRQNumber;
 pub(in crate::bsp) mod irq_map {
     use super::IRQNumber;
 
-    pub const PL011_UART: IRQNumber = IRQNumber::new(33);
+    /// EL1 physical timer (PPI 14).
+    #[cfg(not(feature = "virtual_timer"))]
+    pub const ARCH_TIMER: IRQNumber = IRQNumber::new(30);
+    /// EL1 virtual timer (PPI 11).
+    #[cfg(feature = "virtual_timer")]
+    pub const ARCH_TIMER: IRQNumber = IRQNumber::new(27);
+    /// The console UART (SPI 1), whichever chip it is.
+    pub const UART: IRQNumber = IRQNumber::new(33);
 }
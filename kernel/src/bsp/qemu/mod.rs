@@ -1,4 +1,3 @@
 // SPDX-License-Identifier: MIT
 pub mod driver;
 pub mod exception;
-pub mod mem;
@@ -1,9 +1,13 @@
 // SPDX-License-Identifier: MIT
-use core::fmt::Arguments;
+use core::fmt::{self, Arguments};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-use crate::console::interface::{All, Statistics, Write};
+use crate::console::interface::{All, Read, Statistics, Write};
 use crate::sync::interface::Mutex;
 use crate::sync::IRQSafeNullLock;
+use crate::time;
+
+pub mod ansi;
 
 pub mod interface {
     use core::fmt;
@@ -11,6 +15,14 @@ pub mod interface {
     pub trait Write {
         fn write_char(&self, c: char);
 
+        /// Writes a whole string. Consoles which can output a string faster than one character
+        /// at a time, e.g. by taking their lock only once, should override this.
+        fn write_str(&self, s: &str) {
+            for c in s.chars() {
+                self.write_char(c);
+            }
+        }
+
         fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result;
 
         fn flush(&self);
@@ -34,9 +46,19 @@ pub mod interface {
         fn get_rx_count(&self) -> usize {
             0
         }
+
+        /// Returns roughly how many bytes per second have recently been written to the console.
+        fn get_tx_bytes_per_sec(&self) -> usize {
+            0
+        }
+
+        /// Returns roughly how many bytes per second have recently been read from the console.
+        fn get_rx_bytes_per_sec(&self) -> usize {
+            0
+        }
     }
 
-    pub trait All: Write + Statistics {}
+    pub trait All: Write + Read + Statistics {}
 }
 
 struct NullConsole;
@@ -57,6 +79,10 @@ impl Write for NullConsole {
     fn flush(&self) {}
 }
 
+impl Read for NullConsole {
+    fn clear_rx(&self) {}
+}
+
 impl Statistics for NullConsole {}
 
 impl All for NullConsole {}
@@ -71,4 +97,170 @@ pub fn console() -> &'static dyn All {
 
 pub fn register_console(con: &'static (dyn All + Sync)) {
     CUR_CONSOLE.lock(|cur| *cur = con);
+    CONSOLE_REGISTERED.store(true, Ordering::Release);
+}
+
+/// Whether a console has been registered yet. Until one is, [`console`] discards all output.
+pub fn is_registered() -> bool {
+    CONSOLE_REGISTERED.load(Ordering::Acquire)
+}
+
+/// Whether log output is coloured with ANSI escape sequences.
+///
+/// This starts out enabled if the kernel was built with the `ansi_color` feature.
+pub fn colors_enabled() -> bool {
+    COLORS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turns colouring log output on or off, e.g. to keep serial logs captured to a file plain.
+#[allow(unused)]
+pub fn set_colors_enabled(enabled: bool) {
+    COLORS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// A snapshot of the current console's traffic, from [`console_stats`].
+#[derive(Copy, Clone, Debug)]
+pub struct ConsoleStats {
+    pub tx_bytes: usize,
+    pub rx_bytes: usize,
+    pub tx_bytes_per_sec: usize,
+    pub rx_bytes_per_sec: usize,
+}
+
+/// Returns how much has been written to and read from the current console, and how quickly.
+///
+/// A write rate far above what's normal for the console usually means something is logging in a
+/// loop.
+pub fn console_stats() -> ConsoleStats {
+    let con = console();
+    ConsoleStats {
+        tx_bytes: con.get_tx_count(),
+        rx_bytes: con.get_rx_count(),
+        tx_bytes_per_sec: con.get_tx_bytes_per_sec(),
+        rx_bytes_per_sec: con.get_rx_bytes_per_sec(),
+    }
+}
+
+impl fmt::Display for ConsoleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tx {} bytes ({} B/s), rx {} bytes ({} B/s)",
+            self.tx_bytes, self.tx_bytes_per_sec, self.rx_bytes, self.rx_bytes_per_sec
+        )
+    }
+}
+
+/// Counts the bytes passing one way through a console, and estimates their rate over the last
+/// [`ThroughputCounter::WINDOW_NANOS`].
+///
+/// Everything is atomic, so a driver can count from its IRQ handler and from writers at once
+/// through a shared reference.
+pub struct ThroughputCounter {
+    total: AtomicUsize,
+    /// When the current window started, in nanoseconds of uptime.
+    window_start: AtomicU64,
+    window_bytes: AtomicUsize,
+    /// The rate over the last complete window.
+    last_rate: AtomicUsize,
 }
+
+impl ThroughputCounter {
+    /// How long each window the rate is estimated over lasts.
+    pub const WINDOW_NANOS: u64 = 1_000_000_000;
+
+    pub const fn new() -> Self {
+        Self {
+            total: AtomicUsize::new(0),
+            window_start: AtomicU64::new(0),
+            window_bytes: AtomicUsize::new(0),
+            last_rate: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts `bytes` more bytes.
+    pub fn add(&self, bytes: usize) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+        let window_bytes = self.window_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+        let now = time::now_nanos();
+        let start = self.window_start.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(start);
+        if elapsed < Self::WINDOW_NANOS {
+            return;
+        }
+
+        // whoever moves the window on records its rate; bytes counted since the load above stay
+        // in the new window
+        if self
+            .window_start
+            .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.window_bytes.fetch_sub(window_bytes, Ordering::Relaxed);
+            self.last_rate
+                .store(Self::rate(window_bytes, elapsed), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the total number of bytes counted.
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Returns the rate over the last complete window, in bytes per second. If nothing has been
+    /// counted for longer than a window, the rate since then is returned instead, so it drops off
+    /// once traffic stops.
+    pub fn bytes_per_sec(&self) -> usize {
+        let elapsed = time::now_nanos().saturating_sub(self.window_start.load(Ordering::Relaxed));
+        if elapsed >= Self::WINDOW_NANOS {
+            return Self::rate(self.window_bytes.load(Ordering::Relaxed), elapsed);
+        }
+
+        self.last_rate.load(Ordering::Relaxed)
+    }
+
+    fn rate(bytes: usize, elapsed_nanos: u64) -> usize {
+        (bytes as u64 * 1_000_000_000 / elapsed_nanos.max(1)) as usize
+    }
+}
+
+/// Reads a line of input from the console into `buf`, echoing it back as it's typed, and returns
+/// the number of bytes read. The line ending is not included.
+///
+/// Backspace erases the previous character. Characters typed once `buf` is full are discarded,
+/// until the line is ended.
+#[allow(unused)]
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let con = console();
+    let mut len: usize = 0;
+
+    loop {
+        // the console converts a carriage return to a newline
+        match con.read_char() {
+            '\n' => {
+                con.write_char('\n');
+                return len;
+            }
+            BACKSPACE | DELETE => {
+                if len > 0 {
+                    len -= 1;
+                    // move back, blank out the character, then move back again
+                    con.write_str("\x08 \x08");
+                }
+            }
+            c if len < buf.len() => {
+                buf[len] = c as u8;
+                len += 1;
+                con.write_char(c);
+            }
+            _ => {}
+        }
+    }
+}
+
+static CONSOLE_REGISTERED: AtomicBool = AtomicBool::new(false);
+static COLORS_ENABLED: AtomicBool = AtomicBool::new(cfg!(feature = "ansi_color"));
+
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7f';
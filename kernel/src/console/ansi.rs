@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: MIT
+//! ANSI escape sequences, for consoles that render text themselves.
+//!
+//! Only the subset the kernel emits is understood: SGR colours, cursor positioning, and erasing the
+//! display or a line. Anything else is consumed and ignored, so it never shows up as garbage.
+//!
+//! # Resources
+//!
+//! - <https://www.ecma-international.org/publications-and-standards/standards/ecma-48/>
+
+use core::fmt;
+
+use crate::console;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The most numeric parameters kept for a single control sequence. Any more are dropped.
+pub const MAX_PARAMS: usize = 8;
+
+/// An SGR (Select Graphic Rendition) sequence, written only while colours are enabled on the
+/// console.
+#[derive(Copy, Clone)]
+pub struct Style(&'static str);
+
+/// The numeric parameters of a control sequence.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+/// Something the console should do, decoded from its output.
+#[derive(Copy, Clone, Debug)]
+pub enum Action {
+    /// Print a character, or handle a control character such as `\n`.
+    Print(char),
+    /// `ESC [ n ; ... m`: change the colours and attributes of subsequent text.
+    SelectGraphicRendition(Params),
+    /// `ESC [ row ; column H`: move the cursor. Both are zero-based here.
+    CursorPosition { row: usize, column: usize },
+    /// `ESC [ n J`: erase part of the display.
+    EraseDisplay(EraseMode),
+    /// `ESC [ n K`: erase part of the current line.
+    EraseLine(EraseMode),
+}
+
+/// Which part of the display or line to erase.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EraseMode {
+    /// From the cursor to the end.
+    ToEnd,
+    /// From the start to the cursor.
+    ToCursor,
+    /// Everything.
+    All,
+}
+
+/// Decodes a stream of characters into [`Action`]s.
+pub struct Parser {
+    state: State,
+    params: Params,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+impl Style {
+    pub const RESET: Self = Self("0");
+    pub const DIM: Self = Self("2");
+    pub const RED: Self = Self("31");
+    pub const YELLOW: Self = Self("33");
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !console::colors_enabled() {
+            return Ok(());
+        }
+
+        write!(f, "\x1b[{}m", self.0)
+    }
+}
+
+impl Params {
+    /// Returns the parameter at `index`, or `default` if it's missing or zero.
+    pub fn get(&self, index: usize, default: u16) -> u16 {
+        match self.values[..self.len].get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+
+    /// Returns the parameters in order. An empty parameter list is treated as a single `0`.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        let values = match self.len {
+            0 => &[0][..],
+            len => &self.values[..len],
+        };
+
+        values.iter().copied()
+    }
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: Params {
+                values: [0; MAX_PARAMS],
+                len: 0,
+            },
+        }
+    }
+
+    /// Feeds the next character of output into the parser, returning what to do with it, if
+    /// anything.
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.params = Params::default();
+                    self.state = State::ControlSequence;
+                    None
+                }
+                // two character sequences aren't supported; drop them
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::ControlSequence => match c {
+                '0'..='9' => {
+                    if self.params.len == 0 {
+                        self.params.len = 1;
+                    }
+
+                    if let Some(value) = self.params.values.get_mut(self.params.len - 1) {
+                        let digit = c as u16 - '0' as u16;
+                        *value = value.saturating_mul(10).saturating_add(digit);
+                    }
+                    None
+                }
+                ';' => {
+                    // an empty parameter before the separator still counts
+                    self.params.len = (self.params.len.max(1) + 1).min(MAX_PARAMS + 1);
+                    None
+                }
+                // parameter and intermediate bytes we don't use, e.g. the `?` of private modes
+                '\x20'..='\x3f' => None,
+                '\x40'..='\x7e' => {
+                    self.state = State::Ground;
+                    self.params.len = self.params.len.min(MAX_PARAMS);
+                    self.dispatch(c)
+                }
+                // anything else aborts the sequence
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+const ESC: char = '\x1b';
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    /// Plain text.
+    Ground,
+    /// After an `ESC`.
+    Escape,
+    /// After an `ESC [`, collecting parameters until the final byte.
+    ControlSequence,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl Parser {
+    fn dispatch(&self, final_byte: char) -> Option<Action> {
+        let params = self.params;
+        match final_byte {
+            'm' => Some(Action::SelectGraphicRendition(params)),
+            'H' | 'f' => Some(Action::CursorPosition {
+                row: params.get(0, 1) as usize - 1,
+                column: params.get(1, 1) as usize - 1,
+            }),
+            'J' => EraseMode::from_param(params.iter().next()?).map(Action::EraseDisplay),
+            'K' => EraseMode::from_param(params.iter().next()?).map(Action::EraseLine),
+            _ => None,
+        }
+    }
+}
+
+impl EraseMode {
+    fn from_param(param: u16) -> Option<Self> {
+        match param {
+            0 => Some(Self::ToEnd),
+            1 => Some(Self::ToCursor),
+            2 => Some(Self::All),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: MIT
+//! Block devices, such as disks, which are read and written in whole sectors.
+//!
+//! Filesystem code should only depend on [`interface::BlockDevice`], so that it doesn't care
+//! which driver or backend the data comes from.
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The size of a sector, the unit block devices are addressed in.
+pub const SECTOR_SIZE: usize = 512;
+
+pub mod interface {
+    pub trait BlockDevice {
+        /// The number of sectors on the device.
+        fn sector_count(&self) -> u64;
+
+        /// Reads consecutive sectors, starting at sector `lba`, into `buf`. The length of `buf`
+        /// must be a multiple of [`SECTOR_SIZE`](super::SECTOR_SIZE).
+        fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+        /// Writes `buf` to consecutive sectors, starting at sector `lba`. The length of `buf` must
+        /// be a multiple of [`SECTOR_SIZE`](super::SECTOR_SIZE).
+        fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str>;
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Checks that a request for `len` bytes starting at sector `lba` is made up of whole sectors and
+/// fits on a device with `sector_count` sectors, for block device drivers to call before they
+/// start a transfer.
+///
+/// Returns the number of sectors the request covers.
+pub fn check_request(sector_count: u64, lba: u64, len: usize) -> Result<u64, &'static str> {
+    if len % SECTOR_SIZE != 0 {
+        return Err("buffer length isn't a multiple of the sector size");
+    }
+
+    let sectors = (len / SECTOR_SIZE) as u64;
+    match lba.checked_add(sectors) {
+        Some(end) if end <= sector_count => Ok(sectors),
+        _ => Err("request is beyond the end of the device"),
+    }
+}
@@ -1,31 +1,116 @@
 use core::fmt::Formatter;
 use core::marker::PhantomData;
-use core::{fmt, ops};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{fmt, ops, ptr};
 
 /// A wrapper for usize with an integrated range bound check.
 #[derive(Copy, Clone)]
 pub struct BoundedUsize<const MAX_INCLUSIVE: usize>(usize);
 
-pub struct MMIODerefWrapper<T> {
-    start_addr: usize,
+/// The alignment every MMIO register block must start at. Arm lays out device registers in 4 KiB
+/// frames, so that each block can be mapped on its own with the smallest page size.
+pub const MMIO_ALIGN: usize = 4096;
+
+/// Access to a block of MMIO registers of type `T`, usually declared with `register_structs!`.
+///
+/// The block must start at a multiple of `ALIGN`, which is [`MMIO_ALIGN`] unless the device is
+/// known to pack several blocks into a page.
+///
+/// Dereferencing the wrapper gives a `&T` to the whole block, which is what `tock_registers`
+/// expects; every register inside is an `UnsafeCell` accessed with volatile reads and writes, so
+/// the reference never lets the compiler assume the block's contents. For registers outside of
+/// `T`'s fields, such as those computed from an index, [`read_reg`](Self::read_reg) and
+/// [`write_reg`](Self::write_reg) access them through a raw pointer instead, with the offset
+/// checked against the size of the block.
+pub struct MMIODerefWrapper<T, const ALIGN: usize = MMIO_ALIGN> {
+    start_addr: AtomicUsize,
     phantom: PhantomData<fn() -> T>,
 }
 
-impl<T> MMIODerefWrapper<T> {
+impl<T, const ALIGN: usize> MMIODerefWrapper<T, ALIGN> {
     /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The address must be valid for the registers, and aligned to `ALIGN`, which is checked.
     pub const unsafe fn new(start_addr: usize) -> Self {
+        assert!(start_addr % ALIGN == 0, "MMIO register block isn't aligned");
         Self {
-            start_addr,
+            start_addr: AtomicUsize::new(start_addr),
             phantom: PhantomData,
         }
     }
+
+    /// Points the wrapper at a different MMIO start address, e.g. one discovered at runtime.
+    ///
+    /// # Safety
+    ///
+    /// - The address must be valid for the registers, and nothing may be accessing them. It must
+    ///   also be aligned to `ALIGN`, which is checked.
+    pub unsafe fn set_start_addr(&self, start_addr: usize) {
+        assert!(
+            start_addr % ALIGN == 0,
+            "MMIO register block at {:#x} isn't aligned",
+            start_addr
+        );
+        self.start_addr.store(start_addr, Ordering::Relaxed);
+    }
+
+    /// Returns a pointer to the start of the register block, without creating a reference to it.
+    #[allow(unused)]
+    pub fn as_ptr(&self) -> *const T {
+        self.start_addr.load(Ordering::Relaxed) as *const T
+    }
+
+    /// Returns a pointer to the register of type `R` at `offset` bytes into the block.
+    ///
+    /// The offset's bound is checked at compile time to keep the whole register within `T`, and
+    /// the offset itself must be aligned for `R`.
+    #[allow(unused)]
+    pub fn map_at<R, const MAX_INCLUSIVE: usize>(
+        &self,
+        offset: BoundedUsize<MAX_INCLUSIVE>,
+    ) -> *mut R {
+        assert!(RegisterInBlock::<T, R, MAX_INCLUSIVE>::OK);
+        assert!(
+            offset.get() % mem::align_of::<R>() == 0,
+            "misaligned MMIO register offset"
+        );
+
+        (self.start_addr.load(Ordering::Relaxed) + offset.get()) as *mut R
+    }
+
+    /// Reads the register of type `R` at `offset` bytes into the block, with a volatile read.
+    #[allow(unused)]
+    pub fn read_reg<R: Copy, const MAX_INCLUSIVE: usize>(
+        &self,
+        offset: BoundedUsize<MAX_INCLUSIVE>,
+    ) -> R {
+        // Safe because the wrapper was created for a valid register block, and `map_at` keeps
+        // the register within it.
+        unsafe { ptr::read_volatile(self.map_at(offset)) }
+    }
+
+    /// Writes `value` to the register of type `R` at `offset` bytes into the block, with a
+    /// volatile write.
+    #[allow(unused)]
+    pub fn write_reg<R: Copy, const MAX_INCLUSIVE: usize>(
+        &self,
+        offset: BoundedUsize<MAX_INCLUSIVE>,
+        value: R,
+    ) {
+        // Safe because the wrapper was created for a valid register block, and `map_at` keeps
+        // the register within it.
+        unsafe { ptr::write_volatile(self.map_at(offset), value) }
+    }
 }
 
-impl<T> ops::Deref for MMIODerefWrapper<T> {
+impl<T, const ALIGN: usize> ops::Deref for MMIODerefWrapper<T, ALIGN> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &*(self.start_addr as *const _) }
+        unsafe { &*(self.start_addr.load(Ordering::Relaxed) as *const _) }
     }
 }
 
@@ -44,6 +129,20 @@ impl<const MAX_INCLUSIVE: usize> BoundedUsize<{ MAX_INCLUSIVE }> {
     }
 }
 
+/// Fails to compile when used for a register of type `R` at an offset of up to `MAX_INCLUSIVE`
+/// that could run past the end of the register block `T`.
+struct RegisterInBlock<T, R, const MAX_INCLUSIVE: usize>(PhantomData<(T, R)>);
+
+impl<T, R, const MAX_INCLUSIVE: usize> RegisterInBlock<T, R, MAX_INCLUSIVE> {
+    const OK: bool = {
+        assert!(
+            MAX_INCLUSIVE + mem::size_of::<R>() <= mem::size_of::<T>(),
+            "MMIO register offset can run past the end of the register block"
+        );
+        true
+    };
+}
+
 impl<const MAX_INCLUSIVE: usize> fmt::Display for BoundedUsize<{ MAX_INCLUSIVE }> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
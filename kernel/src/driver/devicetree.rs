@@ -0,0 +1,315 @@
+// SPDX-License-Identifier: MIT
+//! Flattened device tree (FDT) parser, used to discover devices at boot.
+//!
+//! # Resources
+//!
+//! - <https://github.com/devicetree-org/devicetree-specification/releases/download/v0.4/devicetree-specification-v0.4.pdf>
+
+use core::str;
+
+use crate::dt::{self, FdtHeader};
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+/// A validated device tree blob.
+pub struct DeviceTree<'a> {
+    blob: &'a [u8],
+    header: FdtHeader,
+}
+
+/// A node of the device tree, with the properties needed to bind a driver to it.
+#[derive(Copy, Clone)]
+pub struct Node<'a> {
+    /// The node name, including its unit address, e.g. `pl011@9000000`.
+    pub name: &'a str,
+
+    /// The raw `compatible` property, a list of NUL-terminated strings.
+    compatible: &'a [u8],
+
+    /// The raw `reg` property.
+    reg: &'a [u8],
+
+    /// The number of cells in each address of `reg`, inherited from the parent node.
+    address_cells: u32,
+
+    /// The number of cells in each size of `reg`, inherited from the parent node.
+    size_cells: u32,
+}
+
+/// Iterator over the `(address, size)` pairs of a node's `reg` property.
+pub struct RegIter<'a> {
+    reg: &'a [u8],
+    address_cells: u32,
+    size_cells: u32,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private Definitions
+//--------------------------------------------------------------------------------------------------
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Cell counts that apply to the children of a node without `#address-cells`/`#size-cells`.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// Deepest level of node nesting supported while walking the tree.
+const MAX_DEPTH: usize = 16;
+
+/// A node whose properties are still being read while walking the tree.
+#[derive(Copy, Clone)]
+struct PendingNode<'a> {
+    node: Node<'a>,
+
+    /// `#address-cells` of this node, which applies to its children.
+    child_address_cells: u32,
+
+    /// `#size-cells` of this node, which applies to its children.
+    child_size_cells: u32,
+
+    /// Whether the node has already been passed to the walk callback.
+    visited: bool,
+}
+
+/// Reads big endian 32-bit words out of the structure block.
+struct Cursor<'a> {
+    block: &'a [u8],
+    offset: usize,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private Code
+//--------------------------------------------------------------------------------------------------
+
+impl<'a> Cursor<'a> {
+    fn read_u32(&mut self) -> Result<u32, &'static str> {
+        let bytes = self
+            .block
+            .get(self.offset..self.offset + 4)
+            .ok_or("unexpected end of structure block")?;
+        self.offset += 4;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Reads `len` bytes, then skips padding up to the next 32-bit boundary.
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self
+            .block
+            .get(self.offset..self.offset + len)
+            .ok_or("unexpected end of structure block")?;
+        self.offset = (self.offset + len).next_multiple_of(4);
+        Ok(bytes)
+    }
+
+    /// Reads a NUL-terminated string, then skips padding up to the next 32-bit boundary.
+    fn read_str(&mut self) -> Result<&'a str, &'static str> {
+        let rest = self.block.get(self.offset..).unwrap_or(&[]);
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("unterminated node name")?;
+        let name = str::from_utf8(&rest[..len]).map_err(|_| "node name is not UTF-8")?;
+        self.offset = (self.offset + len + 1).next_multiple_of(4);
+        Ok(name)
+    }
+}
+
+/// Reads a big endian value made up of `cells` 32-bit cells from the start of `bytes`.
+fn read_cells(bytes: &[u8], cells: u32) -> u64 {
+    bytes[..cells as usize * 4]
+        .chunks_exact(4)
+        .fold(0, |value, cell| {
+            (value << 32) | u32::from_be_bytes(cell.try_into().unwrap()) as u64
+        })
+}
+
+impl<'a> DeviceTree<'a> {
+    /// Looks up a property name in the strings block.
+    fn property_name(&self, offset: u32) -> Result<&'a str, &'static str> {
+        let start = self.header.off_dt_strings as usize + offset as usize;
+        let end = self.header.off_dt_strings as usize + self.header.size_dt_strings as usize;
+        let strings = self
+            .blob
+            .get(start..end)
+            .ok_or("property name out of bounds")?;
+        let len = strings
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("unterminated property name")?;
+        str::from_utf8(&strings[..len]).map_err(|_| "property name is not UTF-8")
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl<'a> DeviceTree<'a> {
+    /// Validates the header of `blob`, and wraps it for parsing.
+    pub fn new(blob: &'a [u8]) -> Result<Self, &'static str> {
+        let header = dt::validate(blob)?;
+        Ok(Self { blob, header })
+    }
+
+    /// Calls `f` for every node in the tree, parents before their children.
+    pub fn walk(&self, mut f: impl FnMut(&Node<'a>)) -> Result<(), &'static str> {
+        let start = self.header.off_dt_struct as usize;
+        let end = start + self.header.size_dt_struct as usize;
+        let mut cursor = Cursor {
+            block: &self.blob[start..end],
+            offset: 0,
+        };
+
+        let mut stack: [Option<PendingNode<'a>>; MAX_DEPTH] = [None; MAX_DEPTH];
+        let mut depth: usize = 0;
+
+        loop {
+            match cursor.read_u32()? {
+                FDT_BEGIN_NODE => {
+                    let name = cursor.read_str()?;
+
+                    // the parent's properties all come before its first child, so it's complete
+                    let (address_cells, size_cells) = match depth {
+                        0 => (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS),
+                        _ => {
+                            let parent = stack[depth - 1].as_mut().unwrap();
+                            if !parent.visited {
+                                parent.visited = true;
+                                f(&parent.node);
+                            }
+                            (parent.child_address_cells, parent.child_size_cells)
+                        }
+                    };
+
+                    if depth == MAX_DEPTH {
+                        return Err("device tree nested too deeply");
+                    }
+
+                    stack[depth] = Some(PendingNode {
+                        node: Node {
+                            name,
+                            compatible: &[],
+                            reg: &[],
+                            address_cells,
+                            size_cells,
+                        },
+                        child_address_cells: DEFAULT_ADDRESS_CELLS,
+                        child_size_cells: DEFAULT_SIZE_CELLS,
+                        visited: false,
+                    });
+                    depth += 1;
+                }
+                FDT_END_NODE => {
+                    if depth == 0 {
+                        return Err("unbalanced end of node");
+                    }
+
+                    depth -= 1;
+                    let pending = stack[depth].take().unwrap();
+                    if !pending.visited {
+                        f(&pending.node);
+                    }
+                }
+                FDT_PROP => {
+                    let len = cursor.read_u32()? as usize;
+                    let name_offset = cursor.read_u32()?;
+                    let value = cursor.read_bytes(len)?;
+
+                    if depth == 0 {
+                        return Err("property outside of a node");
+                    }
+
+                    let pending = stack[depth - 1].as_mut().unwrap();
+                    match self.property_name(name_offset)? {
+                        "compatible" => pending.node.compatible = value,
+                        "reg" => pending.node.reg = value,
+                        "#address-cells" if len == 4 => {
+                            pending.child_address_cells = read_cells(value, 1) as u32
+                        }
+                        "#size-cells" if len == 4 => {
+                            pending.child_size_cells = read_cells(value, 1) as u32
+                        }
+                        _ => {}
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                _ => return Err("unknown structure block token"),
+            }
+        }
+
+        if depth != 0 {
+            return Err("structure block ended inside a node");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the first node compatible with `compatible`.
+    #[allow(unused)]
+    pub fn find_compatible(&self, compatible: &str) -> Result<Option<Node<'a>>, &'static str> {
+        let mut found = None;
+        self.walk(|node| {
+            if found.is_none() && node.is_compatible(compatible) {
+                found = Some(*node);
+            }
+        })?;
+
+        Ok(found)
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Returns the strings in the node's `compatible` property, most specific first.
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> {
+        self.compatible
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| str::from_utf8(s).ok())
+    }
+
+    /// Whether `compatible` is one of the strings in the node's `compatible` property.
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    /// Returns the `(address, size)` pairs in the node's `reg` property.
+    pub fn reg(&self) -> RegIter<'a> {
+        RegIter {
+            reg: self.reg,
+            address_cells: self.address_cells,
+            size_cells: self.size_cells,
+        }
+    }
+}
+
+impl<'a> Iterator for RegIter<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // cell counts above 2 can't be represented in 64 bits
+        if self.address_cells > 2 || self.size_cells > 2 {
+            return None;
+        }
+
+        let entry_size = (self.address_cells + self.size_cells) as usize * 4;
+        if entry_size == 0 || self.reg.len() < entry_size {
+            return None;
+        }
+
+        let address = read_cells(self.reg, self.address_cells);
+        let size = read_cells(
+            &self.reg[self.address_cells as usize * 4..],
+            self.size_cells,
+        );
+        self.reg = &self.reg[entry_size..];
+
+        Some((address, size))
+    }
+}
@@ -1,10 +1,12 @@
 // SPDX-License-Identifier: MIT
 //! GICC Driver - GIC CPU interface.
 
+use core::mem;
+
 use tock_registers::{
     interfaces::{Readable, Writeable},
     register_bitfields, register_structs,
-    registers::ReadWrite,
+    registers::{ReadOnly, ReadWrite},
 };
 
 use crate::driver::MMIODerefWrapper;
@@ -27,14 +29,26 @@ register_bitfields! {
         Priority OFFSET(0) NUMBITS(8) []
     ],
 
+    /// Binary Point Register
+    BPR [
+        BinaryPoint OFFSET(0) NUMBITS(3) []
+    ],
+
     /// Interrupt Acknowledge Register
     IAR [
-        InterruptID OFFSET(0) NUMBITS(10) []
+        InterruptID OFFSET(0) NUMBITS(10) [],
+        CPUID OFFSET(10) NUMBITS(3) []
     ],
 
     /// End of Interrupt Register
     EOIR [
-        EOIINTID OFFSET(0) NUMBITS(10) []
+        EOIINTID OFFSET(0) NUMBITS(10) [],
+        CPUID OFFSET(10) NUMBITS(3) []
+    ],
+
+    /// Running Priority Register
+    RPR [
+        Priority OFFSET(0) NUMBITS(8) []
     ]
 }
 
@@ -43,13 +57,17 @@ register_structs! {
     pub RegisterBlock {
         (0x000 => CTLR: ReadWrite<u32, CTLR::Register>),
         (0x004 => PMR: ReadWrite<u32, PMR::Register>),
-        (0x008 => _reserved1),
+        (0x008 => BPR: ReadWrite<u32, BPR::Register>),
         (0x00C => IAR: ReadWrite<u32, IAR::Register>),
         (0x010 => EOIR: ReadWrite<u32, EOIR::Register>),
-        (0x014  => @END),
+        (0x014 => RPR: ReadOnly<u32, RPR::Register>),
+        (0x018 => @END),
     }
 }
 
+// The register block must span exactly the registers the driver expects.
+const _: () = assert!(mem::size_of::<RegisterBlock>() == 0x18);
+
 /// Abstraction for the associated MMIO registers.
 type Registers = MMIODerefWrapper<RegisterBlock>;
 
@@ -78,6 +96,15 @@ impl GICC {
         }
     }
 
+    /// Points the driver at a different MMIO start address.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address, before the device is used.
+    pub unsafe fn set_mmio_start_addr(&self, mmio_start_addr: usize) {
+        self.registers.set_start_addr(mmio_start_addr);
+    }
+
     /// Accept interrupts of any priority.
     ///
     /// Quoting the GICv2 Architecture Specification:
@@ -93,6 +120,33 @@ impl GICC {
         self.registers.PMR.write(PMR::Priority.val(255)); // Comment in arch spec.
     }
 
+    /// Let an IRQ preempt another whenever it has a higher priority, by using as many priority bits
+    /// as possible for the group priority.
+    ///
+    /// Quoting the GICv2 Architecture Specification:
+    ///
+    ///   "The group priority field determines interrupt preemption. [...] Writing 0 to GICC_BPR
+    ///    sets it to the minimum value supported by the implementation."
+    ///
+    /// # Safety
+    ///
+    /// - GICC MMIO registers are banked per CPU core. It is therefore safe to have `&self` instead
+    ///   of `&mut self`.
+    pub fn preempt_at_any_priority(&self) {
+        self.registers.BPR.write(BPR::BinaryPoint.val(0));
+    }
+
+    /// The priority of the highest priority IRQ that's active on this core, or 255 if there are
+    /// none. Only IRQs of a higher priority than this can preempt it.
+    ///
+    /// # Safety
+    ///
+    /// - GICC MMIO registers are banked per CPU core. It is therefore safe to have `&self` instead
+    ///   of `&mut self`.
+    pub fn running_priority(&self) -> u8 {
+        self.registers.RPR.read(RPR::Priority) as u8
+    }
+
     /// Enable the interface - start accepting IRQs.
     ///
     /// # Safety
@@ -103,7 +157,10 @@ impl GICC {
         self.registers.CTLR.write(CTLR::Enable::SET);
     }
 
-    /// Extract the number of the highest-priority pending IRQ.
+    /// Extract the number of the highest-priority pending IRQ, along with the ID of the core that
+    /// requested it.
+    ///
+    /// The source core ID is only meaningful for SGIs, and reads as zero for all other IRQs.
     ///
     /// Can only be called from a critical section, which is ensured by taking an `CriticalSection` token.
     ///
@@ -112,18 +169,22 @@ impl GICC {
     /// - GICC MMIO registers are banked per CPU core. It is therefore safe to have `&self` instead
     ///   of `&mut self`.
     #[allow(clippy::trivially_copy_pass_by_ref)]
-    pub fn pending_irq_number<'cs>(
+    pub fn pending_irq<'cs>(
         &self,
         _ic: &exception::asynchronous::CriticalSection<'cs>,
-    ) -> usize {
-        self.registers.IAR.read(IAR::InterruptID) as usize
+    ) -> (usize, u32) {
+        // Reading IAR acknowledges the IRQ, so it must only be read once.
+        let iar = self.registers.IAR.extract();
+
+        (iar.read(IAR::InterruptID) as usize, iar.read(IAR::CPUID))
     }
 
     /// Complete handling of the currently active IRQ.
     ///
     /// Can only be called from a critical section, which is ensured by taking an `CriticalSection` token.
     ///
-    /// To be called after `pending_irq_number()`.
+    /// To be called after `pending_irq()`, with the same IRQ number and source core ID that it
+    /// returned. For SGIs, the GIC only deactivates the interrupt if both values match.
     ///
     /// # Safety
     ///
@@ -133,8 +194,11 @@ impl GICC {
     pub fn mark_completed<'cs>(
         &self,
         irq_number: u32,
+        source_core: u32,
         _ic: &exception::asynchronous::CriticalSection<'cs>,
     ) {
-        self.registers.EOIR.write(EOIR::EOIINTID.val(irq_number));
+        self.registers
+            .EOIR
+            .write(EOIR::EOIINTID.val(irq_number) + EOIR::CPUID.val(source_core));
     }
 }
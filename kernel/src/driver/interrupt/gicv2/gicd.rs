@@ -3,14 +3,20 @@
 //!
 //! # Glossary
 //!   - SPI - Shared Peripheral Interrupt.
+//!   - SGI - Software-Generated Interrupt.
+
+use core::mem;
+use core::ops::Range;
 
 use tock_registers::{
     interfaces::{Readable, Writeable},
     register_bitfields, register_structs,
-    registers::{ReadOnly, ReadWrite},
+    registers::{ReadOnly, ReadWrite, WriteOnly},
 };
 
 use crate::driver::MMIODerefWrapper;
+use crate::exception::asynchronous::IRQTrigger;
+use crate::info;
 //--------------------------------------------------------------------------------------------------
 // Public Code
 //--------------------------------------------------------------------------------------------------
@@ -31,6 +37,7 @@ register_bitfields! {
 
     /// Interrupt Controller Type Register
     TYPER [
+        CPUNumber     OFFSET(5)  NUMBITS(3) [],
         ITLinesNumber OFFSET(0)  NUMBITS(5) []
     ],
 
@@ -40,6 +47,17 @@ register_bitfields! {
         Offset2 OFFSET(16) NUMBITS(8) [],
         Offset1 OFFSET(8)  NUMBITS(8) [],
         Offset0 OFFSET(0)  NUMBITS(8) []
+    ],
+
+    /// Software Generated Interrupt Register
+    SGIR [
+        TargetListFilter OFFSET(24) NUMBITS(2) [
+            TargetList = 0b00,
+            AllOtherCores = 0b01,
+            RequestingCore = 0b10
+        ],
+        CPUTargetList OFFSET(16) NUMBITS(8) [],
+        SGIINTID OFFSET(0) NUMBITS(4) []
     ]
 }
 
@@ -51,8 +69,20 @@ register_structs! {
         (0x008 => _reserved1),
         (0x104 => ISENABLER: [ReadWrite<u32>; 31]),
         (0x180 => _reserved2),
+        (0x184 => ICENABLER: [WriteOnly<u32>; 31]),
+        (0x200 => _reserved3),
+        (0x204 => ISPENDR: [ReadWrite<u32>; 31]),
+        (0x280 => _reserved4),
+        (0x304 => ISACTIVER: [ReadWrite<u32>; 31]),
+        (0x380 => _reserved5),
+        (0x420 => IPRIORITYR: [ReadWrite<u32>; 247]),
+        (0x7FC => _reserved6),
         (0x820 => ITARGETSR: [ReadWrite<u32, ITARGETSR::Register>; 248]),
-        (0xC00 => @END),
+        (0xC00 => _reserved7),
+        (0xC08 => ICFGR: [ReadWrite<u32>; 62]),
+        (0xD00 => _reserved8),
+        (0xF00 => SGIR: WriteOnly<u32, SGIR::Register>),
+        (0xF04 => @END),
     }
 }
 
@@ -62,11 +92,25 @@ register_structs! {
         (0x000 => _reserved1),
         (0x100 => ISENABLER: ReadWrite<u32>),
         (0x104 => _reserved2),
+        (0x180 => ICENABLER: WriteOnly<u32>),
+        (0x184 => _reserved3),
+        (0x200 => ISPENDR: ReadWrite<u32>),
+        (0x204 => _reserved4),
+        (0x300 => ISACTIVER: ReadWrite<u32>),
+        (0x304 => _reserved5),
+        (0x400 => IPRIORITYR: [ReadWrite<u32>; 8]),
+        (0x420 => _reserved6),
         (0x800 => ITARGETSR: [ReadOnly<u32, ITARGETSR::Register>; 8]),
-        (0x820 => @END),
+        (0x820 => _reserved7),
+        (0xC04 => ICFGR: ReadWrite<u32>),
+        (0xC08 => @END),
     }
 }
 
+// The register blocks must span exactly the registers the driver expects.
+const _: () = assert!(mem::size_of::<SharedRegisterBlock>() == 0xF04);
+const _: () = assert!(mem::size_of::<BankedRegisterBlock>() == 0xC08);
+
 /// Abstraction for the non-banked parts of the associated MMIO registers.
 type SharedRegisters = MMIODerefWrapper<SharedRegisterBlock>;
 
@@ -91,6 +135,12 @@ pub struct GICD {
 //--------------------------------------------------------------------------------------------------
 
 impl SharedRegisters {
+    /// Return the number of CPU interfaces that this HW implements.
+    #[inline(always)]
+    fn num_cpu_interfaces(&self) -> usize {
+        (self.TYPER.read(TYPER::CPUNumber) as usize) + 1
+    }
+
     /// Return the number of IRQs that this HW implements.
     #[inline(always)]
     fn num_irqs(&mut self) -> usize {
@@ -130,6 +180,17 @@ impl GICD {
         }
     }
 
+    /// Points the driver at a different MMIO start address.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address, before the device is used.
+    pub unsafe fn set_mmio_start_addr(&self, mmio_start_addr: usize) {
+        self.shared_registers
+            .lock(|regs| regs.set_start_addr(mmio_start_addr));
+        self.banked_registers.set_start_addr(mmio_start_addr);
+    }
+
     /// Use a banked ITARGETSR to retrieve the executing core's GIC target mask.
     ///
     /// Quoting the GICv2 Architecture Specification:
@@ -161,6 +222,135 @@ impl GICD {
         });
     }
 
+    /// Set the priority of an interrupt. Lower values are higher priority.
+    pub fn set_priority(&self, irq_num: &super::IRQNumber, priority: u8) {
+        let irq_num = irq_num.get();
+
+        // Each IPRIORITYR holds the priority of four IRQs, one per byte.
+        let shift = (irq_num % 4) * 8;
+        let update = |reg: &ReadWrite<u32>| {
+            reg.set((reg.get() & !(0xff << shift)) | ((priority as u32) << shift));
+        };
+
+        match irq_num {
+            // Private.
+            0..=31 => update(&self.banked_registers.IPRIORITYR[irq_num >> 2]),
+            // Shared.
+            _ => self
+                .shared_registers
+                .lock(|regs| update(&regs.IPRIORITYR[(irq_num >> 2) - 8])),
+        }
+    }
+
+    /// Route a shared interrupt to the CPU interfaces in `target_list`, one bit per interface.
+    ///
+    /// Private interrupts always go to their own core, so are left alone.
+    pub fn set_targets(&self, irq_num: &super::IRQNumber, target_list: u8) {
+        let irq_num = irq_num.get();
+        if irq_num < 32 {
+            return;
+        }
+
+        // Like IPRIORITYR, each ITARGETSR covers four IRQs, one per byte.
+        let shift = (irq_num % 4) * 8;
+        self.shared_registers.lock(|regs| {
+            let reg = &regs.ITARGETSR[(irq_num >> 2) - 8];
+            reg.set((reg.get() & !(0xff << shift)) | ((target_list as u32) << shift));
+        });
+    }
+
+    /// Configure an interrupt as level-sensitive or edge-triggered.
+    ///
+    /// SGIs are always edge-triggered, so are left alone. Whether PPIs can be configured is
+    /// implementation defined, and writes to them may be ignored.
+    pub fn set_trigger(&self, irq_num: &super::IRQNumber, trigger: IRQTrigger) {
+        let irq_num = irq_num.get();
+
+        // Each ICFGR has two bits per IRQ, of which the upper one is set for edge-triggered.
+        let bit: u32 = 0b10 << ((irq_num % 16) * 2);
+        let update = |reg: &ReadWrite<u32>| match trigger {
+            IRQTrigger::Level => reg.set(reg.get() & !bit),
+            IRQTrigger::Edge => reg.set(reg.get() | bit),
+        };
+
+        match irq_num {
+            // SGIs.
+            0..=15 => {}
+            // PPIs.
+            16..=31 => update(&self.banked_registers.ICFGR),
+            // Shared.
+            _ => self
+                .shared_registers
+                .lock(|regs| update(&regs.ICFGR[(irq_num >> 4) - 2])),
+        }
+    }
+
+    /// Logs the enable, pending, active, priority and target state of each IRQ in `irqs`, as a
+    /// table. The range is cut short at the number of IRQs that the hardware implements.
+    ///
+    /// Private IRQs (0 to 31) are shown as seen by the executing core.
+    #[allow(unused)]
+    pub fn dump(&self, irqs: Range<usize>) {
+        self.shared_registers.lock(|regs| {
+            let num_irqs = regs.num_irqs();
+            info!(
+                "GICD: {} IRQs, {} CPU interfaces",
+                num_irqs,
+                regs.num_cpu_interfaces()
+            );
+            info!("       IRQ  enabled  pending  active  priority  targets");
+
+            for irq_num in irqs.start..irqs.end.min(num_irqs) {
+                let bit = |banked: &ReadWrite<u32>, shared: &[ReadWrite<u32>]| {
+                    let reg = match irq_num {
+                        0..=31 => banked.get(),
+                        _ => shared[(irq_num >> 5) - 1].get(),
+                    };
+                    reg & (1 << (irq_num % 32)) != 0
+                };
+                let byte = |banked: &[ReadWrite<u32>], shared: &[ReadWrite<u32>]| {
+                    let reg = match irq_num {
+                        0..=31 => banked[irq_num >> 2].get(),
+                        _ => shared[(irq_num >> 2) - 8].get(),
+                    };
+                    (reg >> ((irq_num % 4) * 8)) as u8
+                };
+
+                let targets = match irq_num {
+                    0..=31 => self.banked_registers.ITARGETSR[irq_num >> 2].get(),
+                    _ => regs.ITARGETSR[(irq_num >> 2) - 8].get(),
+                } >> ((irq_num % 4) * 8);
+
+                info!(
+                    "      {: >4}  {: <7}  {: <7}  {: <6}  {: >#8x}  {:#010b}",
+                    irq_num,
+                    bit(&self.banked_registers.ISENABLER, &regs.ISENABLER),
+                    bit(&self.banked_registers.ISPENDR, &regs.ISPENDR),
+                    bit(&self.banked_registers.ISACTIVER, &regs.ISACTIVER),
+                    byte(&self.banked_registers.IPRIORITYR, &regs.IPRIORITYR),
+                    targets as u8
+                );
+            }
+        });
+    }
+
+    /// Raise a software-generated interrupt on the cores in `target_list`.
+    ///
+    /// Each bit in `target_list` corresponds to one CPU interface, e.g. bit 0 targets CPU
+    /// interface 0. The caller must ensure that `sgi_num` is in the range 0..=15.
+    pub fn send_sgi(&self, sgi_num: &super::IRQNumber, target_list: u8) {
+        let sgi_num = sgi_num.get();
+        debug_assert!(sgi_num <= 15);
+
+        self.shared_registers.lock(|regs| {
+            regs.SGIR.write(
+                SGIR::TargetListFilter::TargetList
+                    + SGIR::CPUTargetList.val(target_list as u32)
+                    + SGIR::SGIINTID.val(sgi_num as u32),
+            );
+        });
+    }
+
     /// Enable an interrupt.
     pub fn enable(&self, irq_num: &super::IRQNumber) {
         let irq_num = irq_num.get();
@@ -188,4 +378,23 @@ impl GICD {
             }
         }
     }
+
+    /// Disable an interrupt.
+    pub fn disable(&self, irq_num: &super::IRQNumber) {
+        let irq_num = irq_num.get();
+
+        // Writing a 1 to a bit of ICENABLER disables that IRQ, and zeroes are ignored, so there's no
+        // need to read the register first.
+        let disable_reg_index = irq_num >> 5;
+        let disable_bit: u32 = 1u32 << (irq_num % 32);
+
+        match irq_num {
+            // Private.
+            0..=31 => self.banked_registers.ICENABLER.set(disable_bit),
+            // Shared.
+            _ => self
+                .shared_registers
+                .lock(|regs| regs.ICENABLER[disable_reg_index - 1].set(disable_bit)),
+        }
+    }
 }
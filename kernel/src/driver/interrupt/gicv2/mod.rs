@@ -73,14 +73,16 @@
 //!           - 00..15 SGIs
 //!           - 16..31 PPIs
 
-use crate::{cpu, driver, exception};
+use core::cell::Cell;
+
+use crate::{cpu, driver, exception, per_core, warn};
 //------------------------------------------------------------------------------
 // OS Interface Code
 //------------------------------------------------------------------------------
 use crate::driver::{BoundedUsize, DriverLoadOrder};
 use crate::exception::interface;
-use crate::sync::interface::ReadWriteEx;
-use crate::sync::InitStateLock;
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
 
 mod gicc;
 mod gicd;
@@ -89,8 +91,22 @@ mod gicd;
 // Private Definitions
 //--------------------------------------------------------------------------------------------------
 
-type HandlerTable = [Option<exception::asynchronous::IRQHandlerDescriptor<IRQNumber>>;
-    IRQNumber::MAX_INCLUSIVE + 1];
+type HandlerTable =
+    [exception::asynchronous::IRQHandlerChain<IRQNumber>; IRQNumber::MAX_INCLUSIVE + 1];
+
+/// The most preemptible handlers that may be nested on one core, which bounds how deep the
+/// exception stack goes. Handlers past this run with interrupts masked.
+const MAX_NESTED_IRQS: usize = 4;
+
+per_core! {
+    /// The number of preemptible handlers currently running on the core.
+    static NESTED_IRQS: Cell<usize> = Cell::new(0);
+}
+
+per_core! {
+    /// The core that raised the SGI whose handlers are currently running on the core.
+    static SGI_SOURCE_CORE: Cell<Option<u64>> = Cell::new(None);
+}
 
 //--------------------------------------------------------------------------------------------------
 // Public Definitions
@@ -107,8 +123,9 @@ pub struct GICv2 {
     /// The CPU Interface.
     gicc: gicc::GICC,
 
-    /// Stores registered IRQ handlers. Writable only during kernel init. RO afterwards.
-    handler_table: InitStateLock<HandlerTable>,
+    /// Stores registered IRQ handlers. Drivers can be loaded and unloaded at any time, so this can
+    /// change after kernel init.
+    handler_table: IRQSafeNullLock<HandlerTable>,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -117,8 +134,9 @@ pub struct GICv2 {
 
 impl GICv2 {
     const MAX_IRQ_NUMBER: usize = 300; // Normally 1019, but keep it lower to save some space.
+    const MAX_SGI_NUMBER: usize = 15;
 
-    pub const COMPATIBLE: &'static str = "arm,gicv2"; // todo: actual value
+    pub const COMPATIBLE: &'static str = "arm,cortex-a15-gic";
     pub const LOAD_ORDER: DriverLoadOrder = DriverLoadOrder::InterruptController;
 
     /// Create an instance.
@@ -130,7 +148,9 @@ impl GICv2 {
         Self {
             gicd: gicd::GICD::new(gicd_mmio_start_addr),
             gicc: gicc::GICC::new(gicc_mmio_start_addr),
-            handler_table: InitStateLock::new([None; IRQNumber::MAX_INCLUSIVE + 1]),
+            handler_table: IRQSafeNullLock::new(
+                [exception::asynchronous::IRQHandlerChain::new(); IRQNumber::MAX_INCLUSIVE + 1],
+            ),
         }
     }
 }
@@ -146,15 +166,28 @@ impl driver::interface::DeviceDriver for GICv2 {
         Self::COMPATIBLE
     }
 
+    fn mmio_region_count(&self) -> usize {
+        // distributor, then CPU interface
+        2
+    }
+
+    unsafe fn set_mmio_regions(&'static self, regions: &[usize]) -> Result<(), &'static str> {
+        self.gicd.set_mmio_start_addr(regions[0]);
+        self.gicc.set_mmio_start_addr(regions[1]);
+
+        Ok(())
+    }
+
     unsafe fn init(
         &'static self,
         _unused: Option<&Self::IRQNumberType>,
     ) -> Result<(), &'static str> {
-        if cpu::BOOT_CORE_ID == cpu::core_id() {
+        if cpu::BOOT_CORE_ID == cpu::percpu::this_core().id() {
             self.gicd.boot_core_init();
         }
 
         self.gicc.priority_accept_all();
+        self.gicc.preempt_at_any_priority();
         self.gicc.enable();
 
         Ok(())
@@ -168,59 +201,226 @@ impl interface::IRQManager for GICv2 {
         &self,
         irq_handler_descriptor: exception::asynchronous::IRQHandlerDescriptor<Self::IRQNumberType>,
     ) -> Result<(), &'static str> {
-        self.handler_table.write(|table| {
-            let irq_number = irq_handler_descriptor.number().get();
-
-            if table[irq_number].is_some() {
-                return Err("IRQ handler already registered");
+        let is_first = self.handler_table.lock(|table| {
+            let chain = &mut table[irq_handler_descriptor.number().get()];
+            let is_first = chain.is_empty();
+            chain.push(irq_handler_descriptor)?;
+
+            Ok(is_first)
+        })?;
+
+        // any handlers sharing the line have to make do with the first one's configuration
+        if is_first {
+            let irq_number = irq_handler_descriptor.number();
+            let config = irq_handler_descriptor.config();
+
+            self.gicd.set_priority(&irq_number, config.priority);
+            if let Some(target_cores) = config.target_cores {
+                self.gicd.set_targets(&irq_number, target_cores);
             }
+            self.gicd.set_trigger(&irq_number, config.trigger);
+        }
+
+        Ok(())
+    }
+
+    fn remove_handler(
+        &self,
+        irq_number: &Self::IRQNumberType,
+        name: &'static str,
+    ) -> Result<(), &'static str> {
+        let is_last = self.handler_table.lock(|table| {
+            let chain = &mut table[irq_number.get()];
+            chain.remove(name)?;
 
-            table[irq_number] = Some(irq_handler_descriptor);
+            Ok(chain.is_empty())
+        })?;
 
-            Ok(())
-        })
+        // an IRQ without handlers would panic when it's next raised
+        if is_last {
+            self.gicd.disable(irq_number);
+        }
+
+        Ok(())
     }
 
     fn enable(&self, irq_number: &Self::IRQNumberType) {
         self.gicd.enable(irq_number);
     }
 
+    fn send_sgi(
+        &self,
+        irq_number: &Self::IRQNumberType,
+        target_list: u8,
+    ) -> Result<(), &'static str> {
+        if irq_number.get() > GICv2::MAX_SGI_NUMBER {
+            return Err("IRQ number is not an SGI");
+        }
+
+        self.gicd.send_sgi(irq_number, target_list);
+
+        Ok(())
+    }
+
     fn handle_pending_irqs<'cs>(&'cs self, ic: &exception::asynchronous::CriticalSection<'cs>) {
+        // The priority before acknowledging, which must be back in place once the IRQ is completed,
+        // or the GIC's active priorities were dropped out of order.
+        let running_priority = self.gicc.running_priority();
+
         // Extract the highest priority pending IRQ number from the Interrupt Acknowledge Register
-        // (IAR).
-        let irq_number = self.gicc.pending_irq_number(ic);
+        // (IAR). For SGIs, this also identifies the core that raised the interrupt.
+        let (irq_number, source_core) = self.gicc.pending_irq(ic);
 
         // Guard against spurious interrupts.
         if irq_number > GICv2::MAX_IRQ_NUMBER {
             return;
         }
 
-        // Call the IRQ handler. Panic if there is none.
-        self.handler_table.read(|table| {
-            match table[irq_number] {
-                None => panic!("No handler registered for IRQ {}", irq_number),
-                Some(descriptor) => {
-                    // Call the IRQ handler. Panics on failure.
-                    descriptor.handler().handle().expect("Error handling IRQ");
-                }
-            }
-        });
+        // Call the IRQ handlers. Panic if there are none. They run on a copy of the chain, so the
+        // table isn't locked while they do, and handlers that preempt them can look it up too.
+        let chain = self.handler_table.lock(|table| table[irq_number]);
+        if chain.is_empty() {
+            panic!("No handler registered for IRQ {}", irq_number);
+        }
+
+        // Acknowledging raised the running priority to this IRQ's, so only higher priority IRQs
+        // can preempt the handlers while interrupts are unmasked. The context of this exception
+        // is already saved on the stack, so a nested one just stacks another on top.
+        let nested = NESTED_IRQS.get();
+        let preemptible = chain.is_preemptible() && nested.get() < MAX_NESTED_IRQS;
+        if preemptible {
+            nested.set(nested.get() + 1);
+            exception::asynchronous::local_irq_unmask();
+        }
+
+        // A preempting SGI replaces the source core of the one it preempted until it's done.
+        let sgi_source = SGI_SOURCE_CORE.get();
+        let preempted_sgi_source = sgi_source.get();
+        if irq_number <= GICv2::MAX_SGI_NUMBER {
+            sgi_source.set(Some(source_core as u64));
+        }
+
+        // Call each handler until one claims the interrupt. Panics on failure.
+        let status = chain.dispatch().expect("Error handling IRQ");
+        sgi_source.set(preempted_sgi_source);
+
+        // Any nested IRQs have been completed by now, so this one's priority drop comes last.
+        if preemptible {
+            exception::asynchronous::local_irq_mask();
+            nested.set(nested.get() - 1);
+        }
+
+        if status == interface::IRQStatus::NotMine {
+            warn!("No handler claimed IRQ {}", irq_number);
+        }
 
         // Signal completion of handling.
-        self.gicc.mark_completed(irq_number as u32, ic);
+        self.gicc.mark_completed(irq_number as u32, source_core, ic);
+        debug_assert_eq!(
+            self.gicc.running_priority(),
+            running_priority,
+            "IRQ {} completed out of priority order",
+            irq_number
+        );
+
+        exception::latency::record(irq_number, ic.entry_ticks());
+    }
+
+    fn sgi_source_core(&self) -> Option<u64> {
+        SGI_SOURCE_CORE.get().get()
     }
 
     fn print_handlers(&self) {
         use crate::info;
 
-        info!("      Peripheral handler:");
+        self.handler_table.lock(|table| {
+            info!("      Software-generated handler:");
+            for (i, chain) in table[..=GICv2::MAX_SGI_NUMBER].iter().enumerate() {
+                for handler in chain.iter() {
+                    info!("            {: >3}. {}", i, handler.name());
+                }
+            }
 
-        self.handler_table.read(|table| {
-            for (i, opt) in table.iter().enumerate() {
-                if let Some(handler) = opt {
+            info!("      Peripheral handler:");
+            for (i, chain) in table.iter().enumerate().skip(GICv2::MAX_SGI_NUMBER + 1) {
+                for handler in chain.iter() {
                     info!("            {: >3}. {}", i, handler.name());
                 }
             }
         });
     }
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use core::hint;
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    use super::IRQNumber;
+    use crate::cpu;
+    use crate::exception::asynchronous::{irq_manager, IRQHandlerDescriptor};
+    use crate::exception::interface::{IRQHandler, IRQStatus};
+    use crate::selftest::SelfTest;
+    use crate::time;
+
+    pub const TESTS: &[SelfTest] = &[SelfTest {
+        name: "gicv2::SGI 1 loops back to the current core",
+        run: loopback_sgi,
+    }];
+
+    const SGI: IRQNumber = IRQNumber::new(1);
+    const NAME: &str = "selftest: loopback SGI";
+
+    /// How long the SGI may take to arrive, which is far longer than it should ever need.
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Records each SGI it handles, and the core that raised it.
+    struct LoopbackHandler {
+        calls: AtomicUsize,
+        source_core: AtomicU64,
+    }
+
+    static HANDLER: LoopbackHandler = LoopbackHandler {
+        calls: AtomicUsize::new(0),
+        source_core: AtomicU64::new(u64::MAX),
+    };
+
+    impl IRQHandler for LoopbackHandler {
+        fn handle(&self) -> Result<IRQStatus, &'static str> {
+            let source_core = irq_manager()
+                .sgi_source_core()
+                .ok_or("SGI handler called without a source core")?;
+            self.source_core.store(source_core, Ordering::Relaxed);
+            self.calls.fetch_add(1, Ordering::Release);
+
+            Ok(IRQStatus::Handled)
+        }
+    }
+
+    fn loopback_sgi() {
+        irq_manager()
+            .register_handler(IRQHandlerDescriptor::new(SGI, NAME, &HANDLER))
+            .expect("failed to register SGI handler");
+        irq_manager().enable(&SGI);
+
+        // this runs in a kernel thread, so interrupts are unmasked and the SGI is taken right away
+        let core = cpu::percpu::this_core().id();
+        irq_manager()
+            .send_sgi(&SGI, 1 << core)
+            .expect("failed to send SGI");
+
+        let deadline = time::now_nanos() + TIMEOUT.as_nanos() as u64;
+        while HANDLER.calls.load(Ordering::Acquire) == 0 {
+            assert!(time::now_nanos() < deadline, "SGI was never handled");
+            hint::spin_loop();
+        }
+
+        assert_eq!(HANDLER.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(HANDLER.source_core.load(Ordering::Relaxed), core);
+
+        irq_manager()
+            .remove_handler(&SGI, NAME)
+            .expect("failed to remove SGI handler");
+    }
+}
@@ -1,10 +1,13 @@
 use core::fmt;
 
+use crate::driver::devicetree::{DeviceTree, Node};
 use crate::driver::DriverLoadOrder;
 use crate::exception::asynchronous::IRQNumber;
+use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress};
+use crate::mem::{virtual_memory_manager, MemoryManager};
 use crate::sync::interface::Mutex;
-use crate::sync::IRQSafeNullLock;
-use crate::{info, println, todo_print};
+use crate::sync::{EarlyInit, IRQSafeNullLock};
+use crate::{dt, info, println, warn};
 
 static DRIVER_MANAGER: DriverManager<IRQNumber> = DriverManager::new();
 
@@ -14,7 +17,12 @@ pub fn driver_manager() -> &'static DriverManager<IRQNumber> {
 
 const MAX_DRIVERS: usize = 32;
 
-pub type DeviceDriverPostInitCallback = unsafe fn() -> Result<(), &'static str>;
+/// The most MMIO regions a single device can be probed with.
+const MAX_MMIO_REGIONS: usize = 4;
+
+/// Called after a driver has been initialised. During early init, it's handed the [`EarlyInit`]
+/// token, e.g. to register the driver as the IRQ manager.
+pub type DeviceDriverPostInitCallback = unsafe fn(Option<&EarlyInit>) -> Result<(), &'static str>;
 
 #[derive(Copy, Clone)]
 pub struct DeviceDriverDescriptor<T>
@@ -24,6 +32,10 @@ where
     device_driver: &'static (dyn super::interface::DeviceDriver<IRQNumberType = T> + Sync),
     post_init_callback: Option<DeviceDriverPostInitCallback>,
     irq_number: Option<&'static T>,
+    /// The `compatible` strings of the drivers which must be initialised before this one.
+    depends_on: &'static [&'static str],
+    /// Whether the device's MMIO regions have been found and mapped.
+    probed: bool,
     init_complete: bool,
 }
 
@@ -37,9 +49,117 @@ impl<T> DeviceDriverDescriptor<T> {
             device_driver,
             post_init_callback,
             irq_number,
+            depends_on: &[],
+            probed: false,
             init_complete: false,
         }
     }
+
+    /// Makes the driver wait for the drivers with the given `compatible` strings to be initialised
+    /// first.
+    ///
+    /// Dependencies must be loaded in the same phase as this driver, or an earlier one.
+    #[allow(unused)]
+    pub const fn with_dependencies(mut self, depends_on: &'static [&'static str]) -> Self {
+        self.depends_on = depends_on;
+        self
+    }
+}
+
+/// The state of a depth-first search through the dependencies of the drivers in a phase.
+struct TopologicalSort {
+    /// The drivers in the order they're to be initialised.
+    order: [usize; MAX_DRIVERS],
+    count: usize,
+    visits: [Visit; MAX_DRIVERS],
+    /// The chain of drivers that led to the one being visited.
+    path: [usize; MAX_DRIVERS],
+}
+
+/// Where a driver is in the depth-first search for its dependencies.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Visit {
+    Unvisited,
+    Visiting,
+    Done,
+}
+
+impl TopologicalSort {
+    /// Adds the driver at `index` to the order, after all of its dependencies, unless it's already
+    /// there. `depth` is the length of the chain of drivers that led here.
+    fn visit<T: fmt::Display + Copy>(
+        &mut self,
+        inner: &DriverManagerInner<T>,
+        index: usize,
+        load_order: &DriverLoadOrder,
+        depth: usize,
+    ) {
+        match self.visits[index] {
+            Visit::Done => return,
+            Visit::Visiting => {
+                let start = self.path[..depth].iter().position(|&i| i == index).unwrap();
+                panic!(
+                    "Driver dependency cycle: {}",
+                    DependencyCycle {
+                        descriptors: &inner.descriptors,
+                        path: &self.path[start..depth],
+                    }
+                );
+            }
+            Visit::Unvisited => {}
+        }
+
+        self.visits[index] = Visit::Visiting;
+        self.path[depth] = index;
+
+        let descriptor = inner.descriptors[index].unwrap();
+        let driver = descriptor.device_driver;
+        for &dependency in descriptor.depends_on {
+            let dependency_index = inner.find_compatible(dependency).unwrap_or_else(|| {
+                panic!(
+                    "Driver {} depends on {}, which isn't registered",
+                    driver.compatible(),
+                    dependency
+                )
+            });
+
+            if inner.is_pending(dependency_index, load_order) {
+                self.visit(inner, dependency_index, load_order, depth + 1);
+            } else if !inner.descriptors[dependency_index].unwrap().init_complete {
+                panic!(
+                    "Driver {} depends on {}, which is loaded in a later phase",
+                    driver.compatible(),
+                    dependency
+                );
+            }
+        }
+
+        self.visits[index] = Visit::Done;
+        self.order[self.count] = index;
+        self.count += 1;
+    }
+}
+
+/// A chain of drivers which depend on each other in a cycle, for reporting.
+struct DependencyCycle<'a, T: 'static> {
+    descriptors: &'a [Option<DeviceDriverDescriptor<T>>],
+    path: &'a [usize],
+}
+
+impl<T: fmt::Display> fmt::Display for DependencyCycle<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let compatible = |index: usize| match &self.descriptors[index] {
+            Some(descriptor) => descriptor.device_driver.compatible(),
+            None => "<none>",
+        };
+
+        for &index in self.path {
+            write!(f, "{} -> ", compatible(index))?;
+        }
+
+        // back to where it started
+        write!(f, "{}", compatible(self.path[0]))
+    }
 }
 
 struct DriverManagerInner<T>
@@ -59,7 +179,7 @@ where
 
 impl<T> DriverManagerInner<T>
 where
-    T: 'static + Copy,
+    T: 'static + fmt::Display + Copy,
 {
     pub const fn new() -> Self {
         Self {
@@ -67,6 +187,49 @@ where
             descriptors: [None; MAX_DRIVERS],
         }
     }
+
+    /// Returns the indices of the drivers in the given phase that still need to be initialised,
+    /// ordered so that every driver comes after the drivers it depends on, along with how many
+    /// there are.
+    ///
+    /// Panics if a dependency isn't registered, is loaded in a later phase, or is part of a cycle.
+    fn init_order(&self, load_order: &DriverLoadOrder) -> ([usize; MAX_DRIVERS], usize) {
+        let mut sort = TopologicalSort {
+            order: [0; MAX_DRIVERS],
+            count: 0,
+            visits: [Visit::Unvisited; MAX_DRIVERS],
+            path: [0; MAX_DRIVERS],
+        };
+
+        for index in 0..self.next_index {
+            if self.is_pending(index, load_order) {
+                sort.visit(self, index, load_order, 0);
+            }
+        }
+
+        (sort.order, sort.count)
+    }
+
+    /// Returns whether the driver at `index` is in the given phase, and not initialised yet.
+    fn is_pending(&self, index: usize, load_order: &DriverLoadOrder) -> bool {
+        match &self.descriptors[index] {
+            Some(descriptor) => {
+                !descriptor.init_complete && descriptor.device_driver.load_order() == *load_order
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the index of the first registered driver with the given `compatible` string.
+    fn find_compatible(&self, compatible: &str) -> Option<usize> {
+        self.descriptors[..self.next_index]
+            .iter()
+            .position(|descriptor| {
+                descriptor
+                    .as_ref()
+                    .map_or(false, |d| d.device_driver.compatible() == compatible)
+            })
+    }
 }
 
 impl<T> DriverManager<T>
@@ -94,74 +257,247 @@ where
         });
     }
 
-    pub fn init_interrupt_controller(&self) {
+    pub fn init_interrupt_controller(&self, early_init: &EarlyInit) {
         self.probe_devices(DriverLoadOrder::InterruptController);
-        unsafe { self.init_devices(DriverLoadOrder::InterruptController) }
+        unsafe { self.init_devices(DriverLoadOrder::InterruptController, Some(early_init)) }
     }
 
-    pub fn init_early(&self) {
+    pub fn init_early(&self, early_init: &EarlyInit) {
         self.probe_devices(DriverLoadOrder::Early);
         unsafe {
-            self.init_devices(DriverLoadOrder::Early);
+            self.init_devices(DriverLoadOrder::Early, Some(early_init));
         }
     }
 
     pub fn init_normal(&self) {
         self.probe_devices(DriverLoadOrder::Normal);
         unsafe {
-            self.init_devices(DriverLoadOrder::Normal);
+            self.init_devices(DriverLoadOrder::Normal, None);
         }
     }
 
-    unsafe fn init_devices(&self, load_order: DriverLoadOrder) {
-        self.for_each_mut(|descriptor| {
-            if descriptor.init_complete || descriptor.device_driver.load_order() != load_order {
-                return;
+    /// Initialises every driver in the given phase, after the drivers each one depends on.
+    unsafe fn init_devices(&self, load_order: DriverLoadOrder, early_init: Option<&EarlyInit>) {
+        self.inner.lock(|inner| {
+            let (order, count) = inner.init_order(&load_order);
+            for &index in &order[..count] {
+                Self::init_device(inner.descriptors[index].as_mut().unwrap(), early_init);
             }
+        });
+    }
 
-            if let Err(x) = descriptor.device_driver.init(descriptor.irq_number) {
+    /// Brings up a single driver, and runs its post-init callback.
+    unsafe fn init_device(
+        descriptor: &mut DeviceDriverDescriptor<T>,
+        early_init: Option<&EarlyInit>,
+    ) {
+        if let Err(x) = descriptor.device_driver.init(descriptor.irq_number) {
+            panic!(
+                "Failed to init driver: {}: {}",
+                descriptor.device_driver.compatible(),
+                x
+            );
+        }
+
+        if let Some(callback) = descriptor.post_init_callback {
+            if let Err(x) = callback(early_init) {
                 panic!(
-                    "Failed to init driver: {}: {}",
+                    "Error during driver post-init callback: {}: {}",
                     descriptor.device_driver.compatible(),
                     x
                 );
             }
+        }
+
+        descriptor.init_complete = true;
+    }
+
+    /// Initialises a driver that wasn't loaded at boot, such as one with
+    /// [`DriverLoadOrder::Manual`], probing it first if it hasn't been already.
+    ///
+    /// Returns an error if there's no driver registered with the given `compatible` string, it's
+    /// already loaded, any of its dependencies aren't loaded, or it fails to come up.
+    #[allow(unused)]
+    pub fn load(&self, compatible: &str) -> Result<(), &'static str> {
+        let dtb = match dt::blob() {
+            Some(blob) => Some(DeviceTree::new(blob)?),
+            None => None,
+        };
+
+        self.inner.lock(|inner| {
+            let index = inner
+                .find_compatible(compatible)
+                .ok_or("no driver registered with that compatible string")?;
+            let descriptor = inner.descriptors[index].unwrap();
+            if descriptor.init_complete {
+                return Err("driver is already loaded");
+            }
+
+            let dependencies_loaded = descriptor.depends_on.iter().all(|&dependency| {
+                inner
+                    .find_compatible(dependency)
+                    .map_or(false, |i| inner.descriptors[i].unwrap().init_complete)
+            });
+            if !dependencies_loaded {
+                return Err("driver depends on a driver that isn't loaded");
+            }
 
-            if let Some(callback) = descriptor.post_init_callback {
-                if let Err(x) = callback() {
-                    panic!(
-                        "Error during driver post-init callback: {}: {}",
-                        descriptor.device_driver.compatible(),
-                        x
-                    );
+            let descriptor = inner.descriptors[index].as_mut().unwrap();
+            if !descriptor.probed {
+                Self::probe_device(dtb.as_ref(), descriptor)?;
+            }
+
+            // Safe because the driver has been probed, and isn't running yet.
+            unsafe {
+                descriptor.device_driver.init(descriptor.irq_number)?;
+                if let Some(callback) = descriptor.post_init_callback {
+                    callback(None)?;
                 }
             }
 
             descriptor.init_complete = true;
-        });
+            Ok(())
+        })
+    }
+
+    /// Shuts down a loaded driver, after which it can be loaded again with [`load`](Self::load).
+    ///
+    /// Anything the driver's post-init callback registered it with, such as the console, is left
+    /// as it is; it's up to the caller to replace it first.
+    ///
+    /// Returns an error if there's no driver registered with the given `compatible` string, it
+    /// isn't loaded, another loaded driver depends on it, or it fails to shut down.
+    #[allow(unused)]
+    pub fn unload(&self, compatible: &str) -> Result<(), &'static str> {
+        self.inner.lock(|inner| {
+            let index = inner
+                .find_compatible(compatible)
+                .ok_or("no driver registered with that compatible string")?;
+            if !inner.descriptors[index].unwrap().init_complete {
+                return Err("driver isn't loaded");
+            }
+
+            let compatible = inner.descriptors[index].unwrap().device_driver.compatible();
+            let depended_on = inner.descriptors[..inner.next_index]
+                .iter()
+                .flatten()
+                .any(|d| d.init_complete && d.depends_on.contains(&compatible));
+            if depended_on {
+                return Err("another loaded driver depends on this one");
+            }
+
+            let descriptor = inner.descriptors[index].as_mut().unwrap();
+            // Safe because nothing else depends on the driver anymore.
+            unsafe { descriptor.device_driver.shutdown(descriptor.irq_number)? };
+
+            descriptor.init_complete = false;
+            Ok(())
+        })
     }
 
     fn probe_devices(&self, load_order: DriverLoadOrder) {
         println!("initialising device probe (load order: {:?})", load_order);
 
-        todo_print!("probe devices");
         // on ARM, we probe the device tree for info on devices
         #[cfg(not(target_arch = "aarch64"))]
         compile_error!("Add the target_arch to above's check if the following code is safe to use");
-        // let dtb = unsafe { Dtb::from_raw_parts(*DTB_PTR_ADDR as *const u8) }
-        //     .unwrap_or_else(|e| panic!("Failed to parse device tree: {:?}", e));
-
-        // dtb.walk(|path, obj| match obj {
-        //     DtbObj::SubNode { name } => {
-        //         let name_str = core::str::from_utf8(name).unwrap_or("");
-        //         println!("sub - {path}/{name_str}");
-        //         WalkOperation::StepInto
-        //     }
-        //     DtbObj::Property(prop) => {
-        //         println!("prop - {path}/{prop:?}");
-        //         WalkOperation::StepInto
-        //     }
-        // });
+        let dtb = dt::blob().map(|blob| {
+            DeviceTree::new(blob).unwrap_or_else(|e| panic!("Failed to parse device tree: {}", e))
+        });
+
+        self.for_each_mut(|descriptor| {
+            if descriptor.probed || descriptor.device_driver.load_order() != load_order {
+                return;
+            }
+
+            if let Err(x) = Self::probe_device(dtb.as_ref(), descriptor) {
+                panic!(
+                    "Failed to probe driver: {}: {}",
+                    descriptor.device_driver.compatible(),
+                    x
+                );
+            }
+        });
+    }
+
+    /// Finds the device tree node for a driver, and maps its MMIO regions for it.
+    ///
+    /// Every node compatible with the driver is tried in turn, until the driver accepts one. That
+    /// way, drivers for generic transports such as virtio-mmio can turn down the nodes that have a
+    /// different kind of device behind them.
+    fn probe_device(
+        dtb: Option<&DeviceTree>,
+        descriptor: &mut DeviceDriverDescriptor<T>,
+    ) -> Result<(), &'static str> {
+        let driver = descriptor.device_driver;
+        let region_count = driver.mmio_region_count();
+        if region_count == 0 {
+            descriptor.probed = true;
+            return Ok(());
+        }
+
+        assert!(region_count <= MAX_MMIO_REGIONS);
+
+        let mut probed = Err("no device tree node found");
+        dtb.ok_or("no device tree to probe with")?.walk(|node| {
+            if probed.is_err() && node.is_compatible(driver.compatible()) {
+                probed = Self::probe_node(driver, node, region_count);
+            }
+        })?;
+
+        probed?;
+        descriptor.probed = true;
+        Ok(())
+    }
+
+    /// Maps the MMIO regions of a device tree node for a driver, unmapping them again if the
+    /// driver turns the node down.
+    fn probe_node(
+        driver: &'static (dyn super::interface::DeviceDriver<IRQNumberType = T> + Sync),
+        node: &Node,
+        region_count: usize,
+    ) -> Result<(), &'static str> {
+        // map each of the device's register ranges into the kernel's MMIO window
+        let mut regions = [0usize; MAX_MMIO_REGIONS];
+        let mut sizes = [0usize; MAX_MMIO_REGIONS];
+        let mut found: usize = 0;
+        for (address, size) in node.reg().take(region_count) {
+            regions[found] = virtual_memory_manager()
+                .map_mmio_region(PhysicalAddress(address as usize), size as usize)
+                .0;
+            sizes[found] = size as usize;
+            found += 1;
+        }
+
+        let unmap = || {
+            for (&va, &size) in regions[..found].iter().zip(&sizes[..found]) {
+                if let Err(e) = virtual_memory_manager().unmap_mmio_region(VirtualAddress(va), size)
+                {
+                    warn!("Failed to unmap MMIO region of {}: {}", node.name, e);
+                }
+            }
+        };
+
+        if found < region_count {
+            warn!(
+                "Device tree node {} has {} reg entries, but {} needs {}",
+                node.name,
+                found,
+                driver.compatible(),
+                region_count
+            );
+            unmap();
+            return Err("device tree node has too few reg entries");
+        }
+
+        // Safe because the regions were just mapped for the device, and it isn't running yet.
+        if let Err(e) = unsafe { driver.set_mmio_regions(&regions[..region_count]) } {
+            unmap();
+            return Err(e);
+        }
+
+        println!("    {} -> {}", driver.compatible(), node.name);
+        Ok(())
     }
 
     fn for_each<'a>(&'a self, f: impl FnMut(&'a DeviceDriverDescriptor<T>)) {
@@ -184,3 +520,61 @@ where
         })
     }
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use super::{DeviceDriverDescriptor, DriverManager};
+    use crate::driver::interface::DeviceDriver;
+    use crate::driver::DriverLoadOrder;
+    use crate::exception::asynchronous::IRQNumber;
+    use crate::selftest::SelfTest;
+    use crate::sync::interface::Mutex;
+
+    pub const TESTS: &[SelfTest] = &[SelfTest {
+        name: "driver::manager::drivers are initialised after their dependencies",
+        run: dependencies_first,
+    }];
+
+    /// A driver which does nothing, and is only ever loaded manually.
+    struct TestDriver(&'static str);
+
+    impl DeviceDriver for TestDriver {
+        type IRQNumberType = IRQNumber;
+
+        fn load_order(&self) -> DriverLoadOrder {
+            DriverLoadOrder::Manual
+        }
+
+        fn compatible(&self) -> &'static str {
+            self.0
+        }
+    }
+
+    static A: TestDriver = TestDriver("selftest,a");
+    static B: TestDriver = TestDriver("selftest,b");
+    static C: TestDriver = TestDriver("selftest,c");
+
+    fn dependencies_first() {
+        let manager = DriverManager::<IRQNumber>::new();
+        // registered in the opposite order to the one they have to be initialised in
+        manager.register(
+            DeviceDriverDescriptor::new(&A, None, None).with_dependencies(&["selftest,b"]),
+        );
+        manager.register(
+            DeviceDriverDescriptor::new(&B, None, None).with_dependencies(&["selftest,c"]),
+        );
+        manager.register(DeviceDriverDescriptor::new(&C, None, None));
+
+        // drivers are indexed in the order they were registered, so C, B, then A
+        let (order, count) = manager
+            .inner
+            .lock(|inner| inner.init_order(&DriverLoadOrder::Manual));
+        assert_eq!(order[..count], [2, 1, 0]);
+
+        // drivers in other phases aren't part of the order
+        let (_, count) = manager
+            .inner
+            .lock(|inner| inner.init_order(&DriverLoadOrder::Normal));
+        assert_eq!(count, 0);
+    }
+}
@@ -6,8 +6,14 @@ mod common;
 mod descriptor;
 mod manager;
 
+pub mod block;
+pub mod devicetree;
+
 pub mod interrupt;
+pub mod timer;
 pub mod uart;
+pub mod video;
+pub mod virtio;
 
 pub mod interface {
     use core::fmt;
@@ -21,8 +27,22 @@ pub mod interface {
         fn load_order(&self) -> DriverLoadOrder;
 
         /// A string describing the device driver.
+        ///
+        /// This is matched against the `compatible` property of device tree nodes during probe.
         fn compatible(&self) -> &'static str;
 
+        /// The number of MMIO regions the device needs, in the order of its device tree node's
+        /// `reg` property.
+        fn mmio_region_count(&self) -> usize {
+            0
+        }
+
+        /// Called by the kernel during probe, before `init`, with the virtual start address of
+        /// each of the device's MMIO regions.
+        unsafe fn set_mmio_regions(&'static self, _regions: &[usize]) -> Result<(), &'static str> {
+            Ok(())
+        }
+
         /// Called by the kernel to bring up the device.
         unsafe fn init(
             &'static self,
@@ -30,6 +50,16 @@ pub mod interface {
         ) -> Result<(), &'static str> {
             Ok(())
         }
+
+        /// Called by the kernel to bring down the device when the driver is unloaded, with the same
+        /// IRQ number as `init`. Any IRQ handlers `init` registered must be removed here. The device
+        /// may be brought up again with `init` afterwards.
+        unsafe fn shutdown(
+            &'static self,
+            _irq_number: Option<&Self::IRQNumberType>,
+        ) -> Result<(), &'static str> {
+            Ok(())
+        }
     }
 }
 
@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: MIT
+//! ARM Generic Timer driver.
+//!
+//! The timer registers themselves are system registers, and are accessed through the
+//! architecture-specific code in [`crate::time`]. This driver only hooks the EL1 timer's interrupt
+//! up to the IRQ manager, so timeouts can be delivered. That's the physical timer by default, or
+//! the virtual timer with the `virtual_timer` feature.
+
+use crate::driver::DriverLoadOrder;
+use crate::exception::asynchronous::{irq_manager, IRQHandlerDescriptor, IRQNumber};
+use crate::exception::interface::IRQStatus;
+use crate::{driver, exception, time};
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+/// Representation of the ARM Generic Timer.
+pub struct ArmGenericTimer;
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl ArmGenericTimer {
+    pub const LOAD_ORDER: DriverLoadOrder = DriverLoadOrder::Early;
+    pub const COMPATIBLE: &'static str = "arm,armv8-timer";
+
+    /// Create an instance.
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl driver::interface::DeviceDriver for ArmGenericTimer {
+    type IRQNumberType = IRQNumber;
+
+    fn load_order(&self) -> DriverLoadOrder {
+        Self::LOAD_ORDER
+    }
+
+    fn compatible(&self) -> &'static str {
+        Self::COMPATIBLE
+    }
+
+    unsafe fn init(
+        &'static self,
+        irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        let irq_number = irq_number.ok_or("no IRQ number provided for the timer")?;
+
+        // Make sure the timer doesn't fire before anyone asked for it.
+        time::time_manager().cancel_timeout();
+
+        let descriptor = IRQHandlerDescriptor::new(*irq_number, Self::COMPATIBLE, self);
+
+        irq_manager().register_handler(descriptor)?;
+        irq_manager().enable(irq_number);
+
+        Ok(())
+    }
+
+    unsafe fn shutdown(
+        &'static self,
+        irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        time::time_manager().cancel_timeout();
+
+        if let Some(irq_number) = irq_number {
+            irq_manager().remove_handler(irq_number, Self::COMPATIBLE)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl exception::interface::IRQHandler for ArmGenericTimer {
+    fn handle(&self) -> Result<IRQStatus, &'static str> {
+        time::time_manager().handle_timeout();
+
+        Ok(IRQStatus::Handled)
+    }
+}
@@ -0,0 +1,4 @@
+pub use arm_generic_timer::*;
+
+// SPDX-License-Identifier: MIT
+mod arm_generic_timer;
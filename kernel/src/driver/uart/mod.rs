@@ -1,4 +1,22 @@
+pub use ns16550::*;
 pub use pl011::*;
 
 // SPDX-License-Identifier: MIT
+mod ns16550;
 mod pl011;
+mod rx_buffer;
+
+use rx_buffer::RxBuffer;
+
+pub mod interface {
+    use crate::console;
+
+    /// A serial port that can back the console, whichever chip it is.
+    pub trait Uart: console::interface::All {
+        /// A short name for the chip, e.g. `PL011`.
+        fn chip_name(&self) -> &'static str;
+
+        /// The baud rate the UART was set up with.
+        fn baud_rate(&self) -> u32;
+    }
+}
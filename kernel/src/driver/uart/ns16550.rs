@@ -0,0 +1,546 @@
+// SPDX-License-Identifier: MIT
+//! NS16550 (8250 family) UART driver.
+//!
+//! The 16550's eight registers are the same on every chip, but how far apart they are isn't: most
+//! are byte spaced, while many SoCs put them 4 bytes apart (`reg-shift = <2>` in the device tree),
+//! and need them accessed as 32-bit words. The spacing is passed to the driver when it's created.
+//!
+//! Received characters are buffered by the IRQ handler. Characters are sent by polling the line
+//! status register, since console output must keep working with IRQs masked, e.g. while panicking.
+//!
+//! # Resources
+//!
+//! - <https://www.ti.com/lit/ds/symlink/pc16550d.pdf>
+
+use core::{fmt, ptr};
+
+use tock_registers::{register_bitfields, LocalRegisterCopy};
+
+use super::RxBuffer;
+use crate::console::ThroughputCounter;
+use crate::driver::interrupt::gicv2::IRQNumber;
+use crate::driver::DriverLoadOrder;
+use crate::exception::asynchronous::{irq_manager, IRQHandlerDescriptor};
+use crate::exception::interface::IRQStatus;
+use crate::sync::interface::Mutex;
+use crate::{console, cpu, driver, exception, sync::IRQSafeNullLock};
+
+//--------------------------------------------------------------------------------------------------
+// Private Definitions
+//--------------------------------------------------------------------------------------------------
+
+// NS16550 UART registers.
+//
+// Descriptions taken from the "PC16550D Universal Asynchronous Receiver/Transmitter with FIFOs"
+// datasheet.
+register_bitfields! {
+    u8,
+
+    /// Interrupt Enable Register.
+    IER [
+        /// Enable Transmitter Holding Register Empty Interrupt.
+        ETBEI OFFSET(1) NUMBITS(1) [],
+
+        /// Enable Received Data Available Interrupt. Also enables the character timeout
+        /// interrupt when the FIFOs are enabled.
+        ERBFI OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Interrupt Identification Register.
+    IIR [
+        /// The highest priority pending interrupt.
+        ID OFFSET(1) NUMBITS(3) [
+            ModemStatus = 0b000,
+            TransmitterHoldingRegisterEmpty = 0b001,
+            ReceivedDataAvailable = 0b010,
+            ReceiverLineStatus = 0b011,
+            CharacterTimeout = 0b110
+        ],
+
+        /// Set when no interrupt is pending.
+        NO_INTERRUPT_PENDING OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// FIFO Control Register.
+    FCR [
+        /// The number of characters in the RX FIFO that raises the received data interrupt.
+        RX_TRIGGER OFFSET(6) NUMBITS(2) [
+            OneByte = 0b00,
+            FourBytes = 0b01,
+            EightBytes = 0b10,
+            FourteenBytes = 0b11
+        ],
+
+        /// Clears the TX FIFO. Self clearing.
+        TX_FIFO_RESET OFFSET(2) NUMBITS(1) [],
+
+        /// Clears the RX FIFO. Self clearing.
+        RX_FIFO_RESET OFFSET(1) NUMBITS(1) [],
+
+        /// Enables both FIFOs. Must be set for the other bits to be written.
+        FIFO_ENABLE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Line Control Register.
+    LCR [
+        /// Divisor Latch Access Bit. While set, the first two registers are the divisor latch
+        /// rather than the data and interrupt enable registers.
+        DLAB OFFSET(7) NUMBITS(1) [],
+
+        /// Parity enable.
+        PEN OFFSET(3) NUMBITS(1) [],
+
+        /// Number of stop bits: clear for one.
+        STB OFFSET(2) NUMBITS(1) [],
+
+        /// Word length.
+        #[allow(clippy::enum_variant_names)]
+        WLS OFFSET(0) NUMBITS(2) [
+            FiveBit = 0b00,
+            SixBit = 0b01,
+            SevenBit = 0b10,
+            EightBit = 0b11
+        ]
+    ],
+
+    /// Modem Control Register.
+    MCR [
+        /// Auxiliary output 2, which gates the interrupt line on PC compatible designs.
+        OUT2 OFFSET(3) NUMBITS(1) [],
+
+        /// Request To Send.
+        RTS OFFSET(1) NUMBITS(1) [],
+
+        /// Data Terminal Ready.
+        DTR OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Line Status Register.
+    LSR [
+        /// Transmitter empty: both the TX FIFO and the shift register are empty.
+        TEMT OFFSET(6) NUMBITS(1) [],
+
+        /// Transmitter Holding Register empty: the TX FIFO is empty and can take more characters.
+        THRE OFFSET(5) NUMBITS(1) [],
+
+        /// Data Ready: at least one character is waiting in the RX FIFO.
+        DR OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+/// The indices of the registers, which are multiplied by the register stride to get their offsets.
+mod reg {
+    /// Receiver Buffer Register when read, Transmitter Holding Register when written. The divisor
+    /// latch's low byte while `LCR.DLAB` is set.
+    pub const RBR_THR: usize = 0;
+    pub const DLL: usize = 0;
+
+    /// Interrupt Enable Register. The divisor latch's high byte while `LCR.DLAB` is set.
+    pub const IER: usize = 1;
+    pub const DLM: usize = 1;
+
+    /// Interrupt Identification Register when read, FIFO Control Register when written.
+    pub const IIR_FCR: usize = 2;
+
+    pub const LCR: usize = 3;
+    pub const MCR: usize = 4;
+    pub const LSR: usize = 5;
+    pub const MSR: usize = 6;
+}
+
+/// How many characters the TX FIFO holds, once the transmitter holding register reads as empty.
+const TX_FIFO_SIZE: usize = 16;
+
+#[allow(dead_code)]
+#[derive(PartialEq)]
+enum BlockingMode {
+    Blocking,
+    NonBlocking,
+}
+
+struct Ns16550UartInner {
+    mmio_start_addr: usize,
+    reg_stride: usize,
+    clock_hz: u32,
+    rx_buffer: RxBuffer,
+    /// How many more characters can be written before the TX FIFO has to be checked again.
+    tx_fifo_space: usize,
+    tx_counter: ThroughputCounter,
+    rx_counter: ThroughputCounter,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+/// Representation of the UART.
+pub struct Ns16550Uart {
+    inner: IRQSafeNullLock<Ns16550UartInner>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private Code
+//--------------------------------------------------------------------------------------------------
+
+impl Ns16550UartInner {
+    /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address and register stride.
+    const unsafe fn new(mmio_start_addr: usize, reg_stride: usize, clock_hz: u32) -> Self {
+        assert!(
+            reg_stride == 1 || reg_stride == 4,
+            "unsupported NS16550 register stride"
+        );
+
+        Self {
+            mmio_start_addr,
+            reg_stride,
+            clock_hz,
+            rx_buffer: RxBuffer::new(),
+            tx_fifo_space: 0,
+            tx_counter: ThroughputCounter::new(),
+            rx_counter: ThroughputCounter::new(),
+        }
+    }
+
+    /// Reads the register at index `reg`.
+    fn read(&self, reg: usize) -> u8 {
+        let addr = self.mmio_start_addr + reg * self.reg_stride;
+
+        // Safe because the address is within the device's registers, given a correct MMIO start
+        // address and stride.
+        unsafe {
+            match self.reg_stride {
+                4 => ptr::read_volatile(addr as *const u32) as u8,
+                _ => ptr::read_volatile(addr as *const u8),
+            }
+        }
+    }
+
+    /// Writes `value` to the register at index `reg`.
+    fn write(&self, reg: usize, value: u8) {
+        let addr = self.mmio_start_addr + reg * self.reg_stride;
+
+        // Safe because the address is within the device's registers, given a correct MMIO start
+        // address and stride.
+        unsafe {
+            match self.reg_stride {
+                4 => ptr::write_volatile(addr as *mut u32, value as u32),
+                _ => ptr::write_volatile(addr as *mut u8, value),
+            }
+        }
+    }
+
+    fn line_status(&self) -> LocalRegisterCopy<u8, LSR::Register> {
+        LocalRegisterCopy::new(self.read(reg::LSR))
+    }
+
+    /// Set up baud rate and characteristics.
+    ///
+    /// This results in 8N1 at [`Ns16550Uart::BAUD_RATE`], with both FIFOs enabled and cleared, and
+    /// the received data interrupt raised for every character.
+    ///
+    /// The divisor latch holds the UART clock divided by 16 times the baud rate, e.g.
+    /// `1_843_200 / (16 * 115_200) = 1` for the classic 1.8432 MHz crystal.
+    fn init(&mut self) -> Result<(), &'static str> {
+        let divisor = (self.clock_hz + 8 * Ns16550Uart::BAUD_RATE) / (16 * Ns16550Uart::BAUD_RATE);
+        if divisor == 0 || divisor > u16::MAX as u32 {
+            return Err("NS16550 clock can't be divided down to the baud rate");
+        }
+
+        // Don't cut off characters still being sent.
+        self.flush();
+
+        // Mask all interrupts while the UART is being set up.
+        self.write(reg::IER, 0);
+
+        // Program the divisor through the divisor latch, then set 8N1, which also hides the latch
+        // again.
+        self.write(reg::LCR, LCR::DLAB::SET.value);
+        self.write(reg::DLL, divisor as u8);
+        self.write(reg::DLM, (divisor >> 8) as u8);
+        self.write(
+            reg::LCR,
+            (LCR::WLS::EightBit + LCR::PEN::CLEAR + LCR::STB::CLEAR).value,
+        );
+
+        self.write(
+            reg::IIR_FCR,
+            (FCR::FIFO_ENABLE::SET
+                + FCR::RX_FIFO_RESET::SET
+                + FCR::TX_FIFO_RESET::SET
+                + FCR::RX_TRIGGER::OneByte)
+                .value,
+        );
+        self.write(
+            reg::MCR,
+            (MCR::DTR::SET + MCR::RTS::SET + MCR::OUT2::SET).value,
+        );
+        self.tx_fifo_space = 0;
+
+        // Enable the RX IRQ, which also covers the RX timeout.
+        self.write(reg::IER, IER::ERBFI::SET.value);
+
+        Ok(())
+    }
+
+    /// Send a character.
+    fn write_char(&mut self, c: char) {
+        // Once the holding register reads as empty, the whole TX FIFO is free, so it only needs
+        // checking again after a FIFO's worth of characters.
+        while self.tx_fifo_space == 0 {
+            if self.line_status().is_set(LSR::THRE) {
+                self.tx_fifo_space = TX_FIFO_SIZE;
+            } else {
+                cpu::nop();
+            }
+        }
+
+        self.write(reg::RBR_THR, c as u8);
+        self.tx_fifo_space -= 1;
+
+        self.tx_counter.add(1);
+    }
+
+    /// Block execution until the last buffered character has been physically put on the TX wire.
+    fn flush(&self) {
+        while !self.line_status().is_set(LSR::TEMT) {
+            cpu::nop();
+        }
+    }
+
+    /// Retrieve a character.
+    fn read_char_converting(&mut self, blocking_mode: BlockingMode) -> Option<char> {
+        while !self.line_status().is_set(LSR::DR) {
+            if blocking_mode == BlockingMode::NonBlocking {
+                return None;
+            }
+
+            cpu::nop();
+        }
+
+        // Read one character, converting carriage return to newline.
+        let mut ret = self.read(reg::RBR_THR) as char;
+        if ret == '\r' {
+            ret = '\n';
+        }
+
+        self.rx_counter.add(1);
+
+        Some(ret)
+    }
+
+    /// Move all characters currently in the RX FIFO into the RX buffer.
+    fn drain_rx_fifo(&mut self) {
+        while let Some(c) = self.read_char_converting(BlockingMode::NonBlocking) {
+            // drop anything that doesn't fit; nobody is reading it anyway
+            self.rx_buffer.push(c as u8);
+        }
+    }
+
+    /// Retrieve a buffered character, falling back to the RX FIFO in case the character hasn't
+    /// been buffered by the IRQ handler yet (e.g. because IRQs are masked).
+    fn read_buffered_char(&mut self) -> Option<char> {
+        if let Some(c) = self.rx_buffer.pop() {
+            return Some(c as char);
+        }
+
+        self.read_char_converting(BlockingMode::NonBlocking)
+    }
+}
+
+impl fmt::Write for Ns16550UartInner {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl Ns16550Uart {
+    pub const LOAD_ORDER: DriverLoadOrder = DriverLoadOrder::Early;
+    pub const COMPATIBLE: &'static str = "ns16550a";
+    /// The baud rate `init` sets up.
+    pub const BAUD_RATE: u32 = 115_200;
+
+    /// Create an instance.
+    ///
+    /// `reg_stride` is the distance between registers in bytes, either 1 or 4, and `clock_hz` the
+    /// frequency of the UART's input clock.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address and register stride.
+    #[allow(unused)]
+    pub const unsafe fn new(mmio_start_addr: usize, reg_stride: usize, clock_hz: u32) -> Self {
+        Self {
+            inner: IRQSafeNullLock::new(Ns16550UartInner::new(
+                mmio_start_addr,
+                reg_stride,
+                clock_hz,
+            )),
+        }
+    }
+}
+
+impl driver::interface::DeviceDriver for Ns16550Uart {
+    type IRQNumberType = IRQNumber;
+
+    fn load_order(&self) -> DriverLoadOrder {
+        Self::LOAD_ORDER
+    }
+
+    fn compatible(&self) -> &'static str {
+        Self::COMPATIBLE
+    }
+
+    fn mmio_region_count(&self) -> usize {
+        1
+    }
+
+    unsafe fn set_mmio_regions(&'static self, regions: &[usize]) -> Result<(), &'static str> {
+        self.inner.lock(|inner| inner.mmio_start_addr = regions[0]);
+
+        Ok(())
+    }
+
+    unsafe fn init(
+        &'static self,
+        irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        self.inner.lock(|inner| inner.init())?;
+
+        // Enable IRQs.
+        let irq_number = irq_number.ok_or("the NS16550 needs an IRQ number")?;
+        let descriptor = IRQHandlerDescriptor::new(*irq_number, Self::COMPATIBLE, self);
+
+        irq_manager().register_handler(descriptor)?;
+        irq_manager().enable(irq_number);
+
+        Ok(())
+    }
+
+    unsafe fn shutdown(
+        &'static self,
+        irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        if let Some(irq_number) = irq_number {
+            irq_manager().remove_handler(irq_number, Self::COMPATIBLE)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl console::interface::Write for Ns16550Uart {
+    fn write_char(&self, c: char) {
+        self.inner.lock(|inner| inner.write_char(c));
+    }
+
+    fn write_str(&self, s: &str) {
+        self.inner
+            .lock(|inner| fmt::Write::write_str(inner, s))
+            .ok();
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        self.inner.lock(|inner| fmt::Write::write_fmt(inner, args))
+    }
+
+    fn flush(&self) {
+        self.inner.lock(|inner| inner.flush());
+    }
+}
+
+impl console::interface::Read for Ns16550Uart {
+    fn read_char(&self) -> char {
+        // Don't hold the lock while waiting, so that the IRQ handler can fill the buffer.
+        loop {
+            if let Some(c) = self.inner.lock(|inner| inner.read_buffered_char()) {
+                return c;
+            }
+
+            cpu::nop();
+        }
+    }
+
+    fn clear_rx(&self) {
+        self.inner.lock(|inner| {
+            inner.rx_buffer.clear();
+
+            while inner
+                .read_char_converting(BlockingMode::NonBlocking)
+                .is_some()
+            {}
+        });
+    }
+}
+
+impl console::interface::Statistics for Ns16550Uart {
+    fn get_tx_count(&self) -> usize {
+        self.inner.lock(|inner| inner.tx_counter.total())
+    }
+
+    fn get_rx_count(&self) -> usize {
+        self.inner.lock(|inner| inner.rx_counter.total())
+    }
+
+    fn get_tx_bytes_per_sec(&self) -> usize {
+        self.inner.lock(|inner| inner.tx_counter.bytes_per_sec())
+    }
+
+    fn get_rx_bytes_per_sec(&self) -> usize {
+        self.inner.lock(|inner| inner.rx_counter.bytes_per_sec())
+    }
+}
+
+impl console::interface::All for Ns16550Uart {}
+
+impl super::interface::Uart for Ns16550Uart {
+    fn chip_name(&self) -> &'static str {
+        "NS16550"
+    }
+
+    fn baud_rate(&self) -> u32 {
+        Self::BAUD_RATE
+    }
+}
+
+impl exception::interface::IRQHandler for Ns16550Uart {
+    fn handle(&self) -> Result<IRQStatus, &'static str> {
+        self.inner.lock(|inner| {
+            let iir: LocalRegisterCopy<u8, IIR::Register> =
+                LocalRegisterCopy::new(inner.read(reg::IIR_FCR));
+            if iir.is_set(IIR::NO_INTERRUPT_PENDING) {
+                return Ok(IRQStatus::NotMine);
+            }
+
+            match iir.read_as_enum(IIR::ID) {
+                Some(IIR::ID::Value::ReceivedDataAvailable)
+                | Some(IIR::ID::Value::CharacterTimeout) => {
+                    // buffer all available characters until they're read
+                    inner.drain_rx_fifo();
+                }
+                Some(IIR::ID::Value::ReceiverLineStatus) => {
+                    // reading the line status register clears the interrupt
+                    inner.line_status();
+                }
+                Some(IIR::ID::Value::ModemStatus) => {
+                    // as does reading the modem status register for its interrupt
+                    inner.read(reg::MSR);
+                }
+                _ => {}
+            }
+
+            Ok(IRQStatus::Handled)
+        })
+    }
+}
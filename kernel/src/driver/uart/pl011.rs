@@ -6,7 +6,7 @@
 //! - <https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf>
 //! - <https://developer.arm.com/documentation/ddi0183/latest>
 
-use core::fmt;
+use core::{fmt, mem};
 
 use tock_registers::{
     interfaces::{Readable, Writeable},
@@ -18,9 +18,12 @@ use crate::{console, cpu, driver, exception, sync::IRQSafeNullLock};
 //------------------------------------------------------------------------------
 // OS Interface Code
 //------------------------------------------------------------------------------
+use super::RxBuffer;
+use crate::console::ThroughputCounter;
 use crate::driver::interrupt::gicv2::IRQNumber;
 use crate::driver::{DriverLoadOrder, MMIODerefWrapper};
 use crate::exception::asynchronous::{irq_manager, IRQHandlerDescriptor};
+use crate::exception::interface::IRQStatus;
 use crate::sync::interface::Mutex;
 
 //--------------------------------------------------------------------------------------------------
@@ -210,9 +213,13 @@ register_structs! {
     }
 }
 
+// The register block must span exactly the registers the driver expects.
+const _: () = assert!(mem::size_of::<RegisterBlock>() == 0x48);
+
 /// Abstraction for the associated MMIO registers.
 type Registers = MMIODerefWrapper<RegisterBlock>;
 
+#[allow(dead_code)]
 #[derive(PartialEq)]
 enum BlockingMode {
     Blocking,
@@ -221,8 +228,9 @@ enum BlockingMode {
 
 struct PL011UartInner {
     registers: Registers,
-    chars_written: usize,
-    chars_read: usize,
+    rx_buffer: RxBuffer,
+    tx_counter: ThroughputCounter,
+    rx_counter: ThroughputCounter,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -234,6 +242,12 @@ pub struct PL011Uart {
     inner: IRQSafeNullLock<PL011UartInner>,
 }
 
+/// A bare writer for a PL011 that firmware has already set up, for output before the driver is
+/// loaded. It needs neither the heap, nor interrupts, nor the device tree.
+pub struct PL011EarlyWriter {
+    registers: Registers,
+}
+
 //--------------------------------------------------------------------------------------------------
 // Private Code
 //--------------------------------------------------------------------------------------------------
@@ -247,11 +261,21 @@ impl PL011UartInner {
     pub const unsafe fn new(mmio_start_addr: usize) -> Self {
         Self {
             registers: Registers::new(mmio_start_addr),
-            chars_written: 0,
-            chars_read: 0,
+            rx_buffer: RxBuffer::new(),
+            tx_counter: ThroughputCounter::new(),
+            rx_counter: ThroughputCounter::new(),
         }
     }
 
+    /// Points the driver at a different MMIO start address.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address, before the device is used.
+    pub unsafe fn set_mmio_start_addr(&mut self, mmio_start_addr: usize) {
+        self.registers.set_start_addr(mmio_start_addr);
+    }
+
     /// Set up baud rate and characteristics.
     ///
     /// This results in 8N1 and 921_600 baud.
@@ -323,7 +347,7 @@ impl PL011UartInner {
         // Write the character to the buffer.
         self.registers.DR.set(c as u32);
 
-        self.chars_written += 1;
+        self.tx_counter.add(1);
     }
 
     /// Block execution until the last buffered character has been physically put on the TX wire.
@@ -358,10 +382,28 @@ impl PL011UartInner {
         }
 
         // Update statistics.
-        self.chars_read += 1;
+        self.rx_counter.add(1);
 
         Some(ret)
     }
+
+    /// Move all characters currently in the RX FIFO into the RX buffer.
+    fn drain_rx_fifo(&mut self) {
+        while let Some(c) = self.read_char_converting(BlockingMode::NonBlocking) {
+            // drop anything that doesn't fit; nobody is reading it anyway
+            self.rx_buffer.push(c as u8);
+        }
+    }
+
+    /// Retrieve a buffered character, falling back to the RX FIFO in case the character hasn't
+    /// been buffered by the IRQ handler yet (e.g. because IRQs are masked).
+    fn read_buffered_char(&mut self) -> Option<char> {
+        if let Some(c) = self.rx_buffer.pop() {
+            return Some(c as char);
+        }
+
+        self.read_char_converting(BlockingMode::NonBlocking)
+    }
 }
 
 /// Implementing `core::fmt::Write` enables usage of the `format_args!` macros, which in turn are
@@ -390,12 +432,15 @@ impl fmt::Write for PL011UartInner {
 impl PL011Uart {
     pub const LOAD_ORDER: DriverLoadOrder = DriverLoadOrder::Early;
     pub const COMPATIBLE: &'static str = "arm,pl011";
+    /// The baud rate `init` sets up.
+    pub const BAUD_RATE: u32 = 921_600;
 
     /// Create an instance.
     ///
     /// # Safety
     ///
     /// - The user must ensure to provide a correct MMIO start address.
+    #[allow(unused)]
     pub const unsafe fn new(mmio_start_addr: usize) -> Self {
         Self {
             inner: IRQSafeNullLock::new(PL011UartInner::new(mmio_start_addr)),
@@ -403,6 +448,33 @@ impl PL011Uart {
     }
 }
 
+impl PL011EarlyWriter {
+    /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct, mapped MMIO start address.
+    pub const unsafe fn new(mmio_start_addr: usize) -> Self {
+        Self {
+            registers: Registers::new(mmio_start_addr),
+        }
+    }
+}
+
+impl fmt::Write for PL011EarlyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            while self.registers.FR.matches_all(FR::TXFF::SET) {
+                cpu::nop();
+            }
+
+            self.registers.DR.set(c as u32);
+        }
+
+        Ok(())
+    }
+}
+
 impl driver::interface::DeviceDriver for PL011Uart {
     type IRQNumberType = IRQNumber;
 
@@ -414,6 +486,17 @@ impl driver::interface::DeviceDriver for PL011Uart {
         Self::COMPATIBLE
     }
 
+    fn mmio_region_count(&self) -> usize {
+        1
+    }
+
+    unsafe fn set_mmio_regions(&'static self, regions: &[usize]) -> Result<(), &'static str> {
+        self.inner
+            .lock(|inner| inner.set_mmio_start_addr(regions[0]));
+
+        Ok(())
+    }
+
     unsafe fn init(
         &'static self,
         irq_number: Option<&Self::IRQNumberType>,
@@ -428,6 +511,17 @@ impl driver::interface::DeviceDriver for PL011Uart {
 
         Ok(())
     }
+
+    unsafe fn shutdown(
+        &'static self,
+        irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        if let Some(irq_number) = irq_number {
+            irq_manager().remove_handler(irq_number, Self::COMPATIBLE)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl console::interface::Write for PL011Uart {
@@ -437,6 +531,12 @@ impl console::interface::Write for PL011Uart {
         self.inner.lock(|inner| inner.write_char(c));
     }
 
+    fn write_str(&self, s: &str) {
+        self.inner
+            .lock(|inner| fmt::Write::write_str(inner, s))
+            .ok();
+    }
+
     fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
         // Fully qualified syntax for the call to `core::fmt::Write::write_fmt()` to increase
         // readability.
@@ -451,49 +551,77 @@ impl console::interface::Write for PL011Uart {
 
 impl console::interface::Read for PL011Uart {
     fn read_char(&self) -> char {
-        self.inner
-            .lock(|inner| inner.read_char_converting(BlockingMode::Blocking).unwrap())
+        // Don't hold the lock while waiting, so that the IRQ handler can fill the buffer.
+        loop {
+            if let Some(c) = self.inner.lock(|inner| inner.read_buffered_char()) {
+                return c;
+            }
+
+            cpu::nop();
+        }
     }
 
     fn clear_rx(&self) {
-        // Read from the RX FIFO until it is indicating empty.
-        while self
-            .inner
-            .lock(|inner| inner.read_char_converting(BlockingMode::NonBlocking))
-            .is_some()
-        {}
+        self.inner.lock(|inner| {
+            inner.rx_buffer.clear();
+
+            // Read from the RX FIFO until it is indicating empty.
+            while inner
+                .read_char_converting(BlockingMode::NonBlocking)
+                .is_some()
+            {}
+        });
     }
 }
 
 impl console::interface::Statistics for PL011Uart {
     fn get_tx_count(&self) -> usize {
-        self.inner.lock(|inner| inner.chars_written)
+        self.inner.lock(|inner| inner.tx_counter.total())
     }
 
     fn get_rx_count(&self) -> usize {
-        self.inner.lock(|inner| inner.chars_read)
+        self.inner.lock(|inner| inner.rx_counter.total())
+    }
+
+    fn get_tx_bytes_per_sec(&self) -> usize {
+        self.inner.lock(|inner| inner.tx_counter.bytes_per_sec())
+    }
+
+    fn get_rx_bytes_per_sec(&self) -> usize {
+        self.inner.lock(|inner| inner.rx_counter.bytes_per_sec())
     }
 }
 
 impl console::interface::All for PL011Uart {}
 
 impl exception::interface::IRQHandler for PL011Uart {
-    fn handle(&self) -> Result<(), &'static str> {
+    fn handle(&self) -> Result<IRQStatus, &'static str> {
         self.inner.lock(|inner| {
             let pending = inner.registers.MIS.extract();
+            if pending.get() == 0 {
+                return Ok(IRQStatus::NotMine);
+            }
 
             // clear all pending interrupts
             inner.registers.ICR.write(ICR::ALL::CLEAR);
 
             // check for any RX interrupt
             if pending.matches_any(MIS::RXMIS::SET + MIS::RTMIS::SET) {
-                // echo all available characters
-                while let Some(c) = inner.read_char_converting(BlockingMode::NonBlocking) {
-                    inner.write_char(c);
-                }
+                // buffer all available characters until they're read
+                inner.drain_rx_fifo();
             }
-        });
 
-        Ok(())
+            Ok(IRQStatus::Handled)
+        })
+    }
+}
+
+impl super::interface::Uart for PL011Uart {
+    fn chip_name(&self) -> &'static str {
+        "PL011"
+    }
+
+    fn baud_rate(&self) -> u32 {
+        Self::BAUD_RATE
     }
 }
@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: MIT
+//! The buffer UART drivers keep received characters in between their IRQ handler and readers.
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+/// Size of the buffer holding received characters that haven't been read yet.
+pub const RX_BUFFER_SIZE: usize = 256;
+
+/// A fixed-size ring buffer of received characters.
+/// Characters received while the buffer is full are dropped.
+pub struct RxBuffer {
+    data: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl RxBuffer {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends a character, returning `false` if the buffer is full.
+    pub fn push(&mut self, c: u8) -> bool {
+        if self.len == RX_BUFFER_SIZE {
+            return false;
+        }
+
+        self.data[(self.head + self.len) % RX_BUFFER_SIZE] = c;
+        self.len += 1;
+        true
+    }
+
+    /// Removes the oldest character.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let c = self.data[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(c)
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
@@ -0,0 +1,495 @@
+// SPDX-License-Identifier: MIT
+//! Text console on the linear framebuffer provided by the bootloader.
+//!
+//! Characters are rendered with the built-in 8x16 font. Once the cursor passes the last row, the
+//! whole screen is scrolled up by one row of text. ANSI colours and cursor movement are honoured.
+
+use core::fmt;
+use core::ptr;
+
+use limine::LimineFramebufferRequest;
+
+use crate::console::ansi::{self, Action, EraseMode};
+use crate::console::ThroughputCounter;
+use crate::driver::video::font::{self, FONT_HEIGHT, FONT_WIDTH};
+use crate::driver::DriverLoadOrder;
+use crate::exception::asynchronous::IRQNumber;
+use crate::mem::vm::paging::{Attributes, VirtualAddress};
+use crate::mem::{virtual_memory_manager, MemoryManager};
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+use crate::{console, driver, info};
+
+//--------------------------------------------------------------------------------------------------
+// Private Definitions
+//--------------------------------------------------------------------------------------------------
+
+static BOOTLOADER_FRAMEBUFFER_INFO: LimineFramebufferRequest = LimineFramebufferRequest::new(0);
+
+const BYTES_PER_PIXEL: usize = 4;
+
+type Color = (u8, u8, u8);
+
+const FOREGROUND: Color = (0xaa, 0xaa, 0xaa);
+const BACKGROUND: Color = (0x00, 0x00, 0x00);
+
+/// The standard VGA colours, in ANSI order, followed by their bright variants.
+const PALETTE: [Color; 16] = [
+    (0x00, 0x00, 0x00),
+    (0xaa, 0x00, 0x00),
+    (0x00, 0xaa, 0x00),
+    (0xaa, 0x55, 0x00),
+    (0x00, 0x00, 0xaa),
+    (0xaa, 0x00, 0xaa),
+    (0x00, 0xaa, 0xaa),
+    (0xaa, 0xaa, 0xaa),
+    (0x55, 0x55, 0x55),
+    (0xff, 0x55, 0x55),
+    (0x55, 0xff, 0x55),
+    (0xff, 0xff, 0x55),
+    (0x55, 0x55, 0xff),
+    (0xff, 0x55, 0xff),
+    (0x55, 0xff, 0xff),
+    (0xff, 0xff, 0xff),
+];
+
+/// The order of the colour channels in a 32-bit pixel, from the most to the least significant byte.
+#[derive(Copy, Clone, Debug)]
+enum PixelLayout {
+    /// `0x00RRGGBB`
+    Rgb,
+    /// `0x00BBGGRR`
+    Bgr,
+}
+
+/// The geometry of a mapped framebuffer.
+struct Framebuffer {
+    /// The virtual address of the first pixel.
+    address: usize,
+    width: usize,
+    height: usize,
+    /// The number of bytes between the start of one row of pixels and the next.
+    pitch: usize,
+    layout: PixelLayout,
+}
+
+/// The colours and attributes set with SGR sequences.
+#[derive(Copy, Clone)]
+struct TextStyle {
+    /// An index into [`PALETTE`], or `None` for the default foreground colour.
+    foreground: Option<usize>,
+    background: Color,
+    bold: bool,
+    dim: bool,
+}
+
+struct FramebufferConsoleInner {
+    framebuffer: Option<Framebuffer>,
+    parser: ansi::Parser,
+    style: TextStyle,
+    /// The cursor column, in characters.
+    column: usize,
+    /// The cursor row, in characters.
+    row: usize,
+    tx_counter: ThroughputCounter,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+/// A console on the bootloader's framebuffer. Output is dropped if there is no framebuffer.
+pub struct FramebufferConsole {
+    inner: IRQSafeNullLock<FramebufferConsoleInner>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private Code
+//--------------------------------------------------------------------------------------------------
+
+impl PixelLayout {
+    /// Works out the layout from the sizes and positions of the colour channels.
+    fn from_limine(framebuffer: &limine::LimineFramebuffer) -> Result<Self, &'static str> {
+        if framebuffer.bpp as usize != BYTES_PER_PIXEL * 8 {
+            return Err("unsupported framebuffer depth");
+        }
+
+        let sizes = (
+            framebuffer.red_mask_size,
+            framebuffer.green_mask_size,
+            framebuffer.blue_mask_size,
+        );
+        let shifts = (
+            framebuffer.red_mask_shift,
+            framebuffer.green_mask_shift,
+            framebuffer.blue_mask_shift,
+        );
+
+        match (sizes, shifts) {
+            ((8, 8, 8), (16, 8, 0)) => Ok(Self::Rgb),
+            ((8, 8, 8), (0, 8, 16)) => Ok(Self::Bgr),
+            _ => Err("unsupported framebuffer pixel layout"),
+        }
+    }
+
+    fn pixel(&self, (r, g, b): Color) -> u32 {
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+        match self {
+            Self::Rgb => r << 16 | g << 8 | b,
+            Self::Bgr => b << 16 | g << 8 | r,
+        }
+    }
+}
+
+impl Framebuffer {
+    fn columns(&self) -> usize {
+        self.width / FONT_WIDTH
+    }
+
+    fn rows(&self) -> usize {
+        self.height / FONT_HEIGHT
+    }
+
+    /// Draws `c` in the character cell at `column`, `row`.
+    fn draw_char(&self, c: char, column: usize, row: usize, style: &TextStyle) {
+        let foreground = self.layout.pixel(style.foreground());
+        let background = self.layout.pixel(style.background);
+        let x = column * FONT_WIDTH;
+
+        for (i, bits) in font::glyph(c).iter().enumerate() {
+            let line = self.address + (row * FONT_HEIGHT + i) * self.pitch + x * BYTES_PER_PIXEL;
+            for j in 0..FONT_WIDTH {
+                let pixel = match bits & (0x80 >> j) {
+                    0 => background,
+                    _ => foreground,
+                };
+
+                // Safe because the cell is within the mapped framebuffer.
+                unsafe { ptr::write_volatile((line + j * BYTES_PER_PIXEL) as *mut u32, pixel) };
+            }
+        }
+    }
+
+    /// Fills the character rows from `start` up to `end` with `color`.
+    fn clear_rows(&self, start: usize, end: usize, color: Color) {
+        let pixel = self.layout.pixel(color);
+        for y in start * FONT_HEIGHT..(end * FONT_HEIGHT).min(self.height) {
+            let line = self.address + y * self.pitch;
+            for x in 0..self.width {
+                // Safe because the pixel is within the mapped framebuffer.
+                unsafe { ptr::write_volatile((line + x * BYTES_PER_PIXEL) as *mut u32, pixel) };
+            }
+        }
+    }
+
+    /// Fills the character cells of `row` from column `start` up to `end` with `color`.
+    fn clear_cells(&self, row: usize, start: usize, end: usize, color: Color) {
+        let pixel = self.layout.pixel(color);
+        let end = end.min(self.columns());
+        for y in row * FONT_HEIGHT..(row + 1) * FONT_HEIGHT {
+            let line = self.address + y * self.pitch;
+            for x in start * FONT_WIDTH..end * FONT_WIDTH {
+                // Safe because the pixel is within the mapped framebuffer.
+                unsafe { ptr::write_volatile((line + x * BYTES_PER_PIXEL) as *mut u32, pixel) };
+            }
+        }
+    }
+
+    /// Moves every row of text up by one, and clears the last row to `color`.
+    fn scroll(&self, color: Color) {
+        let row_bytes = FONT_HEIGHT * self.pitch;
+        let moved_bytes = (self.rows() - 1) * row_bytes;
+
+        // Safe because both ranges are within the mapped framebuffer. They overlap, so this must be
+        // a memmove.
+        unsafe {
+            ptr::copy(
+                (self.address + row_bytes) as *const u8,
+                self.address as *mut u8,
+                moved_bytes,
+            );
+        }
+
+        self.clear_rows(self.rows() - 1, self.rows(), color);
+    }
+}
+
+impl TextStyle {
+    const DEFAULT: Self = Self {
+        foreground: None,
+        background: BACKGROUND,
+        bold: false,
+        dim: false,
+    };
+
+    fn foreground(&self) -> Color {
+        let color = match self.foreground {
+            Some(index) if self.bold && index < 8 => PALETTE[index + 8],
+            Some(index) => PALETTE[index],
+            None if self.bold => PALETTE[15],
+            None => FOREGROUND,
+        };
+
+        match self.dim {
+            true => (color.0 / 2, color.1 / 2, color.2 / 2),
+            false => color,
+        }
+    }
+
+    /// Applies the parameters of an SGR sequence. Unsupported parameters are ignored.
+    fn apply(&mut self, params: &ansi::Params) {
+        for param in params.iter() {
+            match param {
+                0 => *self = Self::DEFAULT,
+                1 => self.bold = true,
+                2 => self.dim = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                30..=37 => self.foreground = Some(param as usize - 30),
+                39 => self.foreground = None,
+                40..=47 => self.background = PALETTE[param as usize - 40],
+                49 => self.background = BACKGROUND,
+                90..=97 => self.foreground = Some(param as usize - 90 + 8),
+                100..=107 => self.background = PALETTE[param as usize - 100 + 8],
+                _ => {}
+            }
+        }
+    }
+}
+
+impl FramebufferConsoleInner {
+    const fn new() -> Self {
+        Self {
+            framebuffer: None,
+            parser: ansi::Parser::new(),
+            style: TextStyle::DEFAULT,
+            column: 0,
+            row: 0,
+            tx_counter: ThroughputCounter::new(),
+        }
+    }
+
+    /// Maps the first framebuffer the bootloader set up, if there is one, and clears it.
+    fn init(&mut self) -> Result<(), &'static str> {
+        let limine_fb = match BOOTLOADER_FRAMEBUFFER_INFO.get_response().get() {
+            Some(response) => match response.framebuffers().first() {
+                Some(fb) => fb,
+                None => return Ok(()),
+            },
+            // not every machine has a display
+            None => return Ok(()),
+        };
+
+        let layout = PixelLayout::from_limine(limine_fb)?;
+        let pitch = limine_fb.pitch as usize;
+        let width = limine_fb.width as usize;
+        let height = limine_fb.height as usize;
+        if width * BYTES_PER_PIXEL > pitch || width < FONT_WIDTH || height < FONT_HEIGHT {
+            return Err("invalid framebuffer geometry");
+        }
+
+        // the bootloader gives us the framebuffer's direct map address
+        let address = limine_fb
+            .address
+            .as_ptr()
+            .ok_or("framebuffer has no address")? as usize;
+        let pa = VirtualAddress(address).from_direct_map();
+        let va = virtual_memory_manager().map_mmio_region_with(
+            pa,
+            pitch * height,
+            Attributes::NORMAL_NC,
+        );
+
+        let framebuffer = Framebuffer {
+            address: va.0,
+            width,
+            height,
+            pitch,
+            layout,
+        };
+        framebuffer.clear_rows(0, framebuffer.rows(), BACKGROUND);
+
+        info!(
+            "Framebuffer: {}x{}, pitch {}, {:?} at {:#x}",
+            width, height, pitch, layout, pa.0
+        );
+        self.framebuffer = Some(framebuffer);
+
+        Ok(())
+    }
+
+    fn write_char(&mut self, c: char) {
+        if self.framebuffer.is_none() {
+            return;
+        }
+
+        match self.parser.advance(c) {
+            Some(Action::Print(c)) => self.put_char(c),
+            Some(action) => self.perform(action),
+            None => {}
+        }
+
+        self.tx_counter.add(1);
+    }
+
+    /// Draws a character at the cursor, or moves the cursor for control characters.
+    fn put_char(&mut self, c: char) {
+        let Some(framebuffer) = &self.framebuffer else {
+            return;
+        };
+
+        match c {
+            '\n' => {
+                self.column = 0;
+                self.row += 1;
+            }
+            '\r' => self.column = 0,
+            '\x08' => self.column = self.column.saturating_sub(1),
+            _ => {
+                if self.column == framebuffer.columns() {
+                    self.column = 0;
+                    self.row += 1;
+                }
+
+                if self.row == framebuffer.rows() {
+                    framebuffer.scroll(self.style.background);
+                    self.row -= 1;
+                }
+
+                framebuffer.draw_char(c, self.column, self.row, &self.style);
+                self.column += 1;
+            }
+        }
+
+        // scroll as soon as a newline moves past the last row, so the cursor is always on screen
+        if self.row == framebuffer.rows() {
+            framebuffer.scroll(self.style.background);
+            self.row -= 1;
+        }
+    }
+
+    /// Carries out an escape sequence.
+    fn perform(&mut self, action: Action) {
+        let Some(framebuffer) = &self.framebuffer else {
+            return;
+        };
+        let background = self.style.background;
+
+        match action {
+            Action::Print(_) => {}
+            Action::SelectGraphicRendition(params) => self.style.apply(&params),
+            Action::CursorPosition { row, column } => {
+                self.row = row.min(framebuffer.rows() - 1);
+                self.column = column.min(framebuffer.columns() - 1);
+            }
+            Action::EraseDisplay(mode) => {
+                let (row, rows) = (self.row, framebuffer.rows());
+                match mode {
+                    EraseMode::ToEnd => framebuffer.clear_rows(row + 1, rows, background),
+                    EraseMode::ToCursor => framebuffer.clear_rows(0, row, background),
+                    EraseMode::All => framebuffer.clear_rows(0, rows, background),
+                }
+
+                if mode != EraseMode::All {
+                    self.perform(Action::EraseLine(mode));
+                }
+            }
+            Action::EraseLine(mode) => {
+                let (column, columns) = (self.column, framebuffer.columns());
+                let (start, end) = match mode {
+                    EraseMode::ToEnd => (column, columns),
+                    EraseMode::ToCursor => (0, column + 1),
+                    EraseMode::All => (0, columns),
+                };
+                framebuffer.clear_cells(self.row, start, end, background);
+            }
+        }
+    }
+}
+
+/// Implementing `core::fmt::Write` enables usage of the `format_args!` macros, which in turn are
+/// used to implement the `kernel`'s `print!` and `println!` macros.
+impl fmt::Write for FramebufferConsoleInner {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl FramebufferConsole {
+    pub const LOAD_ORDER: DriverLoadOrder = DriverLoadOrder::Normal;
+    pub const COMPATIBLE: &'static str = "limine,framebuffer";
+
+    pub const fn new() -> Self {
+        Self {
+            inner: IRQSafeNullLock::new(FramebufferConsoleInner::new()),
+        }
+    }
+
+    /// Whether the bootloader provided a framebuffer, and it's been mapped.
+    pub fn is_present(&self) -> bool {
+        self.inner.lock(|inner| inner.framebuffer.is_some())
+    }
+}
+
+impl driver::interface::DeviceDriver for FramebufferConsole {
+    type IRQNumberType = IRQNumber;
+
+    fn load_order(&self) -> DriverLoadOrder {
+        Self::LOAD_ORDER
+    }
+
+    fn compatible(&self) -> &'static str {
+        Self::COMPATIBLE
+    }
+
+    unsafe fn init(
+        &'static self,
+        _irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        self.inner.lock(|inner| inner.init())
+    }
+}
+
+impl console::interface::Write for FramebufferConsole {
+    fn write_char(&self, c: char) {
+        self.inner.lock(|inner| inner.write_char(c));
+    }
+
+    fn write_str(&self, s: &str) {
+        self.inner
+            .lock(|inner| fmt::Write::write_str(inner, s))
+            .ok();
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        self.inner.lock(|inner| fmt::Write::write_fmt(inner, args))
+    }
+
+    fn flush(&self) {}
+}
+
+impl console::interface::Read for FramebufferConsole {
+    fn clear_rx(&self) {}
+}
+
+impl console::interface::Statistics for FramebufferConsole {
+    fn get_tx_count(&self) -> usize {
+        self.inner.lock(|inner| inner.tx_counter.total())
+    }
+
+    fn get_tx_bytes_per_sec(&self) -> usize {
+        self.inner.lock(|inner| inner.tx_counter.bytes_per_sec())
+    }
+}
+
+impl console::interface::All for FramebufferConsole {}
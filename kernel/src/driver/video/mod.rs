@@ -0,0 +1,5 @@
+// SPDX-License-Identifier: MIT
+mod font;
+mod framebuffer;
+
+pub use framebuffer::*;
@@ -0,0 +1,406 @@
+// SPDX-License-Identifier: MIT
+//! Virtio block device driver.
+//!
+//! Requests go through the device's single request queue, one at a time, and the driver polls for
+//! each to complete. Each request is a chain of descriptors: the request header, one buffer for
+//! each page of the caller's data, since it needn't be physically contiguous, and the status byte
+//! the device writes once it's done.
+//!
+//! # Resources
+//!
+//! - <https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html#x1-2850002>
+
+use core::mem::size_of;
+use core::time::Duration;
+
+use super::mmio::MmioTransport;
+use super::queue::{Buffer, Virtqueue, QUEUE_SIZE};
+use super::DeviceId;
+use crate::driver::block::{self, SECTOR_SIZE};
+use crate::driver::interrupt::gicv2::IRQNumber;
+use crate::driver::{self as drv, DriverLoadOrder};
+use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress, VirtualMemoryRegion, PAGE_SIZE};
+use crate::mem::vm::translation::KernelTranslation;
+use crate::mem::{self, clean_dcache, invalidate_dcache};
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+use crate::{cpu, time};
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// The header at the start of every request.
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Reads sectors from the device.
+const VIRTIO_BLK_T_IN: u32 = 0;
+/// Writes sectors to the device.
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// What the status byte is set to before each request, which the device never writes.
+const STATUS_PENDING: u8 = 0xff;
+
+/// The number of the request queue.
+const REQUEST_QUEUE: u32 = 0;
+
+/// Where the capacity is in the device's configuration space, in 512-byte sectors.
+const CAPACITY_OFFSET: usize = 0;
+
+/// Where the status byte is in the request page, after the header.
+const STATUS_OFFSET: usize = size_of::<RequestHeader>();
+
+/// How long a request may take before the device is given up on.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NOT_READY: &str = "virtio-blk device isn't initialised";
+
+struct VirtioBlkInner {
+    transport: MmioTransport,
+    queue: Option<Virtqueue>,
+    /// The page holding the request header and status byte, by direct map and physical address.
+    request: Option<(VirtualAddress, PhysicalAddress)>,
+    sector_count: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+pub struct VirtioBlk {
+    inner: IRQSafeNullLock<VirtioBlkInner>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl VirtioBlkInner {
+    const unsafe fn new(mmio_start_addr: usize) -> Self {
+        Self {
+            transport: MmioTransport::new(mmio_start_addr),
+            queue: None,
+            request: None,
+            sector_count: 0,
+        }
+    }
+
+    fn init(&mut self) -> Result<(), &'static str> {
+        // none of the optional features are needed
+        self.transport.negotiate(0)?;
+
+        let queue = Virtqueue::new()?;
+        self.transport.set_up_queue(REQUEST_QUEUE, &queue)?;
+        self.queue = Some(queue);
+
+        if self.request.is_none() {
+            self.request =
+                Some(mem::alloc_dma(PAGE_SIZE, PAGE_SIZE).ok_or("out of memory for requests")?);
+        }
+
+        self.sector_count = self.transport.read_config_u64(CAPACITY_OFFSET);
+        self.transport.driver_ok();
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        // the device lets go of the queue once it's reset, so it can be freed
+        self.transport.reset();
+        self.queue = None;
+        self.sector_count = 0;
+    }
+
+    /// Transfers `len` bytes at `buf` to or from consecutive sectors starting at `lba`, in as many
+    /// requests as it takes.
+    fn transfer(
+        &mut self,
+        kind: u32,
+        lba: u64,
+        buf: usize,
+        len: usize,
+    ) -> Result<(), &'static str> {
+        let (_, request_pa) = self.request.ok_or(NOT_READY)?;
+        let header = Buffer {
+            pa: request_pa,
+            len: STATUS_OFFSET as u32,
+            device_writes: false,
+        };
+        let status = Buffer {
+            pa: PhysicalAddress(request_pa.0 + STATUS_OFFSET),
+            len: 1,
+            device_writes: true,
+        };
+
+        let mut done = 0;
+        while done < len {
+            let start = done;
+            let mut chain = [header; QUEUE_SIZE];
+            let mut count = 1;
+
+            // a buffer for each page, leaving room for the status byte
+            while done < len && count < QUEUE_SIZE - 1 {
+                let va = buf + done;
+                let piece = (len - done).min(PAGE_SIZE - va % PAGE_SIZE);
+                let pa = KernelTranslation
+                    .virtual_to_physical(VirtualAddress(va))
+                    .ok_or("buffer isn't mapped")?;
+
+                chain[count] = Buffer {
+                    pa,
+                    len: piece as u32,
+                    device_writes: kind == VIRTIO_BLK_T_IN,
+                };
+                count += 1;
+                done += piece;
+            }
+
+            // a request covers whole sectors, so any partial sector at the end waits for the next
+            let mut partial = (done - start) % SECTOR_SIZE;
+            done -= partial;
+            while partial > 0 {
+                let last = &mut chain[count - 1];
+                let trim = partial.min(last.len as usize);
+                last.len -= trim as u32;
+                partial -= trim;
+                if last.len == 0 {
+                    count -= 1;
+                }
+            }
+
+            chain[count] = status;
+            count += 1;
+
+            let sector = lba + (start / SECTOR_SIZE) as u64;
+            let data = VirtualMemoryRegion::new(buf + start, buf + done);
+            self.submit(kind, sector, &chain[..count], &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes a single request, made up of `chain`, for the sectors starting at `sector`, and waits
+    /// for the device to complete it. `data` is the part of the caller's buffer it covers.
+    fn submit(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        chain: &[Buffer],
+        data: &VirtualMemoryRegion,
+    ) -> Result<(), &'static str> {
+        let (request_va, _) = self.request.ok_or(NOT_READY)?;
+        let queue = self.queue.as_mut().ok_or(NOT_READY)?;
+        let status = (request_va.0 + STATUS_OFFSET) as *mut u8;
+
+        // Safe because the request page belongs to the driver, and the device doesn't use it
+        // between requests.
+        unsafe {
+            (request_va.0 as *mut RequestHeader).write_volatile(RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            });
+            status.write_volatile(STATUS_PENDING);
+        }
+        clean_dcache(&VirtualMemoryRegion::new(
+            request_va.0,
+            request_va.0 + STATUS_OFFSET + 1,
+        ));
+
+        // whatever the device reads must be in memory, and nothing cached may be written back over
+        // what it writes
+        if kind == VIRTIO_BLK_T_IN {
+            invalidate_dcache(data);
+        } else {
+            clean_dcache(data);
+        }
+
+        queue.push(chain);
+        self.transport.notify(REQUEST_QUEUE);
+
+        let deadline = time::now_nanos() + REQUEST_TIMEOUT.as_nanos() as u64;
+        let written = loop {
+            if let Some(written) = queue.pop_used() {
+                break written;
+            }
+
+            if self.transport.needs_reset() || time::now_nanos() > deadline {
+                // the request may still be in the queue, so the device has to be brought up again
+                self.shutdown();
+                return Err("virtio-blk device stopped responding");
+            }
+            cpu::nop();
+        };
+
+        if kind == VIRTIO_BLK_T_IN {
+            invalidate_dcache(data);
+        }
+        invalidate_dcache(&VirtualMemoryRegion::new(
+            status as usize,
+            status as usize + 1,
+        ));
+
+        // Safe because the device is done with the request.
+        match unsafe { status.read_volatile() } {
+            VIRTIO_BLK_S_OK if kind == VIRTIO_BLK_T_IN && written as usize != data.len() + 1 => {
+                Err("virtio-blk device returned a short read")
+            }
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_IOERR => Err("virtio-blk I/O error"),
+            VIRTIO_BLK_S_UNSUPP => Err("virtio-blk request unsupported"),
+            _ => Err("virtio-blk device returned an invalid status"),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+impl VirtioBlk {
+    /// The disk is optional, so the driver is only loaded when something needs it.
+    pub const LOAD_ORDER: DriverLoadOrder = DriverLoadOrder::Manual;
+    pub const COMPATIBLE: &'static str = "virtio,mmio";
+
+    /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address.
+    pub const unsafe fn new(mmio_start_addr: usize) -> Self {
+        Self {
+            inner: IRQSafeNullLock::new(VirtioBlkInner::new(mmio_start_addr)),
+        }
+    }
+}
+
+impl drv::interface::DeviceDriver for VirtioBlk {
+    type IRQNumberType = IRQNumber;
+
+    fn load_order(&self) -> DriverLoadOrder {
+        Self::LOAD_ORDER
+    }
+
+    fn compatible(&self) -> &'static str {
+        Self::COMPATIBLE
+    }
+
+    fn mmio_region_count(&self) -> usize {
+        1
+    }
+
+    unsafe fn set_mmio_regions(&'static self, regions: &[usize]) -> Result<(), &'static str> {
+        // every transport is compatible, but only some have a block device behind them
+        self.inner.lock(|inner| {
+            inner.transport.set_mmio_start_addr(regions[0]);
+            inner.transport.probe(DeviceId::Block)
+        })
+    }
+
+    unsafe fn init(
+        &'static self,
+        _irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        self.inner.lock(|inner| inner.init())
+    }
+
+    unsafe fn shutdown(
+        &'static self,
+        _irq_number: Option<&Self::IRQNumberType>,
+    ) -> Result<(), &'static str> {
+        self.inner.lock(|inner| inner.shutdown());
+        Ok(())
+    }
+}
+
+impl block::interface::BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.inner.lock(|inner| inner.sector_count)
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.inner.lock(|inner| {
+            block::check_request(inner.sector_count, lba, buf.len())?;
+            inner.transfer(VIRTIO_BLK_T_IN, lba, buf.as_mut_ptr() as usize, buf.len())
+        })
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        self.inner.lock(|inner| {
+            block::check_request(inner.sector_count, lba, buf.len())?;
+            inner.transfer(VIRTIO_BLK_T_OUT, lba, buf.as_ptr() as usize, buf.len())
+        })
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::vec;
+
+    use super::VirtioBlk;
+    use crate::bsp::driver::disk;
+    use crate::driver::block::SECTOR_SIZE;
+    use crate::driver::driver_manager;
+    use crate::mem::allocator::align_up;
+    use crate::mem::vm::paging::PAGE_SIZE;
+    use crate::selftest::SelfTest;
+
+    pub const TESTS: &[SelfTest] = &[SelfTest {
+        name: "virtio::blk::sector 100 write and read back",
+        run: write_read_sector_100,
+    }];
+
+    /// The first sector the test overwrites, well clear of anything a partition table would use.
+    const TEST_SECTOR: u64 = 100;
+    const TEST_SECTORS: usize = 3;
+
+    /// How far before a page boundary each buffer starts, so that every transfer is split across
+    /// pages, and not on a sector boundary either.
+    const PAGE_OFFSET: usize = 700;
+
+    /// Returns where in `buf` a buffer of `len` bytes starts so that it crosses a page boundary
+    /// [`PAGE_OFFSET`] bytes in.
+    fn straddling_start(buf: &[u8], len: usize) -> usize {
+        let addr = buf.as_ptr() as usize;
+        let start = align_up(addr + PAGE_OFFSET, PAGE_SIZE) - PAGE_OFFSET - addr;
+        assert!(start + len <= buf.len());
+        start
+    }
+
+    fn write_read_sector_100() {
+        driver_manager()
+            .load(VirtioBlk::COMPATIBLE)
+            .expect("virtio-blk disk must be attached");
+        let disk = disk();
+        assert!(disk.sector_count() > TEST_SECTOR + TEST_SECTORS as u64);
+
+        let len = TEST_SECTORS * SECTOR_SIZE;
+        let mut source = vec![0u8; len + PAGE_SIZE];
+        let start = straddling_start(&source, len);
+        for (i, byte) in source[start..start + len].iter_mut().enumerate() {
+            *byte = (i * 7 + i / SECTOR_SIZE) as u8;
+        }
+        let pattern = &source[start..start + len];
+        disk.write_blocks(TEST_SECTOR, pattern).unwrap();
+
+        let mut dest = vec![0u8; len + PAGE_SIZE];
+        let start = straddling_start(&dest, len);
+        disk.read_blocks(TEST_SECTOR, &mut dest[start..start + len])
+            .unwrap();
+        assert!(dest[start..start + len] == *pattern);
+
+        // a single sector from the middle of what was written
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        disk.read_blocks(TEST_SECTOR + 1, &mut sector).unwrap();
+        assert!(sector[..] == pattern[SECTOR_SIZE..2 * SECTOR_SIZE]);
+
+        // requests the device must never see
+        assert!(disk.read_blocks(TEST_SECTOR, &mut sector[..100]).is_err());
+        assert!(disk.read_blocks(disk.sector_count(), &mut sector).is_err());
+    }
+}
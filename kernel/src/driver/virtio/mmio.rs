@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: MIT
+//! The virtio-mmio transport, where each device has a block of registers in memory.
+//!
+//! # Resources
+//!
+//! - <https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html#x1-1650002>
+
+use core::mem;
+
+use tock_registers::{
+    interfaces::{ReadWriteable, Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+use super::queue::{Virtqueue, QUEUE_SIZE};
+use super::{DeviceId, VIRTIO_F_VERSION_1};
+use crate::cpu;
+use crate::driver::MMIODerefWrapper;
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+register_bitfields! {
+    u32,
+
+    /// Device Status, which the driver uses to report its progress in setting up the device.
+    STATUS [
+        /// The driver has noticed the device.
+        ACKNOWLEDGE OFFSET(0) NUMBITS(1) [],
+
+        /// The driver knows how to drive the device.
+        DRIVER OFFSET(1) NUMBITS(1) [],
+
+        /// The driver is set up and ready to drive the device.
+        DRIVER_OK OFFSET(2) NUMBITS(1) [],
+
+        /// The driver has acknowledged the features it understands, and negotiation is complete.
+        FEATURES_OK OFFSET(3) NUMBITS(1) [],
+
+        /// The device has hit an error it can't recover from without being reset.
+        DEVICE_NEEDS_RESET OFFSET(6) NUMBITS(1) [],
+
+        /// The driver has given up on the device.
+        FAILED OFFSET(7) NUMBITS(1) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub RegisterBlock {
+        (0x000 => MagicValue: ReadOnly<u32>),
+        (0x004 => Version: ReadOnly<u32>),
+        (0x008 => DeviceID: ReadOnly<u32>),
+        (0x00c => VendorID: ReadOnly<u32>),
+        (0x010 => DeviceFeatures: ReadOnly<u32>),
+        (0x014 => DeviceFeaturesSel: WriteOnly<u32>),
+        (0x018 => _reserved1),
+        (0x020 => DriverFeatures: WriteOnly<u32>),
+        (0x024 => DriverFeaturesSel: WriteOnly<u32>),
+        (0x028 => _reserved2),
+        (0x030 => QueueSel: WriteOnly<u32>),
+        (0x034 => QueueNumMax: ReadOnly<u32>),
+        (0x038 => QueueNum: WriteOnly<u32>),
+        (0x03c => _reserved3),
+        (0x044 => QueueReady: ReadWrite<u32>),
+        (0x048 => _reserved4),
+        (0x050 => QueueNotify: WriteOnly<u32>),
+        (0x054 => _reserved5),
+        (0x060 => InterruptStatus: ReadOnly<u32>),
+        (0x064 => InterruptACK: WriteOnly<u32>),
+        (0x068 => _reserved6),
+        (0x070 => Status: ReadWrite<u32, STATUS::Register>),
+        (0x074 => _reserved7),
+        (0x080 => QueueDescLow: WriteOnly<u32>),
+        (0x084 => QueueDescHigh: WriteOnly<u32>),
+        (0x088 => _reserved8),
+        (0x090 => QueueDriverLow: WriteOnly<u32>),
+        (0x094 => QueueDriverHigh: WriteOnly<u32>),
+        (0x098 => _reserved9),
+        (0x0a0 => QueueDeviceLow: WriteOnly<u32>),
+        (0x0a4 => QueueDeviceHigh: WriteOnly<u32>),
+        (0x0a8 => _reserved10),
+        (0x0fc => ConfigGeneration: ReadOnly<u32>),
+        (0x100 => Config: [ReadOnly<u32>; 64]),
+        (0x200 => @END),
+    }
+}
+
+// The register block must span exactly the registers the driver expects.
+const _: () = assert!(mem::size_of::<RegisterBlock>() == 0x200);
+
+/// Abstraction for the associated MMIO registers.
+type Registers = MMIODerefWrapper<RegisterBlock, TRANSPORT_ALIGN>;
+
+/// QEMU packs its virtio-mmio transports 0x200 bytes apart, several to a page.
+const TRANSPORT_ALIGN: usize = 0x200;
+
+/// "virt" in little endian, which every virtio-mmio register block starts with.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+/// The version of the modern interface. Legacy devices report 1.
+const MODERN_VERSION: u32 = 2;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+pub struct MmioTransport {
+    registers: Registers,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+impl MmioTransport {
+    /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address.
+    pub const unsafe fn new(mmio_start_addr: usize) -> Self {
+        Self {
+            registers: Registers::new(mmio_start_addr),
+        }
+    }
+
+    /// Points the transport at a different MMIO start address.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide a correct MMIO start address, before the device is used.
+    pub unsafe fn set_mmio_start_addr(&mut self, mmio_start_addr: usize) {
+        self.registers.set_start_addr(mmio_start_addr);
+    }
+
+    /// Checks that the registers belong to a modern virtio-mmio transport with a `device` behind
+    /// it. Transports with nothing plugged into them have a device ID of 0.
+    pub fn probe(&self, device: DeviceId) -> Result<(), &'static str> {
+        if self.registers.MagicValue.get() != MAGIC_VALUE {
+            return Err("not a virtio-mmio device");
+        }
+        if self.registers.Version.get() != MODERN_VERSION {
+            return Err("legacy virtio-mmio devices aren't supported");
+        }
+        if self.registers.DeviceID.get() != device as u32 {
+            return Err("wrong kind of virtio device");
+        }
+
+        Ok(())
+    }
+
+    /// Resets the device and negotiates features with it, accepting those in `features` that the
+    /// device offers.
+    pub fn negotiate(&mut self, features: u64) -> Result<(), &'static str> {
+        self.reset();
+        self.registers.Status.write(STATUS::ACKNOWLEDGE::SET);
+        self.registers
+            .Status
+            .modify(STATUS::ACKNOWLEDGE::SET + STATUS::DRIVER::SET);
+
+        let offered = self.device_features();
+        if offered & VIRTIO_F_VERSION_1 == 0 {
+            self.fail();
+            return Err("virtio device doesn't support version 1");
+        }
+
+        self.set_driver_features((features | VIRTIO_F_VERSION_1) & offered);
+        self.registers.Status.modify(STATUS::FEATURES_OK::SET);
+
+        // the device clears FEATURES_OK if it can't work with the features we picked
+        if !self.registers.Status.is_set(STATUS::FEATURES_OK) {
+            self.fail();
+            return Err("virtio device rejected the negotiated features");
+        }
+
+        Ok(())
+    }
+
+    /// Hands `queue` to the device as queue number `index`.
+    pub fn set_up_queue(&mut self, index: u32, queue: &Virtqueue) -> Result<(), &'static str> {
+        self.registers.QueueSel.set(index);
+        if self.registers.QueueReady.get() != 0 {
+            return Err("virtqueue is already in use");
+        }
+        if (self.registers.QueueNumMax.get() as usize) < QUEUE_SIZE {
+            return Err("virtqueue is too small");
+        }
+
+        self.registers.QueueNum.set(QUEUE_SIZE as u32);
+
+        let descriptors = queue.descriptor_area().0 as u64;
+        self.registers.QueueDescLow.set(descriptors as u32);
+        self.registers.QueueDescHigh.set((descriptors >> 32) as u32);
+        let driver = queue.driver_area().0 as u64;
+        self.registers.QueueDriverLow.set(driver as u32);
+        self.registers.QueueDriverHigh.set((driver >> 32) as u32);
+        let device = queue.device_area().0 as u64;
+        self.registers.QueueDeviceLow.set(device as u32);
+        self.registers.QueueDeviceHigh.set((device >> 32) as u32);
+
+        self.registers.QueueReady.set(1);
+        Ok(())
+    }
+
+    /// Tells the device that the driver is ready, after which it starts processing requests.
+    pub fn driver_ok(&mut self) {
+        self.registers.Status.modify(STATUS::DRIVER_OK::SET);
+    }
+
+    /// Tells the device that there are new requests in queue number `index`.
+    pub fn notify(&self, index: u32) {
+        self.registers.QueueNotify.set(index);
+    }
+
+    /// Returns whether the device has stopped working, and needs to be reset.
+    pub fn needs_reset(&self) -> bool {
+        self.registers.Status.is_set(STATUS::DEVICE_NEEDS_RESET)
+    }
+
+    /// Reads the 64-bit value at `offset` in the device-specific configuration space.
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        // the device may change its configuration between the two halves, which the generation
+        // count gives away
+        loop {
+            let generation = self.registers.ConfigGeneration.get();
+            let low = self.registers.Config[offset / 4].get() as u64;
+            let high = self.registers.Config[offset / 4 + 1].get() as u64;
+            if self.registers.ConfigGeneration.get() == generation {
+                return high << 32 | low;
+            }
+        }
+    }
+
+    /// Resets the device, after which it no longer uses any of its queues.
+    pub fn reset(&mut self) {
+        self.registers.Status.set(0);
+
+        // the reset is complete once the status reads back as 0
+        while self.registers.Status.get() != 0 {
+            cpu::nop();
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl MmioTransport {
+    fn device_features(&self) -> u64 {
+        self.registers.DeviceFeaturesSel.set(0);
+        let low = self.registers.DeviceFeatures.get() as u64;
+        self.registers.DeviceFeaturesSel.set(1);
+        let high = self.registers.DeviceFeatures.get() as u64;
+
+        high << 32 | low
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.registers.DriverFeaturesSel.set(0);
+        self.registers.DriverFeatures.set(features as u32);
+        self.registers.DriverFeaturesSel.set(1);
+        self.registers.DriverFeatures.set((features >> 32) as u32);
+    }
+
+    fn fail(&mut self) {
+        self.registers.Status.modify(STATUS::FAILED::SET);
+    }
+}
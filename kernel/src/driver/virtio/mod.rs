@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: MIT
+//! Virtio devices, the paravirtualised devices provided by hypervisors such as QEMU.
+//!
+//! A virtio device is reached through a transport, which is how the driver finds the device and
+//! negotiates with it. Only the virtio-mmio transport is supported, and only its modern (version 2)
+//! interface. Requests are passed to the device through virtqueues in shared memory.
+//!
+//! # Resources
+//!
+//! - <https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html>
+
+mod blk;
+mod mmio;
+mod queue;
+
+pub use blk::*;
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// The kinds of device behind a transport, from section 5 of the specification.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum DeviceId {
+    Block = 2,
+}
+
+/// The device complies with version 1 of the specification, rather than being a legacy device.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: MIT
+//! Split virtqueues, through which requests are passed to a virtio device.
+//!
+//! The driver describes each request as a chain of descriptors, one per buffer, and makes the
+//! chain available to the device by adding its first descriptor to the available ring. Once the
+//! device is done with the request, it adds the chain to the used ring.
+//!
+//! Drivers only ever have a single request in flight, so every chain starts at the first
+//! descriptor.
+
+use core::ptr;
+
+use crate::cpu::barrier;
+use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress, VirtualMemoryRegion, PAGE_SIZE};
+use crate::mem::{self, clean_dcache, invalidate_dcache};
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The number of descriptors in each queue, which is also the longest chain a request can use.
+pub const QUEUE_SIZE: usize = 16;
+
+/// A buffer that makes up part of a request.
+#[derive(Copy, Clone, Debug)]
+pub struct Buffer {
+    pub pa: PhysicalAddress,
+    pub len: u32,
+    /// Whether the device writes to the buffer, rather than reading from it.
+    pub device_writes: bool,
+}
+
+/// A virtqueue, whose descriptor table, available ring and used ring all share a single page.
+pub struct Virtqueue {
+    /// The direct map address of the page.
+    va: VirtualAddress,
+    pa: PhysicalAddress,
+    /// The next index in the available ring that the driver will fill.
+    next_avail: u16,
+    /// The next index in the used ring that the driver will read.
+    next_used: u16,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+impl Virtqueue {
+    /// Allocates an empty queue.
+    pub fn new() -> Result<Self, &'static str> {
+        let (va, pa) = mem::alloc_dma(PAGE_SIZE, PAGE_SIZE).ok_or("out of memory for virtqueue")?;
+
+        Ok(Self {
+            va,
+            pa,
+            next_avail: 0,
+            next_used: 0,
+        })
+    }
+
+    /// The physical address of the descriptor table.
+    pub fn descriptor_area(&self) -> PhysicalAddress {
+        PhysicalAddress(self.pa.0 + DESCRIPTOR_OFFSET)
+    }
+
+    /// The physical address of the available ring, which the driver writes.
+    pub fn driver_area(&self) -> PhysicalAddress {
+        PhysicalAddress(self.pa.0 + AVAIL_OFFSET)
+    }
+
+    /// The physical address of the used ring, which the device writes.
+    pub fn device_area(&self) -> PhysicalAddress {
+        PhysicalAddress(self.pa.0 + USED_OFFSET)
+    }
+
+    /// Makes a request made up of `chain` available to the device, which then has to be notified.
+    ///
+    /// Panics if the chain is empty or longer than [`QUEUE_SIZE`], or the previous request hasn't
+    /// been used yet.
+    pub fn push(&mut self, chain: &[Buffer]) {
+        assert!(
+            !chain.is_empty() && chain.len() <= QUEUE_SIZE,
+            "virtqueue chain must have between 1 and {} buffers",
+            QUEUE_SIZE
+        );
+        assert_eq!(
+            self.next_avail, self.next_used,
+            "virtqueue already has a request in flight"
+        );
+
+        let descriptors = (self.va.0 + DESCRIPTOR_OFFSET) as *mut Descriptor;
+        for (i, buffer) in chain.iter().enumerate() {
+            let mut flags = 0;
+            if buffer.device_writes {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if i + 1 < chain.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+
+            // Safe because the descriptor table has QUEUE_SIZE entries, and the device only reads
+            // it once the chain is made available below.
+            unsafe {
+                descriptors.add(i).write_volatile(Descriptor {
+                    addr: buffer.pa.0 as u64,
+                    len: buffer.len,
+                    flags,
+                    next: (i + 1) as u16,
+                });
+            }
+        }
+
+        let avail = (self.va.0 + AVAIL_OFFSET) as *mut AvailRing;
+        let slot = self.next_avail as usize % QUEUE_SIZE;
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        // Safe because the ring is within the queue's page, and the device only reads the new entry
+        // once the index has been updated.
+        unsafe {
+            ptr::addr_of_mut!((*avail).ring[slot]).write_volatile(0);
+
+            // the chain must be in place before the device can see the new index
+            clean_dcache(&self.region(DESCRIPTOR_OFFSET, USED_OFFSET));
+            barrier::dsb_sy();
+            ptr::addr_of_mut!((*avail).idx).write_volatile(self.next_avail);
+        }
+
+        // and the index must be visible to the device before it's notified
+        clean_dcache(&self.region(AVAIL_OFFSET, USED_OFFSET));
+        barrier::dsb_sy();
+    }
+
+    /// Returns the number of bytes the device wrote to the request, if it has used it.
+    pub fn pop_used(&mut self) -> Option<u32> {
+        let used = (self.va.0 + USED_OFFSET) as *const UsedRing;
+        invalidate_dcache(&self.region(USED_OFFSET, USED_OFFSET + USED_SIZE));
+
+        // Safe because the ring is within the queue's page.
+        let idx = unsafe { ptr::addr_of!((*used).idx).read_volatile() };
+        if idx == self.next_used {
+            return None;
+        }
+
+        // the entry must only be read after the index that says it's there
+        barrier::dsb_sy();
+
+        let slot = self.next_used as usize % QUEUE_SIZE;
+        self.next_used = self.next_used.wrapping_add(1);
+
+        // Safe because the ring is within the queue's page, and the device is done with the entry.
+        let element = unsafe { ptr::addr_of!((*used).ring[slot]).read_volatile() };
+        Some(element.len)
+    }
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        // Safe because the queue is only dropped once the device has been reset, so it no longer
+        // uses the page.
+        unsafe { mem::free_dma(self.pa, PAGE_SIZE) };
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// The buffer continues in the descriptor in `next`.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+
+/// The device writes to the buffer, rather than reading from it.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C, align(16))]
+#[derive(Copy, Clone)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C, align(2))]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C, align(4))]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElement; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+// Where each part of the queue is in its page. The used ring gets its own cache lines, since the
+// CPU discards what it has cached of them before each read.
+const DESCRIPTOR_OFFSET: usize = 0;
+const AVAIL_OFFSET: usize = DESCRIPTOR_OFFSET + core::mem::size_of::<[Descriptor; QUEUE_SIZE]>();
+const USED_OFFSET: usize = 512;
+const USED_SIZE: usize = core::mem::size_of::<UsedRing>();
+
+const _: () = assert!(
+    AVAIL_OFFSET + core::mem::size_of::<AvailRing>() <= USED_OFFSET
+        && USED_OFFSET + USED_SIZE <= PAGE_SIZE
+);
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl Virtqueue {
+    /// The part of the queue's page between the two offsets.
+    fn region(&self, start: usize, end: usize) -> VirtualMemoryRegion {
+        VirtualMemoryRegion::new(self.va.0 + start, self.va.0 + end)
+    }
+}
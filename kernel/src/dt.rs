@@ -0,0 +1,232 @@
+// SPDX-License-Identifier: MIT
+//! Acquisition of the flattened device tree blob (DTB) passed to the kernel at boot.
+//!
+//! The blob is provided by the bootloader, and lives in bootloader-reclaimable memory, so it's
+//! copied onto the kernel heap during init, before that memory is reclaimed.
+
+use alloc::boxed::Box;
+
+use limine::LimineDtbRequest;
+
+use crate::sync::OnceCell;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The header at the start of every flattened device tree. All fields are stored big endian.
+#[derive(Copy, Clone, Debug)]
+pub struct FdtHeader {
+    pub total_size: u32,
+    pub off_dt_struct: u32,
+    pub off_dt_strings: u32,
+    pub off_mem_rsvmap: u32,
+    pub version: u32,
+    pub last_comp_version: u32,
+    pub boot_cpuid_phys: u32,
+    pub size_dt_strings: u32,
+    pub size_dt_struct: u32,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Copies the device tree blob provided by the bootloader onto the kernel heap, so it can later be
+/// retrieved with [`blob`].
+///
+/// Panics if the bootloader provided a blob that isn't a valid device tree.
+///
+/// # Safety
+///
+/// - Must be called once, after the kernel heap is usable, but before bootloader-reclaimable
+///   memory is reclaimed.
+pub unsafe fn init() {
+    let dtb_ptr = match BOOTLOADER_DTB_INFO.get_response().get() {
+        Some(response) => match response.dtb_ptr.as_ptr() {
+            Some(ptr) => ptr as *const u8,
+            None => return,
+        },
+        // not every boot path has a device tree, e.g. ACPI-only systems
+        None => return,
+    };
+
+    let header = core::slice::from_raw_parts(dtb_ptr, FdtHeader::SIZE);
+    let header = FdtHeader::parse(header)
+        .unwrap_or_else(|e| panic!("Invalid device tree header at {:p}: {}", dtb_ptr, e));
+
+    let blob = core::slice::from_raw_parts(dtb_ptr, header.total_size as usize);
+    if let Err(e) = validate(blob) {
+        panic!("Invalid device tree blob at {:p}: {}", dtb_ptr, e);
+    }
+
+    DTB.set(Box::leak(Box::from(blob)));
+}
+
+/// Returns the device tree blob passed to the kernel at boot, if there was one.
+pub fn blob() -> Option<&'static [u8]> {
+    DTB.get().copied()
+}
+
+/// Returns the device tree blob passed to the kernel at boot.
+///
+/// Panics if there is none, for code that can't continue without a device tree.
+#[allow(unused)]
+pub fn require_blob() -> &'static [u8] {
+    blob().expect("No device tree blob was passed to the kernel at boot")
+}
+
+/// Checks that `blob` starts with a valid device tree header, and that all the blocks it describes
+/// are within the blob.
+pub fn validate(blob: &[u8]) -> Result<FdtHeader, &'static str> {
+    let header = FdtHeader::parse(blob)?;
+    if blob.len() < header.total_size as usize {
+        return Err("blob is smaller than its total size");
+    }
+
+    let blocks = [
+        (header.off_mem_rsvmap, 0),
+        (header.off_dt_struct, header.size_dt_struct),
+        (header.off_dt_strings, header.size_dt_strings),
+    ];
+    for (offset, size) in blocks {
+        match offset.checked_add(size) {
+            Some(end) if offset as usize >= FdtHeader::SIZE && end <= header.total_size => {}
+            _ => return Err("block out of bounds"),
+        }
+    }
+
+    Ok(header)
+}
+
+impl FdtHeader {
+    pub const MAGIC: u32 = 0xd00d_feed;
+
+    /// The size of the header, as of version 17.
+    pub const SIZE: usize = 40;
+
+    /// The newest device tree version this kernel understands.
+    const SUPPORTED_VERSION: u32 = 17;
+
+    /// Parses the header at the start of `blob`, checking its magic and version.
+    pub fn parse(blob: &[u8]) -> Result<Self, &'static str> {
+        if blob.len() < Self::SIZE {
+            return Err("blob is smaller than the header");
+        }
+
+        let field = |index: usize| {
+            let offset = index * 4;
+            u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+        };
+
+        if field(0) != Self::MAGIC {
+            return Err("bad magic");
+        }
+
+        let header = Self {
+            total_size: field(1),
+            off_dt_struct: field(2),
+            off_dt_strings: field(3),
+            off_mem_rsvmap: field(4),
+            version: field(5),
+            last_comp_version: field(6),
+            boot_cpuid_phys: field(7),
+            size_dt_strings: field(8),
+            size_dt_struct: field(9),
+        };
+
+        if header.last_comp_version > Self::SUPPORTED_VERSION {
+            return Err("unsupported version");
+        }
+
+        if header.version < header.last_comp_version {
+            return Err("version is older than the last compatible version");
+        }
+
+        if (header.total_size as usize) < Self::SIZE {
+            return Err("total size is smaller than the header");
+        }
+
+        Ok(header)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+static BOOTLOADER_DTB_INFO: LimineDtbRequest = LimineDtbRequest::new(0);
+
+static DTB: OnceCell<&'static [u8]> = OnceCell::new();
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::vec::Vec;
+
+    use super::{validate, FdtHeader};
+    use crate::selftest::SelfTest;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "dt::a well-formed blob is valid",
+            run: sample_is_valid,
+        },
+        SelfTest {
+            name: "dt::corrupted headers are rejected",
+            run: corrupted_headers_rejected,
+        },
+    ];
+
+    /// A version 17 blob with a root node and its `compatible` property, laid out as the header,
+    /// an empty memory reservation map at 40, the structure block at 56, and the strings block at
+    /// 100, padded to 112 bytes.
+    const SAMPLE: &[u8] = b"\
+        \xd0\x0d\xfe\xed\0\0\0\x70\0\0\0\x38\0\0\0\x64\0\0\0\x28\
+        \0\0\0\x11\0\0\0\x10\0\0\0\0\0\0\0\x0b\0\0\0\x2c\
+        \0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+        \0\0\0\x01\0\0\0\0\
+        \0\0\0\x03\0\0\0\x0e\0\0\0\0flow,selftest\0\0\0\
+        \0\0\0\x02\0\0\0\x09\
+        compatible\0\0";
+
+    /// The indices of the header fields the tests corrupt, in big endian words.
+    const TOTAL_SIZE: usize = 1;
+    const OFF_DT_STRINGS: usize = 3;
+    const VERSION: usize = 5;
+    const LAST_COMP_VERSION: usize = 6;
+
+    /// Returns a copy of the sample with the header field at `index` replaced by `value`.
+    fn with_field(index: usize, value: u32) -> Vec<u8> {
+        let mut blob = Vec::from(SAMPLE);
+        blob[index * 4..index * 4 + 4].copy_from_slice(&value.to_be_bytes());
+        blob
+    }
+
+    fn sample_is_valid() {
+        let header = validate(SAMPLE).expect("sample blob is invalid");
+        assert_eq!(header.total_size as usize, SAMPLE.len());
+        assert_eq!(header.version, 17);
+        assert_eq!(header.off_dt_struct, 56);
+        assert_eq!(header.size_dt_strings, 11);
+
+        // trailing data past the total size is allowed
+        let mut padded = Vec::from(SAMPLE);
+        padded.extend_from_slice(&[0; 16]);
+        assert!(validate(&padded).is_ok());
+    }
+
+    fn corrupted_headers_rejected() {
+        let mut bad_magic = Vec::from(SAMPLE);
+        bad_magic[0] ^= 0xff;
+        assert!(validate(&bad_magic).is_err());
+
+        // larger than the blob, smaller than the header, and cutting the strings block short
+        assert!(validate(&with_field(TOTAL_SIZE, SAMPLE.len() as u32 + 4)).is_err());
+        assert!(validate(&with_field(TOTAL_SIZE, FdtHeader::SIZE as u32 - 4)).is_err());
+        assert!(validate(&with_field(TOTAL_SIZE, 104)).is_err());
+        assert!(validate(&SAMPLE[..SAMPLE.len() - 4]).is_err());
+
+        // newer than supported, and older than it claims to be compatible with
+        assert!(validate(&with_field(LAST_COMP_VERSION, 18)).is_err());
+        assert!(validate(&with_field(VERSION, 1)).is_err());
+
+        assert!(validate(&with_field(OFF_DT_STRINGS, u32::MAX)).is_err());
+    }
+}
@@ -3,13 +3,14 @@ use core::marker::PhantomData;
 use critical_section::{set_impl, RawRestoreState};
 
 pub use arch_asynchronous::{
-    is_local_irq_masked, local_irq_mask, local_irq_mask_save, local_irq_restore, local_irq_unmask,
+    is_local_irq_masked, local_daif_restore, local_daif_save, local_fiq_mask, local_fiq_unmask,
+    local_irq_mask, local_irq_mask_save, local_irq_restore, local_irq_unmask, local_mask_all_save,
+    local_serror_mask, local_serror_unmask,
 };
 
-use crate::bsp;
 use crate::exception::{interface, null_irq_manager};
-use crate::sync::interface::ReadWriteEx;
-use crate::sync::InitStateLock;
+use crate::sync::{EarlyInit, InitStateLock};
+use crate::{bsp, time};
 
 // SPDX-License-Identifier: MIT
 #[cfg(target_arch = "aarch64")]
@@ -26,8 +27,47 @@ where
     number: T,
     name: &'static str,
     handler: &'static (dyn interface::IRQHandler + Sync),
+    shared: bool,
+    preemptible: bool,
+    config: IRQConfig,
 }
 
+/// How an interrupt is signalled by its device.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IRQTrigger {
+    /// The interrupt is asserted for as long as the device's line is held active.
+    Level,
+    /// The interrupt is asserted once on each rising edge of the device's line.
+    Edge,
+}
+
+/// How the interrupt controller should prioritise and route an IRQ, set when its first handler is
+/// registered.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IRQConfig {
+    /// Lower values are higher priority. Interrupt controllers may ignore the low bits.
+    pub priority: u8,
+
+    /// The cores the IRQ is delivered to, one bit per core, or `None` to keep the routing set up at
+    /// boot, which sends every IRQ to the boot core.
+    pub target_cores: Option<u8>,
+
+    pub trigger: IRQTrigger,
+}
+
+/// The handlers registered for a single IRQ line, in the order they were registered.
+#[derive(Copy, Clone)]
+pub struct IRQHandlerChain<T>
+where
+    T: Copy,
+{
+    handlers: [Option<IRQHandlerDescriptor<T>>; MAX_HANDLERS_PER_IRQ],
+}
+
+/// The most handlers that may share a single IRQ line.
+pub const MAX_HANDLERS_PER_IRQ: usize = 4;
+
 /// An instance of this type indicates that the local core is currently executing in IRQ
 /// context, aka executing an interrupt vector or subcalls of it.
 ///
@@ -36,12 +76,24 @@ where
 #[derive(Clone, Copy)]
 pub struct CriticalSection<'cs> {
     _0: PhantomData<&'cs ()>,
+    /// The counter value when the interrupt was taken, from [`time::now_ticks`].
+    entry_ticks: u64,
 }
 
 static CURRENT_IRQ_MANAGER: InitStateLock<
     &'static (dyn interface::IRQManager<IRQNumberType = IRQNumber> + Sync),
 > = InitStateLock::new(&null_irq_manager::NULL_IRQ_MANAGER);
 
+impl IRQConfig {
+    /// A mid-range priority, routed to the boot core and level-triggered, which suits most
+    /// peripherals.
+    pub const DEFAULT: Self = Self {
+        priority: 0x80,
+        target_cores: None,
+        trigger: IRQTrigger::Level,
+    };
+}
+
 impl<T> IRQHandlerDescriptor<T>
 where
     T: Copy,
@@ -55,9 +107,48 @@ where
             number,
             name,
             handler,
+            shared: false,
+            preemptible: false,
+            config: IRQConfig::DEFAULT,
+        }
+    }
+
+    /// Creates a descriptor for a handler that's willing to share its IRQ line with other shared
+    /// handlers. The handler must return [`interface::IRQStatus::NotMine`] for interrupts that
+    /// weren't raised by its device.
+    #[allow(unused)]
+    pub const fn new_shared(
+        number: T,
+        name: &'static str,
+        handler: &'static (dyn interface::IRQHandler + Sync),
+    ) -> Self {
+        Self {
+            number,
+            name,
+            handler,
+            shared: true,
+            preemptible: false,
+            config: IRQConfig::DEFAULT,
         }
     }
 
+    /// Replaces the default [`IRQConfig`] of the IRQ, for devices which need a particular priority,
+    /// routing or trigger.
+    #[allow(unused)]
+    pub const fn with_config(mut self, config: IRQConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Lets the handler be preempted by higher priority IRQs, for handlers that take long enough
+    /// to hold them up noticeably. It must not rely on interrupts being masked, other than inside
+    /// its own locks.
+    #[allow(unused)]
+    pub const fn preemptible(mut self) -> Self {
+        self.preemptible = true;
+        self
+    }
+
     pub fn number(&self) -> T {
         self.number
     }
@@ -69,6 +160,88 @@ where
     pub fn handler(&self) -> &'static (dyn interface::IRQHandler + Sync) {
         self.handler
     }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    pub fn is_preemptible(&self) -> bool {
+        self.preemptible
+    }
+
+    pub fn config(&self) -> &IRQConfig {
+        &self.config
+    }
+}
+
+impl<T> IRQHandlerChain<T>
+where
+    T: Copy,
+{
+    pub const fn new() -> Self {
+        Self {
+            handlers: [None; MAX_HANDLERS_PER_IRQ],
+        }
+    }
+
+    /// Adds a handler to the end of the chain.
+    ///
+    /// A line can only have several handlers if every one of them was registered as shared.
+    pub fn push(&mut self, descriptor: IRQHandlerDescriptor<T>) -> Result<(), &'static str> {
+        let can_share = descriptor.is_shared() && self.iter().all(|d| d.is_shared());
+        if !self.is_empty() && !can_share {
+            return Err("IRQ handler already registered");
+        }
+
+        let slot = self
+            .handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("Too many handlers registered for IRQ")?;
+        *slot = Some(descriptor);
+
+        Ok(())
+    }
+
+    /// Removes the handler called `name`, keeping the rest in the order they were registered.
+    pub fn remove(&mut self, name: &'static str) -> Result<(), &'static str> {
+        let index = self
+            .iter()
+            .position(|d| d.name() == name)
+            .ok_or("No such handler registered for IRQ")?;
+
+        self.handlers[index..].rotate_left(1);
+        self.handlers[MAX_HANDLERS_PER_IRQ - 1] = None;
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers[0].is_none()
+    }
+
+    /// Returns true if every handler in the chain may be preempted while it runs.
+    pub fn is_preemptible(&self) -> bool {
+        !self.is_empty() && self.iter().all(|d| d.is_preemptible())
+    }
+
+    /// Returns the registered handlers, in the order they're called.
+    pub fn iter(&self) -> impl Iterator<Item = &IRQHandlerDescriptor<T>> {
+        self.handlers.iter().map_while(|slot| slot.as_ref())
+    }
+
+    /// Calls each handler in turn, until one of them handles the interrupt.
+    ///
+    /// Returns [`interface::IRQStatus::NotMine`] if none of them did.
+    pub fn dispatch(&self) -> Result<interface::IRQStatus, &'static str> {
+        for descriptor in self.iter() {
+            if descriptor.handler().handle()? == interface::IRQStatus::Handled {
+                return Ok(interface::IRQStatus::Handled);
+            }
+        }
+
+        Ok(interface::IRQStatus::NotMine)
+    }
 }
 
 impl<'cs> CriticalSection<'cs> {
@@ -81,7 +254,16 @@ impl<'cs> CriticalSection<'cs> {
     ///   for this type, otherwise it might become inferred to `'static`.
     #[inline(always)]
     pub unsafe fn new() -> Self {
-        Self { _0: PhantomData }
+        Self {
+            _0: PhantomData,
+            entry_ticks: time::now_ticks(),
+        }
+    }
+
+    /// Returns the counter value when the interrupt was taken, as returned by
+    /// [`time::now_ticks`].
+    pub fn entry_ticks(&self) -> u64 {
+        self.entry_ticks
     }
 }
 
@@ -104,15 +286,26 @@ pub fn exec_with_masked_irqs<T>(f: impl FnOnce() -> T) -> T {
     ret
 }
 
+/// Runs `f` with IRQs, FIQs and SErrors all masked, then restores the previous DAIF state.
+#[inline(always)]
+pub fn exec_with_all_masked<T>(f: impl FnOnce() -> T) -> T {
+    let saved = local_mask_all_save();
+    let ret = f();
+    local_daif_restore(saved);
+
+    ret
+}
+
 pub fn setup_critical_section_handler() {
     set_impl!(CriticalSection);
 }
 
-/// Register a new IRQ manager.
+/// Register a new IRQ manager. This can only be done during early init.
 pub fn register_irq_manager(
+    early_init: &EarlyInit,
     new_manager: &'static (dyn interface::IRQManager<IRQNumberType = IRQNumber> + Sync),
 ) {
-    CURRENT_IRQ_MANAGER.write(|manager| *manager = new_manager);
+    CURRENT_IRQ_MANAGER.write(early_init, |manager| *manager = new_manager);
 }
 
 /// Return a reference to the currently registered IRQ manager.
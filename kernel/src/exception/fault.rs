@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MIT
+//! Deliberately triggered CPU faults, for exercising the exception handlers.
+//!
+//! Only available in debug builds.
+
+#[cfg(feature = "selftest")]
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[cfg(feature = "selftest")]
+use crate::exception::ExceptionContext;
+use crate::info;
+
+#[cfg(target_arch = "aarch64")]
+#[path = "../arch/aarch64/exception/fault.rs"]
+mod arch_fault;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A class of CPU fault that can be triggered on request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultKind {
+    /// A load from an address that isn't mapped.
+    DataAbort,
+    /// A branch to an address that isn't mapped.
+    InstructionAbort,
+    /// A load from a misaligned address.
+    Alignment,
+    /// Execution of a permanently undefined instruction.
+    UndefinedInstruction,
+    /// A supervisor call.
+    Svc,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+#[allow(unused)]
+impl FaultKind {
+    pub const ALL: [FaultKind; 5] = [
+        FaultKind::DataAbort,
+        FaultKind::InstructionAbort,
+        FaultKind::Alignment,
+        FaultKind::UndefinedInstruction,
+        FaultKind::Svc,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FaultKind::DataAbort => "data-abort",
+            FaultKind::InstructionAbort => "instruction-abort",
+            FaultKind::Alignment => "alignment",
+            FaultKind::UndefinedInstruction => "undefined",
+            FaultKind::Svc => "svc",
+        }
+    }
+
+    /// Looks up a fault kind by its name, as returned by [`FaultKind::name`].
+    pub fn from_name(name: &str) -> Option<FaultKind> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+/// Triggers the given fault on the executing core.
+///
+/// # Safety
+///
+/// - This deliberately faults. With the current exception handlers, it will not return, unless a
+///   self-test is catching it.
+#[allow(unused)]
+pub unsafe fn trigger(kind: FaultKind) {
+    info!("fault: triggering {}", kind.name());
+
+    match kind {
+        FaultKind::DataAbort => arch_fault::data_abort(),
+        FaultKind::InstructionAbort => arch_fault::instruction_abort(),
+        FaultKind::Alignment => arch_fault::alignment_fault(),
+        FaultKind::UndefinedInstruction => arch_fault::undefined_instruction(),
+        FaultKind::Svc => arch_fault::supervisor_call(),
+    }
+}
+
+/// Called first thing by the handlers for synchronous exceptions taken from EL1. If a self-test is
+/// waiting for a fault in [`catch`](selftest::catch), records the exception class for it, makes
+/// the exception return to the trigger, and returns true.
+#[cfg(feature = "selftest")]
+pub fn catch_exception(exc: &mut ExceptionContext) -> bool {
+    if !CATCHING.swap(false, Ordering::Relaxed) {
+        return false;
+    }
+
+    CAUGHT_CLASS.store(exc.exception_class_code(), Ordering::Relaxed);
+    arch_fault::resume_after(exc);
+    true
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// Set while a self-test is waiting for the next exception.
+#[cfg(feature = "selftest")]
+static CATCHING: AtomicBool = AtomicBool::new(false);
+
+/// The exception class of the last exception caught for a self-test.
+#[cfg(feature = "selftest")]
+static CAUGHT_CLASS: AtomicU8 = AtomicU8::new(0);
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use core::sync::atomic::Ordering;
+
+    use super::{trigger, FaultKind, CATCHING, CAUGHT_CLASS};
+    use crate::exception::asynchronous::exec_with_masked_irqs;
+    use crate::selftest::SelfTest;
+
+    pub const TESTS: &[SelfTest] = &[SelfTest {
+        name: "fault::each fault reaches the handler with its exception class",
+        run: faults_reach_handler,
+    }];
+
+    /// Triggers `kind`, catching the exception instead of panicking, and returns its exception
+    /// class, or `None` if no exception was taken.
+    pub fn catch(kind: FaultKind) -> Option<u8> {
+        exec_with_masked_irqs(|| {
+            CATCHING.store(true, Ordering::Relaxed);
+            // Safe because the fault is caught, and resumes in the trigger.
+            unsafe { trigger(kind) };
+
+            // still set if nothing was caught
+            (!CATCHING.swap(false, Ordering::Relaxed)).then(|| CAUGHT_CLASS.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Returns the `ESR_EL1.EC` value each fault is taken with, from EL1.
+    fn expected_class(kind: FaultKind) -> u8 {
+        match kind {
+            FaultKind::DataAbort | FaultKind::Alignment => 0x25,
+            FaultKind::InstructionAbort => 0x21,
+            FaultKind::UndefinedInstruction => 0x00,
+            FaultKind::Svc => 0x15,
+        }
+    }
+
+    fn faults_reach_handler() {
+        for kind in FaultKind::ALL {
+            assert_eq!(
+                catch(kind),
+                Some(expected_class(kind)),
+                "{} fault",
+                kind.name()
+            );
+        }
+    }
+}
@@ -1,8 +1,18 @@
 // SPDX-License-Identifier: MIT
 use crate::exception::asynchronous::{CriticalSection, IRQHandlerDescriptor};
 
+/// Whether an IRQ handler dealt with an interrupt.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IRQStatus {
+    /// The interrupt was raised by this handler's device, and has been handled.
+    Handled,
+    /// The interrupt wasn't raised by this handler's device, so the next handler sharing the line
+    /// should be tried.
+    NotMine,
+}
+
 pub trait IRQHandler {
-    fn handle(&self) -> Result<(), &'static str>;
+    fn handle(&self) -> Result<IRQStatus, &'static str>;
 }
 
 pub trait IRQManager {
@@ -13,11 +23,32 @@ pub trait IRQManager {
         ih_desc: IRQHandlerDescriptor<Self::IRQNumberType>,
     ) -> Result<(), &'static str>;
 
+    /// Removes the handler called `name` from an IRQ, disabling the IRQ if that was its last one.
+    fn remove_handler(
+        &self,
+        irq_number: &Self::IRQNumberType,
+        name: &'static str,
+    ) -> Result<(), &'static str>;
+
     fn enable(&self, irq_number: &Self::IRQNumberType);
 
+    /// Raises a software-generated interrupt on each core whose bit is set in `target_list`.
+    fn send_sgi(
+        &self,
+        irq_number: &Self::IRQNumberType,
+        target_list: u8,
+    ) -> Result<(), &'static str>;
+
+    /// Returns the core that raised the software-generated interrupt being handled on this core,
+    /// or `None` if this core isn't handling one.
+    fn sgi_source_core(&self) -> Option<u64> {
+        None
+    }
+
     fn print_handlers(&self) {}
 
     /// Handles pending interrupts. This is called directly from the CPU's IRQ exception vector.
-    /// This function cannot be preempted by other interrupts.
+    /// This function cannot be preempted by other interrupts, except by higher priority ones while
+    /// running handlers that were registered as preemptible.
     fn handle_pending_irqs<'cs>(&'cs self, cs: &CriticalSection<'cs>);
 }
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: MIT
+//! Interrupt latency measurement.
+//!
+//! The time from an interrupt being taken to its handlers finishing is recorded for each IRQ line,
+//! as raw counter ticks so that nothing more than a counter read and a few atomic updates happen
+//! on the interrupt path. They're only converted to nanoseconds when read back.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::exception::asynchronous::IRQNumber;
+use crate::time;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The latency of the interrupts handled on one IRQ line, from [`irq_latency_stats`].
+#[derive(Copy, Clone, Debug)]
+pub struct IrqLatencyStats {
+    pub irq_number: usize,
+
+    /// The number of interrupts handled.
+    pub count: u64,
+
+    pub min_nanos: u64,
+    pub max_nanos: u64,
+    pub avg_nanos: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Returns the latency of each IRQ line that has handled at least one interrupt, in order of IRQ
+/// number.
+#[allow(unused)]
+pub fn irq_latency_stats() -> impl Iterator<Item = IrqLatencyStats> {
+    LATENCY.iter().enumerate().filter_map(|(irq_number, line)| {
+        // the fields are read separately, so may be very slightly out of step with each other
+        let count = line.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+
+        Some(IrqLatencyStats {
+            irq_number,
+            count,
+            min_nanos: time::ticks_to_nanos(line.min_ticks.load(Ordering::Relaxed)),
+            max_nanos: time::ticks_to_nanos(line.max_ticks.load(Ordering::Relaxed)),
+            avg_nanos: time::ticks_to_nanos(line.total_ticks.load(Ordering::Relaxed) / count),
+        })
+    })
+}
+
+/// Records that an interrupt on `irq_number`, taken when the counter read `entry_ticks`, has just
+/// finished being handled.
+#[inline(always)]
+pub fn record(irq_number: usize, entry_ticks: u64) {
+    let Some(line) = LATENCY.get(irq_number) else {
+        return;
+    };
+
+    let ticks = time::now_ticks().wrapping_sub(entry_ticks);
+    line.count.fetch_add(1, Ordering::Relaxed);
+    line.total_ticks.fetch_add(ticks, Ordering::Relaxed);
+    line.min_ticks.fetch_min(ticks, Ordering::Relaxed);
+    line.max_ticks.fetch_max(ticks, Ordering::Relaxed);
+}
+
+impl fmt::Display for IrqLatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IRQ {}: {} handled, latency min {} ns, max {} ns, avg {} ns",
+            self.irq_number, self.count, self.min_nanos, self.max_nanos, self.avg_nanos
+        )
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+struct LineLatency {
+    count: AtomicU64,
+    total_ticks: AtomicU64,
+    min_ticks: AtomicU64,
+    max_ticks: AtomicU64,
+}
+
+static LATENCY: [LineLatency; IRQNumber::MAX_INCLUSIVE + 1] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: LineLatency = LineLatency::new();
+    [INIT; IRQNumber::MAX_INCLUSIVE + 1]
+};
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl LineLatency {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_ticks: AtomicU64::new(0),
+            min_ticks: AtomicU64::new(u64::MAX),
+            max_ticks: AtomicU64::new(0),
+        }
+    }
+}
@@ -1,4 +1,5 @@
-pub use arch_exception::init;
+pub use arch_exception::{enter_context, init, ExceptionContext};
+pub use latency::{irq_latency_stats, IrqLatencyStats};
 
 // SPDX-License-Identifier: MIT
 #[cfg(target_arch = "aarch64")]
@@ -7,4 +8,7 @@ mod arch_exception;
 mod null_irq_manager;
 
 pub mod asynchronous;
+#[cfg(debug_assertions)]
+pub mod fault;
 pub mod interface;
+pub mod latency;
@@ -14,13 +14,29 @@ impl IRQManager for NullIRQManager {
         &self,
         _ih_desc: IRQHandlerDescriptor<Self::IRQNumberType>,
     ) -> Result<(), &'static str> {
-        panic!("IRQ manager not registered yet!");
+        Err("IRQ manager not registered yet")
+    }
+
+    fn remove_handler(
+        &self,
+        _irq_number: &Self::IRQNumberType,
+        _name: &'static str,
+    ) -> Result<(), &'static str> {
+        Err("IRQ manager not registered yet")
     }
 
     fn enable(&self, _irq_number: &Self::IRQNumberType) {
         panic!("IRQ manager not registered yet!");
     }
 
+    fn send_sgi(
+        &self,
+        _irq_number: &Self::IRQNumberType,
+        _target_list: u8,
+    ) -> Result<(), &'static str> {
+        panic!("IRQ manager not registered yet!");
+    }
+
     fn handle_pending_irqs<'cs>(&'cs self, _cs: &CriticalSection<'cs>) {
         panic!("IRQ manager not registered yet!");
     }
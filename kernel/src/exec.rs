@@ -1,17 +1,28 @@
 // SPDX-License-Identifier: MIT
 
-use crate::mem::allocator::align_up;
-use crate::mem::vm::paging::{Attributes, RootPageTable, VirtualMemoryRegion};
-use crate::mem::{virtual_memory_manager, MemoryManager};
+use crate::exception::{asynchronous, ExceptionContext};
+use crate::mem::allocator::{align_down, align_up, checked_align_up};
+use crate::mem::copy::fast_copy;
+use crate::mem::vm::paging::{
+    Attributes, PhysicalAddress, RootPageTable, VirtualAddress, VirtualMemoryRegion, LEAF_LEVEL,
+    PAGE_SIZE, VA_BITS,
+};
+use crate::mem::vm::MapError;
+use crate::mem::{self, virtual_memory_manager, MemoryManager, OutOfMemory};
+use crate::sched::{self, PROCESS_RETURN_ADDRESS};
 use crate::sync::interface::Mutex;
 use crate::sync::{IRQSafeNullLock, OnceCell};
-use crate::{info, println};
+use crate::{cpu, info, modules, println, time, warn};
 use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
-use core::slice::SliceIndex;
-use object::elf::{FileHeader64, PF_R, PF_W, PF_X, PT_LOAD};
+use core::fmt::{self, Display, Formatter};
+use core::ops::Range;
+use core::slice::{self, SliceIndex};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use object::elf::{FileHeader64, PF_R, PF_W, PF_X, PT_LOAD, PT_PHDR, PT_TLS};
 use object::read::elf::{FileHeader, ProgramHeader};
 use object::{
     Architecture, BinaryFormat, Endianness, File, FileKind, LittleEndian, Object, ObjectComdat,
@@ -21,9 +32,16 @@ use object::{
 //--------------------------------------------------------------------------------------------------
 // Public definitions
 //--------------------------------------------------------------------------------------------------
+/// The executable loaded when the bootloader's modules don't provide [`INIT_PATH`].
 const TEST_EXECUTABLE: &[u8] = include_bytes!("../../flow-init-stub");
+/// The name of the first executable to load, within the bootloader's modules.
+const INIT_PATH: &str = "init";
 static PROCESS_MANAGER: ProcessManager = ProcessManager::new();
 
+/// The top of every process's stack, placed in the middle of the lower half of the address space.
+const USER_STACK_TOP: usize = 1 << (VA_BITS - 1);
+const USER_STACK_SIZE: usize = 64 * 1024;
+
 #[inline(always)]
 pub fn process_manager() -> &'static ProcessManager {
     &PROCESS_MANAGER
@@ -38,12 +56,101 @@ pub struct Process {
     name: String,
     asid: u16,
     address_space: IRQSafeNullLock<RootPageTable>,
+    /// Physical memory backing this process's user mappings, released when the process is dropped.
+    mappings: IRQSafeNullLock<Vec<ProcessMapping>>,
+    /// Regions reserved for demand paging, which are populated a page at a time as they're touched.
+    demand_regions: IRQSafeNullLock<Vec<DemandRegion>>,
+    /// The user context of this process, saved whenever it's switched out by the scheduler.
+    context: IRQSafeNullLock<Option<ExceptionContext>>,
+    /// The process's thread pointer (`TPIDR_EL0`), saved whenever it's switched out.
+    thread_pointer: AtomicUsize,
+    /// The number of instructions left to single-step.
+    #[cfg(debug_assertions)]
+    steps_remaining: AtomicUsize,
+}
+
+/// An error found while loading an executable.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LoadError {
+    /// The entry point isn't within any `PT_LOAD` segment.
+    EntryNotMapped(usize),
+    /// The entry point is within a `PT_LOAD` segment, but that segment isn't executable.
+    EntryNotExecutable(usize),
+    /// The `PT_LOAD` segment at this address is both writable and executable.
+    WritableAndExecutable(usize),
+    /// A segment couldn't be mapped into the process's address space.
+    Map(MapError),
+    /// The arguments and environment don't fit on the process's stack.
+    ArgumentsTooLarge,
+    /// The `PT_TLS` segment is malformed.
+    InvalidTls,
+    /// The file isn't an ELF file.
+    NotElf,
+    /// The executable isn't for AArch64.
+    WrongArch,
+    /// The executable isn't little endian.
+    WrongEndian,
+    /// The file is malformed.
+    Parse(object::read::Error),
+    /// There isn't enough memory, or a free PID, for the process.
+    OutOfMemory,
+    /// The file contents of the segment at this file offset aren't within the file, or are larger
+    /// than the segment in memory.
+    SegmentOutOfBounds(usize),
 }
 
 //--------------------------------------------------------------------------------------------------
 // Public code
 //--------------------------------------------------------------------------------------------------
 
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::EntryNotMapped(entry) => {
+                write!(f, "entry point {:#x} is not in any loaded segment", entry)
+            }
+            Self::EntryNotExecutable(entry) => {
+                write!(f, "entry point {:#x} is in a non-executable segment", entry)
+            }
+            Self::WritableAndExecutable(start) => {
+                write!(f, "segment at {:#x} is both writable and executable", start)
+            }
+            Self::Map(MapError::AddressRange(va)) => {
+                write!(f, "program too large for address space ({})", va)
+            }
+            Self::Map(err) => write!(f, "failed to map program: {}", err),
+            Self::ArgumentsTooLarge => write!(f, "arguments don't fit on the stack"),
+            Self::InvalidTls => write!(f, "invalid thread-local storage segment"),
+            Self::NotElf => write!(f, "not an ELF file"),
+            Self::WrongArch => write!(f, "not an AArch64 executable"),
+            Self::WrongEndian => write!(f, "not a little endian executable"),
+            Self::Parse(err) => write!(f, "malformed executable: {}", err),
+            Self::OutOfMemory => write!(f, "out of memory creating the process"),
+            Self::SegmentOutOfBounds(offset) => {
+                write!(f, "segment at file offset {:#x} is out of bounds", offset)
+            }
+        }
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(err: MapError) -> Self {
+        Self::Map(err)
+    }
+}
+
+impl From<OutOfMemory> for LoadError {
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+impl From<object::read::Error> for LoadError {
+    fn from(err: object::read::Error) -> Self {
+        Self::Parse(err)
+    }
+}
+
 impl ProcessManager {
     pub const fn new() -> Self {
         Self {
@@ -54,6 +161,53 @@ impl ProcessManager {
     pub fn create_process(&self, name: &str) -> Result<(usize, &Process), ()> {
         self.inner.lock(|pm| pm.create_process(name))
     }
+
+    /// Removes the process with the given PID, dropping it and releasing its address space.
+    pub fn destroy_process(&self, pid: usize) -> Result<(), ()> {
+        self.inner.lock(|pm| pm.destroy_process(pid))
+    }
+
+    /// Removes an exited process, releasing everything it owns, and records its exit code for
+    /// `wait`. Anything waiting for the process is woken up.
+    ///
+    /// Its PID can't be reused until the exit code has been collected by `wait`.
+    pub fn exit_process(&self, pid: usize, code: i32) -> Result<(), &'static str> {
+        self.inner.lock(|pm| pm.exit_process(pid, code))?;
+        cpu::send_event();
+        Ok(())
+    }
+
+    /// Waits for the process with the given PID to exit, and returns its exit code.
+    ///
+    /// This blocks the caller until the process exits, so it must only be called from a task that
+    /// can be preempted, such as a kernel thread. Returns an error if there is no such process, or
+    /// its exit code has already been collected.
+    #[allow(unused)]
+    pub fn wait(&self, pid: usize) -> Result<i32, &'static str> {
+        loop {
+            let status = self.inner.lock(|pm| {
+                if let Some(code) = pm.exit_codes.remove(&pid) {
+                    return Ok(Some(code));
+                }
+
+                match pm.get_process(pid) {
+                    Some(_) => Ok(None),
+                    None => Err("no such process"),
+                }
+            });
+
+            match status? {
+                Some(code) => return Ok(code),
+                // woken by `exit_process`, or by the next interrupt
+                None => cpu::wait_for_event(),
+            }
+        }
+    }
+
+    /// Runs `f` with the process with the given PID, if it exists.
+    pub fn with_process<R>(&self, pid: usize, f: impl FnOnce(&Process) -> R) -> Option<R> {
+        self.inner.lock(|pm| pm.get_process(pid).map(f))
+    }
 }
 
 impl Process {
@@ -65,32 +219,323 @@ impl Process {
             name,
             asid,
             address_space: IRQSafeNullLock::new(address_space),
+            mappings: IRQSafeNullLock::new(Vec::new()),
+            demand_regions: IRQSafeNullLock::new(Vec::new()),
+            context: IRQSafeNullLock::new(None),
+            thread_pointer: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            steps_remaining: AtomicUsize::new(0),
         }
     }
 
+    /// Single-steps the next `steps` instructions of this process: it traps back into the kernel
+    /// after each one, and the PC it reached is reported. Passing 0 stops stepping.
+    #[cfg(debug_assertions)]
+    #[allow(unused)]
+    pub fn single_step(&self, steps: usize) {
+        self.steps_remaining.store(steps, Ordering::Relaxed);
+    }
+
+    /// Whether the process should be single-stepped when it's next returned to.
+    #[cfg(debug_assertions)]
+    pub fn is_stepping(&self) -> bool {
+        self.steps_remaining.load(Ordering::Relaxed) != 0
+    }
+
+    /// Records that a single-stepped instruction has completed, returning the number of steps
+    /// still to go.
+    #[cfg(debug_assertions)]
+    pub fn step_completed(&self) -> usize {
+        let remaining = self
+            .steps_remaining
+            .load(Ordering::Relaxed)
+            .saturating_sub(1);
+        self.steps_remaining.store(remaining, Ordering::Relaxed);
+        remaining
+    }
+
+    /// Saves the user context of this process, to be restored when it's next scheduled.
+    pub fn save_context(&self, context: &ExceptionContext) {
+        self.context.lock(|saved| *saved = Some(context.clone()));
+    }
+
+    /// Returns the saved user context of this process.
+    ///
+    /// Panics if the process has no saved context, i.e. it hasn't been loaded yet.
+    pub fn saved_context(&self) -> ExceptionContext {
+        self.context
+            .lock(|saved| saved.clone().expect("process has no saved context"))
+    }
+
+    /// Switches the lower half of the address space to the address space of this process, and
+    /// restores its thread pointer.
+    pub fn activate(&self) {
+        asynchronous::exec_with_all_masked(|| self.with_page_table(|pt| pt.activate()));
+        cpu::set_user_thread_pointer(self.thread_pointer.load(Ordering::Relaxed));
+    }
+
+    /// Switches the lower half of the address space back to what it was before `activate`, saving
+    /// the process's thread pointer.
+    pub fn deactivate(&self) {
+        self.thread_pointer
+            .store(cpu::user_thread_pointer(), Ordering::Relaxed);
+        cpu::set_user_thread_pointer(0);
+        asynchronous::exec_with_all_masked(|| self.with_page_table(|pt| pt.deactivate()));
+    }
+
+    /// Resolves a write to a copy-on-write page of this process at `va`, giving the process its own
+    /// writable copy of the page, or making it writable in place if no one else shares it anymore.
+    ///
+    /// Returns false if `va` isn't in a copy-on-write page, or [`OutOfMemory`] if there's no memory
+    /// left for the copy.
+    pub fn handle_cow_fault(&self, va: usize) -> Result<bool, OutOfMemory> {
+        let page_va = align_down(va, PAGE_SIZE);
+        let page = VirtualMemoryRegion::new(page_va, page_va + PAGE_SIZE);
+
+        self.with_page_table(|pt| {
+            let (pa, flags) = match pt.translate(VirtualAddress(page_va)) {
+                Some((pa, flags)) if flags.contains(Attributes::COPY_ON_WRITE) => (pa, flags),
+                _ => return Ok(false),
+            };
+            let flags = flags
+                - (Attributes::COPY_ON_WRITE
+                    | Attributes::READ_ONLY
+                    | Attributes::VALID
+                    | Attributes::TABLE_OR_PAGE);
+
+            let vmm = virtual_memory_manager();
+            let target = if vmm.unshare_page(pa) {
+                let (copy_pa, copy_dm, copy_size) = match vmm.process_alloc(PAGE_SIZE) {
+                    Ok(copy) => copy,
+                    Err(err) => {
+                        // the page is still mapped here, so keep holding on to it
+                        vmm.share_pages(pa, PAGE_SIZE);
+                        return Err(err);
+                    }
+                };
+                // Safe because the new page was just allocated, and the shared page is mapped
+                // read-only everywhere, so neither can change while it's copied.
+                unsafe {
+                    fast_copy(
+                        copy_dm.0 as *mut u8,
+                        VirtualAddress::from(pa).0 as *const u8,
+                        PAGE_SIZE,
+                    );
+                }
+
+                // the shared page now belongs to whoever else is still using it
+                self.untrack_page(pa);
+                self.track_mapping(copy_pa, copy_size);
+                copy_pa
+            } else {
+                pa
+            };
+
+            // break before make, so the read-only translation can't linger in the TLB
+            pt.unmap_range(&page)
+                .and_then(|_| pt.map_range_with(&page, target, flags, LEAF_LEVEL))
+                .expect("failed to remap copy-on-write page");
+
+            Ok(true)
+        })
+    }
+
+    /// Maps `len` bytes of fresh, zeroed memory into this process, at or above `hint` if one is
+    /// given. The memory is freed along with the process, if it isn't unmapped before then.
+    ///
+    /// Returns the address the memory was mapped at.
+    #[allow(unused)]
+    pub fn map_anonymous(
+        &self,
+        hint: Option<VirtualAddress>,
+        len: usize,
+        flags: Attributes,
+    ) -> Result<VirtualAddress, MapError> {
+        self.with_page_table(|pt| {
+            let va = pt.map_anonymous(hint, len, flags)?;
+            let (pa, _) = pt.translate(va).expect("anonymous mapping not mapped");
+            self.track_mapping(pa, align_up(len, PAGE_SIZE));
+            Ok(va)
+        })
+    }
+
+    /// Reserves `len` bytes of this process's address space, at or above `hint` if one is given,
+    /// without backing it with any memory yet. Each page is allocated, zeroed, and mapped with
+    /// `flags` the first time it's accessed, by [`handle_demand_fault`](Self::handle_demand_fault).
+    ///
+    /// Returns the address the region was reserved at.
+    #[allow(unused)]
+    pub fn map_demand_paged(
+        &self,
+        hint: Option<VirtualAddress>,
+        len: usize,
+        flags: Attributes,
+    ) -> Result<VirtualAddress, MapError> {
+        let range = self.with_page_table(|pt| pt.reserve_anonymous(hint, len))?;
+        let start = range.start();
+
+        self.demand_regions.lock(|regions| {
+            debug_assert!(
+                !regions.iter().any(|region| region.range.overlaps(&range)),
+                "demand-paged region {} overlaps an existing one",
+                range
+            );
+            regions.push(DemandRegion { range, flags })
+        });
+        Ok(start)
+    }
+
+    /// Resolves a fault at `va` on a page of this process that's reserved for demand paging, by
+    /// mapping a fresh zeroed page there, after which the faulting access can be retried.
+    ///
+    /// Returns false if `va` isn't in a reserved page of a demand-paged region, or [`OutOfMemory`]
+    /// if there's no memory left for the page.
+    pub fn handle_demand_fault(&self, va: usize) -> Result<bool, OutOfMemory> {
+        let page_va = align_down(va, PAGE_SIZE);
+        let page = VirtualMemoryRegion::new(page_va, page_va + PAGE_SIZE);
+
+        let flags = self.demand_regions.lock(|regions| {
+            regions
+                .iter()
+                .find(|region| region.contains(page_va))
+                .map(|region| region.flags)
+        });
+        let Some(flags) = flags else {
+            return Ok(false);
+        };
+
+        self.with_page_table(|pt| {
+            // already populated, so this is some other fault
+            if !pt.is_reserved(VirtualAddress(page_va)) {
+                return Ok(false);
+            }
+
+            let (pa, dm, size) = virtual_memory_manager().process_alloc(PAGE_SIZE)?;
+            // Safe because the page was just allocated, and isn't mapped anywhere else yet.
+            unsafe {
+                core::ptr::write_bytes(dm.0 as *mut u8, 0, PAGE_SIZE);
+            }
+
+            pt.map_range_with(&page, pa, flags, LEAF_LEVEL)
+                .expect("failed to map demand-paged page");
+            self.track_mapping(pa, size);
+            Ok(true)
+        })
+    }
+
+    /// Returns whether the page containing `va` is mapped into this process and readable from user
+    /// mode, first populating it if it's reserved for demand paging.
+    ///
+    /// Returns [`OutOfMemory`] if the page is reserved, but there's no memory left to populate it.
+    pub fn is_user_readable(&self, va: usize) -> Result<bool, OutOfMemory> {
+        self.handle_demand_fault(va)?;
+        Ok(self.with_page_table(|pt| {
+            matches!(
+                pt.translate(VirtualAddress(va)),
+                Some((_, flags)) if flags.contains(Attributes::USER)
+            )
+        }))
+    }
+
+    /// Unmaps memory mapped by [`map_anonymous`](Self::map_anonymous) or
+    /// [`map_demand_paged`](Self::map_demand_paged), and frees it.
+    ///
     /// # Safety
-    /// Changes the lower half of the address space to the address space of this process.
-    unsafe fn with_context<'a>(&'a self, f: impl FnOnce(&'a Process) -> ()) {
-        self.with_page_table(|pt: &mut RootPageTable| {
-            pt.activate();
-            f(self);
-            pt.deactivate();
+    ///
+    /// Everything mapped in `range` must have been mapped by `map_anonymous` or
+    /// `map_demand_paged`.
+    #[allow(unused)]
+    pub unsafe fn unmap_anonymous(&self, range: &VirtualMemoryRegion) -> Result<(), MapError> {
+        // pages that haven't been touched yet mustn't be populated anymore either
+        self.demand_regions.lock(|regions| {
+            *regions = regions
+                .drain(..)
+                .flat_map(|region| region.without(range))
+                .collect();
+        });
+
+        self.with_page_table(|pt| {
+            // the pages are freed by the unmap, so they mustn't be freed again with the process
+            for page in (range.start().0..range.end().0).step_by(PAGE_SIZE) {
+                if let Some((pa, _)) = pt.translate(VirtualAddress(page)) {
+                    self.untrack_page(pa);
+                }
+            }
+
+            pt.unmap_anonymous(range)
+        })
+    }
+
+    /// Records physical memory allocated with `process_alloc` as belonging to this process, so that
+    /// it is freed along with the process.
+    fn track_mapping(&self, pa: PhysicalAddress, size: usize) {
+        self.mappings
+            .lock(|mappings| mappings.push(ProcessMapping { pa, size }));
+    }
+
+    /// Stops tracking a single page of this process's memory, splitting the mapping it's part of.
+    fn untrack_page(&self, pa: PhysicalAddress) {
+        self.mappings.lock(|mappings| {
+            let Some(index) = mappings
+                .iter()
+                .position(|m| m.pa <= pa && pa.0 < m.pa.0 + m.size)
+            else {
+                return;
+            };
+
+            let mapping = mappings.swap_remove(index);
+            let below = pa - mapping.pa;
+            let above = mapping.size - below - PAGE_SIZE;
+            if below > 0 {
+                mappings.push(ProcessMapping {
+                    pa: mapping.pa,
+                    size: below,
+                });
+            }
+            if above > 0 {
+                mappings.push(ProcessMapping {
+                    pa: pa + PAGE_SIZE,
+                    size: above,
+                });
+            }
         });
     }
 
-    fn with_page_table<'a>(&'a self, f: impl FnOnce(&'a mut RootPageTable)) {
+    fn with_page_table<'a, R>(&'a self, f: impl FnOnce(&'a mut RootPageTable) -> R) -> R {
         self.address_space.lock(f)
     }
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
+        // free the backing memory of all user mappings before the page tables go away
+        self.mappings.lock(|mappings| {
+            for mapping in mappings.drain(..) {
+                // Safe because the process is being dropped, so nothing can use its mappings anymore
+                unsafe {
+                    virtual_memory_manager().process_free(mapping.pa, mapping.size);
+                }
+            }
+
+            debug_assert!(
+                mappings.is_empty(),
+                "process {} still has tracked mappings after release",
+                self.pid
+            );
+        });
+
         virtual_memory_manager()
             .free_address_space(self.asid)
             .expect("failed to free address space");
     }
 }
 
+/// Returns true if `va` is within the guard page below a process's stack.
+pub fn is_user_stack_guard(va: usize) -> bool {
+    let guard = mem::stack_guard(VirtualAddress(USER_STACK_TOP - USER_STACK_SIZE));
+    guard.contains(VirtualAddress(va))
+}
+
 pub fn read_test_executable() {
     info!("read_test_executable: start");
     let binary = File::parse(TEST_EXECUTABLE).unwrap();
@@ -222,166 +667,823 @@ pub fn read_test_executable() {
     }
 }
 
-pub fn load_test_executable() {
+/// Loads the test executable into a new process and schedules it, passing it `args` as its
+/// arguments. Failures are logged.
+///
+/// The executable is [`INIT_PATH`] from the bootloader's modules, or the one built into the
+/// kernel if there isn't one.
+pub fn load_test_executable(args: &[&str]) {
     info!("load_test_executable: start");
-    let binary = File::parse(TEST_EXECUTABLE).unwrap();
+    let data = match modules::open(INIT_PATH) {
+        Some(data) => data,
+        None => {
+            info!(
+                "load_test_executable: no {} module, using the built in executable",
+                INIT_PATH
+            );
+            TEST_EXECUTABLE
+        }
+    };
+
+    match load_executable(data, "test_executable", args) {
+        Ok(pid) => info!("load_test_executable: started process {}", pid),
+        Err(err) => warn!("load_test_executable: {}", err),
+    }
+}
+
+/// Loads the ELF executable in `data` into a new process called `name` and schedules it, passing
+/// it `args` as its arguments. Returns the PID of the new process.
+///
+/// If loading fails part of the way through, the process and everything allocated for it so far
+/// are released again.
+pub fn load_executable(data: &[u8], name: &str, args: &[&str]) -> Result<usize, LoadError> {
+    let binary = File::parse(data)?;
     if binary.format() != BinaryFormat::Elf {
-        info!("load_test_executable: not an ELF file");
-        return;
+        return Err(LoadError::NotElf);
     }
 
     if binary.architecture() != Architecture::Aarch64 {
-        info!("load_test_executable: not an AArch64 file");
-        return;
+        return Err(LoadError::WrongArch);
     }
 
     if binary.endianness() != Endianness::Little {
-        info!("load_test_executable: not a little endian file");
-        return;
+        return Err(LoadError::WrongEndian);
     }
 
-    let process = process_manager().create_process("test_executable");
-    if let Err(err) = process {
-        info!("load_test_executable: failed to create process");
-        return;
+    let elf = Elf::parse(data)?;
+    let (pid, process) = process_manager()
+        .create_process(name)
+        .map_err(|_| LoadError::OutOfMemory)?;
+
+    if let Err(err) = load_into(process, elf, data, args) {
+        // abort the load; dropping the process releases everything allocated for it so far
+        process_manager()
+            .destroy_process(pid)
+            .expect("failed to destroy process");
+        return Err(err);
     }
-    let process = process.unwrap().1;
-    let elf = Elf::parse(TEST_EXECUTABLE).unwrap();
 
+    sched::scheduler().add(pid);
+    Ok(pid)
+}
+
+/// Maps the segments of `elf`, whose file is `data`, into `process`, and sets the process up to
+/// start at the entry point with `args` on its stack.
+fn load_into(process: &Process, elf: &Elf, data: &[u8], args: &[&str]) -> Result<(), LoadError> {
     // first iteration through: gather total needed phys mem size
+    // each segment gets its own whole pages, matching how they're mapped below
     let mut load_size: usize = 0;
-    for phdr in elf.program_headers(LittleEndian, TEST_EXECUTABLE).unwrap() {
+    for phdr in elf.program_headers(LittleEndian, data)? {
         if phdr.p_type(LittleEndian) == PT_LOAD {
-            load_size = align_up(load_size, phdr.p_align(LittleEndian) as usize);
-            load_size += phdr.p_memsz(LittleEndian) as usize;
+            let start_virt = phdr.p_vaddr(LittleEndian) as usize;
+            let end_virt = start_virt.saturating_add(phdr.p_memsz(LittleEndian) as usize);
+            let segment_size = checked_align_up(end_virt, PAGE_SIZE)
+                .map(|end| end - align_down(start_virt, PAGE_SIZE));
+
+            load_size = segment_size
+                .and_then(|size| load_size.checked_add(size))
+                .ok_or(MapError::AddressRange(VirtualAddress(start_virt)))?;
         }
     }
 
-    info!("load_test_executable: load_size: {} bytes", load_size);
+    info!("load_executable: load_size: {} bytes", load_size);
 
     // allocate the memory to load the process into
     let (process_phys, process_virt_dm, alloc_size) =
-        virtual_memory_manager().process_alloc(load_size);
+        virtual_memory_manager().process_alloc(load_size)?;
+    process.track_mapping(process_phys, alloc_size);
     let process_virt: OnceCell<usize> = OnceCell::new();
     let mut phys_offset: usize = 0;
 
     // second iteration: set up the page tables for the process
-    process.with_page_table(|pt: &mut RootPageTable| {
-        for phdr in elf.program_headers(LittleEndian, TEST_EXECUTABLE).unwrap() {
-            info!("Program Header: {:?}", phdr);
-            if phdr.p_type(LittleEndian) == PT_LOAD {
-                let flags = phdr.p_flags(LittleEndian);
-                let flag_r = flags & PF_R != 0;
-                let flag_w = flags & PF_W != 0;
-                let flag_x = flags & PF_X != 0;
-                let flags_string = format!(
-                    "{}{}{}",
-                    if flag_r { "R" } else { "-" },
-                    if flag_w { "W" } else { "-" },
-                    if flag_x { "X" } else { "-" }
-                );
-                info!("PT_LOAD section with flags: {}", flags_string);
+    let stack_pointer =
+        process.with_page_table(|pt: &mut RootPageTable| -> Result<usize, LoadError> {
+            for phdr in elf.program_headers(LittleEndian, data)? {
+                info!("Program Header: {:?}", phdr);
+                if phdr.p_type(LittleEndian) == PT_LOAD {
+                    let flags = phdr.p_flags(LittleEndian);
+                    let flag_r = flags & PF_R != 0;
+                    let flag_w = flags & PF_W != 0;
+                    let flag_x = flags & PF_X != 0;
+                    let flags_string = format!(
+                        "{}{}{}",
+                        if flag_r { "R" } else { "-" },
+                        if flag_w { "W" } else { "-" },
+                        if flag_x { "X" } else { "-" }
+                    );
+                    info!("PT_LOAD section with flags: {}", flags_string);
 
-                let start_virt = phdr.p_vaddr(LittleEndian) as usize;
-                let end_virt = start_virt + phdr.p_memsz(LittleEndian) as usize;
-                let start_phys = phdr.p_paddr(LittleEndian) as usize;
+                    let start_virt = phdr.p_vaddr(LittleEndian) as usize;
+                    let end_virt = start_virt
+                        .checked_add(phdr.p_memsz(LittleEndian) as usize)
+                        .ok_or(MapError::AddressRange(VirtualAddress(start_virt)))?;
+                    let start_phys = phdr.p_paddr(LittleEndian) as usize;
 
-                // todo: this isn't really correct I think (not guaranteed to be first?)
-                if process_virt.get().is_none() {
-                    process_virt.set(start_virt);
-                }
+                    // todo: this isn't really correct I think (not guaranteed to be first?)
+                    if process_virt.get().is_none() {
+                        process_virt.set(start_virt);
+                    }
 
-                info!(
-                    "VA: {:>8x}; PA: {:>8x}; size: {:x}",
-                    start_virt,
-                    start_phys,
-                    end_virt - start_virt
-                );
+                    info!(
+                        "VA: {:>8x}; PA: {:>8x}; size: {:x}",
+                        start_virt,
+                        start_phys,
+                        end_virt - start_virt
+                    );
 
-                // determine pt flags
-                let mut pt_flags = Attributes::NORMAL | Attributes::USER | Attributes::NON_GLOBAL;
-                if !flag_w && flag_r {
-                    pt_flags |= Attributes::READ_ONLY;
-                }
+                    let pt_flags = Attributes::from_elf_flags(flag_r, flag_w, flag_x)
+                        .ok_or(LoadError::WritableAndExecutable(start_virt))?;
 
-                if !flag_x {
-                    pt_flags |= Attributes::EXECUTE_NEVER;
-                }
+                    info!("Page table flags: {:?}", pt_flags);
 
-                info!("Page table flags: {:?}", pt_flags);
+                    // map the pages
+                    pt.map_range(
+                        &VirtualMemoryRegion::new(start_virt, end_virt),
+                        process_phys + phys_offset,
+                        pt_flags,
+                    )?;
 
-                // map the pages
-                pt.map_range(
-                    &VirtualMemoryRegion::new(start_virt, end_virt),
-                    process_phys + phys_offset,
-                    pt_flags,
-                )
-                .unwrap();
+                    // the segment's pages start at phys_offset, but the segment itself may not start
+                    // on a page boundary
+                    let map_start = align_down(start_virt, PAGE_SIZE);
+                    let map_end = align_up(end_virt, PAGE_SIZE);
+                    let segment_dm = process_virt_dm.0 + phys_offset + (start_virt - map_start);
 
-                phys_offset += end_virt - start_virt;
+                    phys_offset += map_end - map_start;
 
-                // copy the data from the file into the process
-                let executable_addr = TEST_EXECUTABLE.as_ptr();
-                let start_file = phdr.p_offset(LittleEndian) as usize;
-                let end_file = start_file + phdr.p_filesz(LittleEndian) as usize;
+                    // copy the data from the file into the process
+                    let start_file = phdr.p_offset(LittleEndian) as usize;
+                    let file_size = phdr.p_filesz(LittleEndian) as usize;
+                    let end_file = start_file
+                        .checked_add(file_size)
+                        .ok_or(LoadError::SegmentOutOfBounds(start_file))?;
 
-                // not even gonna pretend this is safe right now
-                unsafe {
-                    // todo: need to zero bss here
-                    core::ptr::copy_nonoverlapping(
-                        (executable_addr as usize + start_file) as *const u8,
-                        process_virt_dm.0 as *mut u8,
-                        end_file - start_file,
+                    let copy_start = time::time_manager().uptime_kernel();
+
+                    // Safe because the segment's pages were just allocated for this process, and
+                    // cover everything from start_virt to map_end.
+                    unsafe {
+                        copy_segment(
+                            data,
+                            start_file..end_file,
+                            segment_dm as *mut u8,
+                            end_virt - start_virt,
+                        )?;
+
+                        // zero the rest of the segment (.bss), up to the end of its last page, so that
+                        // nothing left over in the recycled physical pages is visible to the process
+                        core::ptr::write_bytes(
+                            (segment_dm + file_size) as *mut u8,
+                            0,
+                            map_end - start_virt - file_size,
+                        );
+                    }
+
+                    // the code was written through the data cache, so make sure it's what gets fetched
+                    if flag_x {
+                        mem::sync_icache(&VirtualMemoryRegion::new(
+                            segment_dm,
+                            segment_dm + (map_end - start_virt),
+                        ));
+                    }
+
+                    info!(
+                        "Loaded {} bytes in {:?}",
+                        file_size,
+                        time::time_manager().uptime_kernel() - copy_start
                     );
                 }
             }
-        }
-    });
 
-    // enter process context
-    unsafe {
-        process.with_context(|process| {
-            info!("load_test_executable: entering process context");
+            let thread_pointer = map_tls(pt, process, elf, data)?;
+            process
+                .thread_pointer
+                .store(thread_pointer, Ordering::Relaxed);
 
-            // execute it!
-            let entry_addr = elf.e_entry(LittleEndian) as usize;
-            let entry: extern "C" fn() = core::mem::transmute(entry_addr);
+            // map the stack just below the top of the process's half of the address space
+            let (stack_phys, stack_virt_dm, stack_alloc_size) =
+                virtual_memory_manager().process_alloc(USER_STACK_SIZE)?;
+            process.track_mapping(stack_phys, stack_alloc_size);
 
-            info!(
-                "load_test_executable: entering via entry point: 0x{:08x}",
-                entry_addr
-            );
-            entry();
+            // the recycled physical pages may still contain another process's data
+            unsafe {
+                core::ptr::write_bytes(stack_virt_dm.0 as *mut u8, 0, USER_STACK_SIZE);
+            }
 
-            info!("load_test_executable: exiting process context");
-        });
-    }
+            mem::map_stack(
+                pt,
+                &VirtualMemoryRegion::new(USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_TOP),
+                stack_phys,
+                Attributes::user_data(),
+            )?;
+
+            // Safe because the stack was just allocated for this process, and is only mapped into it.
+            let stack =
+                unsafe { slice::from_raw_parts_mut(stack_virt_dm.0 as *mut u8, USER_STACK_SIZE) };
+            let auxv = auxiliary_vector(elf, data)?;
+            write_initial_stack(stack, USER_STACK_TOP, args, &[], &auxv)
+        })?;
+
+    // catch broken binaries here, rather than with a confusing fault once the process runs
+    let entry_addr = elf.e_entry(LittleEndian) as usize;
+    validate_entry(elf, data)?;
+
+    // the process starts at its entry point the first time it's scheduled
+    process.save_context(&ExceptionContext::new_user(
+        entry_addr,
+        stack_pointer,
+        PROCESS_RETURN_ADDRESS,
+    ));
+
+    info!(
+        "load_executable: process {} ready, entry point: 0x{:08x}",
+        process.pid, entry_addr
+    );
+
+    Ok(())
 }
 //--------------------------------------------------------------------------------------------------
 // Private definitions
 //--------------------------------------------------------------------------------------------------
 type Elf = FileHeader64<LittleEndian>;
+
+const WORD_SIZE: usize = core::mem::size_of::<u64>();
+
+/// The size of the thread control block that the thread pointer points to, in the AArch64 TLS
+/// layout. It's reserved for the runtime, and left zeroed.
+const TCB_SIZE: usize = 16;
+
+// auxiliary vector entry types, from the System V ABI
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// A physical memory allocation backing part of a process's address space.
+struct ProcessMapping {
+    pa: PhysicalAddress,
+    size: usize,
+}
+
+/// A region of a process's address space that's populated on demand.
+struct DemandRegion {
+    range: VirtualMemoryRegion,
+    flags: Attributes,
+}
+
 struct ProcessManagerInner {
     processes: Vec<Process>,
+    /// The exit codes of processes that have exited, but haven't been waited for yet.
+    exit_codes: BTreeMap<usize, i32>,
     next_pid: usize,
 }
 
 //--------------------------------------------------------------------------------------------------
 // Private code
 //--------------------------------------------------------------------------------------------------
+/// Copies the bytes of `src` in `src_range` to `dst`, which is `dst_len` bytes long. Returns an
+/// error instead if the range isn't within `src`, or doesn't fit in `dst`.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `dst_len` bytes, and mustn't overlap `src`.
+unsafe fn copy_segment(
+    src: &[u8],
+    src_range: Range<usize>,
+    dst: *mut u8,
+    dst_len: usize,
+) -> Result<(), LoadError> {
+    let start = src_range.start;
+    let bytes = src
+        .get(src_range)
+        .ok_or(LoadError::SegmentOutOfBounds(start))?;
+    if bytes.len() > dst_len {
+        return Err(LoadError::SegmentOutOfBounds(start));
+    }
+
+    fast_copy(dst, bytes.as_ptr(), bytes.len());
+    Ok(())
+}
+
+/// Checks that the entry point of `elf` lies within an executable `PT_LOAD` segment.
+fn validate_entry(elf: &Elf, data: &[u8]) -> Result<(), LoadError> {
+    let entry = elf.e_entry(LittleEndian) as usize;
+    let mut mapped = false;
+
+    for phdr in elf.program_headers(LittleEndian, data)? {
+        if phdr.p_type(LittleEndian) != PT_LOAD {
+            continue;
+        }
+
+        let start_virt = phdr.p_vaddr(LittleEndian) as usize;
+        let end_virt = start_virt.saturating_add(phdr.p_memsz(LittleEndian) as usize);
+        if (start_virt..end_virt).contains(&entry) {
+            if phdr.p_flags(LittleEndian) & PF_X != 0 {
+                return Ok(());
+            }
+
+            // segments may overlap, so keep looking for an executable one
+            mapped = true;
+        }
+    }
+
+    if mapped {
+        Err(LoadError::EntryNotExecutable(entry))
+    } else {
+        Err(LoadError::EntryNotMapped(entry))
+    }
+}
+
+/// Allocates and maps the thread-local storage for the process's initial thread, from the `PT_TLS`
+/// segment of `elf`, and returns the thread pointer to start it with. Without a `PT_TLS` segment,
+/// the thread pointer is left null.
+///
+/// This follows the AArch64 variant I layout: the thread pointer points to a 16 byte thread
+/// control block, which is directly followed by the TLS block at the segment's alignment. The
+/// block holds the initialisation image (`.tdata`), then zeroes up to its memory size (`.tbss`).
+/// It's placed below the stack's guard page.
+fn map_tls(
+    pt: &mut RootPageTable,
+    process: &Process,
+    elf: &Elf,
+    data: &[u8],
+) -> Result<usize, LoadError> {
+    let Some(tls) = elf
+        .program_headers(LittleEndian, data)?
+        .iter()
+        .find(|phdr| phdr.p_type(LittleEndian) == PT_TLS)
+    else {
+        return Ok(0);
+    };
+
+    let file_offset = tls.p_offset(LittleEndian) as usize;
+    let file_size = tls.p_filesz(LittleEndian) as usize;
+    let mem_size = tls.p_memsz(LittleEndian) as usize;
+    // 0 and 1 both mean no alignment is needed
+    let align = (tls.p_align(LittleEndian) as usize).max(1);
+    if !align.is_power_of_two()
+        || file_size > mem_size
+        || file_offset
+            .checked_add(file_size)
+            .map_or(true, |end| end > data.len())
+    {
+        return Err(LoadError::InvalidTls);
+    }
+
+    let block_offset = align_up(TCB_SIZE, align);
+    let size = block_offset
+        .checked_add(mem_size)
+        .and_then(|size| checked_align_up(size, PAGE_SIZE))
+        .ok_or(LoadError::InvalidTls)?;
+
+    // the thread pointer is at the start of the region, so that has to be aligned as well
+    let guard = mem::stack_guard(VirtualAddress(USER_STACK_TOP - USER_STACK_SIZE));
+    let start = guard
+        .start()
+        .0
+        .checked_sub(size)
+        .map(|start| align_down(start, align.max(PAGE_SIZE)))
+        .ok_or(LoadError::InvalidTls)?;
+
+    let (pa, dm, alloc_size) = virtual_memory_manager().process_alloc(size)?;
+    process.track_mapping(pa, alloc_size);
+
+    // Safe because the memory was just allocated for this process, and the image was checked to be
+    // within the file.
+    unsafe {
+        core::ptr::write_bytes(dm.0 as *mut u8, 0, size);
+        fast_copy(
+            (dm.0 + block_offset) as *mut u8,
+            data.as_ptr().add(file_offset),
+            file_size,
+        );
+    }
+
+    pt.map_range(
+        &VirtualMemoryRegion::new(start, start + size),
+        pa,
+        Attributes::user_data(),
+    )?;
+
+    Ok(start)
+}
+
+/// Returns the auxiliary vector describing `elf` to the process, without the final `AT_NULL`.
+fn auxiliary_vector(elf: &Elf, data: &[u8]) -> Result<[(u64, u64); 5], LoadError> {
+    let phoff = elf.e_phoff(LittleEndian);
+    let phdrs = elf.program_headers(LittleEndian, data)?;
+
+    // prefer PT_PHDR, otherwise find the loaded segment that the file's program headers are in
+    let phdr_addr = phdrs
+        .iter()
+        .find(|phdr| phdr.p_type(LittleEndian) == PT_PHDR)
+        .map(|phdr| phdr.p_vaddr(LittleEndian))
+        .or_else(|| {
+            phdrs.iter().find_map(|phdr| {
+                let offset = phdr.p_offset(LittleEndian);
+                let in_segment = phdr.p_type(LittleEndian) == PT_LOAD
+                    && offset <= phoff
+                    && phoff < offset + phdr.p_filesz(LittleEndian);
+                in_segment.then(|| phdr.p_vaddr(LittleEndian) + (phoff - offset))
+            })
+        })
+        .unwrap_or(0);
+
+    Ok([
+        (AT_PHDR, phdr_addr),
+        (AT_PHENT, elf.e_phentsize(LittleEndian) as u64),
+        (AT_PHNUM, elf.e_phnum(LittleEndian) as u64),
+        (AT_ENTRY, elf.e_entry(LittleEndian)),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+    ])
+}
+
+/// Lays out the initial stack of a process in `stack`, which is mapped into the process so that it
+/// ends at `stack_top`, and returns the stack pointer to start the process with.
+///
+/// Following the System V ABI, the stack pointer points to `argc`, followed by the `argv`
+/// pointers, a NULL, the `envp` pointers, a NULL, and then the auxiliary vector, ending with
+/// `AT_NULL`. The strings themselves are copied to the very top of the stack.
+fn write_initial_stack(
+    stack: &mut [u8],
+    stack_top: usize,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<usize, LoadError> {
+    let stack_bottom = stack_top - stack.len();
+    let user_address = |offset: usize| (stack_bottom + offset) as u64;
+
+    // the strings, each NUL terminated
+    let mut offset = stack.len();
+    let mut pointers = Vec::with_capacity(args.len() + env.len());
+    for string in args.iter().chain(env) {
+        offset = offset
+            .checked_sub(string.len() + 1)
+            .ok_or(LoadError::ArgumentsTooLarge)?;
+        stack[offset..offset + string.len()].copy_from_slice(string.as_bytes());
+        stack[offset + string.len()] = 0;
+        pointers.push(user_address(offset));
+    }
+
+    let (argv, envp) = pointers.split_at(args.len());
+    let mut words = Vec::with_capacity(pointers.len() + 3 + 2 * (auxv.len() + 1));
+    words.push(args.len() as u64);
+    words.extend_from_slice(argv);
+    words.push(0);
+    words.extend_from_slice(envp);
+    words.push(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.push(key);
+        words.push(value);
+    }
+
+    // the stack pointer must be 16 byte aligned on entry
+    let offset = offset
+        .checked_sub(words.len() * WORD_SIZE)
+        .map(|offset| align_down(offset, 16))
+        .ok_or(LoadError::ArgumentsTooLarge)?;
+    for (i, word) in words.iter().enumerate() {
+        let start = offset + i * WORD_SIZE;
+        stack[start..start + WORD_SIZE].copy_from_slice(&word.to_le_bytes());
+    }
+
+    Ok(user_address(offset) as usize)
+}
+
+impl DemandRegion {
+    fn contains(&self, va: usize) -> bool {
+        self.range.contains(VirtualAddress(va))
+    }
+
+    /// Returns what's left of this region once `range` is removed from it, which may be split in
+    /// two.
+    fn without(self, range: &VirtualMemoryRegion) -> impl Iterator<Item = DemandRegion> {
+        let (start, end) = (self.range.start().0, self.range.end().0);
+        let below = VirtualMemoryRegion::new(start, end.min(range.start().0));
+        let above = VirtualMemoryRegion::new(start.max(range.end().0), end);
+
+        [below, above]
+            .into_iter()
+            .filter(|part| part.start() < part.end())
+            .map(move |part| DemandRegion {
+                range: part,
+                flags: self.flags,
+            })
+    }
+}
+
 impl ProcessManagerInner {
     const fn new() -> Self {
         Self {
             processes: Vec::new(),
+            exit_codes: BTreeMap::new(),
             next_pid: 1,
         }
     }
 
     fn create_process(&mut self, name: &str) -> Result<(usize, &Process), ()> {
-        let pid = self.next_pid;
-        self.next_pid += 1;
+        let pid = self.allocate_pid().ok_or(())?;
         let process = Process::new(pid, name.to_owned());
         self.processes.push(process);
         Ok((pid, self.processes.last().unwrap()))
     }
+
+    fn get_process(&self, pid: usize) -> Option<&Process> {
+        self.processes.iter().find(|process| process.pid == pid)
+    }
+
+    /// Returns the next PID that isn't used by a running process, or one that's still to be
+    /// waited for.
+    fn allocate_pid(&mut self) -> Option<usize> {
+        let in_use =
+            |pm: &Self, pid| pm.get_process(pid).is_some() || pm.exit_codes.contains_key(&pid);
+
+        // every PID can only be in use once, so we'll find a free one within this many tries
+        for _ in 0..=self.processes.len() + self.exit_codes.len() {
+            let pid = self.next_pid;
+            self.next_pid = self.next_pid.checked_add(1).unwrap_or(1);
+            if !in_use(self, pid) {
+                return Some(pid);
+            }
+        }
+
+        None
+    }
+
+    fn exit_process(&mut self, pid: usize, code: i32) -> Result<(), &'static str> {
+        if self.exit_codes.contains_key(&pid) {
+            return Err("process has already exited");
+        }
+
+        self.destroy_process(pid).map_err(|_| "no such process")?;
+        self.exit_codes.insert(pid, code);
+        Ok(())
+    }
+
+    fn destroy_process(&mut self, pid: usize) -> Result<(), ()> {
+        let index = self
+            .processes
+            .iter()
+            .position(|process| process.pid == pid)
+            .ok_or(())?;
+        self.processes.remove(index);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::vec::Vec;
+    use core::mem::size_of;
+    use core::ptr;
+
+    use object::elf::{
+        ProgramHeader64, ELFCLASS64, ELFDATA2LSB, EM_AARCH64, ET_EXEC, EV_CURRENT, PF_R, PF_W,
+        PF_X, PT_LOAD,
+    };
+    use object::read::elf::FileHeader;
+    use object::LittleEndian;
+
+    use super::{load_executable, process_manager, validate_entry, Elf, LoadError};
+    use crate::exception::ExceptionContext;
+    use crate::mem::vm::paging::{Attributes, VirtualAddress, VirtualMemoryRegion, PAGE_SIZE};
+    use crate::mem::vm::MapError;
+    use crate::mem::{self, virtual_memory_manager, MemoryManager, SharedPage};
+    use crate::sched::{self, PROCESS_RETURN_ADDRESS};
+    use crate::selftest::SelfTest;
+    use crate::sync::interface::Mutex;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "exec::segment beyond the address space is rejected and cleaned up",
+            run: segment_beyond_address_space,
+        },
+        SelfTest {
+            name: "exec::dropping a process frees its memory and page tables",
+            run: drop_frees_memory_and_page_tables,
+        },
+        SelfTest {
+            name: "exec::entry point outside every segment",
+            run: entry_not_mapped,
+        },
+        SelfTest {
+            name: "exec::entry point in a non-executable segment",
+            run: entry_not_executable,
+        },
+    ];
+
+    /// Where [`spawn_code`] maps the page it shares with the process.
+    pub const SHARED_PAGE_ADDRESS: usize = 0x10_0000;
+
+    /// Where [`spawn_code`] maps the process's code.
+    const CODE_ADDRESS: usize = 0x20_0000;
+
+    /// Creates a process running the position independent machine code `code`, with `shared`
+    /// mapped read-write at [`SHARED_PAGE_ADDRESS`], and adds it to the run queue. Each of
+    /// `registers` is a general purpose register number and the value it starts with.
+    ///
+    /// The process has no stack, so `code` must not use one. Returns the PID of the process.
+    ///
+    /// The mapping of `shared` keeps a reference to it, which must be released once the process
+    /// has exited.
+    pub fn spawn_code(
+        name: &str,
+        code: &[u8],
+        shared: &SharedPage,
+        registers: &[(usize, u64)],
+    ) -> usize {
+        assert!(code.len() <= PAGE_SIZE, "self-test code must fit in a page");
+
+        let (pid, process) = process_manager()
+            .create_process(name)
+            .expect("failed to create self-test process");
+
+        let code_va = process
+            .map_anonymous(
+                Some(VirtualAddress(CODE_ADDRESS)),
+                PAGE_SIZE,
+                Attributes::user_code(),
+            )
+            .expect("failed to map self-test code");
+
+        process.with_page_table(|pt| {
+            let (pa, _) = pt.translate(code_va).expect("self-test code not mapped");
+            let dm = pa.to_direct_map_virtual().0;
+
+            // Safe because the page was just allocated for this process, which hasn't run yet.
+            unsafe {
+                ptr::copy_nonoverlapping(code.as_ptr(), dm as *mut u8, code.len());
+            }
+            mem::sync_icache(&VirtualMemoryRegion::new(dm, dm + code.len()));
+
+            let shared_range =
+                VirtualMemoryRegion::new(SHARED_PAGE_ADDRESS, SHARED_PAGE_ADDRESS + PAGE_SIZE);
+            pt.map_shared(&shared_range, shared, Attributes::user_data())
+                .expect("failed to map self-test shared page");
+        });
+
+        let mut context = ExceptionContext::new_user(code_va.0, 0, PROCESS_RETURN_ADDRESS);
+        for &(n, value) in registers {
+            context.set_gpr(n, value);
+        }
+        process.save_context(&context);
+
+        sched::scheduler().add(pid);
+        pid
+    }
+
+    /// Returns whether the address space of the process with the given PID is active on this core.
+    pub fn is_address_space_active(pid: usize) -> bool {
+        process_manager()
+            .with_process(pid, |process| process.with_page_table(|pt| pt.is_active()))
+            .unwrap_or(false)
+    }
+
+    /// Builds an AArch64 executable starting at `entry`, with no file contents, and a `PT_LOAD`
+    /// segment for each of `segments`: its address, its size in memory, and its `p_flags`.
+    fn build_elf(entry: usize, segments: &[(usize, usize, u32)]) -> Vec<u8> {
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        elf.resize(16, 0);
+        elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+        elf.extend_from_slice(&EM_AARCH64.to_le_bytes());
+        elf.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+        elf.extend_from_slice(&(entry as u64).to_le_bytes());
+        // the program headers directly follow the file header, and there are no sections
+        elf.extend_from_slice(&(size_of::<Elf>() as u64).to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        elf.extend_from_slice(&(size_of::<Elf>() as u16).to_le_bytes());
+        elf.extend_from_slice(&(size_of::<ProgramHeader64<LittleEndian>>() as u16).to_le_bytes());
+        elf.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        elf.resize(size_of::<Elf>(), 0);
+
+        for &(vaddr, memsz, flags) in segments {
+            elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+            elf.extend_from_slice(&flags.to_le_bytes());
+            elf.extend_from_slice(&0u64.to_le_bytes());
+            elf.extend_from_slice(&(vaddr as u64).to_le_bytes());
+            elf.extend_from_slice(&(vaddr as u64).to_le_bytes());
+            elf.extend_from_slice(&0u64.to_le_bytes());
+            elf.extend_from_slice(&(memsz as u64).to_le_bytes());
+            elf.extend_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+        }
+
+        elf
+    }
+
+    /// Returns the PIDs of every live process.
+    fn pids() -> Vec<usize> {
+        process_manager()
+            .inner
+            .lock(|pm| pm.processes.iter().map(|process| process.pid).collect())
+    }
+
+    /// Returns how much physical memory is free, which is where both `process_alloc` and page
+    /// tables get their memory.
+    fn physical_free() -> usize {
+        virtual_memory_manager().physical_stats().free
+    }
+
+    fn segment_beyond_address_space() {
+        let (pid, process) = process_manager()
+            .create_process("selftest: address space size")
+            .expect("failed to create self-test process");
+        let size = process.with_page_table(|pt| pt.size());
+        process_manager().destroy_process(pid).unwrap();
+
+        // the segment's first page fits, but its second is past the end of the address space
+        let start = size - PAGE_SIZE;
+        let elf = build_elf(start, &[(start, 2 * PAGE_SIZE, PF_R | PF_X)]);
+
+        // the first load grows the kernel heap for the process's bookkeeping, which stays grown
+        for attempt in 0..2 {
+            let pids_before = pids();
+            let free_before = physical_free();
+
+            let result = load_executable(&elf, "selftest: too large", &[]);
+            assert!(
+                matches!(result, Err(LoadError::Map(MapError::AddressRange(_)))),
+                "expected an address range error, got {:?}",
+                result
+            );
+
+            assert_eq!(pids(), pids_before, "failed load left its process behind");
+            if attempt > 0 {
+                assert_eq!(physical_free(), free_before, "failed load leaked memory");
+            }
+        }
+    }
+
+    fn drop_frees_memory_and_page_tables() {
+        // far enough apart that each mapping needs page tables of its own
+        const HINTS: [usize; 3] = [0x40_0000, 0x4000_0000, 0x80_0000_0000];
+
+        for attempt in 0..2 {
+            let free_before = physical_free();
+
+            let (pid, process) = process_manager()
+                .create_process("selftest: drop")
+                .expect("failed to create self-test process");
+            for (i, &hint) in HINTS.iter().enumerate() {
+                process
+                    .map_anonymous(
+                        Some(VirtualAddress(hint)),
+                        (i + 1) * PAGE_SIZE,
+                        Attributes::user_data(),
+                    )
+                    .expect("failed to map self-test memory");
+            }
+            assert!(physical_free() < free_before);
+
+            process_manager().destroy_process(pid).unwrap();
+            if attempt > 0 {
+                assert_eq!(
+                    physical_free(),
+                    free_before,
+                    "dropped process leaked memory"
+                );
+            }
+        }
+    }
+
+    /// A code segment and a data segment, with a gap between them.
+    const SEGMENTS: [(usize, usize, u32); 2] = [
+        (0x40_0000, PAGE_SIZE, PF_R | PF_X),
+        (0x40_2000, PAGE_SIZE, PF_R | PF_W),
+    ];
+
+    /// Checks the entry point of an executable with [`SEGMENTS`] starting at `entry`.
+    fn validate(entry: usize) -> Result<(), LoadError> {
+        let data = build_elf(entry, &SEGMENTS);
+        let elf = Elf::parse(&*data).expect("failed to parse self-test executable");
+        validate_entry(elf, &data)
+    }
+
+    fn entry_not_mapped() {
+        assert_eq!(validate(0x40_0000), Ok(()));
+        assert_eq!(
+            validate(0x40_1000),
+            Err(LoadError::EntryNotMapped(0x40_1000))
+        );
+        assert_eq!(
+            validate(0x40_3000),
+            Err(LoadError::EntryNotMapped(0x40_3000))
+        );
+    }
+
+    fn entry_not_executable() {
+        assert_eq!(validate(0x40_0ffc), Ok(()));
+        assert_eq!(
+            validate(0x40_2000),
+            Err(LoadError::EntryNotExecutable(0x40_2000))
+        );
+        assert_eq!(
+            validate(0x40_2ffc),
+            Err(LoadError::EntryNotExecutable(0x40_2ffc))
+        );
+    }
 }
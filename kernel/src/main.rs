@@ -23,11 +23,17 @@ mod bsp;
 mod console;
 mod cpu;
 mod driver;
+mod dt;
 mod exception;
+mod exec;
 mod mem;
+mod modules;
 mod panic;
 mod print;
+mod sched;
+#[cfg(feature = "selftest")]
+mod selftest;
 mod sync;
+mod syscall;
 mod time;
 mod util;
-mod exec;
@@ -4,13 +4,14 @@
 // Public definitions
 //--------------------------------------------------------------------------------------------------
 
+// 0xFFFF_FFFF_0000_0000 - 0xFFFF_FFFF_7FFF_FFFF (2GB) - kernel MMIO window (RW, device memory)
 // 0xFFFF_FFFF_8000_0000 - 0xFFFF_FFFF_FAFF_FFFF (1968MB) - kernel heap (RW)
 // 0xFFFF_FFFF_FB00_0000 - 0xFFFF_FFFF_FBFF_FFFF (16MB) - kernel stack (RW)
 // 0xFFFF_FFFF_FC00_0000 - 0xFFFF_FFFF_FFFF_FFFF (64MB) - kernel code (RX) + kernel .data/.bss (RW)
 
 // Kernel allocation scheme:
 // Physical page allocator (kernel)
-// - Uses direct mapped physical memory + linked list allocator
+// - Uses direct mapped physical memory + linked list or bitmap allocator (see `FRAME_ALLOCATOR`)
 // - Allocates pages to the virtual memory heap
 //   - user space applications: 0x0 -> ...
 //   - kernel: 0xFFFF_FFFF_8000_0000 -> 0xFFFF_FFFF_FAFF_FFFF (kernel heap)
@@ -21,38 +22,148 @@
 //   - if granted, the vm alloc request is retried
 //   - if not granted, the kernel panics
 
-use aarch64_cpu::registers::TCR_EL1;
+use aarch64_cpu::registers::{ID_AA64MMFR0_EL1, MAIR_EL1, TCR_EL1};
 
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
-use core::intrinsics::unlikely;
+use core::fmt;
+use core::intrinsics::{likely, unlikely};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use limine::{LimineHhdmRequest, LimineMemmapRequest, LimineMemoryMapEntryType};
-use tock_registers::interfaces::Writeable;
+use limine::{LimineHhdmRequest, LimineMemmapEntry, LimineMemmapRequest, LimineMemoryMapEntryType};
+use tock_registers::fields::FieldValue;
+use tock_registers::interfaces::{Readable, Writeable};
 
-use crate::info;
-use crate::mem::allocator::align_up;
-use crate::mem::allocator::physical_page::PhysicalPageAllocator;
+use crate::mem::allocator::{
+    align_down, align_up, checked_align_up, AllocatorStats, FrameAllocator, FrameAllocatorKind,
+    EMERGENCY_RESERVE_SIZE,
+};
 use crate::mem::vm::paging::{
-    Attributes, PhysicalAddress, RootPageTable, VaRange, VirtualAddress, VirtualMemoryRegion,
-    PAGE_SIZE,
+    invalidate_tlb_all, invalidate_tlb_asid, Attributes, PhysicalAddress, PhysicalMemoryRegion,
+    RootPageTable, VaRange, VirtualAddress, VirtualMemoryRegion, FIRST_BLOCK_LEVEL, PAGE_SIZE,
+    VA_BITS,
 };
+use crate::mem::vm::MapError;
 use crate::sync::interface::Mutex;
 use crate::sync::{IRQSafeNullLock, OnceCell};
 use crate::util::size_human_readable_ceil;
+use crate::{cpu, info};
 
 pub mod allocator;
+pub mod copy;
+mod shared_page;
 pub mod vm;
 
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64/cache.rs"]
+mod arch_cache;
+
+pub use allocator::is_low_memory;
+pub use arch_cache::{clean_dcache, invalidate_dcache, sync_icache};
+pub use shared_page::SharedPage;
+
 static BOOTLOADER_HHDM_INFO: LimineHhdmRequest = LimineHhdmRequest::new(0);
 static BOOTLOADER_MAP_INFO: LimineMemmapRequest = LimineMemmapRequest::new(0);
 
+/// The direct map offset, copied out of the bootloader's response on first use, since the response
+/// itself is freed once bootloader-reclaimable memory is reclaimed.
+static DIRECT_MAP_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
 static VMM: VirtualMemoryManager = VirtualMemoryManager::new();
 
+/// The address space ID reserved for the kernel's own page tables.
+/// This is never handed out to a user process, so kernel and user translations can't alias in
+/// the TLB.
+pub const KERNEL_ASID: u16 = 0;
+
+/// The error returned when there's no physical memory left to satisfy an allocation.
+///
+/// Allocations made for a process return this, so that the process can be killed instead of the
+/// kernel; only allocations the kernel can't continue without panic.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "out of memory")
+    }
+}
+
 #[inline(always)]
 pub fn virtual_memory_manager() -> &'static VirtualMemoryManager {
     &VMM
 }
 
+/// Returns the guard page directly below a stack starting at `stack_start`.
+///
+/// Stacks grow downwards, so an overflowing stack runs into its guard page, which is never mapped.
+pub fn stack_guard(stack_start: VirtualAddress) -> VirtualMemoryRegion {
+    VirtualMemoryRegion::new(stack_start.0 - PAGE_SIZE, stack_start.0)
+}
+
+/// Maps `stack` into `pt` at `pa`, making sure that its guard page is left unmapped.
+pub fn map_stack(
+    pt: &mut RootPageTable,
+    stack: &VirtualMemoryRegion,
+    pa: PhysicalAddress,
+    flags: Attributes,
+) -> Result<(), MapError> {
+    pt.map_range(stack, pa, flags)?;
+
+    // the guard may still be covered by a block mapping of whatever lies below the stack
+    pt.unmap_range(&stack_guard(stack.start()))
+}
+
+/// Allocates a zeroed, physically contiguous buffer of at least `size` bytes for sharing with a
+/// device, starting at a multiple of `align` (and at least page aligned).
+///
+/// Returns the buffer's direct-map address, for the CPU, and its physical address, for the device,
+/// or `None` if no suitable run of physical memory is free.
+///
+/// The buffer is mapped cacheable, so coherency is up to the caller: clean it with
+/// [`clean_dcache`] before the device reads it, and invalidate it with [`invalidate_dcache`] before
+/// reading what the device wrote.
+#[allow(unused)]
+pub fn alloc_dma(size: usize, align: usize) -> Option<(VirtualAddress, PhysicalAddress)> {
+    assert!(
+        align.is_power_of_two(),
+        "DMA alignment must be a power of two"
+    );
+    let size = checked_align_up(size, PAGE_SIZE).filter(|&size| size > 0)?;
+
+    let pa = VMM.inner.lock(|inner| {
+        inner
+            .physical_allocator
+            .allocate_aligned(size, align.max(PAGE_SIZE))
+    })?;
+
+    let va: VirtualAddress = pa.into();
+    // Safe because the memory was just allocated, and is direct mapped.
+    unsafe {
+        core::ptr::write_bytes(va.0 as *mut u8, 0, size);
+    }
+    clean_dcache(&VirtualMemoryRegion::new(va.0, va.0 + size));
+
+    Some((va, pa))
+}
+
+/// Frees a buffer allocated with [`alloc_dma`].
+///
+/// # Safety
+///
+/// `pa` and `size` must be as passed to and returned from `alloc_dma`, and neither the CPU nor any
+/// device may still be using the buffer.
+#[allow(unused)]
+pub unsafe fn free_dma(pa: PhysicalAddress, size: usize) {
+    VMM.inner.lock(|inner| {
+        inner
+            .physical_allocator
+            .deallocate(pa, align_up(size, PAGE_SIZE))
+    })
+}
+
 pub struct VirtualMemoryManager {
     inner: IRQSafeNullLock<VirtualMemoryManagerInner>,
 }
@@ -64,19 +175,51 @@ pub trait MemoryManager {
     unsafe fn init(&self);
 
     /// Allocates memory to load a process.
-    /// If the allocation fails, the kernel will panic.
+    /// If physical memory is exhausted, [`OutOfMemory`] is returned.
     ///
     /// Returns a tuple containing:
     /// - The physical address of the allocation
     /// - The direct-map virtual address of the allocation (for kernel use)
     /// - The size of the allocation
-    fn process_alloc(&self, size: usize) -> (PhysicalAddress, VirtualAddress, usize);
+    fn process_alloc(
+        &self,
+        size: usize,
+    ) -> Result<(PhysicalAddress, VirtualAddress, usize), OutOfMemory>;
+
+    /// Records that the pages in the given range of memory allocated with `process_alloc` are
+    /// now mapped by one more address space, e.g. after cloning a page table with `clone_cow`.
+    fn share_pages(&self, pa: PhysicalAddress, size: usize);
+
+    /// Drops one address space's reference to a page shared with `share_pages`.
+    ///
+    /// Returns true if the page is still mapped by another address space, or false if the caller
+    /// was its only user, and so owns it exclusively again.
+    fn unshare_page(&self, pa: PhysicalAddress) -> bool;
+
+    /// Frees memory previously allocated with `process_alloc`.
+    ///
+    /// Pages that are still shared with other address spaces aren't freed; the reference to them
+    /// is dropped instead.
+    ///
+    /// # Safety
+    ///
+    /// The memory must no longer be mapped into any address space or otherwise in use.
+    unsafe fn process_free(&self, pa: PhysicalAddress, size: usize);
 
     /// Attempts to allocate a block of memory from the kernel heap.
     /// Upon success, a tuple is returned containing the virtual address of
     /// the allocated block, as well as its size.
-    /// If allocation fails, the kernel will panic.
-    fn kernel_alloc(&self, size: usize) -> (VirtualAddress, usize);
+    /// If physical memory is exhausted, [`OutOfMemory`] is returned.
+    fn kernel_alloc(&self, size: usize) -> Result<(VirtualAddress, usize), OutOfMemory>;
+
+    /// Returns memory allocated by [`kernel_alloc`](Self::kernel_alloc) to the physical page
+    /// allocator.
+    ///
+    /// # Safety
+    ///
+    /// `va` and `size` must be exactly as returned by `kernel_alloc`, and the memory must no longer
+    /// be in use.
+    unsafe fn kernel_free(&self, va: VirtualAddress, size: usize);
 
     /// Creates new root page tables in the lower half of the virtual address space.
     /// This is used for user processes.
@@ -84,7 +227,72 @@ pub trait MemoryManager {
     /// Returns a tuple containing the address space ID and the new page table.
     fn new_address_space(&self) -> (u16, RootPageTable);
 
+    /// Releases an address space ID handed out by [`new_address_space`](Self::new_address_space),
+    /// so it can be reused. Any TLB entries still tagged with it are invalidated first.
+    ///
+    /// The page table using the ASID must already have been deactivated.
     fn free_address_space(&self, asid: u16) -> Result<(), &'static str>;
+
+    /// Maps a region of device memory into an unused range of the kernel's MMIO window.
+    /// If the mapping fails, the kernel will panic.
+    ///
+    /// Returns the virtual address corresponding to `pa`.
+    fn map_mmio_region(&self, pa: PhysicalAddress, size: usize) -> VirtualAddress;
+
+    /// Like [`map_mmio_region`](Self::map_mmio_region), but maps the region with the memory type
+    /// in `memory_type` rather than as device memory, e.g. [`Attributes::NORMAL_NC`] for a
+    /// framebuffer.
+    fn map_mmio_region_with(
+        &self,
+        pa: PhysicalAddress,
+        size: usize,
+        memory_type: Attributes,
+    ) -> VirtualAddress;
+
+    /// Unmaps a region of device memory previously mapped with `map_mmio_region`.
+    fn unmap_mmio_region(&self, va: VirtualAddress, size: usize) -> Result<(), &'static str>;
+
+    /// Allocates and maps a stack of at least `size` bytes in the kernel's stack window, with an
+    /// unmapped guard page below it.
+    /// If the allocation fails or the window is exhausted, the kernel will panic.
+    ///
+    /// Returns the region covered by the stack.
+    fn kernel_stack_alloc(&self, size: usize) -> VirtualMemoryRegion;
+
+    /// Unmaps and frees a stack previously allocated with `kernel_stack_alloc`.
+    ///
+    /// # Safety
+    ///
+    /// The stack must no longer be in use.
+    unsafe fn kernel_stack_free(&self, stack: &VirtualMemoryRegion);
+
+    /// Returns true if `va` is within the guard page of a stack allocated with
+    /// `kernel_stack_alloc`.
+    fn is_kernel_stack_guard(&self, va: VirtualAddress) -> bool;
+
+    /// Maps a newly available range of RAM into the kernel's direct map, and adds it to the
+    /// physical page allocator, e.g. for memory discovered or hot-added after boot.
+    /// As at boot, the range is mapped with the largest blocks possible.
+    ///
+    /// Returns an error if the range is beyond the physical memory the direct map can cover.
+    ///
+    /// # Safety
+    ///
+    /// The range must be RAM that isn't in use, and hasn't already been given to the allocator.
+    unsafe fn extend_direct_map(&self, region: PhysicalMemoryRegion) -> Result<(), &'static str>;
+
+    /// Returns the statistics of the physical page allocator.
+    fn physical_stats(&self) -> AllocatorStats;
+
+    /// Adds the memory the bootloader marked as reclaimable to the physical page allocator.
+    ///
+    /// Returns the number of bytes reclaimed.
+    ///
+    /// # Safety
+    ///
+    /// All bootloader responses must have been consumed (or copied out) before calling this, as
+    /// they live in the memory being reclaimed.
+    unsafe fn reclaim_bootloader_memory(&self) -> usize;
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -95,7 +303,14 @@ pub trait MemoryManager {
 /// as the kernel does not need to perform a lookup in the page tables.
 #[inline(always)]
 pub(crate) fn direct_map_virt_offset() -> usize {
-    return BOOTLOADER_HHDM_INFO.get_response().get().unwrap().offset as usize;
+    let offset = DIRECT_MAP_OFFSET.load(Ordering::Relaxed);
+    if likely(offset != 0) {
+        return offset;
+    }
+
+    let offset = BOOTLOADER_HHDM_INFO.get_response().get().unwrap().offset as usize;
+    DIRECT_MAP_OFFSET.store(offset, Ordering::Relaxed);
+    offset
 }
 
 pub(crate) fn print_physical_memory_map() {
@@ -115,19 +330,45 @@ pub(crate) fn print_physical_memory_map() {
     );
 }
 
+/// Logs how much of the kernel heap and of physical memory is in use, and how fragmented the free
+/// memory of each is.
+pub(crate) fn print_stats() {
+    print_allocator_stats("Kernel heap", allocator::heap_stats());
+    print_allocator_stats("Physical memory", virtual_memory_manager().physical_stats());
+}
+
 impl MemoryManager for VirtualMemoryManager {
     unsafe fn init(&self) {
         self.inner.lock(|inner| inner.init())
     }
 
-    fn process_alloc(&self, size: usize) -> (PhysicalAddress, VirtualAddress, usize) {
+    fn process_alloc(
+        &self,
+        size: usize,
+    ) -> Result<(PhysicalAddress, VirtualAddress, usize), OutOfMemory> {
         self.inner.lock(|inner| inner.process_alloc(size))
     }
 
-    fn kernel_alloc(&self, size: usize) -> (VirtualAddress, usize) {
+    fn share_pages(&self, pa: PhysicalAddress, size: usize) {
+        self.inner.lock(|inner| inner.share_pages(pa, size))
+    }
+
+    fn unshare_page(&self, pa: PhysicalAddress) -> bool {
+        self.inner.lock(|inner| inner.unshare_page(pa))
+    }
+
+    unsafe fn process_free(&self, pa: PhysicalAddress, size: usize) {
+        self.inner.lock(|inner| inner.process_free(pa, size))
+    }
+
+    fn kernel_alloc(&self, size: usize) -> Result<(VirtualAddress, usize), OutOfMemory> {
         self.inner.lock(|inner| inner.kernel_alloc(size))
     }
 
+    unsafe fn kernel_free(&self, va: VirtualAddress, size: usize) {
+        self.inner.lock(|inner| inner.kernel_free(va, size))
+    }
+
     fn new_address_space(&self) -> (u16, RootPageTable) {
         self.inner.lock(|inner| inner.new_address_space())
     }
@@ -135,6 +376,48 @@ impl MemoryManager for VirtualMemoryManager {
     fn free_address_space(&self, asid: u16) -> Result<(), &'static str> {
         self.inner.lock(|inner| inner.free_address_space(asid))
     }
+
+    fn map_mmio_region(&self, pa: PhysicalAddress, size: usize) -> VirtualAddress {
+        self.inner.lock(|inner| inner.map_mmio_region(pa, size))
+    }
+
+    fn map_mmio_region_with(
+        &self,
+        pa: PhysicalAddress,
+        size: usize,
+        memory_type: Attributes,
+    ) -> VirtualAddress {
+        self.inner
+            .lock(|inner| inner.map_mmio_region_with(pa, size, memory_type))
+    }
+
+    fn unmap_mmio_region(&self, va: VirtualAddress, size: usize) -> Result<(), &'static str> {
+        self.inner.lock(|inner| inner.unmap_mmio_region(va, size))
+    }
+
+    fn kernel_stack_alloc(&self, size: usize) -> VirtualMemoryRegion {
+        self.inner.lock(|inner| inner.kernel_stack_alloc(size))
+    }
+
+    unsafe fn kernel_stack_free(&self, stack: &VirtualMemoryRegion) {
+        self.inner.lock(|inner| inner.kernel_stack_free(stack))
+    }
+
+    fn is_kernel_stack_guard(&self, va: VirtualAddress) -> bool {
+        self.inner.lock(|inner| inner.is_kernel_stack_guard(va))
+    }
+
+    unsafe fn extend_direct_map(&self, region: PhysicalMemoryRegion) -> Result<(), &'static str> {
+        self.inner.lock(|inner| inner.extend_direct_map(region))
+    }
+
+    fn physical_stats(&self) -> AllocatorStats {
+        self.inner.lock(|inner| inner.physical_allocator.stats())
+    }
+
+    unsafe fn reclaim_bootloader_memory(&self) -> usize {
+        self.inner.lock(|inner| inner.reclaim_bootloader_memory())
+    }
 }
 
 impl VirtualMemoryManager {
@@ -153,9 +436,14 @@ extern "Rust" {
     static __kernel_binary_start: UnsafeCell<()>;
     static __kernel_code_start: UnsafeCell<()>;
     static __kernel_code_end: UnsafeCell<()>;
+    static __kernel_rodata_start: UnsafeCell<()>;
+    static __kernel_rodata_end: UnsafeCell<()>;
     static __kernel_data_start: UnsafeCell<()>;
     static __kernel_data_end: UnsafeCell<()>;
     static __kernel_heap_start: UnsafeCell<()>;
+    static __kernel_mmio_start: UnsafeCell<()>;
+    static __kernel_stack_start: UnsafeCell<()>;
+    static __kernel_stack_end: UnsafeCell<()>;
 }
 
 #[inline(always)]
@@ -173,6 +461,16 @@ fn kernel_code_end() -> usize {
     unsafe { __kernel_code_end.get() as usize }
 }
 
+#[inline(always)]
+fn kernel_rodata_start() -> usize {
+    unsafe { __kernel_rodata_start.get() as usize }
+}
+
+#[inline(always)]
+fn kernel_rodata_end() -> usize {
+    unsafe { __kernel_rodata_end.get() as usize }
+}
+
 #[inline(always)]
 fn kernel_data_start() -> usize {
     unsafe { __kernel_data_start.get() as usize }
@@ -188,11 +486,224 @@ fn kernel_heap_start() -> usize {
     unsafe { __kernel_heap_start.get() as usize }
 }
 
+#[inline(always)]
+pub(crate) fn kernel_mmio_start() -> usize {
+    unsafe { __kernel_mmio_start.get() as usize }
+}
+
+/// The MMIO window ends where the kernel heap begins.
+#[inline(always)]
+fn kernel_mmio_end() -> usize {
+    kernel_heap_start()
+}
+
+#[inline(always)]
+pub(crate) fn kernel_stack_start() -> usize {
+    unsafe { __kernel_stack_start.get() as usize }
+}
+
+#[inline(always)]
+pub(crate) fn kernel_stack_end() -> usize {
+    // the linker script symbol is the last byte of the window
+    unsafe { __kernel_stack_end.get() as usize + 1 }
+}
+
+fn print_allocator_stats(name: &str, stats: AllocatorStats) {
+    let (allocated, allocated_unit) = size_human_readable_ceil(stats.allocated);
+    let (free, free_unit) = size_human_readable_ceil(stats.free);
+    let (largest, largest_unit) = size_human_readable_ceil(stats.largest_free_region);
+    info!(
+        "{}: {} {} used, {} {} free in {} regions (largest {} {})",
+        name, allocated, allocated_unit, free, free_unit, stats.free_regions, largest, largest_unit
+    );
+}
+
+/// The amount of physical memory the direct map can cover before running into the MMIO window.
+#[inline(always)]
+fn max_direct_map_size() -> usize {
+    kernel_mmio_start() - direct_map_virt_offset()
+}
+
+/// Maps a range of physical memory at its direct map address, with the largest blocks possible to
+/// keep the number of page tables and TLB entries down.
+///
+/// Returns the number of blocks or pages mapped.
+fn map_direct_range(
+    pt: &mut RootPageTable,
+    range: &PhysicalMemoryRegion,
+    flags: Attributes,
+) -> Result<usize, MapError> {
+    let dm_offset = direct_map_virt_offset();
+    pt.map_range_with(
+        &VirtualMemoryRegion::new(dm_offset + range.start().0, dm_offset + range.end().0),
+        range.start(),
+        flags | Attributes::EXECUTE_NEVER,
+        FIRST_BLOCK_LEVEL,
+    )
+}
+
+/// Maps each section of the kernel binary into `pt`, with the section's permissions: code is
+/// executable and read-only, read-only data is neither executable nor writable, and data and BSS
+/// are writable but not executable.
+///
+/// The binary is loaded physically contiguous at `kernel_pa`, so each section's physical address is
+/// found from its offset within the binary, however the sections are laid out.
+pub fn map_kernel_image(
+    pt: &mut RootPageTable,
+    kernel_pa: PhysicalAddress,
+) -> Result<(), MapError> {
+    let sections = [
+        (
+            kernel_code_start(),
+            kernel_code_end(),
+            Attributes::READ_ONLY,
+        ),
+        (
+            kernel_rodata_start(),
+            kernel_rodata_end(),
+            Attributes::READ_ONLY | Attributes::EXECUTE_NEVER,
+        ),
+        (
+            kernel_data_start(),
+            kernel_data_end(),
+            Attributes::EXECUTE_NEVER,
+        ),
+    ];
+
+    for (start, end, permissions) in sections {
+        if start == end {
+            continue;
+        }
+
+        pt.map_range(
+            &VirtualMemoryRegion::new(start, end),
+            kernel_pa + (start - kernel_binary_start()),
+            Attributes::NORMAL | permissions,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns the memory type the direct map uses for a type of memory map entry.
+///
+/// Anything that's RAM is mapped as normal cacheable memory; everything else, including the holes
+/// between entries, may be MMIO, so is mapped as device memory.
+fn direct_map_memory_type(typ: LimineMemoryMapEntryType) -> Attributes {
+    match typ {
+        LimineMemoryMapEntryType::Usable
+        | LimineMemoryMapEntryType::KernelAndModules
+        | LimineMemoryMapEntryType::BootloaderReclaimable
+        | LimineMemoryMapEntryType::AcpiReclaimable => Attributes::NORMAL,
+        LimineMemoryMapEntryType::Framebuffer => Attributes::NORMAL_NC,
+        _ => Attributes::DEVICE_NGNRNE,
+    }
+}
+
+/// Adds the bootloader-reclaimable `entries` of a memory map to `allocator`, except the one
+/// containing `in_use`, and returns the number of bytes added.
+///
+/// # Safety
+///
+/// The reclaimable memory, other than the entry containing `in_use`, must no longer be in use.
+unsafe fn reclaim_regions<'a>(
+    allocator: &mut FrameAllocator,
+    entries: impl Iterator<Item = &'a LimineMemmapEntry>,
+    in_use: PhysicalAddress,
+) -> usize {
+    // copy the regions out first, as the memory map itself lives in reclaimable memory
+    let regions: Vec<(PhysicalAddress, usize)> = entries
+        .filter(|entry| entry.typ == LimineMemoryMapEntryType::BootloaderReclaimable)
+        .map(|entry| (PhysicalAddress(entry.base as usize), entry.len as usize))
+        .filter(|(base, len)| !(base.0..base.0 + len).contains(&in_use.0))
+        .collect();
+
+    let mut reclaimed = 0;
+    for (base, len) in regions {
+        allocator.add_heap_region(base, len);
+        reclaimed += len;
+    }
+
+    reclaimed
+}
+
+/// Returns the `MAIR_EL1` value matching the memory type indices used by [`Attributes`].
+///
+/// - 0: device nGnRnE
+/// - 1: normal, write-back cacheable
+/// - 2: normal, non-cacheable
+#[inline(always)]
+fn mair() -> FieldValue<u64, MAIR_EL1::Register> {
+    MAIR_EL1::Attr0_Device::nonGathering_nonReordering_noEarlyWriteAck
+        + MAIR_EL1::Attr1_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
+        + MAIR_EL1::Attr1_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
+        + MAIR_EL1::Attr2_Normal_Outer::NonCacheable
+        + MAIR_EL1::Attr2_Normal_Inner::NonCacheable
+}
+
+/// Returns the `TCR_EL1.TG0`/`TCR_EL1.TG1` values for the translation granule selected at build
+/// time.
+#[inline(always)]
+fn tcr_granule() -> FieldValue<u64, TCR_EL1::Register> {
+    #[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+    return TCR_EL1::TG0::KiB_4 + TCR_EL1::TG1::KiB_4;
+
+    #[cfg(feature = "granule_16k")]
+    return TCR_EL1::TG0::KiB_16 + TCR_EL1::TG1::KiB_16;
+
+    #[cfg(feature = "granule_64k")]
+    return TCR_EL1::TG0::KiB_64 + TCR_EL1::TG1::KiB_64;
+}
+
+/// Returns the `TCR_EL1.IPS` value for the physical address range supported by the CPU.
+///
+/// Descriptors only hold 48-bit output addresses here, so the range is capped there, even on CPUs
+/// with 52-bit physical addresses.
+fn tcr_ips() -> FieldValue<u64, TCR_EL1::Register> {
+    match cpu::features().physical_address_bits {
+        32 => TCR_EL1::IPS::Bits_32,
+        36 => TCR_EL1::IPS::Bits_36,
+        40 => TCR_EL1::IPS::Bits_40,
+        42 => TCR_EL1::IPS::Bits_42,
+        44 => TCR_EL1::IPS::Bits_44,
+        _ => TCR_EL1::IPS::Bits_48,
+    }
+}
+
+/// Hands out address space IDs to user address spaces, and recycles them once they're freed.
+///
+/// ASIDs are handed out in increasing order, skipping any that are still in use. Each time the
+/// search wraps back around to the start, a new generation begins: the whole TLB is flushed first,
+/// so no translation cached under a recycled ASID can outlive the address space it belonged to.
+struct AsidAllocator {
+    /// One bit per ASID, set while it's in use. This is allocated on first use, since the kernel
+    /// heap isn't available yet when the VMM is constructed.
+    in_use: Vec<u64>,
+    /// The number of ASIDs supported by the CPU.
+    count: usize,
+    /// The ASID the next search starts from.
+    next: usize,
+    /// The number of times the search has wrapped around.
+    generation: u64,
+}
+
+/// The physical frame allocator the kernel is built with. The bitmap allocator finds contiguous
+/// runs of frames, e.g. for DMA buffers, more cheaply, at the cost of a bitmap covering all of
+/// physical memory.
+const FRAME_ALLOCATOR: FrameAllocatorKind = FrameAllocatorKind::LinkedList;
+
 struct VirtualMemoryManagerInner {
-    physical_allocator: PhysicalPageAllocator,
+    physical_allocator: FrameAllocator,
     kernel_page_table: OnceCell<IRQSafeNullLock<RootPageTable>>,
     use_kernel_heap_addresses: bool,
-    next_asid: u16,
+    asids: AsidAllocator,
+    next_mmio_offset: usize,
+    next_stack_offset: usize,
+    /// The end of the physical address range covered by the direct map.
+    direct_map_end: PhysicalAddress,
+    /// The number of address spaces mapping each page shared between processes. Pages that aren't
+    /// in here are owned by a single address space.
+    shared_pages: BTreeMap<PhysicalAddress, usize>,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -204,26 +715,134 @@ struct MemoryMapResult {
     kernel_physical_address: PhysicalAddress,
 }
 
+/// Returns the number of ASIDs supported by the CPU.
+fn asid_count() -> usize {
+    match ID_AA64MMFR0_EL1.read_as_enum(ID_AA64MMFR0_EL1::ASIDBits) {
+        Some(ID_AA64MMFR0_EL1::ASIDBits::Value::Bits_16) => 1 << 16,
+        _ => 1 << 8,
+    }
+}
+
+/// Returns the `TCR_EL1.AS` value for the ASID size supported by the CPU.
+fn tcr_asid_size() -> FieldValue<u64, TCR_EL1::Register> {
+    match asid_count() {
+        count if count > 1 << 8 => TCR_EL1::AS::ASID16Bits,
+        _ => TCR_EL1::AS::ASID8Bits,
+    }
+}
+
+impl AsidAllocator {
+    const fn new() -> Self {
+        Self {
+            in_use: Vec::new(),
+            count: 0,
+            next: KERNEL_ASID as usize + 1,
+            generation: 0,
+        }
+    }
+
+    /// Allocates an unused ASID, or returns `None` if every ASID is in use.
+    fn allocate(&mut self) -> Option<u16> {
+        if self.in_use.is_empty() {
+            self.count = asid_count();
+            self.in_use = vec![0; self.count / 64];
+            self.set(KERNEL_ASID as usize, true);
+        }
+
+        for _ in 0..self.count {
+            let asid = self.next;
+            self.next += 1;
+            if self.next == self.count {
+                self.rollover();
+            }
+
+            if !self.get(asid) {
+                self.set(asid, true);
+                return Some(asid as u16);
+            }
+        }
+
+        None
+    }
+
+    fn free(&mut self, asid: u16) -> Result<(), &'static str> {
+        let asid = asid as usize;
+        if asid == KERNEL_ASID as usize {
+            return Err("cannot free the kernel ASID");
+        }
+
+        if asid >= self.count || !self.get(asid) {
+            return Err("ASID is not in use");
+        }
+
+        // nothing cached under this ASID may be seen by the next address space to use it
+        invalidate_tlb_asid(asid);
+        self.set(asid, false);
+        Ok(())
+    }
+
+    /// Starts a new generation, wrapping the search back around to the first user ASID.
+    fn rollover(&mut self) {
+        invalidate_tlb_all();
+        self.generation += 1;
+        self.next = KERNEL_ASID as usize + 1;
+    }
+
+    fn get(&self, asid: usize) -> bool {
+        self.in_use[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn set(&mut self, asid: usize, in_use: bool) {
+        match in_use {
+            true => self.in_use[asid / 64] |= 1 << (asid % 64),
+            false => self.in_use[asid / 64] &= !(1 << (asid % 64)),
+        }
+    }
+}
+
 impl VirtualMemoryManagerInner {
     const fn new() -> Self {
         Self {
-            physical_allocator: PhysicalPageAllocator::new(),
+            physical_allocator: FrameAllocator::new(FRAME_ALLOCATOR),
             // we can't allocate the page table yet, so we use OnceCell here
             kernel_page_table: OnceCell::new(),
             use_kernel_heap_addresses: false,
-            next_asid: 1,
+            asids: AsidAllocator::new(),
+            next_mmio_offset: 0,
+            next_stack_offset: 0,
+            direct_map_end: PhysicalAddress(0),
+            shared_pages: BTreeMap::new(),
         }
     }
 
     unsafe fn init(&mut self) {
+        // 0. Make sure the CPU supports the translation regime we're about to set up
+        let features = cpu::features();
+        if !features.supports_granule(PAGE_SIZE) {
+            panic!(
+                "CPU doesn't support the {} KiB translation granule",
+                PAGE_SIZE / 1024
+            );
+        }
+
         // 1. Initialise the physical memory allocator with the Limine memory map
         let memory_map = self.init_memory_map();
+        let pa_bits = features.physical_address_bits.min(48);
+        if memory_map.highest_physical_address.0 > 1 << pa_bits {
+            panic!(
+                "physical memory ends at {}, beyond the CPU's {}-bit physical address range",
+                memory_map.highest_physical_address, pa_bits
+            );
+        }
 
-        // 2. Manually allocate a bit of memory to bootstrap the kernel page tables
+        // 2. Manually allocate a bit of memory to bootstrap the kernel heap
         // Note: as of 23/Nov/2022, we needed just over 28KB of memory here.
-        // We'll allocate 64KB to allow for the second stage bootstrapping.
-        const INITIAL_ALLOC_SIZE: usize = 64 * 1024;
-        let (alloc_start, alloc_size) = self.kernel_alloc_unchecked(INITIAL_ALLOC_SIZE);
+        // We'll allocate 64KB to allow for the second stage bootstrapping, plus the heap's
+        // emergency reserve, which is taken from the end.
+        const INITIAL_ALLOC_SIZE: usize = 64 * 1024 + EMERGENCY_RESERVE_SIZE;
+        let (alloc_start, alloc_size) = self
+            .kernel_alloc_unchecked(INITIAL_ALLOC_SIZE)
+            .expect("out of memory bootstrapping the kernel heap");
 
         // Now, make the Rust global allocator aware of the memory we just allocated
         allocator::GLOBAL_ALLOCATOR.lock(|alloc| {
@@ -242,16 +861,22 @@ impl VirtualMemoryManagerInner {
         allocator::GLOBAL_ALLOCATOR.lock(|alloc| {
             let used_size = alloc.use_main_allocator();
             let start_offset = align_up(used_size, PAGE_SIZE);
+            let reserve_offset = alloc_size - EMERGENCY_RESERVE_SIZE;
 
             alloc.add_heap_region(
                 VirtualAddress(kernel_heap_start() + start_offset),
-                alloc_size - start_offset,
+                reserve_offset - start_offset,
+            );
+            alloc.init_reserve(
+                VirtualAddress(kernel_heap_start() + reserve_offset),
+                EMERGENCY_RESERVE_SIZE,
             );
         });
         self.use_kernel_heap_addresses = true;
 
         // 4. Re-allocate the kernel table with only heap addresses instead of direct-maps
         self.create_kernel_page_table(memory_map, alloc_start, alloc_size);
+        self.direct_map_end = memory_map.highest_physical_address;
 
         // 5. Drop the old tables (TTBR0 + TTBR1)
         //    (this happens automatically at the end of this function)
@@ -271,14 +896,54 @@ impl VirtualMemoryManagerInner {
             kernel_physical_address: PhysicalAddress(0),
         };
 
-        for entry in BOOTLOADER_MAP_INFO.get_response().get().unwrap().memmap() {
-            // entries are guaranteed to be sorted by physical address, lowest to highest
+        // entries are guaranteed to be sorted by physical address, lowest to highest
+        let memmap = BOOTLOADER_MAP_INFO.get_response().get().unwrap().memmap();
+        if let Some(entry) = memmap.last() {
             result.highest_physical_address = PhysicalAddress((entry.base + entry.len) as usize);
+        }
 
+        // the allocator's own storage (if any) comes from the start of the first usable region
+        // that's big enough for it, and is left out when that region is added to the allocator
+        let storage_size = self
+            .physical_allocator
+            .storage_size(result.highest_physical_address);
+        let storage = match storage_size {
+            0 => None,
+            _ => {
+                let entry = memmap
+                    .iter()
+                    .find(|entry| {
+                        entry.typ == LimineMemoryMapEntryType::Usable
+                            && entry.len as usize >= storage_size
+                    })
+                    .expect("no usable memory region for the physical frame allocator");
+                let storage = PhysicalAddress(entry.base as usize);
+
+                self.physical_allocator
+                    .init(storage.into(), result.highest_physical_address);
+                let (size, unit) = size_human_readable_ceil(storage_size);
+                info!(
+                    "Reserved {} {} for the physical frame allocator at {}",
+                    size, unit, storage
+                );
+                Some(storage)
+            }
+        };
+
+        for entry in memmap {
             match entry.typ {
                 LimineMemoryMapEntryType::Usable => {
-                    self.physical_allocator
-                        .add_heap_region(PhysicalAddress(entry.base as usize), entry.len as usize);
+                    let mut base = entry.base as usize;
+                    let mut len = entry.len as usize;
+                    if storage == Some(PhysicalAddress(base)) {
+                        base += storage_size;
+                        len -= storage_size;
+                    }
+
+                    if len > 0 {
+                        self.physical_allocator
+                            .add_heap_region(PhysicalAddress(base), len);
+                    }
                 }
                 LimineMemoryMapEntryType::KernelAndModules => {
                     // we've found where the kernel itself is mapped
@@ -291,9 +956,50 @@ impl VirtualMemoryManagerInner {
         result
     }
 
-    #[allow(unused)]
-    fn with_kernel_page_table<'a>(&'a self, f: impl FnOnce(&'a mut RootPageTable)) {
-        self.kernel_page_table.get().unwrap().lock(f);
+    /// Adds all bootloader-reclaimable regions of the memory map to the physical page allocator.
+    ///
+    /// The region holding the current stack is skipped, as Limine's stack is also the kernel's.
+    ///
+    /// Returns the number of bytes reclaimed.
+    unsafe fn reclaim_bootloader_memory(&mut self) -> usize {
+        let stack_marker = 0u8;
+        let stack_phys = &stack_marker as *const u8 as usize - direct_map_virt_offset();
+
+        let memmap = BOOTLOADER_MAP_INFO.get_response().get().unwrap().memmap();
+        reclaim_regions(
+            &mut self.physical_allocator,
+            memmap.iter().map(|entry| &**entry),
+            PhysicalAddress(stack_phys),
+        )
+    }
+
+    unsafe fn extend_direct_map(
+        &mut self,
+        region: PhysicalMemoryRegion,
+    ) -> Result<(), &'static str> {
+        if region.end().0 > max_direct_map_size() {
+            return Err("region is beyond the physical memory the direct map can cover");
+        }
+
+        // everything below the current end is already mapped, holes included
+        if region.end() > self.direct_map_end {
+            let unmapped = PhysicalMemoryRegion::new(
+                region.start().0.max(self.direct_map_end.0),
+                region.end().0,
+            );
+            self.with_kernel_page_table(|pt| map_direct_range(pt, &unmapped, Attributes::NORMAL))
+                .map_err(|_| "failed to map region into the direct map")?;
+            self.direct_map_end = region.end();
+        }
+
+        self.physical_allocator
+            .add_heap_region(region.start(), region.len());
+
+        Ok(())
+    }
+
+    fn with_kernel_page_table<'a, R>(&'a self, f: impl FnOnce(&'a mut RootPageTable) -> R) -> R {
+        self.kernel_page_table.get().unwrap().lock(f)
     }
 
     /// Initialises the kernel's page tables and switches the MMU to use them.
@@ -301,8 +1007,8 @@ impl VirtualMemoryManagerInner {
     /// mapped virtual address that the bootloader set up for us.
     ///
     /// Limine's typical higher-half direct map address is 0xFFFF_8000_0000_0000.
-    /// If the start of the kernel heap is at 0xFFFF_FFFF_8000_0000, this means our current
-    /// memory management implementation can tolerate up to 0x7FFF_8000_0000 bytes, or ~128TB,
+    /// If the start of the kernel MMIO window is at 0xFFFF_FFFF_0000_0000, this means our current
+    /// memory management implementation can tolerate up to 0x7FFF_0000_0000 bytes, or ~128TB,
     /// of physical memory. I don't think we'll be seeing anywhere close to those numbers on any
     /// system running Flow, but we do a sanity check and panic if we exceed this limit anyways :)
     unsafe fn bootstrap_kernel_page_table(
@@ -311,7 +1017,7 @@ impl VirtualMemoryManagerInner {
         initial_alloc_start: PhysicalAddress,
         initial_alloc_size: usize,
     ) -> IRQSafeNullLock<RootPageTable> {
-        let max_phys_mem = kernel_binary_start() - direct_map_virt_offset();
+        let max_phys_mem = max_direct_map_size();
         if memory_map_result.highest_physical_address.0 > max_phys_mem {
             let (size, unit) = size_human_readable_ceil(max_phys_mem);
             panic!(
@@ -322,7 +1028,8 @@ impl VirtualMemoryManagerInner {
 
         // create a new root table, but don't set it as the kernel page table
         // this initial table is temporary to bootstrap the real kernel page table, so we'll drop it soon
-        let bootstrap_table = IRQSafeNullLock::new(RootPageTable::new(0, VaRange::Upper));
+        let bootstrap_table =
+            IRQSafeNullLock::new(RootPageTable::new(KERNEL_ASID as usize, VaRange::Upper));
         bootstrap_table.lock(|table| {
             self.fill_kernel_page_table(
                 table,
@@ -334,19 +1041,20 @@ impl VirtualMemoryManagerInner {
             // configure TCR_EL1
             TCR_EL1.write(
                 TCR_EL1::TBI0::Used
-                    + TCR_EL1::IPS::Bits_48
-                    + TCR_EL1::TG1::KiB_4
+                    + tcr_ips()
+                    + tcr_granule()
+                    + tcr_asid_size()
                     + TCR_EL1::SH1::Outer
                     + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::EPD1::EnableTTBR1Walks
                     + TCR_EL1::A1::TTBR0
-                    + TCR_EL1::T1SZ.val(16)
+                    + TCR_EL1::T1SZ.val((64 - VA_BITS) as u64)
                     + TCR_EL1::SH0::Outer
                     + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::EPD0::EnableTTBR0Walks
-                    + TCR_EL1::T0SZ.val(16),
+                    + TCR_EL1::T0SZ.val((64 - VA_BITS) as u64),
             );
 
             // invalidate the previous TTBR that the bootloader provided, as we don't want to switch
@@ -364,7 +1072,7 @@ impl VirtualMemoryManagerInner {
         initial_alloc_start: PhysicalAddress,
         initial_alloc_size: usize,
     ) {
-        let table = IRQSafeNullLock::new(RootPageTable::new(0, VaRange::Upper));
+        let table = IRQSafeNullLock::new(RootPageTable::new(KERNEL_ASID as usize, VaRange::Upper));
         table.lock(|table| {
             self.fill_kernel_page_table(
                 table,
@@ -376,20 +1084,21 @@ impl VirtualMemoryManagerInner {
             // configure TCR_EL1
             TCR_EL1.write(
                 TCR_EL1::TBI0::Used
-                    + TCR_EL1::IPS::Bits_48
-                    + TCR_EL1::TG1::KiB_4
+                    + tcr_ips()
+                    + tcr_granule()
+                    + tcr_asid_size()
                     + TCR_EL1::SH1::Outer
                     + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::EPD1::EnableTTBR1Walks
                     + TCR_EL1::A1::TTBR0
-                    + TCR_EL1::T1SZ.val(16)
+                    + TCR_EL1::T1SZ.val((64 - VA_BITS) as u64)
                     // + TCR_EL1::EPD0::DisableTTBR0Walks,
                     + TCR_EL1::SH0::Outer
                     + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
                     + TCR_EL1::EPD0::EnableTTBR0Walks
-                    + TCR_EL1::T0SZ.val(16),
+                    + TCR_EL1::T0SZ.val((64 - VA_BITS) as u64),
             );
         });
 
@@ -403,37 +1112,40 @@ impl VirtualMemoryManagerInner {
         initial_alloc_start: PhysicalAddress,
         initial_alloc_size: usize,
     ) {
-        // direct map all of physical memory (RW)
-        let dm_offset = direct_map_virt_offset();
-        kernel_table
-            .map_range(
-                &VirtualMemoryRegion::new(
-                    dm_offset,
-                    dm_offset + memory_map_result.highest_physical_address.0,
-                ),
-                PhysicalAddress(0),
-                Attributes::DEVICE_NGNRNE | Attributes::EXECUTE_NEVER,
-            )
-            .unwrap();
+        // direct map all of physical memory (RW), with RAM as normal memory and the rest as device
+        // memory. Entries don't have to be page aligned, so a page shared by two entries takes the
+        // memory type of the first.
+        let mut dm_blocks = 0;
+        let mut dm_end = 0;
+        for entry in BOOTLOADER_MAP_INFO.get_response().get().unwrap().memmap() {
+            let start = align_down(entry.base as usize, PAGE_SIZE).max(dm_end);
+            let end = align_up((entry.base + entry.len) as usize, PAGE_SIZE);
+            if end <= start {
+                continue;
+            }
 
-        // map the kernel code (RX)
-        kernel_table
-            .map_range(
-                &VirtualMemoryRegion::new(kernel_code_start(), kernel_code_end()),
-                memory_map_result.kernel_physical_address,
-                Attributes::NORMAL | Attributes::READ_ONLY,
-            )
-            .unwrap();
+            if start > dm_end {
+                dm_blocks += map_direct_range(
+                    kernel_table,
+                    &PhysicalMemoryRegion::new(dm_end, start),
+                    Attributes::DEVICE_NGNRNE,
+                )
+                .unwrap();
+            }
 
-        // map the kernel data (RW)
-        kernel_table
-            .map_range(
-                &VirtualMemoryRegion::new(kernel_data_start(), kernel_data_end()),
-                memory_map_result.kernel_physical_address
-                    + (kernel_data_start() - kernel_binary_start()),
-                Attributes::NORMAL | Attributes::EXECUTE_NEVER,
+            dm_blocks += map_direct_range(
+                kernel_table,
+                &PhysicalMemoryRegion::new(start, end),
+                direct_map_memory_type(entry.typ),
             )
             .unwrap();
+            dm_end = end;
+        }
+        debug_assert!(dm_blocks > 0, "direct map was mapped without any blocks");
+        debug_assert!(dm_end >= memory_map_result.highest_physical_address.0);
+
+        // map the kernel code (RX), read-only data (R) and data (RW)
+        map_kernel_image(kernel_table, memory_map_result.kernel_physical_address).unwrap();
 
         // map kernel heap (RW)
         kernel_table
@@ -447,8 +1159,13 @@ impl VirtualMemoryManagerInner {
             )
             .unwrap();
 
+        // the bootloader's memory types may not match the ones our page tables use, so program
+        // ours before switching, and drop anything cached under the old ones afterwards
+        MAIR_EL1.write(mair());
+
         // activate the new page table
         kernel_table.activate();
+        invalidate_tlb_all();
     }
 
     /// Creates new root page tables in the lower half of the virtual address space.
@@ -456,55 +1173,251 @@ impl VirtualMemoryManagerInner {
     ///
     /// Returns a tuple containing the address space ID and the new page table.
     pub fn new_address_space(&mut self) -> (u16, RootPageTable) {
-        let asid = self.next_asid as usize;
-        let table = RootPageTable::new(asid, VaRange::Lower);
-        self.next_asid += 1;
-        (asid as u16, table)
+        // every live address space holds on to its ASID, so there's nothing to reclaim here
+        let asid = self
+            .asids
+            .allocate()
+            .expect("Out of address space IDs; too many live address spaces");
+
+        let table = RootPageTable::new(asid as usize, VaRange::Lower);
+        (asid, table)
     }
 
     pub fn free_address_space(&mut self, asid: u16) -> Result<(), &'static str> {
-        // todo
-        Ok(())
+        self.asids.free(asid)
+    }
+
+    /// Maps a region of device memory into the next unused range of the kernel's MMIO window.
+    /// If the window is exhausted or the mapping fails, the kernel will panic.
+    ///
+    /// Virtual ranges are handed out sequentially and aren't reused after being unmapped; drivers
+    /// typically map their registers once, so the window is large enough for now.
+    ///
+    /// Returns the virtual address corresponding to `pa`.
+    pub fn map_mmio_region(&mut self, pa: PhysicalAddress, size: usize) -> VirtualAddress {
+        self.map_mmio_region_with(pa, size, Attributes::DEVICE_NGNRNE)
+    }
+
+    /// Maps a region into the next unused range of the kernel's MMIO window, with the given memory
+    /// type.
+    pub fn map_mmio_region_with(
+        &mut self,
+        pa: PhysicalAddress,
+        size: usize,
+        memory_type: Attributes,
+    ) -> VirtualAddress {
+        let page_offset = pa.0 - align_down(pa.0, PAGE_SIZE);
+        let map_size = align_up(page_offset + size, PAGE_SIZE);
+
+        let va_start = kernel_mmio_start() + self.next_mmio_offset;
+        if unlikely(map_size > kernel_mmio_end() - va_start) {
+            panic!(
+                "map_mmio_region: kernel MMIO window exhausted ({} bytes requested)",
+                map_size
+            );
+        }
+        self.next_mmio_offset += map_size;
+
+        self.with_kernel_page_table(|pt| {
+            pt.map_range(
+                &VirtualMemoryRegion::new(va_start, va_start + map_size),
+                pa - page_offset,
+                memory_type | Attributes::EXECUTE_NEVER,
+            )
+        })
+        .expect("map_mmio_region: failed to map MMIO region");
+
+        VirtualAddress(va_start + page_offset)
+    }
+
+    /// Unmaps a region of device memory previously mapped with `map_mmio_region`.
+    pub fn unmap_mmio_region(
+        &mut self,
+        va: VirtualAddress,
+        size: usize,
+    ) -> Result<(), &'static str> {
+        let va_start = align_down(va.0, PAGE_SIZE);
+        let va_end = align_up(va.0 + size, PAGE_SIZE);
+        if va_start < kernel_mmio_start() || va_end > kernel_mmio_start() + self.next_mmio_offset {
+            return Err("address range is not within the mapped kernel MMIO window");
+        }
+
+        self.with_kernel_page_table(|pt| {
+            pt.unmap_range(&VirtualMemoryRegion::new(va_start, va_end))
+        })
+        .map_err(|_| "failed to unmap MMIO region")
+    }
+
+    /// Allocates and maps a stack in the next unused range of the kernel's stack window, leaving
+    /// the page below it unmapped as a guard.
+    ///
+    /// Like the MMIO window, virtual ranges are handed out sequentially and aren't reused.
+    pub fn kernel_stack_alloc(&mut self, size: usize) -> VirtualMemoryRegion {
+        // Safe because we're not allocating from the kernel heap
+        let (pa, alloc_size) = unsafe { self.kernel_alloc_unchecked(size) }.unwrap_or_else(|_| {
+            panic!(
+                "kernel_stack_alloc: out of memory allocating a {} byte stack",
+                size
+            )
+        });
+
+        let va_start = kernel_stack_start() + self.next_stack_offset + PAGE_SIZE;
+        if unlikely(alloc_size > kernel_stack_end().saturating_sub(va_start)) {
+            panic!(
+                "kernel_stack_alloc: kernel stack window exhausted ({} bytes requested)",
+                alloc_size
+            );
+        }
+        self.next_stack_offset += PAGE_SIZE + alloc_size;
+
+        let stack = VirtualMemoryRegion::new(va_start, va_start + alloc_size);
+        self.with_kernel_page_table(|pt| {
+            map_stack(
+                pt,
+                &stack,
+                pa,
+                Attributes::NORMAL | Attributes::EXECUTE_NEVER,
+            )
+        })
+        .expect("kernel_stack_alloc: failed to map kernel stack");
+
+        stack
+    }
+
+    /// Unmaps a stack allocated with `kernel_stack_alloc`, and frees its memory.
+    ///
+    /// # Safety
+    ///
+    /// The stack must no longer be in use.
+    pub unsafe fn kernel_stack_free(&mut self, stack: &VirtualMemoryRegion) {
+        let pa = self
+            .with_kernel_page_table(|pt| {
+                let (pa, _) = pt.translate(stack.start())?;
+                pt.unmap_range(stack).ok()?;
+                Some(pa)
+            })
+            .expect("kernel_stack_free: stack is not mapped");
+
+        self.physical_allocator.deallocate(pa, stack.len());
+    }
+
+    /// Returns true if `va` is within the guard page of a live kernel stack, i.e. it's an unmapped
+    /// page in the stack window with a mapped page directly above it.
+    pub fn is_kernel_stack_guard(&self, va: VirtualAddress) -> bool {
+        if va.0 < kernel_stack_start() || va.0 >= kernel_stack_end() {
+            return false;
+        }
+
+        let page = align_down(va.0, PAGE_SIZE);
+        self.with_kernel_page_table(|pt| {
+            pt.translate(VirtualAddress(page)).is_none()
+                && pt.translate(VirtualAddress(page + PAGE_SIZE)).is_some()
+        })
     }
 
     /// Allocates memory to load a process.
-    /// If the allocation fails, the kernel will panic.
+    /// If physical memory is exhausted, [`OutOfMemory`] is returned.
     ///
     /// Returns a tuple containing:
     /// - The physical address of the allocation
     /// - The direct-map virtual address of the allocation (for kernel use)
     /// - The size of the allocation
-    pub fn process_alloc(&mut self, size: usize) -> (PhysicalAddress, VirtualAddress, usize) {
+    pub fn process_alloc(
+        &mut self,
+        size: usize,
+    ) -> Result<(PhysicalAddress, VirtualAddress, usize), OutOfMemory> {
         // Safe because we're not allocating from the kernel heap
-        let (alloc_start, alloc_size) = unsafe { self.kernel_alloc_unchecked(size) };
-        (alloc_start, alloc_start.into(), alloc_size)
+        let (alloc_start, alloc_size) = unsafe { self.kernel_alloc_unchecked(size) }?;
+        Ok((alloc_start, alloc_start.into(), alloc_size))
+    }
+
+    /// Adds a reference to every page in the given range, for another address space mapping it.
+    pub fn share_pages(&mut self, pa: PhysicalAddress, size: usize) {
+        for page in (pa.0..pa.0 + size).step_by(PAGE_SIZE) {
+            // a page that isn't shared yet has a single user already
+            *self.shared_pages.entry(PhysicalAddress(page)).or_insert(1) += 1;
+        }
+    }
+
+    /// Drops a reference to a shared page, returning whether another address space still maps it.
+    pub fn unshare_page(&mut self, pa: PhysicalAddress) -> bool {
+        let Some(users) = self.shared_pages.get_mut(&pa) else {
+            return false;
+        };
+
+        *users -= 1;
+        if *users == 1 {
+            self.shared_pages.remove(&pa);
+        }
+
+        true
+    }
+
+    /// Returns memory allocated by `process_alloc` to the physical page allocator, skipping any
+    /// pages still shared with another address space.
+    ///
+    /// # Safety
+    ///
+    /// The memory must no longer be mapped into the caller's address space or otherwise in use.
+    pub unsafe fn process_free(&mut self, pa: PhysicalAddress, size: usize) {
+        if likely(self.shared_pages.is_empty()) {
+            self.physical_allocator.deallocate(pa, size);
+            return;
+        }
+
+        for page in (pa.0..pa.0 + size).step_by(PAGE_SIZE) {
+            if !self.unshare_page(PhysicalAddress(page)) {
+                self.physical_allocator
+                    .deallocate(PhysicalAddress(page), PAGE_SIZE);
+            }
+        }
     }
 
     /// Allocates memory from the kernel's physical page allocator.
-    /// If the allocation fails, the kernel will panic.
+    /// If physical memory is exhausted, [`OutOfMemory`] is returned.
     ///
     /// Returns a tuple containing the allocation start address and allocation size, in that order.
-    pub fn kernel_alloc(&mut self, size: usize) -> (VirtualAddress, usize) {
+    pub fn kernel_alloc(&mut self, size: usize) -> Result<(VirtualAddress, usize), OutOfMemory> {
         if unlikely(self.kernel_page_table.get().is_none()) {
             // we haven't yet initialised the permanent kernel page table, so we can't allocate memory
             panic!("kernel_alloc called before kernel page table initialised");
         }
 
         // Safe because we've already checked that the kernel page table is initialised.
-        let (alloc_start, alloc_size) = unsafe { self.kernel_alloc_unchecked(size) };
+        let (alloc_start, alloc_size) = unsafe { self.kernel_alloc_unchecked(size) }?;
 
-        (
+        Ok((
             if self.use_kernel_heap_addresses {
                 VirtualAddress(alloc_start.0 + kernel_heap_start())
             } else {
                 alloc_start.into()
             },
             alloc_size,
-        )
+        ))
+    }
+
+    /// Returns memory allocated by `kernel_alloc` to the physical page allocator.
+    ///
+    /// The allocation may have been handed out as either a kernel heap or a direct-map address,
+    /// depending on whether it was made before or after the switch to the kernel heap.
+    ///
+    /// # Safety
+    ///
+    /// The memory must have been returned by `kernel_alloc`, and must no longer be in use.
+    pub unsafe fn kernel_free(&mut self, va: VirtualAddress, size: usize) {
+        let pa = if va.0 >= kernel_heap_start() {
+            PhysicalAddress(va.0 - kernel_heap_start())
+        } else {
+            va.from_direct_map()
+        };
+
+        self.physical_allocator
+            .deallocate(pa, align_up(size, PAGE_SIZE));
     }
 
     /// Allocates memory from the kernel's physical page allocator.
-    /// If the allocation fails, the kernel will panic.
+    /// If physical memory is exhausted, or `size` is too large to ever be allocated,
+    /// [`OutOfMemory`] is returned.
     ///
     /// Returns a tuple containing the allocation start address and allocation size, in that order.
     ///
@@ -512,15 +1425,232 @@ impl VirtualMemoryManagerInner {
     ///
     /// Unsafe because the kernel page table is not checked for proper state before the allocation.
     /// This should only be directly called during the kernel's initialisation.
-    unsafe fn kernel_alloc_unchecked(&mut self, size: usize) -> (PhysicalAddress, usize) {
-        let size = align_up(size, PAGE_SIZE);
-        if let Some(alloc_start) = self.physical_allocator.allocate(size) {
-            return (alloc_start, size);
+    unsafe fn kernel_alloc_unchecked(
+        &mut self,
+        size: usize,
+    ) -> Result<(PhysicalAddress, usize), OutOfMemory> {
+        let size = checked_align_up(size, PAGE_SIZE).ok_or(OutOfMemory)?;
+        let alloc_start = self.physical_allocator.allocate(size).ok_or(OutOfMemory)?;
+
+        Ok((alloc_start, size))
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use limine::{LimineMemmapEntry, LimineMemoryMapEntryType};
+
+    use super::{
+        align_down, align_up, asid_count, kernel_mmio_end, kernel_mmio_start, max_direct_map_size,
+        reclaim_regions, virtual_memory_manager, AsidAllocator, FrameAllocator, FrameAllocatorKind,
+        MemoryManager, KERNEL_ASID,
+    };
+    use crate::exception::asynchronous::exec_with_masked_irqs;
+    use crate::mem::allocator::selftest::{drain_physical_memory, refill_physical_memory};
+    use crate::mem::vm::paging::{
+        PhysicalAddress, PhysicalMemoryRegion, VirtualAddress, VirtualMemoryRegion, PAGE_SIZE,
+    };
+    use crate::selftest::SelfTest;
+    use crate::sync::interface::Mutex;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "mem::the kernel ASID is never handed out or freed",
+            run: kernel_asid_reserved,
+        },
+        SelfTest {
+            name: "mem::only bootloader-reclaimable memory is reclaimed",
+            run: reclaim_bootloader_regions,
+        },
+        SelfTest {
+            name: "mem::MMIO regions get distinct ranges in the MMIO window",
+            run: mmio_regions_distinct,
+        },
+        SelfTest {
+            name: "mem::memory added to the direct map can be translated and allocated",
+            run: extend_direct_map_with_region,
+        },
+    ];
+
+    /// The physical address of the UART on QEMU's virt machine, which is only mapped, never
+    /// accessed, so any device would do.
+    const DEVICE: usize = 0x0900_0000;
+
+    fn kernel_asid_reserved() {
+        let (asid, table) = virtual_memory_manager().new_address_space();
+        assert_ne!(asid, KERNEL_ASID);
+        drop(table);
+        virtual_memory_manager().free_address_space(asid).unwrap();
+
+        assert!(virtual_memory_manager()
+            .free_address_space(KERNEL_ASID)
+            .is_err());
+
+        // a whole generation, past the rollover and into the next, without page tables to back it
+        let mut asids = AsidAllocator::new();
+        for _ in 0..asid_count() + 1 {
+            let asid = asids.allocate().expect("ran out of ASIDs");
+            assert_ne!(asid, KERNEL_ASID);
+            asids.free(asid).unwrap();
         }
+        assert!(asids.free(KERNEL_ASID).is_err());
+    }
 
-        panic!(
-            "kernel_alloc: failed to allocate {} bytes to kernel heap",
-            size
-        );
+    fn reclaim_bootloader_regions() {
+        // real pages stand in for the bootloader's, as the allocator writes to the memory it's given
+        let pages: [PhysicalAddress; 4] = core::array::from_fn(|_| {
+            virtual_memory_manager()
+                .process_alloc(PAGE_SIZE)
+                .expect("failed to allocate a page")
+                .0
+        });
+        let entry = |pa: PhysicalAddress, len: usize, typ| LimineMemmapEntry {
+            base: pa.0 as u64,
+            len: len as u64,
+            typ,
+        };
+        let memmap = [
+            entry(pages[0], PAGE_SIZE, LimineMemoryMapEntryType::Usable),
+            entry(
+                pages[1],
+                PAGE_SIZE,
+                LimineMemoryMapEntryType::BootloaderReclaimable,
+            ),
+            entry(
+                PhysicalAddress(0),
+                PAGE_SIZE,
+                LimineMemoryMapEntryType::KernelAndModules,
+            ),
+            entry(
+                pages[2],
+                PAGE_SIZE,
+                LimineMemoryMapEntryType::BootloaderReclaimable,
+            ),
+            entry(
+                PhysicalAddress(0),
+                PAGE_SIZE,
+                LimineMemoryMapEntryType::Reserved,
+            ),
+            // stands in for the entry holding the stack, which is never reclaimed
+            entry(
+                pages[3],
+                PAGE_SIZE,
+                LimineMemoryMapEntryType::BootloaderReclaimable,
+            ),
+        ];
+
+        let mut allocator = FrameAllocator::new(FrameAllocatorKind::LinkedList);
+        // Safe because the page was just allocated, and isn't used by anything else.
+        unsafe { allocator.add_heap_region(pages[0], PAGE_SIZE) };
+        let free = allocator.stats().free;
+
+        // Safe because the reclaimable pages were just allocated, and aren't used by anything else.
+        let reclaimed =
+            unsafe { reclaim_regions(&mut allocator, memmap.iter(), pages[3] + PAGE_SIZE / 2) };
+        assert_eq!(reclaimed, 2 * PAGE_SIZE);
+        assert_eq!(allocator.stats().free, free + reclaimed);
+        assert_eq!(allocator.stats().allocated, 0);
+
+        // the test allocator is abandoned, so its pages can go back to where they came from
+        for pa in pages {
+            // Safe because the test allocator that was handed the pages is never used again.
+            unsafe { virtual_memory_manager().process_free(pa, PAGE_SIZE) };
+        }
+    }
+
+    /// Returns the pages of the MMIO window that `len` bytes mapped at `va` take up.
+    fn mmio_pages(va: VirtualAddress, len: usize) -> VirtualMemoryRegion {
+        VirtualMemoryRegion::new(align_down(va.0, PAGE_SIZE), align_up(va.0 + len, PAGE_SIZE))
+    }
+
+    fn mmio_regions_distinct() {
+        let window = VirtualMemoryRegion::new(kernel_mmio_start(), kernel_mmio_end());
+        // one within a page, and one spanning several, neither of them page aligned
+        let regions = [
+            (DEVICE + 0x10, 0x20),
+            (DEVICE + PAGE_SIZE - 8, 2 * PAGE_SIZE),
+        ];
+
+        let [a, b] = regions
+            .map(|(pa, len)| virtual_memory_manager().map_mmio_region(PhysicalAddress(pa), len));
+        let (a_pages, b_pages) = (mmio_pages(a, regions[0].1), mmio_pages(b, regions[1].1));
+        assert_ne!(a, b);
+        assert!(!a_pages.overlaps(&b_pages));
+        assert!(window.contains_region(&a_pages));
+        assert!(window.contains_region(&b_pages));
+
+        // the offset into the page is kept, and every page maps the device
+        for ((pa, len), va) in regions.into_iter().zip([a, b]) {
+            assert_eq!(va.0 % PAGE_SIZE, pa % PAGE_SIZE);
+            for offset in [0, len - 1] {
+                let translated = virtual_memory_manager()
+                    .inner
+                    .lock(|inner| inner.with_kernel_page_table(|pt| pt.translate(va + offset)));
+                assert_eq!(
+                    translated.map(|(pa, _)| pa),
+                    Some(PhysicalAddress(pa + offset))
+                );
+            }
+        }
+
+        for ((_, len), va) in regions.into_iter().zip([a, b]) {
+            virtual_memory_manager()
+                .unmap_mmio_region(va, len)
+                .expect("failed to unmap MMIO region");
+        }
+    }
+
+    /// Returns the physical address that `va` translates to in the kernel's page table.
+    fn kernel_translate(va: VirtualAddress) -> Option<PhysicalAddress> {
+        virtual_memory_manager().inner.lock(|inner| {
+            inner
+                .with_kernel_page_table(|pt| pt.translate(va))
+                .map(|(pa, _)| pa)
+        })
+    }
+
+    fn extend_direct_map_with_region() {
+        // stands in for hot-added RAM: taken from the allocator, so it's neither in use nor free
+        let (pa, _, size) = virtual_memory_manager()
+            .process_alloc(4 * PAGE_SIZE)
+            .expect("failed to allocate the region");
+        let region = PhysicalMemoryRegion::new(pa.0, pa.0 + size);
+
+        let beyond = max_direct_map_size();
+        // Safe because the region is rejected before anything is mapped or added.
+        assert!(unsafe {
+            virtual_memory_manager()
+                .extend_direct_map(PhysicalMemoryRegion::new(beyond, beyond + PAGE_SIZE))
+        }
+        .is_err());
+
+        // with nothing else free, the only memory that can be allocated is what's added here, and
+        // nothing else may run meanwhile, as it couldn't allocate either
+        let (extended, added, allocated) = exec_with_masked_irqs(|| {
+            let drained = drain_physical_memory();
+            let free = virtual_memory_manager().physical_stats().free;
+            // Safe because the region was taken from the allocator above, and isn't used.
+            let extended = unsafe { virtual_memory_manager().extend_direct_map(region) };
+            let added = virtual_memory_manager().physical_stats().free - free;
+            let allocated = virtual_memory_manager().process_alloc(size).ok();
+            refill_physical_memory(drained);
+            (extended, added, allocated)
+        });
+
+        assert!(extended.is_ok());
+        assert_eq!(added, size);
+        let (allocated_pa, dm, allocated_size) =
+            allocated.expect("failed to allocate from the added region");
+        assert_eq!((allocated_pa, allocated_size), (pa, size));
+        assert_eq!(dm, pa.to_direct_map_virtual());
+        assert_eq!(kernel_translate(dm), Some(pa));
+        assert_eq!(kernel_translate(dm + (size - 1)), Some(pa + (size - 1)));
+
+        // Safe because the region was just allocated, and is only used here.
+        unsafe {
+            core::ptr::write_bytes(dm.0 as *mut u8, 0x5a, size);
+            assert_eq!(((dm.0 + size - 1) as *const u8).read(), 0x5a);
+            virtual_memory_manager().process_free(pa, size);
+        }
     }
 }
@@ -3,30 +3,182 @@
 use core::alloc::{GlobalAlloc, Layout};
 
 use core::intrinsics::unlikely;
-use core::sync::atomic::Ordering;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use crate::mem::allocator::bitmap::BitmapFrameAllocator;
 use crate::mem::allocator::bump::BumpAllocator;
 use crate::mem::allocator::linked_list::LinkedListAllocator;
+use crate::mem::allocator::physical_page::PhysicalPageAllocator;
 
-use crate::mem::vm::paging::VirtualAddress;
+use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress, PAGE_SIZE};
 use crate::mem::{virtual_memory_manager, MemoryManager};
 use crate::sync::interface::Mutex;
 use crate::sync::IRQSafeNullLock;
 use crate::EARLY_INIT_COMPLETE;
 
+pub mod bitmap;
 pub mod bump;
 pub mod linked_list;
 
 pub mod physical_page;
+pub mod slab;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A snapshot of how much of an allocator's memory is in use, and how fragmented the rest is.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AllocatorStats {
+    /// Bytes currently handed out.
+    pub allocated: usize,
+
+    /// Bytes available to allocate, summed over the free list.
+    pub free: usize,
+
+    /// The number of nodes in the free list.
+    pub free_regions: usize,
+
+    /// The size of the largest contiguous free region.
+    pub largest_free_region: usize,
+}
+
+/// The byte that freed memory is filled with in debug builds, so that reads through dangling
+/// pointers are easy to spot.
+pub const POISON_BYTE: u8 = 0xde;
+
+/// The size of the emergency reserve that the kernel heap falls back on once no more memory can be
+/// had from the virtual memory manager, so that out of memory handling can itself allocate.
+pub const EMERGENCY_RESERVE_SIZE: usize = 4 * PAGE_SIZE;
+
+/// The kinds of physical frame allocator the virtual memory manager can be built with.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FrameAllocatorKind {
+    /// A free list threaded through the free frames themselves.
+    LinkedList,
+    /// A bitmap with one bit per frame, which is better at finding contiguous runs of frames.
+    Bitmap,
+}
+
+/// The physical frame allocator used by the virtual memory manager.
+pub enum FrameAllocator {
+    LinkedList(PhysicalPageAllocator),
+    Bitmap(BitmapFrameAllocator),
+}
 
 //--------------------------------------------------------------------------------------------------
 // Public code
 //--------------------------------------------------------------------------------------------------
+/// Returns the statistics of the kernel heap.
+pub fn heap_stats() -> AllocatorStats {
+    GLOBAL_ALLOCATOR.lock(|alloc| alloc.stats())
+}
+
+/// Returns true if the kernel heap has had to allocate from its emergency reserve, and the reserve
+/// hasn't yet been fully returned. Subsystems holding on to memory they can do without, such as
+/// caches, should give it back while this is set.
+#[allow(unused)]
+pub fn is_low_memory() -> bool {
+    LOW_MEMORY.load(Ordering::Relaxed)
+}
+
+impl FrameAllocator {
+    pub const fn new(kind: FrameAllocatorKind) -> Self {
+        match kind {
+            FrameAllocatorKind::LinkedList => Self::LinkedList(PhysicalPageAllocator::new()),
+            FrameAllocatorKind::Bitmap => Self::Bitmap(BitmapFrameAllocator::new()),
+        }
+    }
+
+    /// Returns the number of bytes of physical memory the allocator needs set aside for its own
+    /// use before any memory is added to it, to cover memory up to `highest_physical_address`.
+    pub fn storage_size(&self, highest_physical_address: PhysicalAddress) -> usize {
+        match self {
+            Self::LinkedList(_) => 0,
+            Self::Bitmap(_) => BitmapFrameAllocator::storage_size(highest_physical_address),
+        }
+    }
+
+    /// Sets up the allocator's own storage, of [`storage_size`](Self::storage_size) bytes.
+    ///
+    /// # Safety
+    ///
+    /// `storage` must be a direct-mapped region which is never added to the allocator.
+    pub unsafe fn init(
+        &mut self,
+        storage: VirtualAddress,
+        highest_physical_address: PhysicalAddress,
+    ) {
+        match self {
+            Self::LinkedList(_) => {}
+            Self::Bitmap(alloc) => alloc.init(storage, highest_physical_address),
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        match self {
+            Self::LinkedList(alloc) => alloc.stats(),
+            Self::Bitmap(alloc) => alloc.stats(),
+        }
+    }
+
+    /// Adds a physical memory region to the allocator.
+    pub unsafe fn add_heap_region(&mut self, heap_start: PhysicalAddress, heap_size: usize) {
+        match self {
+            Self::LinkedList(alloc) => alloc.add_heap_region(heap_start, heap_size),
+            Self::Bitmap(alloc) => alloc.add_heap_region(heap_start, heap_size),
+        }
+    }
+
+    /// Returns a previously allocated physical region to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The region must have been returned by `allocate`, and must no longer be in use.
+    pub unsafe fn deallocate(&mut self, addr: PhysicalAddress, size: usize) {
+        match self {
+            Self::LinkedList(alloc) => alloc.deallocate(addr, size),
+            Self::Bitmap(alloc) => alloc.deallocate(addr, size),
+        }
+    }
+
+    /// Allocates `size` bytes of physically contiguous memory, returning its start address.
+    pub fn allocate(&mut self, size: usize) -> Option<PhysicalAddress> {
+        match self {
+            Self::LinkedList(alloc) => alloc.allocate(size),
+            Self::Bitmap(alloc) => alloc.allocate(size),
+        }
+    }
+
+    /// Like [`allocate`](Self::allocate), but the memory starts at a multiple of `align`, which
+    /// must be a power of two no smaller than the page size.
+    pub fn allocate_aligned(&mut self, size: usize, align: usize) -> Option<PhysicalAddress> {
+        match self {
+            Self::LinkedList(alloc) => alloc.allocate_aligned(size, align),
+            Self::Bitmap(alloc) => alloc.allocate_aligned(size, align),
+        }
+    }
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("kernel memory allocation failed: {:?}", layout);
 }
 
+/// Fills `size` bytes of memory that was just freed, starting at `start`, with [`POISON_BYTE`].
+/// This does nothing in release builds.
+///
+/// # Safety
+///
+/// The memory must be writable, and no longer in use.
+#[inline(always)]
+pub unsafe fn poison(start: *mut u8, size: usize) {
+    if cfg!(debug_assertions) {
+        ptr::write_bytes(start, POISON_BYTE, size);
+    }
+}
+
 /// Align downwards. Returns the greatest x with alignment `align`
 /// so that x <= addr. The alignment must be a power of 2.
 pub const fn align_down(size: usize, align: usize) -> usize {
@@ -41,11 +193,24 @@ pub const fn align_down(size: usize, align: usize) -> usize {
 
 /// Align the given address upwards to the given alignment.
 ///
-/// Requires that the alignment is a power of two.
+/// Requires that the alignment is a power of two. The address must not be so close to
+/// `usize::MAX` that aligning it overflows; use [`checked_align_up`] for sizes that could be.
 pub const fn align_up(addr: usize, align: usize) -> usize {
+    debug_assert!(addr <= usize::MAX - (align - 1), "align_up overflowed");
     (addr + align - 1) & !(align - 1)
 }
 
+/// Align the given address upwards to the given alignment, or return `None` if the aligned address
+/// doesn't fit in a `usize`.
+///
+/// Requires that the alignment is a power of two.
+pub const fn checked_align_up(addr: usize, align: usize) -> Option<usize> {
+    match addr.checked_add(align - 1) {
+        Some(addr) => Some(addr & !(align - 1)),
+        None => None,
+    }
+}
+
 //--------------------------------------------------------------------------------------------------
 // Private definitions
 //--------------------------------------------------------------------------------------------------
@@ -57,8 +222,16 @@ pub(crate) struct KernelAllocator {
     boot_allocator: BumpAllocator,
     main_allocator: LinkedListAllocator,
     use_main_allocator: bool,
+
+    /// Only allocated from when the main allocator can't grow any further.
+    reserve_allocator: LinkedListAllocator,
+    reserve_start: usize,
+    reserve_end: usize,
 }
 
+/// Set while any of the emergency reserve is allocated.
+static LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+
 //--------------------------------------------------------------------------------------------------
 // Private code
 //--------------------------------------------------------------------------------------------------
@@ -75,8 +248,13 @@ unsafe impl GlobalAlloc for IRQSafeNullLock<KernelAllocator> {
 
                 // if that fails, ask vmm for additional memory
                 // take additional memory in pages
-                let (alloc_start, size) =
-                    virtual_memory_manager().kernel_alloc(layout.pad_to_align().size());
+                let Ok((alloc_start, size)) =
+                    virtual_memory_manager().kernel_alloc(layout.pad_to_align().size())
+                else {
+                    // dip into the reserve, so whatever is handling the failure can still allocate,
+                    // and otherwise leave it to the caller, or failing that the alloc error handler
+                    return alloc.reserve_alloc(layout);
+                };
 
                 // add the new region to the allocator
                 alloc.main_allocator.add_heap_region(alloc_start, size);
@@ -92,13 +270,37 @@ unsafe impl GlobalAlloc for IRQSafeNullLock<KernelAllocator> {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         // todo: in the future, can we free pages from kernel space when they are no longer needed?
         self.lock(|alloc| {
-            if alloc.use_main_allocator {
+            if alloc.is_reserve(ptr) {
+                alloc.reserve_dealloc(ptr, layout)
+            } else if alloc.use_main_allocator {
                 alloc.main_allocator.dealloc(ptr, layout)
             } else {
                 alloc.boot_allocator.dealloc(ptr, layout)
             }
         })
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // first, try to grow or shrink the allocation where it is
+        let resized = self.lock(|alloc| {
+            alloc.use_main_allocator
+                && !alloc.is_reserve(ptr)
+                && alloc.main_allocator.realloc_in_place(ptr, layout, new_size)
+        });
+        if resized {
+            return ptr;
+        }
+
+        // otherwise, move it to a new allocation
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
+    }
 }
 
 impl KernelAllocator {
@@ -107,6 +309,9 @@ impl KernelAllocator {
             boot_allocator: BumpAllocator::new(),
             main_allocator: LinkedListAllocator::new(),
             use_main_allocator: false,
+            reserve_allocator: LinkedListAllocator::new(),
+            reserve_start: 0,
+            reserve_end: 0,
         }
     }
 
@@ -118,6 +323,15 @@ impl KernelAllocator {
         self.main_allocator.add_heap_region(heap_start, heap_size);
     }
 
+    /// Sets aside a region as the emergency reserve. This can only be done once.
+    pub(crate) unsafe fn init_reserve(&mut self, start: VirtualAddress, size: usize) {
+        assert_eq!(self.reserve_end, 0, "emergency reserve already initialised");
+
+        self.reserve_allocator.add_heap_region(start, size);
+        self.reserve_start = start.0;
+        self.reserve_end = start.0 + size;
+    }
+
     pub(crate) unsafe fn init_boot_allocator(
         &mut self,
         start: VirtualAddress,
@@ -126,6 +340,14 @@ impl KernelAllocator {
         self.boot_allocator.init(start, end);
     }
 
+    /// Returns the statistics of the allocator in use. Memory handed out by the boot allocator is
+    /// never freed, so it always counts as allocated.
+    pub(crate) fn stats(&self) -> AllocatorStats {
+        let mut stats = self.main_allocator.stats();
+        stats.allocated += self.boot_allocator.get_size();
+        stats
+    }
+
     /// Switches to the main allocator.
     /// Returns the amount of memory that was allocated by the boot allocator.
     ///
@@ -136,4 +358,139 @@ impl KernelAllocator {
         self.use_main_allocator = true;
         self.boot_allocator.get_size()
     }
+
+    fn is_reserve(&self, ptr: *mut u8) -> bool {
+        (self.reserve_start..self.reserve_end).contains(&(ptr as usize))
+    }
+
+    unsafe fn reserve_alloc(&mut self, layout: Layout) -> *mut u8 {
+        let result = self.reserve_allocator.alloc(layout);
+        if !result.is_null() {
+            LOW_MEMORY.store(true, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    unsafe fn reserve_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.reserve_allocator.dealloc(ptr, layout);
+
+        // the reserve is whole again once everything taken from it has been given back
+        if self.reserve_allocator.stats().allocated == 0 {
+            LOW_MEMORY.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::ptr;
+
+    use super::{align_down, is_low_memory, KernelAllocator};
+    use crate::exception::asynchronous::exec_with_masked_irqs;
+    use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress, PAGE_SIZE};
+    use crate::mem::{virtual_memory_manager, MemoryManager};
+    use crate::selftest::SelfTest;
+    use crate::sync::interface::Mutex;
+    use crate::sync::IRQSafeNullLock;
+
+    pub const TESTS: &[SelfTest] = &[SelfTest {
+        name: "allocator::the reserve is used once the heap can't grow",
+        run: reserve_fallback,
+    }];
+
+    const ARENA_SIZE: usize = 4096;
+
+    /// Memory for the heap under test to manage, so it doesn't touch the kernel heap.
+    #[repr(align(16))]
+    struct Arena([u8; ARENA_SIZE]);
+
+    static mut MAIN_ARENA: Arena = Arena([0; ARENA_SIZE]);
+    static mut RESERVE_ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+    /// The header written at the start of each chunk taken by [`drain_physical_memory`].
+    struct Chunk {
+        next: Option<PhysicalAddress>,
+        size: usize,
+    }
+
+    /// Allocates all of the free physical memory, so that no heap can grow, and returns the chunks
+    /// taken as a list threaded through their first bytes.
+    pub fn drain_physical_memory() -> Option<PhysicalAddress> {
+        let mut head = None;
+        let mut size = align_down(virtual_memory_manager().physical_stats().free, PAGE_SIZE);
+
+        while size >= PAGE_SIZE {
+            match virtual_memory_manager().process_alloc(size) {
+                Ok((pa, dm, size)) => {
+                    // Safe because the chunk was just allocated, and is at least a page long.
+                    unsafe { (dm.0 as *mut Chunk).write(Chunk { next: head, size }) };
+                    head = Some(pa);
+                }
+                Err(_) => size = align_down(size / 2, PAGE_SIZE),
+            }
+        }
+
+        head
+    }
+
+    /// Frees the chunks taken by [`drain_physical_memory`].
+    pub fn refill_physical_memory(mut head: Option<PhysicalAddress>) {
+        while let Some(pa) = head {
+            // Safe because every chunk in the list starts with a header, and is no longer used.
+            unsafe {
+                let chunk = (pa.to_direct_map_virtual().0 as *const Chunk).read();
+                virtual_memory_manager().process_free(pa, chunk.size);
+                head = chunk.next;
+            }
+        }
+    }
+
+    fn reserve_fallback() {
+        let block = Layout::from_size_align(64, 16).unwrap();
+        assert!(!is_low_memory());
+
+        let heap = IRQSafeNullLock::new(KernelAllocator::new());
+        // Safe because the arenas aren't used by anything else.
+        heap.lock(|alloc| unsafe {
+            alloc.main_allocator.add_heap_region(
+                VirtualAddress(ptr::addr_of_mut!(MAIN_ARENA) as usize),
+                ARENA_SIZE,
+            );
+            alloc.init_reserve(
+                VirtualAddress(ptr::addr_of_mut!(RESERVE_ARENA) as usize),
+                ARENA_SIZE,
+            );
+            alloc.use_main_allocator();
+
+            // use up the whole main heap, so it has to grow to satisfy anything else
+            while !alloc.main_allocator.alloc(block).is_null() {}
+        });
+
+        // nothing else may run while physical memory is drained, as it couldn't allocate either
+        let (a, b) = exec_with_masked_irqs(|| {
+            let drained = drain_physical_memory();
+            // Safe because both blocks are only freed below, with the same layout.
+            let blocks = unsafe { (heap.alloc(block), heap.alloc(block)) };
+            refill_physical_memory(drained);
+            blocks
+        });
+
+        assert!(!a.is_null() && !b.is_null());
+        assert_ne!(a, b);
+        heap.lock(|alloc| assert!(alloc.is_reserve(a) && alloc.is_reserve(b)));
+        assert!(is_low_memory());
+
+        // Safe because both blocks came from this heap, with this layout.
+        unsafe {
+            heap.dealloc(b, block);
+            assert!(
+                is_low_memory(),
+                "low memory cleared with the reserve still in use"
+            );
+            heap.dealloc(a, block);
+        }
+        assert!(!is_low_memory());
+    }
 }
@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: MIT
+//! A physical frame allocator backed by a bitmap, with one bit per frame of physical memory.
+//!
+//! Unlike [`PhysicalPageAllocator`](super::physical_page::PhysicalPageAllocator), nothing is stored
+//! in the free frames themselves, and runs of contiguous frames are found by scanning the bitmap
+//! first-fit, a word at a time where possible. That makes it the better fit for large contiguous
+//! allocations, such as DMA buffers. Freeing is O(1) per frame.
+//!
+//! The bitmap covers every frame up to the highest physical address, holes included, and lives in
+//! the direct map. Its storage must be set aside before any memory is added to the allocator.
+
+use core::intrinsics::unlikely;
+use core::ptr::NonNull;
+use core::slice;
+
+use crate::mem::allocator::{align_down, align_up, AllocatorStats};
+use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress, PAGE_SIZE};
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+pub struct BitmapFrameAllocator {
+    /// One bit per frame, set while the frame is in use or isn't usable memory at all.
+    bitmap: NonNull<u64>,
+    words: usize,
+    frames: usize,
+    allocated: usize,
+    /// The first word which might have a clear bit; every word before it is full.
+    first_free_word: usize,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+unsafe impl Send for BitmapFrameAllocator {}
+
+impl BitmapFrameAllocator {
+    pub const fn new() -> Self {
+        Self {
+            bitmap: NonNull::dangling(),
+            words: 0,
+            frames: 0,
+            allocated: 0,
+            first_free_word: 0,
+        }
+    }
+
+    /// Returns the number of bytes of storage, in whole pages, needed for a bitmap covering all
+    /// physical memory below `highest_physical_address`.
+    pub const fn storage_size(highest_physical_address: PhysicalAddress) -> usize {
+        let frames = highest_physical_address.0.div_ceil(PAGE_SIZE);
+        align_up(frames.div_ceil(BITS) * BITS / 8, PAGE_SIZE)
+    }
+
+    /// Sets up the bitmap in `storage`, with every frame marked as in use.
+    ///
+    /// # Safety
+    ///
+    /// `storage` must be a direct-mapped region at least
+    /// [`storage_size(highest_physical_address)`](Self::storage_size) bytes long, which is never
+    /// added to this or any other allocator.
+    pub unsafe fn init(
+        &mut self,
+        storage: VirtualAddress,
+        highest_physical_address: PhysicalAddress,
+    ) {
+        assert_eq!(self.frames, 0, "bitmap frame allocator already initialised");
+        assert_eq!(
+            storage.0 % PAGE_SIZE,
+            0,
+            "bitmap storage isn't page aligned"
+        );
+
+        self.frames = highest_physical_address.0.div_ceil(PAGE_SIZE);
+        self.words = self.frames.div_ceil(BITS);
+        self.bitmap = NonNull::new(storage.0 as *mut u64).expect("bitmap storage is null");
+
+        // frames past the end of physical memory stay marked as in use, so they're never found
+        self.bitmap_mut().fill(u64::MAX);
+    }
+
+    /// Returns the number of bytes allocated, and a summary of the free frames.
+    pub fn stats(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats {
+            allocated: self.allocated,
+            ..Default::default()
+        };
+
+        let mut run = 0;
+        for frame in 0..=self.frames {
+            if frame < self.frames && !self.is_used(frame) {
+                run += 1;
+                continue;
+            }
+
+            if run > 0 {
+                stats.free += run * PAGE_SIZE;
+                stats.free_regions += 1;
+                stats.largest_free_region = stats.largest_free_region.max(run * PAGE_SIZE);
+                run = 0;
+            }
+        }
+
+        stats
+    }
+
+    /// Adds a physical memory region to the allocator. Any partial frames at either end of the
+    /// region are left out.
+    pub unsafe fn add_heap_region(&mut self, heap_start: PhysicalAddress, heap_size: usize) {
+        let start = align_up(heap_start.0, PAGE_SIZE) / PAGE_SIZE;
+        let end = align_down(heap_start.0 + heap_size, PAGE_SIZE) / PAGE_SIZE;
+        assert!(
+            end <= self.frames,
+            "physical memory region {} is beyond the end of the bitmap",
+            heap_start
+        );
+
+        for frame in start..end {
+            self.set_used(frame, false);
+        }
+    }
+
+    /// Returns a previously allocated physical region to the allocator.
+    /// Panics if any part of the region is already free, as that indicates a double free.
+    ///
+    /// # Safety
+    ///
+    /// The region must have been returned by `allocate`, and must no longer be in use.
+    pub unsafe fn deallocate(&mut self, addr: PhysicalAddress, size: usize) {
+        let start = addr.0 / PAGE_SIZE;
+        for frame in start..start + size.div_ceil(PAGE_SIZE) {
+            if unlikely(!self.is_used(frame)) {
+                panic!(
+                    "double free of physical memory: {} ({} bytes)",
+                    PhysicalAddress(frame * PAGE_SIZE),
+                    size
+                );
+            }
+
+            self.set_used(frame, false);
+        }
+
+        self.allocated -= size;
+    }
+
+    /// Finds the first run of free frames big enough for `size` bytes, marks it as in use, and
+    /// returns its start physical address.
+    pub fn allocate(&mut self, size: usize) -> Option<PhysicalAddress> {
+        self.allocate_aligned(size, PAGE_SIZE)
+    }
+
+    /// Like [`allocate`](Self::allocate), but the run starts at a multiple of `align`, which must
+    /// be a power of two no smaller than the page size.
+    pub fn allocate_aligned(&mut self, size: usize, align: usize) -> Option<PhysicalAddress> {
+        let count = size.div_ceil(PAGE_SIZE);
+        let start = self.find_run(count, align / PAGE_SIZE)?;
+        for frame in start..start + count {
+            self.set_used(frame, true);
+        }
+
+        let bitmap = self.bitmap();
+        let mut first_free_word = self.first_free_word;
+        while first_free_word < self.words && bitmap[first_free_word] == u64::MAX {
+            first_free_word += 1;
+        }
+
+        self.first_free_word = first_free_word;
+        self.allocated += size;
+        Some(PhysicalAddress(start * PAGE_SIZE))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+const BITS: usize = u64::BITS as usize;
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl BitmapFrameAllocator {
+    /// Returns the first frame of the first run of `count` free frames starting at a multiple of
+    /// `align` frames.
+    fn find_run(&self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+
+        let bitmap = self.bitmap();
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut frame = self.first_free_word * BITS;
+
+        while frame < self.frames {
+            // a run can only start on an aligned frame
+            if run_len == 0 && frame % align != 0 {
+                frame = align_up(frame, align);
+                continue;
+            }
+
+            // whole words can be stepped over at once, if they're all in use or all free
+            if frame % BITS == 0 {
+                match bitmap[frame / BITS] {
+                    u64::MAX => {
+                        run_len = 0;
+                        frame += BITS;
+                        continue;
+                    }
+                    0 if count - run_len >= BITS => {
+                        if run_len == 0 {
+                            run_start = frame;
+                        }
+
+                        run_len += BITS;
+                        frame += BITS;
+                        if run_len == count {
+                            return Some(run_start);
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
+            if self.is_used(frame) {
+                run_len = 0;
+            } else {
+                if run_len == 0 {
+                    run_start = frame;
+                }
+
+                run_len += 1;
+                if run_len == count {
+                    return Some(run_start);
+                }
+            }
+
+            frame += 1;
+        }
+
+        None
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap()[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        let word = frame / BITS;
+        match used {
+            true => self.bitmap_mut()[word] |= 1 << (frame % BITS),
+            false => {
+                self.bitmap_mut()[word] &= !(1 << (frame % BITS));
+                self.first_free_word = self.first_free_word.min(word);
+            }
+        }
+    }
+
+    fn bitmap(&self) -> &[u64] {
+        // Safe because the storage was set aside for the bitmap in `init`, or is empty before then.
+        unsafe { slice::from_raw_parts(self.bitmap.as_ptr(), self.words) }
+    }
+
+    fn bitmap_mut(&mut self) -> &mut [u64] {
+        // Safe because the storage was set aside for the bitmap in `init`, or is empty before then.
+        unsafe { slice::from_raw_parts_mut(self.bitmap.as_ptr(), self.words) }
+    }
+}
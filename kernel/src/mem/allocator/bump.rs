@@ -1,6 +1,6 @@
 // SPDX-License-Identifier: MIT
 
-use crate::mem::allocator::align_up;
+use crate::mem::allocator::checked_align_up;
 use crate::mem::vm::paging::VirtualAddress;
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::Cell;
@@ -43,10 +43,15 @@ impl BumpAllocator {
 
 unsafe impl GlobalAlloc for BumpAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        assert_ne!(self.start.get().0, 0, "BumpAllocator not initialised");
+        debug_assert_ne!(self.start.get().0, 0, "BumpAllocator not initialised");
 
-        let alloc_start = VirtualAddress(align_up(self.next.get().0, layout.align()));
-        let alloc_end = alloc_start + layout.size();
+        let Some(alloc_start) = checked_align_up(self.next.get().0, layout.align()) else {
+            return core::ptr::null_mut();
+        };
+        let Some(alloc_end) = alloc_start.checked_add(layout.size()) else {
+            return core::ptr::null_mut();
+        };
+        let (alloc_start, alloc_end) = (VirtualAddress(alloc_start), VirtualAddress(alloc_end));
 
         if alloc_end >= self.end.get() {
             core::ptr::null_mut()
@@ -59,7 +64,7 @@ unsafe impl GlobalAlloc for BumpAllocator {
     }
 
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        assert_ne!(self.start.get().0, 0, "BumpAllocator not initialised");
+        debug_assert_ne!(self.start.get().0, 0, "BumpAllocator not initialised");
 
         if self.allocations.update(|x| x - 1) == 0 {
             self.next.set(self.start.get());
@@ -1,10 +1,11 @@
 // SPDX-License-Identifier: MIT
 
 use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::Ordering;
 use core::intrinsics::unlikely;
 use core::mem;
 
-use crate::mem::allocator::align_up;
+use crate::mem::allocator::{align_up, checked_align_up, poison, AllocatorStats};
 use crate::mem::vm::paging::VirtualAddress;
 use crate::sync::interface::Mutex;
 use crate::sync::IRQSafeNullLock;
@@ -17,6 +18,20 @@ pub const LIST_NODE_SIZE: usize = mem::size_of::<ListNode>();
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    strategy: FitStrategy,
+    allocated: usize,
+}
+
+/// How the allocator picks a free region to allocate from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[allow(dead_code)]
+pub enum FitStrategy {
+    /// Use the first region, by address, that the allocation fits in.
+    FirstFit,
+
+    /// Scan the whole free list and use the region that leaves the least space over, to keep
+    /// larger regions intact for larger allocations.
+    BestFit,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -26,16 +41,44 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            strategy: FitStrategy::FirstFit,
+            allocated: 0,
+        }
+    }
+
+    /// Returns the number of bytes allocated, and a summary of the free list.
+    pub fn stats(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats {
+            allocated: self.allocated,
+            ..Default::default()
+        };
+
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            stats.free += region.size;
+            stats.free_regions += 1;
+            stats.largest_free_region = stats.largest_free_region.max(region.size);
+            current = region;
         }
+
+        stats
+    }
+
+    /// Sets how free regions are picked for allocations from now on.
+    #[allow(unused)]
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
     }
 
     /// Adds a virtual memory region to the allocator.
+    ///
+    /// Free regions are kept sorted by address, and contiguous regions are merged together.
     pub unsafe fn add_heap_region(&mut self, heap_start: VirtualAddress, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
     }
 
     unsafe fn add_free_region(&mut self, addr: VirtualAddress, size: usize) {
-        assert_eq!(align_up(addr.0, mem::align_of::<ListNode>()), addr.0);
+        debug_assert_eq!(align_up(addr.0, mem::align_of::<ListNode>()), addr.0);
 
         const MIN_SIZE: usize = LIST_NODE_SIZE;
         if unlikely(size < MIN_SIZE) {
@@ -46,20 +89,95 @@ impl LinkedListAllocator {
             return;
         }
 
+        // find the last region which starts before the new one, so the list stays sorted by address
+        let head_ptr = &self.head as *const ListNode;
+        let mut current = &mut self.head;
+        while let Some(ref next) = current.next {
+            if next.start_addr() >= addr.0 {
+                break;
+            }
+
+            current = current.next.as_mut().unwrap();
+        }
+
+        // merge with the following region if it's contiguous
+        let mut size = size;
+        if let Some(next) = current.next.take() {
+            if addr.0 + size == next.start_addr() {
+                size += next.size;
+                current.next = next.next.take();
+            } else {
+                current.next = Some(next);
+            }
+        }
+
+        // merge with the preceding region if it's contiguous (the head is a dummy node, so it can't
+        // be merged into)
+        if !core::ptr::eq(current, head_ptr) && current.end_addr() == addr.0 {
+            current.size += size;
+            return;
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
         let node_ptr = addr.0 as *mut ListNode;
         node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        current.next = Some(&mut *node_ptr)
     }
 
-    /// Finds a free region with the given size and alignment, removes it from the list, and returns
-    /// the list node and its start address.
+    /// Finds a free region with the given size and alignment using the configured strategy,
+    /// removes it from the list, and returns the list node and its start address.
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        match self.strategy {
+            FitStrategy::FirstFit => {
+                self.take_region(|region| Self::alloc_from_region(region, size, align).ok())
+            }
+            FitStrategy::BestFit => {
+                let best = self.best_fit_region(size, align)?;
+                self.take_region(|region| {
+                    Self::alloc_from_region(region, size, align)
+                        .ok()
+                        .filter(|_| region.start_addr() == best)
+                })
+            }
+        }
+    }
+
+    /// Returns the start address of the free region that the allocation fits in with the least
+    /// space left over.
+    fn best_fit_region(&self, size: usize, align: usize) -> Option<usize> {
+        let mut best: Option<(usize, usize)> = None;
+        let mut current = &self.head;
+
+        while let Some(ref region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let excess_size = region.end_addr() - (alloc_start + size);
+                if best.map_or(true, |(_, best_excess)| excess_size < best_excess) {
+                    best = Some((region.start_addr(), excess_size));
+                }
+
+                // nothing can beat a perfect fit
+                if excess_size == 0 {
+                    break;
+                }
+            }
+
+            current = region;
+        }
+
+        best.map(|(start, _)| start)
+    }
+
+    /// Removes the first free region that `alloc_start` returns an allocation start address for
+    /// from the list, and returns the list node and that start address.
+    fn take_region(
+        &mut self,
+        alloc_start: impl Fn(&ListNode) -> Option<usize>,
+    ) -> Option<(&'static mut ListNode, usize)> {
         let mut current = &mut self.head;
 
         while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
+            if let Some(alloc_start) = alloc_start(region) {
                 // we can allocate this region, so remove it from the list
                 let next = region.next.take();
                 let ret = Some((current.next.take().unwrap(), alloc_start));
@@ -77,7 +195,7 @@ impl LinkedListAllocator {
     /// Tries to allocate a region of the given size and alignment from the given region.
     /// Returns the start address of the allocated region if successful.
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_start = checked_align_up(region.start_addr(), align).ok_or(())?;
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -117,6 +235,7 @@ impl LinkedListAllocator {
                 self.add_free_region(VirtualAddress(alloc_end), excess_size);
             }
 
+            self.allocated += size;
             alloc_start as *mut u8
         } else {
             core::ptr::null_mut()
@@ -125,7 +244,72 @@ impl LinkedListAllocator {
 
     pub(crate) unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
         let (size, _) = LinkedListAllocator::size_align(layout);
+        // poisoned before the region is added, so the list node is written over the top
+        poison(ptr, size);
         self.add_free_region(VirtualAddress(ptr as usize), size);
+        self.allocated -= size;
+    }
+
+    /// Tries to resize the allocation at `ptr` to `new_size` bytes without moving it, returning
+    /// whether it was resized.
+    ///
+    /// Shrinking returns the tail to the free list, and growing takes space from the free region
+    /// immediately after the allocation. Either fails if it would leave a sliver of free memory too
+    /// small to hold a [`ListNode`], in which case the caller must move the allocation instead.
+    pub(crate) unsafe fn realloc_in_place(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> bool {
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return false;
+        };
+        let (old_size, _) = LinkedListAllocator::size_align(layout);
+        let (new_size, _) = LinkedListAllocator::size_align(new_layout);
+        let old_end = ptr as usize + old_size;
+        let new_end = ptr as usize + new_size;
+
+        match new_size.cmp(&old_size) {
+            Ordering::Less => {
+                let tail = old_size - new_size;
+                if tail < LIST_NODE_SIZE {
+                    // too small to be a region on its own, but it can still join a free region that
+                    // directly follows the allocation
+                    let Some((next, _)) =
+                    self.take_region(|region| (region.start_addr() == old_end).then_some(old_end))
+                else {
+                    return false;
+                };
+
+                    let size = next.size;
+                    self.add_free_region(VirtualAddress(new_end), tail + size);
+                } else {
+                    self.add_free_region(VirtualAddress(new_end), tail);
+                }
+
+                self.allocated -= tail;
+            }
+            Ordering::Greater => {
+                let needed = new_size - old_size;
+                let Some((next, _)) = self.take_region(|region| {
+                let fits = region.size == needed || region.size >= needed + LIST_NODE_SIZE;
+                (region.start_addr() == old_end && fits).then_some(old_end)
+            }) else {
+                return false;
+            };
+
+                let excess_size = next.size - needed;
+                if excess_size > 0 {
+                    self.add_free_region(VirtualAddress(new_end), excess_size);
+                }
+
+                self.allocated += needed;
+            }
+            Ordering::Equal => {}
+        }
+
+        true
     }
 }
 
@@ -171,3 +355,323 @@ impl LinkedListAllocator {
         (size, layout.align())
     }
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::alloc::{alloc, dealloc, realloc};
+    use core::alloc::Layout;
+    use core::{mem, ptr};
+
+    use super::{FitStrategy, LinkedListAllocator, ListNode, LIST_NODE_SIZE};
+    use crate::mem::allocator::POISON_BYTE;
+    use crate::mem::vm::paging::VirtualAddress;
+    use crate::selftest::SelfTest;
+    use crate::{info, time};
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "linked_list::adjacent regions freed out of order merge",
+            run: adjacent_regions_merge,
+        },
+        SelfTest {
+            name: "linked_list::realloc_in_place shrinks",
+            run: realloc_in_place_shrinks,
+        },
+        SelfTest {
+            name: "linked_list::realloc_in_place shrinks by less than a list node",
+            run: realloc_in_place_shrinks_by_a_sliver,
+        },
+        SelfTest {
+            name: "linked_list::realloc_in_place grows",
+            run: realloc_in_place_grows,
+        },
+        SelfTest {
+            name: "linked_list::realloc moves when it can't grow in place",
+            run: realloc_moves,
+        },
+        SelfTest {
+            name: "linked_list::freed blocks are poisoned in debug builds",
+            run: freed_blocks_are_poisoned,
+        },
+        SelfTest {
+            name: "linked_list::fragmentation of best fit and first fit",
+            run: compare_fit_strategies,
+        },
+    ];
+
+    const ARENA_SIZE: usize = 16 * 1024;
+
+    /// Memory for the allocators under test to manage, so they don't touch the kernel heap.
+    #[repr(align(16))]
+    struct Arena([u8; ARENA_SIZE]);
+
+    static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+    /// Returns the start of the arena. Only one test uses it at a time.
+    fn arena() -> usize {
+        // Safe because only the address is taken, and the tests run one after the other.
+        unsafe { ptr::addr_of_mut!(ARENA) as usize }
+    }
+
+    fn adjacent_regions_merge() {
+        const BLOCK: usize = 4 * LIST_NODE_SIZE;
+        let base = arena();
+
+        let mut allocator = LinkedListAllocator::new();
+        // Safe because the arena isn't used by anything else.
+        unsafe {
+            allocator.add_free_region(VirtualAddress(base + 2 * BLOCK), BLOCK);
+            allocator.add_free_region(VirtualAddress(base), BLOCK);
+            allocator.add_free_region(VirtualAddress(base + BLOCK), BLOCK);
+        }
+
+        let stats = allocator.stats();
+        assert_eq!(stats.free_regions, 1);
+        assert_eq!(stats.free, 3 * BLOCK);
+        let node = allocator.head.next.as_ref().unwrap();
+        assert_eq!(node.start_addr(), base);
+        assert_eq!(node.size, 3 * BLOCK);
+    }
+
+    /// Returns an allocator managing the whole arena.
+    fn arena_allocator() -> LinkedListAllocator {
+        let mut allocator = LinkedListAllocator::new();
+        // Safe because the arena isn't used by anything else.
+        unsafe { allocator.add_heap_region(VirtualAddress(arena()), ARENA_SIZE) };
+        allocator
+    }
+
+    /// Returns a layout that the allocator won't pad, so sizes can be reasoned about exactly.
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, mem::align_of::<ListNode>()).unwrap()
+    }
+
+    fn realloc_in_place_shrinks() {
+        let mut allocator = arena_allocator();
+        let size = 8 * LIST_NODE_SIZE;
+
+        // Safe because every pointer came from this allocator, with the same layout.
+        unsafe {
+            let a = allocator.alloc(layout(size));
+            let b = allocator.alloc(layout(size));
+            assert_eq!(b as usize, a as usize + size);
+
+            // the tail is freed as a region of its own, between the two allocations
+            assert!(allocator.realloc_in_place(a, layout(size), size / 2));
+            let stats = allocator.stats();
+            assert_eq!(stats.allocated, size + size / 2);
+            assert_eq!(stats.free_regions, 2);
+            assert_eq!(stats.free, ARENA_SIZE - stats.allocated);
+
+            allocator.dealloc(a, layout(size / 2));
+            allocator.dealloc(b, layout(size));
+        }
+        assert_eq!(allocator.stats().free_regions, 1);
+    }
+
+    fn realloc_in_place_shrinks_by_a_sliver() {
+        let mut allocator = arena_allocator();
+        let size = 8 * LIST_NODE_SIZE;
+        let smaller = size - LIST_NODE_SIZE / 2;
+
+        // Safe because every pointer came from this allocator, with the same layout.
+        unsafe {
+            // with free memory after it, the sliver joins the free region
+            let a = allocator.alloc(layout(size));
+            assert!(allocator.realloc_in_place(a, layout(size), smaller));
+            let stats = allocator.stats();
+            assert_eq!(stats.allocated, smaller);
+            assert_eq!(stats.free_regions, 1);
+            assert_eq!(stats.free, ARENA_SIZE - smaller);
+            allocator.dealloc(a, layout(smaller));
+
+            // with another allocation right after it, the sliver has nowhere to go
+            let a = allocator.alloc(layout(size));
+            let b = allocator.alloc(layout(ARENA_SIZE - size));
+            assert!(!b.is_null());
+            assert!(!allocator.realloc_in_place(a, layout(size), smaller));
+            assert_eq!(allocator.stats().allocated, ARENA_SIZE);
+
+            allocator.dealloc(a, layout(size));
+            allocator.dealloc(b, layout(ARENA_SIZE - size));
+        }
+        assert_eq!(allocator.stats().free, ARENA_SIZE);
+    }
+
+    fn realloc_in_place_grows() {
+        let mut allocator = arena_allocator();
+        let size = 8 * LIST_NODE_SIZE;
+
+        // Safe because every pointer came from this allocator, with the same layout.
+        unsafe {
+            let a = allocator.alloc(layout(size));
+            assert!(allocator.realloc_in_place(a, layout(size), 2 * size));
+            assert_eq!(allocator.stats().allocated, 2 * size);
+
+            // it can't grow into another allocation, nor leave a sliver before the free region
+            let b = allocator.alloc(layout(size));
+            assert_eq!(b as usize, a as usize + 2 * size);
+            assert!(!allocator.realloc_in_place(a, layout(2 * size), 3 * size));
+            assert!(!allocator.realloc_in_place(
+                b,
+                layout(size),
+                ARENA_SIZE - 2 * size - LIST_NODE_SIZE / 2
+            ));
+            assert_eq!(allocator.stats().allocated, 3 * size);
+
+            // but it can take the whole free region
+            assert!(allocator.realloc_in_place(b, layout(size), ARENA_SIZE - 2 * size));
+            assert_eq!(allocator.stats().free_regions, 0);
+
+            allocator.dealloc(a, layout(2 * size));
+            allocator.dealloc(b, layout(ARENA_SIZE - 2 * size));
+        }
+        assert_eq!(allocator.stats().free_regions, 1);
+    }
+
+    fn realloc_moves() {
+        let size = 8 * LIST_NODE_SIZE;
+
+        // Safe because every pointer came from the kernel heap, with the same layout.
+        unsafe {
+            let a = alloc(layout(size));
+            let b = alloc(layout(size));
+            for i in 0..size {
+                a.add(i).write(i as u8);
+            }
+
+            // a can only grow in place if b isn't in the way
+            let grown = realloc(a, layout(size), 4 * size);
+            assert!(!grown.is_null());
+            if b as usize == a as usize + size {
+                assert_ne!(grown, a);
+            }
+            for i in 0..size {
+                assert_eq!(grown.add(i).read(), i as u8);
+            }
+
+            dealloc(grown, layout(4 * size));
+            dealloc(b, layout(size));
+        }
+    }
+
+    fn freed_blocks_are_poisoned() {
+        const FILL: u8 = 0x5a;
+        let mut allocator = arena_allocator();
+        let size = 8 * LIST_NODE_SIZE;
+        let expected = if cfg!(debug_assertions) {
+            POISON_BYTE
+        } else {
+            FILL
+        };
+
+        // Safe because every pointer came from this allocator, with the same layout, and the freed
+        // block is only read while it's still part of the arena.
+        unsafe {
+            let a = allocator.alloc(layout(size));
+            // keeps a's block from merging with the rest of the free memory
+            let b = allocator.alloc(layout(size));
+            ptr::write_bytes(a, FILL, size);
+
+            // everything after the list node written at the start of the block is poisoned
+            allocator.dealloc(a, layout(size));
+            for i in LIST_NODE_SIZE..size {
+                assert_eq!(a.add(i).read(), expected, "byte {} of a freed block", i);
+            }
+
+            // the block can still be handed out and used again
+            let c = allocator.alloc(layout(size));
+            assert_eq!(c, a);
+            ptr::write_bytes(c, FILL, size);
+            assert_eq!(c.add(size - 1).read(), FILL);
+
+            allocator.dealloc(c, layout(size));
+            allocator.dealloc(b, layout(size));
+        }
+        assert_eq!(allocator.stats().free, ARENA_SIZE);
+    }
+
+    /// The outcome of running the fragmentation workload with one strategy.
+    struct Fragmentation {
+        failed: usize,
+        free: usize,
+        free_regions: usize,
+        largest_free_region: usize,
+        nanos: u64,
+    }
+
+    /// Allocates and frees a pseudo-random mix of small sizes, with more live at once than fit in
+    /// the arena, and reports how fragmented the free list ends up.
+    fn fragment(strategy: FitStrategy) -> Fragmentation {
+        const SLOTS: usize = 64;
+        const STEPS: usize = 4000;
+
+        let mut allocator = arena_allocator();
+        allocator.set_strategy(strategy);
+        let mut live = [None; SLOTS];
+        let mut failed = 0;
+
+        // the same sequence for both strategies, from a fixed seed
+        let mut seed: u32 = 0x2019;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as usize
+        };
+
+        let start = time::now_ticks();
+        // Safe because every pointer came from this allocator, with the same layout.
+        unsafe {
+            for _ in 0..STEPS {
+                let slot = next() % SLOTS;
+                match live[slot].take() {
+                    Some((ptr, size)) => allocator.dealloc(ptr, layout(size)),
+                    None => {
+                        let size = LIST_NODE_SIZE * (1 + next() % 32);
+                        let ptr = allocator.alloc(layout(size));
+                        if ptr.is_null() {
+                            failed += 1;
+                        } else {
+                            live[slot] = Some((ptr, size));
+                        }
+                    }
+                }
+            }
+        }
+        let nanos = time::ticks_to_nanos(time::now_ticks() - start);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.allocated + stats.free, ARENA_SIZE);
+
+        // everything freed has to come back together again
+        // Safe because every pointer came from this allocator, with the same layout.
+        unsafe {
+            for (ptr, size) in live.into_iter().flatten() {
+                allocator.dealloc(ptr, layout(size));
+            }
+        }
+        assert_eq!(allocator.stats().free_regions, 1);
+
+        Fragmentation {
+            failed,
+            free: stats.free,
+            free_regions: stats.free_regions,
+            largest_free_region: stats.largest_free_region,
+            nanos,
+        }
+    }
+
+    fn compare_fit_strategies() {
+        for strategy in [FitStrategy::FirstFit, FitStrategy::BestFit] {
+            let result = fragment(strategy);
+            info!(
+                "    {:?}: {} failed allocations, {} bytes free in {} regions, largest {} bytes, {} us",
+                strategy,
+                result.failed,
+                result.free,
+                result.free_regions,
+                result.largest_free_region,
+                result.nanos / 1000
+            );
+        }
+    }
+}
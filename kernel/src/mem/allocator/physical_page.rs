@@ -1,9 +1,9 @@
 // SPDX-License-Identifier: MIT
 
 use core::intrinsics::unlikely;
-use core::mem;
+use core::{mem, ptr};
 
-use crate::mem::allocator::align_up;
+use crate::mem::allocator::{align_up, checked_align_up, poison, AllocatorStats};
 use crate::mem::direct_map_virt_offset;
 use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress, PAGE_SIZE};
 
@@ -12,6 +12,7 @@ use crate::mem::vm::paging::{PhysicalAddress, VirtualAddress, PAGE_SIZE};
 //--------------------------------------------------------------------------------------------------
 pub struct PhysicalPageAllocator {
     head: ListNode,
+    allocated: usize,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -21,44 +22,145 @@ impl PhysicalPageAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            allocated: 0,
         }
     }
 
+    /// Returns the number of bytes allocated, and a summary of the free list.
+    pub fn stats(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats {
+            allocated: self.allocated,
+            ..Default::default()
+        };
+
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            stats.free += region.size;
+            stats.free_regions += 1;
+            stats.largest_free_region = stats.largest_free_region.max(region.size);
+            current = region;
+        }
+
+        stats
+    }
+
     /// Adds a physical memory region to the allocator.
+    ///
+    /// Free regions are kept sorted by address, and contiguous regions are merged together.
     pub unsafe fn add_heap_region(&mut self, heap_start: PhysicalAddress, heap_size: usize) {
         self.add_free_region(heap_start.into(), heap_size);
     }
 
+    /// Returns a previously allocated physical region to the allocator.
+    /// Panics if any part of the region is already free, as that indicates a double free.
+    ///
+    /// # Safety
+    ///
+    /// The region must have been returned by `allocate`, and must no longer be in use.
+    pub unsafe fn deallocate(&mut self, addr: PhysicalAddress, size: usize) {
+        let addr: VirtualAddress = addr.into();
+        // poisoned before the region is added, so the list node is written over the top
+        poison(addr.0 as *mut u8, size);
+        self.add_free_region(addr, size);
+        self.allocated -= size;
+    }
+
     /// Adds a direct-mapped virtual address to the physical allocator.
     unsafe fn add_free_region(&mut self, addr: VirtualAddress, size: usize) {
-        assert_eq!(align_up(addr.0, mem::align_of::<ListNode>()), addr.0);
-        assert!(size >= mem::size_of::<ListNode>());
+        debug_assert_eq!(align_up(addr.0, mem::align_of::<ListNode>()), addr.0);
+        debug_assert!(size >= mem::size_of::<ListNode>());
 
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        // find the last region which starts before the new one, so the list stays sorted by address
+        let head_ptr = &self.head as *const ListNode;
+        let mut current = &mut self.head;
+        while let Some(ref next) = current.next {
+            if next.start_addr() >= addr.0 {
+                break;
+            }
+
+            current = current.next.as_mut().unwrap();
+        }
+
+        // a region overlapping one that's already free means it was freed twice
+        let is_head = ptr::eq(current, head_ptr);
+        let overlaps_previous = !is_head && current.end_addr() > addr.0;
+        let overlaps_next =
+            matches!(current.next, Some(ref next) if next.start_addr() < addr.0 + size);
+        if unlikely(overlaps_previous || overlaps_next) {
+            panic!(
+                "double free of physical memory: {} ({} bytes)",
+                addr.from_direct_map(),
+                size
+            );
+        }
+
+        // merge with the following region if it's contiguous
+        let mut size = size;
+        if let Some(next) = current.next.take() {
+            if addr.0 + size == next.start_addr() {
+                size += next.size;
+                current.next = next.next.take();
+            } else {
+                current.next = Some(next);
+            }
+        }
 
+        // merge with the preceding region if it's contiguous (the head is a dummy node, so it can't
+        // be merged into)
+        if !is_head && current.end_addr() == addr.0 {
+            current.size += size;
+            return;
+        }
+
+        let mut node = ListNode::new(size);
+        node.next = current.next.take();
         let node_ptr = addr.0 as *mut ListNode;
         node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        current.next = Some(&mut *node_ptr)
     }
 
     /// Finds a free region with the given size, removes it from the list, and returns
     /// its start physical address from the direct-map.
     pub fn allocate(&mut self, size: usize) -> Option<PhysicalAddress> {
-        self.find_region(size)
-            .map(|alloc_start| PhysicalAddress(alloc_start.0 - direct_map_virt_offset()))
+        self.allocate_aligned(size, PAGE_SIZE)
+    }
+
+    /// Like [`allocate`](Self::allocate), but the region starts at a multiple of `align`, which
+    /// must be a power of two no smaller than the page size.
+    pub fn allocate_aligned(&mut self, size: usize, align: usize) -> Option<PhysicalAddress> {
+        let alloc_start = self.find_region(size, align)?;
+        self.allocated += size;
+        Some(alloc_start.from_direct_map())
     }
 
     /// Finds a free region with the given size and alignment, removes it from the list, and returns
     /// the list node and its start address.
-    fn find_region(&mut self, size: usize) -> Option<VirtualAddress> {
+    fn find_region(&mut self, size: usize, align: usize) -> Option<VirtualAddress> {
         let mut current = &mut self.head;
 
         while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size) {
+            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
                 // we can allocate this region, so remove it from the list
+                let region_start = region.start_addr();
+                let region_end = region.end_addr();
                 let next = region.next.take();
                 current.next = next;
+
+                // return whatever is left of the region on either side of the allocation
+                let alloc_end = alloc_start + size;
+                unsafe {
+                    if alloc_end < region_end {
+                        self.add_free_region(VirtualAddress(alloc_end), region_end - alloc_end);
+                    }
+
+                    if alloc_start - region_start >= mem::size_of::<ListNode>() {
+                        self.add_free_region(
+                            VirtualAddress(region_start),
+                            alloc_start - region_start,
+                        );
+                    }
+                }
+
                 return Some(VirtualAddress(alloc_start));
             } else {
                 // try the next region
@@ -75,8 +177,11 @@ impl PhysicalPageAllocator {
     /// # Safety
     ///
     /// Assumes the input size is a multiple of the page size.
-    fn alloc_from_region(region: &ListNode, size: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), PAGE_SIZE);
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        // the direct map offset is at least page aligned, but may not be aligned to `align`, so the
+        // alignment has to be applied to the physical address
+        let offset = direct_map_virt_offset();
+        let alloc_start = checked_align_up(region.start_addr() - offset, align).ok_or(())? + offset;
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: MIT
+//! A slab allocator for small, fixed-size kernel objects.
+//!
+//! Each slab is a single page: a header at the start, followed by as many slots for `T` as
+//! fit. Free slots are threaded into a list through the slots themselves, so allocating and
+//! freeing are both O(1). The slab a slot belongs to is found by rounding its address down to the
+//! page boundary.
+
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+use crate::mem::allocator::align_up;
+use crate::mem::vm::paging::{VirtualAddress, PAGE_SIZE};
+use crate::mem::{virtual_memory_manager, MemoryManager, OutOfMemory};
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A cache of page-sized slabs, each holding a fixed number of `T`s.
+pub struct SlabCache<T> {
+    /// Slabs with at least one free slot. Full slabs aren't kept on any list; they're put back on
+    /// this one when a slot in them is freed.
+    partial: Option<NonNull<SlabHeader>>,
+    slabs: usize,
+    allocated: usize,
+    _marker: PhantomData<T>,
+}
+
+/// An owned `T` allocated from a [`SlabCache`], which is dropped and returned to the cache when
+/// the box goes out of scope.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static IRQSafeNullLock<SlabCache<T>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+unsafe impl<T> Send for SlabCache<T> where T: Send {}
+
+unsafe impl<T> Send for SlabBox<T> where T: Send {}
+unsafe impl<T> Sync for SlabBox<T> where T: Sync {}
+
+#[allow(dead_code)]
+impl<T> SlabCache<T> {
+    /// The size of each slot, which is big enough to hold either a `T` or a free list link.
+    pub const SLOT_SIZE: usize = align_up(
+        max(mem::size_of::<T>(), mem::size_of::<FreeSlot>()),
+        Self::SLOT_ALIGN,
+    );
+
+    /// The number of slots in each slab.
+    pub const SLOTS_PER_SLAB: usize = (PAGE_SIZE - Self::FIRST_SLOT_OFFSET) / Self::SLOT_SIZE;
+
+    pub const fn new() -> Self {
+        Self {
+            partial: None,
+            slabs: 0,
+            allocated: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns uninitialised memory for a `T`, growing the cache by another slab if every slot is
+    /// in use. Returns `None` if the slab can't be allocated.
+    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+        let mut slab = match self.partial {
+            Some(slab) => slab,
+            None => self.grow()?,
+        };
+
+        // Safe because slabs on the partial list are ours, and always have a free slot.
+        unsafe {
+            let header = slab.as_mut();
+            let slot = header.free.expect("Partial slab has no free slots");
+            header.free = slot.as_ref().next;
+            header.in_use += 1;
+
+            if header.free.is_none() {
+                self.partial = header.next.take();
+            }
+
+            self.allocated += 1;
+            Some(slot.cast())
+        }
+    }
+
+    /// Returns a slot to the cache. The value in it isn't dropped.
+    ///
+    /// # Safety
+    ///
+    /// - `ptr` must have been returned by [`alloc`](Self::alloc) on this cache, and not freed since.
+    /// - Any value in the slot must already have been dropped or moved out.
+    pub unsafe fn free(&mut self, ptr: NonNull<T>) {
+        let mut slab = Self::slab_of(ptr);
+        let header = slab.as_mut();
+
+        // a full slab isn't on the partial list, so it needs to go back on
+        if header.free.is_none() {
+            header.next = self.partial;
+            self.partial = Some(slab);
+        }
+
+        let slot = ptr.cast::<FreeSlot>();
+        slot.as_ptr().write(FreeSlot { next: header.free });
+        header.free = Some(slot);
+        header.in_use -= 1;
+        self.allocated -= 1;
+    }
+
+    /// Returns every slab with no slots in use to the memory manager.
+    ///
+    /// Unlike allocating and freeing, this walks the whole partial list.
+    ///
+    /// Returns the number of slabs released.
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        let mut link = &mut self.partial as *mut Option<NonNull<SlabHeader>>;
+
+        // Safe because every slab on the partial list is ours, and unlinked before it's freed.
+        unsafe {
+            while let Some(slab) = *link {
+                let header = &mut *slab.as_ptr();
+                if header.in_use != 0 {
+                    link = &mut header.next;
+                    continue;
+                }
+
+                *link = header.next;
+                virtual_memory_manager()
+                    .kernel_free(VirtualAddress(slab.as_ptr() as usize), PAGE_SIZE);
+                released += 1;
+            }
+        }
+
+        self.slabs -= released;
+        released
+    }
+
+    /// Returns the number of slots currently handed out.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Returns the number of slabs, and so pages, the cache holds.
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+}
+
+#[allow(dead_code)]
+impl<T> SlabBox<T> {
+    /// Moves `value` into a slot allocated from `cache`. If the cache can't grow, the kernel will
+    /// panic.
+    pub fn new(cache: &'static IRQSafeNullLock<SlabCache<T>>, value: T) -> Self {
+        Self::try_new(cache, value).expect("out of memory growing a slab cache")
+    }
+
+    /// Like [`new`](Self::new), but returns [`OutOfMemory`] if the cache can't grow.
+    pub fn try_new(
+        cache: &'static IRQSafeNullLock<SlabCache<T>>,
+        value: T,
+    ) -> Result<Self, OutOfMemory> {
+        let ptr = cache.lock(|cache| cache.alloc()).ok_or(OutOfMemory)?;
+
+        // Safe because the slot was just allocated for a `T`, and isn't aliased.
+        unsafe { ptr.as_ptr().write(value) };
+
+        Ok(Self { ptr, cache })
+    }
+
+    /// Moves the value out of the box, returning its slot to the cache.
+    pub fn into_inner(this: Self) -> T {
+        let this = mem::ManuallyDrop::new(this);
+
+        // Safe because the value is read exactly once, and the box isn't dropped.
+        unsafe {
+            let value = this.ptr.as_ptr().read();
+            this.cache.lock(|cache| cache.free(this.ptr));
+            value
+        }
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safe because the box owns an initialised `T`.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safe because the box owns an initialised `T`, and we have it mutably.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        // Safe because the box owns the value and its slot, and neither is used again.
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.lock(|cache| cache.free(self.ptr));
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// Sits at the start of every slab.
+struct SlabHeader {
+    /// The next slab on the partial list.
+    next: Option<NonNull<SlabHeader>>,
+    /// The first free slot in this slab.
+    free: Option<NonNull<FreeSlot>>,
+    /// The number of slots handed out from this slab.
+    in_use: usize,
+}
+
+/// The contents of a slot while it's free.
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeSlot>());
+
+    const FIRST_SLOT_OFFSET: usize = align_up(mem::size_of::<SlabHeader>(), Self::SLOT_ALIGN);
+
+    /// Allocates a new slab, threads all of its slots into its free list, and puts it on the
+    /// partial list.
+    fn grow(&mut self) -> Option<NonNull<SlabHeader>> {
+        assert!(
+            Self::SLOTS_PER_SLAB > 0,
+            "Slab object doesn't fit in a single page"
+        );
+
+        let (start, _) = virtual_memory_manager().kernel_alloc(PAGE_SIZE).ok()?;
+        assert_eq!(start.0 % PAGE_SIZE, 0, "Slab isn't page aligned");
+
+        // Safe because the page was just allocated for us, and is large enough for the header and
+        // every slot.
+        unsafe {
+            let mut free = None;
+            for i in (0..Self::SLOTS_PER_SLAB).rev() {
+                let slot =
+                    (start.0 + Self::FIRST_SLOT_OFFSET + i * Self::SLOT_SIZE) as *mut FreeSlot;
+                slot.write(FreeSlot { next: free });
+                free = Some(NonNull::new_unchecked(slot));
+            }
+
+            let header = start.0 as *mut SlabHeader;
+            header.write(SlabHeader {
+                next: self.partial,
+                free,
+                in_use: 0,
+            });
+
+            let slab = NonNull::new_unchecked(header);
+            self.partial = Some(slab);
+            self.slabs += 1;
+            Some(slab)
+        }
+    }
+
+    /// Returns the header of the slab that `ptr` was allocated from.
+    fn slab_of(ptr: NonNull<T>) -> NonNull<SlabHeader> {
+        let start = ptr.as_ptr() as usize & !(PAGE_SIZE - 1);
+
+        // Safe because slots never start at the beginning of a page, so `ptr` is above `start`.
+        unsafe { NonNull::new_unchecked(start as *mut SlabHeader) }
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::vec::Vec;
+    use core::mem;
+
+    use super::SlabCache;
+    use crate::mem::vm::paging::PAGE_SIZE;
+    use crate::selftest::SelfTest;
+
+    pub const TESTS: &[SelfTest] = &[SelfTest {
+        name: "slab::small objects are packed into few pages",
+        run: small_objects_are_packed,
+    }];
+
+    type Object = [u8; 48];
+
+    fn small_objects_are_packed() {
+        const COUNT: usize = 4096;
+        let mut cache = SlabCache::<Object>::new();
+        let mut objects = Vec::with_capacity(COUNT);
+
+        for i in 0..COUNT {
+            let ptr = cache.alloc().expect("out of memory filling the slab cache");
+            // Safe because the slot was just allocated, and is big enough for an Object.
+            unsafe { ptr.as_ptr().write([i as u8; 48]) };
+            objects.push(ptr);
+        }
+
+        // only the header and the leftover at the end of each page are wasted, which adds up to
+        // less than a page over the lot
+        let pages = COUNT.div_ceil(SlabCache::<Object>::SLOTS_PER_SLAB);
+        assert_eq!(SlabCache::<Object>::SLOT_SIZE, mem::size_of::<Object>());
+        assert_eq!(cache.slabs(), pages);
+        assert!(pages <= COUNT * mem::size_of::<Object>() / PAGE_SIZE + 1);
+        assert_eq!(cache.allocated(), COUNT);
+
+        // no slot was handed out twice
+        for (i, ptr) in objects.iter().enumerate() {
+            // Safe because every object was written above, and is still allocated.
+            assert_eq!(unsafe { ptr.as_ptr().read() }, [i as u8; 48]);
+        }
+
+        for ptr in objects {
+            // Safe because every object came from this cache, and Object needs no dropping.
+            unsafe { cache.free(ptr) };
+        }
+        assert_eq!(cache.allocated(), 0);
+        assert_eq!(cache.shrink(), pages);
+        assert_eq!(cache.slabs(), 0);
+    }
+}
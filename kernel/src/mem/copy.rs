@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT
+//! Bulk memory copies for loading data into process memory.
+
+use core::ptr;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The size of a cache line on the cores we run on.
+pub const CACHE_LINE_SIZE: usize = 64;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Copies `len` bytes from `src` to `dst`, in whole cache lines where possible.
+///
+/// The destination is first brought up to a cache line boundary, after which the bulk of the data
+/// is copied a cache line at a time, using word-wide accesses. The source may have any alignment.
+/// Anything left over at the end is copied a word, then a byte, at a time.
+///
+/// This only copies data; callers loading executable code must still call
+/// [`sync_icache`](crate::mem::sync_icache) over it before the code is run.
+///
+/// # Safety
+///
+/// - `src` must be valid for reads of `len` bytes, and `dst` valid for writes of `len` bytes.
+/// - The two regions must not overlap.
+pub unsafe fn fast_copy(dst: *mut u8, src: *const u8, len: usize) {
+    let mut dst = dst;
+    let mut src = src;
+    let mut remaining = len;
+
+    // bytes, until the destination is word aligned
+    while remaining > 0 && dst as usize % WORD_SIZE != 0 {
+        copy_byte(&mut dst, &mut src, &mut remaining);
+    }
+
+    // words, until the destination is cache line aligned
+    while remaining >= WORD_SIZE && dst as usize % CACHE_LINE_SIZE != 0 {
+        copy_word(&mut dst, &mut src, &mut remaining);
+    }
+
+    // whole cache lines
+    while remaining >= CACHE_LINE_SIZE {
+        let src_words = src as *const u64;
+        let dst_words = dst as *mut u64;
+        for i in 0..CACHE_LINE_SIZE / WORD_SIZE {
+            ptr::write(dst_words.add(i), ptr::read_unaligned(src_words.add(i)));
+        }
+
+        dst = dst.add(CACHE_LINE_SIZE);
+        src = src.add(CACHE_LINE_SIZE);
+        remaining -= CACHE_LINE_SIZE;
+    }
+
+    // tail
+    while remaining >= WORD_SIZE {
+        copy_word(&mut dst, &mut src, &mut remaining);
+    }
+
+    while remaining > 0 {
+        copy_byte(&mut dst, &mut src, &mut remaining);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+const WORD_SIZE: usize = core::mem::size_of::<u64>();
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+#[inline(always)]
+unsafe fn copy_byte(dst: &mut *mut u8, src: &mut *const u8, remaining: &mut usize) {
+    ptr::write(*dst, ptr::read(*src));
+    *dst = dst.add(1);
+    *src = src.add(1);
+    *remaining -= 1;
+}
+
+/// Copies one word to a word-aligned destination, from a source of any alignment.
+#[inline(always)]
+unsafe fn copy_word(dst: &mut *mut u8, src: &mut *const u8, remaining: &mut usize) {
+    ptr::write(*dst as *mut u64, ptr::read_unaligned(*src as *const u64));
+    *dst = dst.add(WORD_SIZE);
+    *src = src.add(WORD_SIZE);
+    *remaining -= WORD_SIZE;
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::{fast_copy, CACHE_LINE_SIZE, WORD_SIZE};
+    use crate::selftest::SelfTest;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "copy::small copies at every misalignment",
+            run: small_copies,
+        },
+        SelfTest {
+            name: "copy::large copies at every misalignment",
+            run: large_copies,
+        },
+    ];
+
+    /// The byte the destination is filled with, so writes outside the copy can be spotted.
+    const GUARD: u8 = 0xa5;
+
+    /// Sizes spanning several cache lines, with and without a partial line and word at the end.
+    const LARGE_SIZES: [usize; 4] = [1024, 4096 + 3, 8192 + WORD_SIZE, 16384 + 123];
+
+    /// A source and destination buffer big enough for copies of up to `max_len` bytes at any
+    /// misalignment from a cache line boundary.
+    struct Buffers {
+        src: Vec<u8>,
+        dst: Vec<u8>,
+    }
+
+    impl Buffers {
+        fn new(max_len: usize) -> Self {
+            // room to align the start to a cache line, misalign it by up to a word, and leave a
+            // guard after the end
+            let len = max_len + CACHE_LINE_SIZE + 2 * WORD_SIZE;
+            Self {
+                src: vec![0; len],
+                dst: vec![0; len],
+            }
+        }
+
+        /// Copies `len` bytes with `fast_copy`, from `src_offset` and to `dst_offset` bytes past a
+        /// cache line boundary, and checks that exactly those bytes were copied.
+        fn check(&mut self, len: usize, src_offset: usize, dst_offset: usize) {
+            let src_start = self.src.as_ptr().align_offset(CACHE_LINE_SIZE) + src_offset;
+            let dst_start = self.dst.as_ptr().align_offset(CACHE_LINE_SIZE) + dst_offset;
+            for (i, byte) in self.src.iter_mut().enumerate() {
+                *byte = (i * 7 + len) as u8;
+            }
+            self.dst.fill(GUARD);
+
+            // Safe because both buffers have room for `len` bytes past their starts, and are
+            // separate allocations.
+            unsafe {
+                fast_copy(
+                    self.dst.as_mut_ptr().add(dst_start),
+                    self.src.as_ptr().add(src_start),
+                    len,
+                );
+            }
+
+            let dst_end = dst_start + len;
+            assert!(
+                self.dst[dst_start..dst_end] == self.src[src_start..src_start + len],
+                "copy of {} bytes from offset {} to offset {} differs",
+                len,
+                src_offset,
+                dst_offset
+            );
+            assert!(
+                self.dst[..dst_start].iter().all(|&b| b == GUARD)
+                    && self.dst[dst_end..].iter().all(|&b| b == GUARD),
+                "copy of {} bytes from offset {} to offset {} wrote outside the destination",
+                len,
+                src_offset,
+                dst_offset
+            );
+        }
+    }
+
+    fn small_copies() {
+        let mut buffers = Buffers::new(2 * CACHE_LINE_SIZE);
+        for len in 0..=2 * CACHE_LINE_SIZE {
+            for src_offset in 0..WORD_SIZE {
+                for dst_offset in 0..WORD_SIZE {
+                    buffers.check(len, src_offset, dst_offset);
+                }
+            }
+        }
+    }
+
+    fn large_copies() {
+        let mut buffers = Buffers::new(LARGE_SIZES[LARGE_SIZES.len() - 1]);
+        for len in LARGE_SIZES {
+            for src_offset in 0..WORD_SIZE {
+                for dst_offset in 0..WORD_SIZE {
+                    buffers.check(len, src_offset, dst_offset);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: MIT
+//! Physical pages shared between address spaces, e.g. for IPC.
+
+use alloc::boxed::Box;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::mem::vm::paging::{PhysicalAddress, PAGE_SIZE};
+use crate::mem::{virtual_memory_manager, MemoryManager, OutOfMemory};
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A physical page which can be mapped into several address spaces at once, with
+/// [`RootPageTable::map_shared`](crate::mem::vm::paging::RootPageTable::map_shared).
+///
+/// The page is reference counted: every handle holds a reference, as does every mapping of it. It's
+/// only freed once the last handle has been dropped and every mapping has been unmapped.
+pub struct SharedPage {
+    inner: NonNull<SharedPageInner>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+unsafe impl Send for SharedPage {}
+unsafe impl Sync for SharedPage {}
+
+#[allow(unused)]
+impl SharedPage {
+    /// Allocates a new zeroed page, or returns [`OutOfMemory`] if there's no physical memory left.
+    pub fn new() -> Result<Self, OutOfMemory> {
+        let (pa, dm, _) = virtual_memory_manager().process_alloc(PAGE_SIZE)?;
+        // Safe because the page was just allocated, and isn't mapped anywhere else yet.
+        unsafe {
+            core::ptr::write_bytes(dm.0 as *mut u8, 0, PAGE_SIZE);
+        }
+
+        let inner = Box::new(SharedPageInner {
+            pa,
+            refs: AtomicUsize::new(1),
+        });
+
+        Ok(Self {
+            inner: NonNull::from(Box::leak(inner)),
+        })
+    }
+
+    /// Returns the physical address of the page.
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.inner().pa
+    }
+
+    /// Returns the number of handles and mappings referring to the page.
+    pub fn ref_count(&self) -> usize {
+        self.inner().refs.load(Ordering::Relaxed)
+    }
+
+    /// Adds a reference to the page, for a new mapping of it.
+    pub(crate) fn acquire(&self) {
+        self.inner().refs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drops a reference to the page, for a mapping of it that's been removed.
+    ///
+    /// The page is never freed here, since `self` still holds a reference.
+    pub(crate) fn release(&self) {
+        let previous = self.inner().refs.fetch_sub(1, Ordering::Release);
+        debug_assert!(
+            previous > 1,
+            "shared page released more times than acquired"
+        );
+    }
+}
+
+impl Clone for SharedPage {
+    fn clone(&self) -> Self {
+        self.acquire();
+        Self { inner: self.inner }
+    }
+}
+
+impl Drop for SharedPage {
+    fn drop(&mut self) {
+        if self.inner().refs.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // make sure every other user of the page is done with it before it's freed
+        fence(Ordering::Acquire);
+
+        // Safe because this was the last reference, so nothing maps or refers to the page anymore.
+        unsafe {
+            let inner = Box::from_raw(self.inner.as_ptr());
+            virtual_memory_manager().process_free(inner.pa, PAGE_SIZE);
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+struct SharedPageInner {
+    pa: PhysicalAddress,
+    refs: AtomicUsize,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl SharedPage {
+    fn inner(&self) -> &SharedPageInner {
+        // Safe because the inner data lives until the last handle is dropped.
+        unsafe { self.inner.as_ref() }
+    }
+}
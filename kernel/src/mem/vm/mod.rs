@@ -11,7 +11,10 @@ use core::fmt::{self, Display, Formatter};
 
 use paging::{VirtualAddress, VirtualMemoryRegion};
 
+use crate::mem::OutOfMemory;
+
 pub mod paging;
+pub mod translation;
 
 /// An error attempting to map some range in the page table.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -21,6 +24,14 @@ pub enum MapError {
     AddressRange(VirtualAddress),
     /// The end of the memory region is before the start.
     RegionBackwards(VirtualMemoryRegion),
+    /// A mapping of zero bytes was requested.
+    EmptyRegion,
+    /// There's no unmapped region of this many bytes left in the page table.
+    NoSpace(usize),
+    /// The address isn't mapped to what was expected.
+    NotMapped(VirtualAddress),
+    /// There's no physical memory left to back the mapping.
+    OutOfMemory,
 }
 
 impl Display for MapError {
@@ -30,10 +41,20 @@ impl Display for MapError {
             Self::RegionBackwards(region) => {
                 write!(f, "End of memory region {} is before start.", region)
             }
+            Self::EmptyRegion => write!(f, "Memory region is empty"),
+            Self::NoSpace(len) => write!(f, "No unmapped region of {} bytes available", len),
+            Self::NotMapped(va) => write!(f, "Virtual address {} is not mapped as expected", va),
+            Self::OutOfMemory => write!(f, "Out of memory"),
         }
     }
 }
 
+impl From<OutOfMemory> for MapError {
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
+
 //--------------------------------------------------------------------------------------------------
 // Public code
 //--------------------------------------------------------------------------------------------------
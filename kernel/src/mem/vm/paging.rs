@@ -6,33 +6,73 @@
 //! Generic aarch64 page table manipulation functionality which doesn't assume anything about how
 //! addresses are mapped.
 
-use aarch64_cpu::registers::PAR_EL1;
-use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use aarch64_cpu::registers::{PAR_EL1, TTBR0_EL1, TTBR1_EL1};
 use core::arch::asm;
 use core::fmt::{self, Debug, Display, Formatter};
 
 use core::ops::{Add, Range, Sub};
 use core::ptr::NonNull;
 
+use crate::cpu::barrier;
 use crate::mem::allocator::{align_down, align_up};
-use crate::mem::{direct_map_virt_offset, kernel_heap_start};
+use crate::mem::vm::translation::KernelTranslation;
+use crate::mem::{direct_map_virt_offset, virtual_memory_manager, MemoryManager, SharedPage};
 use bitflags::bitflags;
 use tock_registers::interfaces::Readable;
 
 use crate::mem::vm::MapError;
 
+// The translation granule is selected at build time. 4 KiB is the default, and 16 KiB or 64 KiB
+// can be selected with the `granule_16k` or `granule_64k` features, provided the CPU supports it.
+#[cfg(all(feature = "granule_16k", feature = "granule_64k"))]
+compile_error!("only one of the `granule_16k` and `granule_64k` features can be enabled");
+
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
 const PAGE_SHIFT: usize = 12;
+#[cfg(feature = "granule_16k")]
+const PAGE_SHIFT: usize = 14;
+#[cfg(feature = "granule_64k")]
+const PAGE_SHIFT: usize = 16;
+
+/// The lowest pagetable level at which block mappings are permitted for the configured granule.
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+pub const FIRST_BLOCK_LEVEL: usize = 1;
+#[cfg(any(feature = "granule_16k", feature = "granule_64k"))]
+pub const FIRST_BLOCK_LEVEL: usize = 2;
 
 /// The pagetable level at which all entries are page mappings.
-const LEAF_LEVEL: usize = 3;
+pub const LEAF_LEVEL: usize = 3;
 
-/// The page size in bytes assumed by this library, 4 KiB.
+/// The number of virtual address bits translated by a page table, i.e. `64 - TCR_EL1.TxSZ`.
+pub const VA_BITS: usize = 48;
+
+/// The page size in bytes assumed by this library; 4 KiB, or 16 KiB or 64 KiB with the
+/// `granule_16k` or `granule_64k` features.
 pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 
 /// The number of address bits resolved in one level of page table lookup. This is a function of the
 /// page size.
 pub const BITS_PER_LEVEL: usize = PAGE_SHIFT - 3;
 
+/// The number of descriptors in a table at any level.
+pub const ENTRIES_PER_TABLE: usize = 1 << BITS_PER_LEVEL;
+
+/// The pagetable level that translation starts at, i.e. the highest level needed to resolve
+/// [`VA_BITS`] with the configured granule. With 64 KiB pages, levels 1 to 3 already cover 48 bits.
+pub const ROOT_LEVEL: usize =
+    LEAF_LEVEL + 1 - (VA_BITS - PAGE_SHIFT + BITS_PER_LEVEL - 1) / BITS_PER_LEVEL;
+
+// The number of adjacent page descriptors which can share a TLB entry by setting the contiguous
+// hint, which depends on the granule.
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+const CONTIGUOUS_PAGES: usize = 16;
+#[cfg(feature = "granule_16k")]
+const CONTIGUOUS_PAGES: usize = 128;
+#[cfg(feature = "granule_64k")]
+const CONTIGUOUS_PAGES: usize = 32;
+
+const CONTIGUOUS_SIZE: usize = CONTIGUOUS_PAGES * PAGE_SIZE;
+
 bitflags! {
     /// Attribute bits for a mapping in a page table.
     pub struct Attributes: usize {
@@ -43,12 +83,65 @@ bitflags! {
         // have been programmed accordingly.
         const DEVICE_NGNRNE = 0 << 2;
         const NORMAL        = 1 << 2 | 3 << 8; // inner shareable
+        /// Normal memory that isn't cached, e.g. for framebuffers and DMA buffers.
+        const NORMAL_NC     = 2 << 2 | 2 << 8; // outer shareable
 
         const USER          = 1 << 6;
         const READ_ONLY     = 1 << 7;
         const ACCESSED      = 1 << 10;
         const NON_GLOBAL    = 1 << 11;
+        /// A hint that this page is part of a naturally aligned, physically contiguous run with
+        /// identical attributes, so the TLB can cache the whole run as one entry. Set by
+        /// [`RootPageTable::map_range`] where possible, and ignored in the attributes passed to it.
+        const CONTIGUOUS    = 1 << 52;
+        /// Never executable at EL1, but still executable at EL0 unless `EXECUTE_NEVER` is set.
+        const PRIVILEGED_EXECUTE_NEVER = 1 << 53;
         const EXECUTE_NEVER = 3 << 53;
+
+        // Bits 55-58 are ignored by the hardware, and reserved for software use.
+        /// A read-only mapping of a page shared with another address space, which is copied
+        /// on the first write to it.
+        const COPY_ON_WRITE = 1 << 55;
+        /// An unmapped page reserved for demand paging. The `VALID` bit is clear, so any access
+        /// faults, but the page is kept apart from truly unmapped memory.
+        const RESERVED      = 1 << 56;
+    }
+}
+
+impl Attributes {
+    /// Read-only user memory, which only the process can execute.
+    pub fn user_code() -> Self {
+        Self::user() | Self::READ_ONLY
+    }
+
+    /// Read-only, non-executable user memory.
+    pub fn user_rodata() -> Self {
+        Self::user() | Self::READ_ONLY | Self::EXECUTE_NEVER
+    }
+
+    /// Writable, non-executable user memory.
+    pub fn user_data() -> Self {
+        Self::user() | Self::EXECUTE_NEVER
+    }
+
+    /// Returns the attributes for user memory with the given ELF segment permissions.
+    ///
+    /// Mappings can't be made writable or executable without also being readable, so the read
+    /// permission is implied. Returns `None` for segments that are both writable and executable, as
+    /// no user memory may be (W^X).
+    pub fn from_elf_flags(_r: bool, w: bool, x: bool) -> Option<Self> {
+        match (w, x) {
+            (true, true) => None,
+            (true, false) => Some(Self::user_data()),
+            (false, true) => Some(Self::user_code()),
+            (false, false) => Some(Self::user_rodata()),
+        }
+    }
+
+    /// Normal memory, accessible from EL0, private to one address space. The kernel never executes
+    /// user memory, even where the process can.
+    fn user() -> Self {
+        Self::NORMAL | Self::USER | Self::NON_GLOBAL | Self::PRIVILEGED_EXECUTE_NEVER
     }
 }
 
@@ -67,6 +160,56 @@ pub enum VaRange {
 #[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
 pub struct VirtualAddress(pub usize);
 
+#[allow(unused)]
+impl VirtualAddress {
+    /// Returns true if the address is at the start of a page.
+    pub const fn is_page_aligned(self) -> bool {
+        self.offset_in_page() == 0
+    }
+
+    /// Rounds the address up to a multiple of `align`, which must be a power of two.
+    pub const fn align_up_to(self, align: usize) -> Self {
+        Self(align_up(self.0, align))
+    }
+
+    /// Rounds the address down to a multiple of `align`, which must be a power of two.
+    pub const fn align_down_to(self, align: usize) -> Self {
+        Self(align_down(self.0, align))
+    }
+
+    /// Returns the number of the page the address is in.
+    pub const fn page_number(self) -> usize {
+        self.0 >> PAGE_SHIFT
+    }
+
+    /// Returns how far into its page the address is.
+    pub const fn offset_in_page(self) -> usize {
+        self.0 & (PAGE_SIZE - 1)
+    }
+
+    /// Returns the address `offset` bytes higher, or `None` if that overflows.
+    pub const fn checked_add(self, offset: usize) -> Option<Self> {
+        match self.0.checked_add(offset) {
+            Some(addr) => Some(Self(addr)),
+            None => None,
+        }
+    }
+
+    /// Returns the address `offset` bytes lower, or `None` if that underflows.
+    pub const fn checked_sub(self, offset: usize) -> Option<Self> {
+        match self.0.checked_sub(offset) {
+            Some(addr) => Some(Self(addr)),
+            None => None,
+        }
+    }
+
+    /// Returns the physical address that this direct map address maps.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn from_direct_map(self) -> PhysicalAddress {
+        PhysicalAddress(self.0 - direct_map_virt_offset())
+    }
+}
+
 impl Display for VirtualAddress {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         write!(f, "{:#018x}", self.0)
@@ -79,11 +222,12 @@ impl Debug for VirtualAddress {
     }
 }
 
+/// Returns the distance in bytes from `other` up to `self`, or 0 if `other` is the higher address.
 impl Sub for VirtualAddress {
     type Output = usize;
 
     fn sub(self, other: Self) -> Self::Output {
-        self.0 - other.0
+        self.0.saturating_sub(other.0)
     }
 }
 
@@ -115,9 +259,58 @@ pub struct PhysicalMemoryRegion(Range<PhysicalAddress>);
 #[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
 pub struct PhysicalAddress(pub usize);
 
+#[allow(unused)]
+impl PhysicalAddress {
+    /// Returns true if the address is at the start of a page.
+    pub const fn is_page_aligned(self) -> bool {
+        self.offset_in_page() == 0
+    }
+
+    /// Rounds the address up to a multiple of `align`, which must be a power of two.
+    pub const fn align_up_to(self, align: usize) -> Self {
+        Self(align_up(self.0, align))
+    }
+
+    /// Rounds the address down to a multiple of `align`, which must be a power of two.
+    pub const fn align_down_to(self, align: usize) -> Self {
+        Self(align_down(self.0, align))
+    }
+
+    /// Returns the number of the page the address is in.
+    pub const fn page_number(self) -> usize {
+        self.0 >> PAGE_SHIFT
+    }
+
+    /// Returns how far into its page the address is.
+    pub const fn offset_in_page(self) -> usize {
+        self.0 & (PAGE_SIZE - 1)
+    }
+
+    /// Returns the address `offset` bytes higher, or `None` if that overflows.
+    pub const fn checked_add(self, offset: usize) -> Option<Self> {
+        match self.0.checked_add(offset) {
+            Some(addr) => Some(Self(addr)),
+            None => None,
+        }
+    }
+
+    /// Returns the address `offset` bytes lower, or `None` if that underflows.
+    pub const fn checked_sub(self, offset: usize) -> Option<Self> {
+        match self.0.checked_sub(offset) {
+            Some(addr) => Some(Self(addr)),
+            None => None,
+        }
+    }
+
+    /// Returns the address that this physical address is mapped at in the direct map.
+    pub fn to_direct_map_virtual(self) -> VirtualAddress {
+        VirtualAddress(self.0 + direct_map_virt_offset())
+    }
+}
+
 impl From<PhysicalAddress> for VirtualAddress {
     fn from(pa: PhysicalAddress) -> Self {
-        VirtualAddress(pa.0 + direct_map_virt_offset())
+        pa.to_direct_map_virtual()
     }
 }
 
@@ -133,11 +326,12 @@ impl Debug for PhysicalAddress {
     }
 }
 
+/// Returns the distance in bytes from `other` up to `self`, or 0 if `other` is the higher address.
 impl Sub for PhysicalAddress {
     type Output = usize;
 
     fn sub(self, other: Self) -> Self::Output {
-        self.0 - other.0
+        self.0.saturating_sub(other.0)
     }
 }
 
@@ -204,9 +398,83 @@ impl VirtualMemoryRegion {
         self.0.end
     }
 
-    /// Returns the length of the memory region in bytes.
+    /// Returns the length of the memory region in bytes, which is 0 if the region is empty.
+    pub const fn len(&self) -> usize {
+        self.0.end.0.saturating_sub(self.0.start.0)
+    }
+
+    /// Returns whether the region covers no addresses at all.
+    pub const fn is_empty(&self) -> bool {
+        self.0.start.0 >= self.0.end.0
+    }
+
+    /// Returns whether `va` is within the region.
+    pub const fn contains(&self, va: VirtualAddress) -> bool {
+        self.0.start.0 <= va.0 && va.0 < self.0.end.0
+    }
+
+    /// Returns whether every address in `other` is also in this region. An empty region is
+    /// contained in any region.
+    pub const fn contains_region(&self, other: &Self) -> bool {
+        other.is_empty() || (self.0.start.0 <= other.0.start.0 && other.0.end.0 <= self.0.end.0)
+    }
+
+    /// Returns whether any address is in both regions. Regions which only touch, where one ends
+    /// where the other starts, don't overlap, and neither does an empty region.
+    pub const fn overlaps(&self, other: &Self) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.0.start.0 < other.0.end.0
+            && other.0.start.0 < self.0.end.0
+    }
+
+    /// Returns the addresses in both regions, or `None` if they don't overlap.
+    #[allow(unused)]
+    pub const fn intersection(&self, other: &Self) -> Option<Self> {
+        if !self.overlaps(other) {
+            return None;
+        }
+
+        let start = if self.0.start.0 > other.0.start.0 {
+            self.0.start
+        } else {
+            other.0.start
+        };
+        let end = if self.0.end.0 < other.0.end.0 {
+            self.0.end
+        } else {
+            other.0.end
+        };
+
+        Some(VirtualMemoryRegion(start..end))
+    }
+}
+
+impl PhysicalMemoryRegion {
+    /// Constructs a new `PhysicalMemoryRegion` for the given range of physical addresses.
+    ///
+    /// The start is inclusive and the end is exclusive. Both will be aligned to the [`PAGE_SIZE`],
+    /// with the start being rounded down and the end being rounded up.
+    pub const fn new(start: usize, end: usize) -> PhysicalMemoryRegion {
+        PhysicalMemoryRegion(
+            PhysicalAddress(align_down(start, PAGE_SIZE))
+                ..PhysicalAddress(align_up(end, PAGE_SIZE)),
+        )
+    }
+
+    /// Returns the first physical address of the memory range.
+    pub const fn start(&self) -> PhysicalAddress {
+        self.0.start
+    }
+
+    /// Returns the first physical address after the memory range.
+    pub const fn end(&self) -> PhysicalAddress {
+        self.0.end
+    }
+
+    /// Returns the length of the memory region in bytes, which is 0 if the region is empty.
     pub const fn len(&self) -> usize {
-        self.0.end.0 - self.0.start.0
+        self.0.end.0.saturating_sub(self.0.start.0)
     }
 }
 
@@ -240,13 +508,21 @@ pub struct RootPageTable {
 }
 
 impl RootPageTable {
-    /// Creates a new page table starting at the given root level.
+    /// Creates a new page table starting at [`ROOT_LEVEL`].
     ///
-    /// The level must be between 0 and 3. The value of `TCR_EL1.T0SZ` must be set appropriately
-    /// to match.
-    /// Always level 0, TxSZ = 16
+    /// The value of `TCR_EL1.TxSZ` must be set to match [`VA_BITS`].
     pub fn new(asid: usize, va_range: VaRange) -> Self {
-        let (table, pa) = PageTable::new(0);
+        Self::with_translation(asid, va_range, &KernelTranslation)
+    }
+
+    /// Like [`new`](Self::new), but the tables are allocated and accessed with `translation`
+    /// rather than the kernel's.
+    pub fn with_translation(
+        asid: usize,
+        va_range: VaRange,
+        translation: &'static dyn Translation,
+    ) -> Self {
+        let (table, pa) = PageTable::new(ROOT_LEVEL, translation);
         RootPageTable {
             table,
             pa,
@@ -259,9 +535,10 @@ impl RootPageTable {
     /// Returns the size in bytes of the virtual address space which can be mapped in this page
     /// table.
     ///
-    /// This is a function of the chosen root level.
+    /// This is a function of the chosen root level, limited by the number of virtual address bits
+    /// in use, as the root table may not be fully populated with larger granules.
     pub fn size(&self) -> usize {
-        granularity_at_level(self.table.level) << BITS_PER_LEVEL
+        (granularity_at_level(self.table.level) << BITS_PER_LEVEL).min(1 << VA_BITS)
     }
 
     /// Recursively maps a range into the pagetable hierarchy starting at the root level, mapping
@@ -274,6 +551,267 @@ impl RootPageTable {
         pa: PhysicalAddress,
         flags: Attributes,
     ) -> Result<(), MapError> {
+        self.map_range_with(range, pa, flags, FIRST_BLOCK_LEVEL)
+            .map(|_| ())
+    }
+
+    /// Like [`map_range`](Self::map_range), but only uses block mappings at `max_level` or any
+    /// finer level, wherever the range and physical address are suitably aligned. Passing
+    /// [`LEAF_LEVEL`] maps the range with pages only.
+    ///
+    /// Returns the number of block mappings that were put down.
+    pub fn map_range_with(
+        &mut self,
+        range: &VirtualMemoryRegion,
+        pa: PhysicalAddress,
+        flags: Attributes,
+        max_level: usize,
+    ) -> Result<usize, MapError> {
+        self.verify_region(range)?;
+
+        let tlb = self.tlb_scope();
+        let blocks = self
+            .table
+            .map_range(range, pa, flags, max_level.max(FIRST_BLOCK_LEVEL), tlb);
+
+        // make sure the new descriptors are visible to table walks before anything accesses them
+        barrier::dsb_ishst();
+
+        Ok(blocks)
+    }
+
+    /// Recursively unmaps a range from the pagetable hierarchy starting at the root level, and
+    /// invalidates any TLB entries for the unmapped addresses.
+    ///
+    /// Block mappings which only partially overlap the range are split into a subtable first, so
+    /// that the rest of the block stays mapped. Subtables left empty by the unmap are kept; use
+    /// [`unmap_range_and_free`](Self::unmap_range_and_free) to release them as well.
+    ///
+    /// Returns an error if the virtual address range is out of the range covered by the page table.
+    #[allow(unused)]
+    pub fn unmap_range(&mut self, range: &VirtualMemoryRegion) -> Result<(), MapError> {
+        self.verify_region(range)?;
+
+        self.table.unmap_range(range, self.tlb_scope(), false);
+
+        Ok(())
+    }
+
+    /// Same as [`unmap_range`](Self::unmap_range), but also frees any subtables which no longer
+    /// contain any valid entries after the unmap.
+    ///
+    /// This should be used for long-lived address spaces, so that they don't leak page table
+    /// pages as regions are mapped and unmapped over time.
+    #[allow(unused)]
+    pub fn unmap_range_and_free(&mut self, range: &VirtualMemoryRegion) -> Result<(), MapError> {
+        self.verify_region(range)?;
+
+        self.table.unmap_range(range, self.tlb_scope(), true);
+
+        Ok(())
+    }
+
+    /// Maps `len` bytes of fresh, zeroed memory into an unmapped part of this page table, at or
+    /// above `hint` if one is given, and returns the address it was mapped at.
+    ///
+    /// The memory is allocated with [`process_alloc`](MemoryManager::process_alloc), and should be
+    /// returned with [`unmap_anonymous`](Self::unmap_anonymous). The first page of the address
+    /// space is never used, so that null pointers always fault.
+    ///
+    /// Returns an error if there's no unmapped region large enough at or above `hint`, or no
+    /// physical memory left to back it. Only page tables for the lower half of the address space
+    /// are supported.
+    #[allow(unused)]
+    pub fn map_anonymous(
+        &mut self,
+        hint: Option<VirtualAddress>,
+        len: usize,
+        flags: Attributes,
+    ) -> Result<VirtualAddress, MapError> {
+        assert_eq!(
+            self.va_range,
+            VaRange::Lower,
+            "only lower half page tables can have anonymous mappings"
+        );
+
+        if len == 0 {
+            return Err(MapError::EmptyRegion);
+        }
+
+        let len = align_up(len, PAGE_SIZE);
+        let hint = hint.map_or(PAGE_SIZE, |hint| align_up(hint.0, PAGE_SIZE).max(PAGE_SIZE));
+        let start = self
+            .find_unmapped(hint, len)
+            .ok_or(MapError::NoSpace(len))?;
+        let range = VirtualMemoryRegion::new(start, start + len);
+
+        let (pa, dm, _) = virtual_memory_manager().process_alloc(len)?;
+        // Safe because the memory was just allocated, and isn't mapped anywhere else yet.
+        unsafe {
+            core::ptr::write_bytes(dm.0 as *mut u8, 0, len);
+        }
+
+        if let Err(err) = self.map_range(&range, pa, flags) {
+            // Safe because the memory was never mapped.
+            unsafe { virtual_memory_manager().process_free(pa, len) };
+            return Err(err);
+        }
+
+        Ok(range.start())
+    }
+
+    /// Unmaps a region mapped by [`map_anonymous`](Self::map_anonymous), and frees the memory that
+    /// was backing it. Parts of the region that aren't mapped are skipped.
+    ///
+    /// # Safety
+    ///
+    /// Every page mapped in `range` must have been allocated by `map_anonymous`, and must not be
+    /// referred to by anything else once it's unmapped.
+    #[allow(unused)]
+    pub unsafe fn unmap_anonymous(&mut self, range: &VirtualMemoryRegion) -> Result<(), MapError> {
+        self.verify_region(range)?;
+
+        let tlb = self.tlb_scope();
+        for page in (range.start().0..range.end().0).step_by(PAGE_SIZE) {
+            let Some((pa, _)) = self.translate(VirtualAddress(page)) else {
+                continue;
+            };
+
+            self.table
+                .unmap_range(&VirtualMemoryRegion::new(page, page + PAGE_SIZE), tlb, true);
+            virtual_memory_manager().process_free(pa, PAGE_SIZE);
+        }
+
+        Ok(())
+    }
+
+    /// Maps `shared` at the single page `range`, adding a reference to it which is held until it's
+    /// unmapped with [`unmap_shared`](Self::unmap_shared).
+    ///
+    /// Panics if `range` isn't exactly one page long.
+    #[allow(unused)]
+    pub fn map_shared(
+        &mut self,
+        range: &VirtualMemoryRegion,
+        shared: &SharedPage,
+        flags: Attributes,
+    ) -> Result<(), MapError> {
+        assert_eq!(
+            range.len(),
+            PAGE_SIZE,
+            "shared pages are mapped one at a time"
+        );
+
+        self.map_range_with(range, shared.physical_address(), flags, LEAF_LEVEL)?;
+        shared.acquire();
+
+        Ok(())
+    }
+
+    /// Unmaps `shared` from the page `range`, dropping the reference held by the mapping. The page
+    /// itself is freed once every mapping and handle to it is gone.
+    ///
+    /// Returns an error if `range` isn't mapped to `shared`.
+    #[allow(unused)]
+    pub fn unmap_shared(
+        &mut self,
+        range: &VirtualMemoryRegion,
+        shared: &SharedPage,
+    ) -> Result<(), MapError> {
+        match self.translate(range.start()) {
+            Some((pa, _)) if pa == shared.physical_address() && range.len() == PAGE_SIZE => {}
+            _ => return Err(MapError::NotMapped(range.start())),
+        }
+
+        self.unmap_range(range)?;
+        shared.release();
+
+        Ok(())
+    }
+
+    /// Reserves `len` bytes of unmapped address space for demand paging, at or above `hint` if one
+    /// is given, and returns the reserved region.
+    ///
+    /// Nothing is mapped until pages in the region are populated with
+    /// [`map_range_with`](Self::map_range_with), typically from the fault taken on first access.
+    /// Reserved pages can be released with [`unmap_range`](Self::unmap_range).
+    ///
+    /// Returns an error if there's no unmapped region large enough at or above `hint`. Only page
+    /// tables for the lower half of the address space are supported.
+    #[allow(unused)]
+    pub fn reserve_anonymous(
+        &mut self,
+        hint: Option<VirtualAddress>,
+        len: usize,
+    ) -> Result<VirtualMemoryRegion, MapError> {
+        assert_eq!(
+            self.va_range,
+            VaRange::Lower,
+            "only lower half page tables can have anonymous mappings"
+        );
+
+        if len == 0 {
+            return Err(MapError::EmptyRegion);
+        }
+
+        let len = align_up(len, PAGE_SIZE);
+        let hint = hint.map_or(PAGE_SIZE, |hint| align_up(hint.0, PAGE_SIZE).max(PAGE_SIZE));
+        let start = self
+            .find_unmapped(hint, len)
+            .ok_or(MapError::NoSpace(len))?;
+        let range = VirtualMemoryRegion::new(start, start + len);
+
+        self.table.reserve_range(&range);
+        Ok(range)
+    }
+
+    /// Returns whether the page containing `va` is reserved for demand paging, and not yet mapped.
+    #[allow(unused)]
+    pub fn is_reserved(&self, va: VirtualAddress) -> bool {
+        self.va_range == VaRange::Lower
+            && (va.0 as isize) >= 0
+            && va.0 < self.size()
+            && self.table.is_reserved(va)
+    }
+
+    /// Returns the start of the first unmapped region of `len` bytes at or above `from`, which must
+    /// both be page aligned.
+    fn find_unmapped(&self, from: usize, len: usize) -> Option<usize> {
+        let mut candidate = from;
+        loop {
+            let end = candidate
+                .checked_add(len)
+                .filter(|&end| end <= self.size())?;
+            match self.table.next_mapping(0, candidate) {
+                // skip past the mapping that's in the way, and try again
+                Some((start, mapping_end)) if start < end => candidate = mapping_end,
+                _ => return Some(candidate),
+            }
+        }
+    }
+
+    /// Returns where TLB entries for this table need to be invalidated as its mappings change.
+    ///
+    /// A table that isn't active can't have any entries cached, since [`deactivate`] flushes its
+    /// ASID, so tables being built offline skip invalidation entirely. The kernel's table is shared
+    /// by every core, and its mappings are global, so they're invalidated for every ASID on every
+    /// core.
+    ///
+    /// [`deactivate`]: Self::deactivate
+    fn tlb_scope(&self) -> Option<TlbScope> {
+        if !self.is_active() {
+            return None;
+        }
+
+        Some(match self.va_range {
+            VaRange::Lower => TlbScope::Asid(self.asid),
+            VaRange::Upper => TlbScope::Global,
+        })
+    }
+
+    /// Checks that the given range is well-formed, and lies within the range covered by this page
+    /// table.
+    fn verify_region(&self, range: &VirtualMemoryRegion) -> Result<(), MapError> {
         if range.end() < range.start() {
             return Err(MapError::RegionBackwards(range.clone()));
         }
@@ -295,11 +833,60 @@ impl RootPageTable {
             }
         }
 
-        self.table.map_range(range, pa, flags);
-
         Ok(())
     }
 
+    /// Looks up the mapping for the given virtual address by walking the pagetable hierarchy.
+    ///
+    /// Returns the physical address that `va` translates to, including its offset within the page
+    /// or block, and the attributes of the mapping. Returns `None` if the address is outside of the
+    /// range covered by this page table, or is not mapped.
+    #[allow(unused)]
+    pub fn translate(&self, va: VirtualAddress) -> Option<(PhysicalAddress, Attributes)> {
+        let in_range = match self.va_range {
+            VaRange::Lower => (va.0 as isize) >= 0 && va.0 < self.size(),
+            VaRange::Upper => (va.0 as isize) < 0 && (va.0 as isize).unsigned_abs() <= self.size(),
+        };
+
+        if !in_range {
+            return None;
+        }
+
+        self.table.translate(va)
+    }
+
+    /// Creates a copy of this page table for the address space `asid`, sharing all of the
+    /// physical memory mapped by it.
+    ///
+    /// Writable mappings are made read-only and [`COPY_ON_WRITE`](Attributes::COPY_ON_WRITE) in
+    /// both tables, so that a write from either side faults and can be given its own copy of the
+    /// page. `share` is called with the physical address and size of every mapping, so the caller
+    /// can track how many address spaces are using it.
+    ///
+    /// Only page tables for the lower half of the address space can be cloned.
+    #[allow(unused)]
+    pub fn clone_cow(
+        &mut self,
+        asid: usize,
+        mut share: impl FnMut(PhysicalAddress, usize),
+    ) -> RootPageTable {
+        assert_eq!(
+            self.va_range,
+            VaRange::Lower,
+            "only lower half page tables can be cloned"
+        );
+
+        let (table, pa) = self.table.clone_cow(0, self.tlb_scope(), &mut share);
+        barrier::dsb_ishst();
+        RootPageTable {
+            table,
+            pa,
+            va_range: self.va_range,
+            asid,
+            previous_ttbr: None,
+        }
+    }
+
     /// Returns the physical address of the root table in memory.
     pub fn to_physical(&self) -> PhysicalAddress {
         self.pa
@@ -310,6 +897,45 @@ impl RootPageTable {
         self.va_range
     }
 
+    /// Returns an iterator over the mappings in this page table, in address order, as the virtual
+    /// range mapped, the physical address it's mapped to, and its attributes.
+    ///
+    /// Adjacent pages and blocks which map contiguous physical memory with the same attributes
+    /// are coalesced into a single region, and block mappings are always reported in full. The
+    /// attributes don't include the bits describing the descriptor itself (`VALID` and
+    /// `TABLE_OR_PAGE`), so pages and blocks can be coalesced. Reserved pages aren't mappings, and
+    /// are skipped.
+    ///
+    /// The page table mustn't be changed while the iterator is in use.
+    #[allow(unused)]
+    pub fn iter_mappings(
+        &self,
+    ) -> impl Iterator<Item = (VirtualMemoryRegion, PhysicalAddress, Attributes)> + '_ {
+        let va_base = match self.va_range {
+            VaRange::Lower => 0,
+            VaRange::Upper => usize::MAX << VA_BITS,
+        };
+
+        MappingIterator {
+            table: &self.table,
+            va_base,
+            next: Some(va_base),
+            pending: None,
+        }
+    }
+
+    /// Returns whether this is the table currently in `TTBRn_EL1` on the calling core.
+    #[cfg(target_arch = "aarch64")]
+    pub fn is_active(&self) -> bool {
+        let ttbr = match self.va_range {
+            VaRange::Lower => TTBR0_EL1.get(),
+            VaRange::Upper => TTBR1_EL1.get(),
+        };
+
+        // BADDR is bits [47:1]; the ASID and CnP bits are masked off
+        ttbr as usize & ((1 << 48) - 2) == self.pa.0
+    }
+
     /// Activates the page table by setting `TTBRn_EL1` to point to it, and saves the previous value
     /// of `TTBRn_EL1` so that it may later be restored by [`deactivate`](Self::deactivate).
     ///
@@ -319,6 +945,9 @@ impl RootPageTable {
     pub fn activate(&mut self) {
         assert!(self.previous_ttbr.is_none());
 
+        // the table walker mustn't see any descriptor writes still in flight
+        barrier::dsb_ishst();
+
         let mut previous_ttbr;
         unsafe {
             // Safe because we trust that self.root.to_physical() returns a valid physical address
@@ -328,7 +957,6 @@ impl RootPageTable {
                 VaRange::Lower => asm!(
                     "mrs   {previous_ttbr}, ttbr0_el1",
                     "msr   ttbr0_el1, {ttbrval}",
-                    "isb",
                     ttbrval = in(reg) self.to_physical().0 | (self.asid << 48),
                     previous_ttbr = out(reg) previous_ttbr,
                     options(preserves_flags),
@@ -336,13 +964,13 @@ impl RootPageTable {
                 VaRange::Upper => asm!(
                     "mrs   {previous_ttbr}, ttbr1_el1",
                     "msr   ttbr1_el1, {ttbrval}",
-                    "isb",
                     ttbrval = in(reg) self.to_physical().0 | (self.asid << 48),
                     previous_ttbr = out(reg) previous_ttbr,
                     options(preserves_flags),
                 ),
             }
         }
+        barrier::isb();
         self.previous_ttbr = Some(previous_ttbr);
     }
 
@@ -360,26 +988,28 @@ impl RootPageTable {
             match self.va_range() {
                 VaRange::Lower => asm!(
                     "msr   ttbr0_el1, {ttbrval}",
-                    "isb",
-                    "tlbi  aside1, {asid}",
-                    "dsb   nsh",
-                    "isb",
-                    asid = in(reg) self.asid << 48,
                     ttbrval = in(reg) self.previous_ttbr.unwrap(),
                     options(preserves_flags),
                 ),
                 VaRange::Upper => asm!(
                     "msr   ttbr1_el1, {ttbrval}",
-                    "isb",
-                    "tlbi  aside1, {asid}",
-                    "dsb   nsh",
-                    "isb",
-                    asid = in(reg) self.asid << 48,
                     ttbrval = in(reg) self.previous_ttbr.unwrap(),
                     options(preserves_flags),
                 ),
             }
+
+            barrier::isb();
+
+            // Safe because this only discards cached translations for the ASID being switched away
+            // from.
+            asm!(
+                "tlbi  aside1, {asid}",
+                asid = in(reg) self.asid << 48,
+                options(preserves_flags)
+            );
         }
+        barrier::dsb_nsh();
+        barrier::isb();
         self.previous_ttbr = None;
     }
 
@@ -417,6 +1047,65 @@ struct ChunkedIterator<'a> {
     start: usize,
 }
 
+/// Walks the mappings of a page table, for [`RootPageTable::iter_mappings`].
+struct MappingIterator<'a> {
+    table: &'a PageTable,
+    /// The first virtual address covered by the root table.
+    va_base: usize,
+    /// Where to look for the next mapping from, or `None` once the end of the table is reached.
+    next: Option<usize>,
+    /// A mapping found while extending the previous region, which couldn't be coalesced with it.
+    pending: Option<(usize, usize, PhysicalAddress, Attributes)>,
+}
+
+impl MappingIterator<'_> {
+    /// Returns the next page or block mapping, as its start and end virtual addresses, physical
+    /// address, and attributes.
+    fn next_leaf(&mut self) -> Option<(usize, usize, PhysicalAddress, Attributes)> {
+        if let Some(leaf) = self.pending.take() {
+            return Some(leaf);
+        }
+
+        let (start, len, pa, flags) = self.table.next_leaf(self.va_base, self.next?)?;
+        // the end of the upper half wraps around to 0
+        self.next = start.checked_add(len);
+        Some((
+            start,
+            start.wrapping_add(len),
+            pa,
+            flags - (Attributes::VALID | Attributes::TABLE_OR_PAGE | Attributes::CONTIGUOUS),
+        ))
+    }
+}
+
+impl Iterator for MappingIterator<'_> {
+    type Item = (VirtualMemoryRegion, PhysicalAddress, Attributes);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start, mut end, pa, flags) = self.next_leaf()?;
+
+        while end != 0 {
+            let Some(leaf) = self.next_leaf() else {
+                break;
+            };
+
+            let (next_start, next_end, next_pa, next_flags) = leaf;
+            if next_start != end || next_pa.0 != pa.0 + (end - start) || next_flags != flags {
+                self.pending = Some(leaf);
+                break;
+            }
+
+            end = next_end;
+        }
+
+        Some((
+            VirtualMemoryRegion(VirtualAddress(start)..VirtualAddress(end)),
+            pa,
+            flags,
+        ))
+    }
+}
+
 impl Iterator for ChunkedIterator<'_> {
     type Item = VirtualMemoryRegion;
 
@@ -424,13 +1113,11 @@ impl Iterator for ChunkedIterator<'_> {
         if !self.range.0.contains(&VirtualAddress(self.start)) {
             return None;
         }
-        let min = self.start | (self.granularity - 1);
-        let end = self
-            .range
-            .0
-            .end
-            .0
-            .min(if min == usize::MAX { min } else { min + 1 });
+        // the last chunk of the upper half ends at the top of the address space
+        let chunk_end = VirtualAddress(self.start | (self.granularity - 1))
+            .checked_add(1)
+            .map_or(usize::MAX, |end| end.0);
+        let end = self.range.0.end.0.min(chunk_end);
         let c = VirtualMemoryRegion::new(self.start, end);
         self.start = end;
         Some(c)
@@ -456,26 +1143,32 @@ impl VirtualMemoryRegion {
 /// Smart pointer which owns a [`PageTable`] and knows what level it is at. This allows it to
 /// implement `Debug` and `Drop`, as walking the page table hierarchy requires knowing the starting
 /// level.
-#[derive(Debug)]
 struct PageTable {
     table: NonNull<RawPageTable>,
     level: usize,
+    /// How the table and its subtables are allocated and accessed.
+    translation: &'static dyn Translation,
 }
 
 impl PageTable {
     /// Allocates a new, zeroed, appropriately-aligned page table with the given translation,
     /// returning both a pointer to it and its physical address.
-    fn new(level: usize) -> (Self, PhysicalAddress) {
+    fn new(level: usize, translation: &'static dyn Translation) -> (Self, PhysicalAddress) {
         assert!(level <= LEAF_LEVEL);
-        let table = RawPageTable::new();
-        (
-            Self::from_pointer(table, level),
-            unsafe { table.as_ref() }.get_physical_base(),
-        )
+        let (table, pa) = translation.allocate_table();
+        (Self::from_pointer(table, level, translation), pa)
     }
 
-    fn from_pointer(table: NonNull<RawPageTable>, level: usize) -> Self {
-        Self { table, level }
+    fn from_pointer(
+        table: NonNull<RawPageTable>,
+        level: usize,
+        translation: &'static dyn Translation,
+    ) -> Self {
+        Self {
+            table,
+            level,
+            translation,
+        }
     }
 
     #[inline(always)]
@@ -488,10 +1181,27 @@ impl PageTable {
         }
     }
 
+    /// Returns the index of the descriptor corresponding to a given virtual address.
+    fn entry_index(&self, va: VirtualAddress) -> usize {
+        let shift = PAGE_SHIFT + (LEAF_LEVEL - self.level) * BITS_PER_LEVEL;
+        // The root table only resolves the remaining address bits, which may be fewer than a full
+        // level's worth depending on the granule.
+        let bits = BITS_PER_LEVEL.min(VA_BITS - shift);
+        (va.0 >> shift) % (1 << bits)
+    }
+
+    /// Returns a reference to the descriptor corresponding to a given virtual address.
+    fn get_entry(&self, va: VirtualAddress) -> &Descriptor {
+        let index = self.entry_index(va);
+        // Safe because we know that the pointer is properly aligned, dereferenced and initialised,
+        // and the PageTable won't be mutated while we are using it.
+        let table = unsafe { self.get_mapped_table().as_ref() };
+        &table.entries[index]
+    }
+
     /// Returns a mutable reference to the descriptor corresponding to a given virtual address.
     fn get_entry_mut(&mut self, va: VirtualAddress) -> &mut Descriptor {
-        let shift = PAGE_SHIFT + (LEAF_LEVEL - self.level) * BITS_PER_LEVEL;
-        let index = (va.0 >> shift) % (1 << BITS_PER_LEVEL);
+        let index = self.entry_index(va);
         // Safe because we know that the pointer is properly aligned, dereferenced and initialised,
         // and nothing else can access the page table while we hold a mutable reference to the
         // PageTable (assuming it is not currently active).
@@ -503,49 +1213,388 @@ impl PageTable {
     /// address range starting at the given `pa`, recursing into any subtables as necessary.
     ///
     /// Assumes that the entire range is within the range covered by this page table.
+    ///
+    /// Block mappings are only used at `max_level` or finer. Returns the number of block mappings
+    /// that were put down.
+    ///
+    /// Any valid mapping that's replaced has its TLB entries invalidated in `tlb`, if given.
     fn map_range(
         &mut self,
         range: &VirtualMemoryRegion,
         mut pa: PhysicalAddress,
         flags: Attributes,
-    ) {
+        max_level: usize,
+        tlb: Option<TlbScope>,
+    ) -> usize {
         let level = self.level;
+        let translation = self.translation;
         let granularity = granularity_at_level(level);
+        let flags = flags - Attributes::CONTIGUOUS;
+        let mut blocks: usize = 0;
 
         for chunk in range.split(level) {
             let entry = self.get_entry_mut(chunk.0.start);
 
             if level == LEAF_LEVEL {
-                // Put down a page mapping.
-                entry.set(pa, flags | Attributes::ACCESSED | Attributes::TABLE_OR_PAGE);
-            } else if chunk.is_block(level)
+                // Put down a page mapping, with the contiguous hint if its whole group is being
+                // mapped to a suitably aligned physical range.
+                let mut page_flags = flags | Attributes::ACCESSED | Attributes::TABLE_OR_PAGE;
+                let whole_group = contiguous_group(chunk.0.start)
+                    .map_or(false, |group| range.contains_region(&group));
+                if whole_group && is_aligned(chunk.0.start.0 ^ pa.0, CONTIGUOUS_SIZE) {
+                    page_flags |= Attributes::CONTIGUOUS;
+                }
+
+                let old = *entry;
+                let break_first = old.needs_break_before_make(pa, page_flags);
+                if break_first {
+                    entry.clear();
+                    invalidate_tlb_entry(chunk.0.start, tlb);
+                }
+                if old.is_contiguous() && !page_flags.contains(Attributes::CONTIGUOUS) {
+                    // the rest of the group no longer matches this page
+                    self.clear_contiguous(chunk.0.start, tlb);
+                }
+
+                let entry = self.get_entry_mut(chunk.0.start);
+                entry.set(pa, page_flags);
+                if old.is_valid() && !break_first {
+                    // only the permissions changed, which can be done in place
+                    invalidate_tlb_entry(chunk.0.start, tlb);
+                }
+            } else if level >= max_level
+                && chunk.is_block(level)
                 && !entry.is_table_or_page()
                 && is_aligned(pa.0, granularity)
             {
                 // Rather than leak the entire sub-hierarchy, only put down
                 // a block mapping if the region is not already covered by
                 // a table mapping.
-                entry.set(pa, flags | Attributes::ACCESSED);
+                let block_flags = flags | Attributes::ACCESSED;
+                let break_first = entry.needs_break_before_make(pa, block_flags);
+                let replaced = entry.is_valid() && !break_first;
+                if break_first {
+                    entry.clear();
+                    invalidate_tlb_entry(chunk.0.start, tlb);
+                }
+                entry.set(pa, block_flags);
+                if replaced {
+                    invalidate_tlb_entry(chunk.0.start, tlb);
+                }
+                blocks += 1;
             } else {
-                let mut subtable = if let Some(subtable) = entry.subtable(level) {
-                    subtable
-                } else {
-                    let old = *entry;
-                    let (mut subtable, subtable_pa) = Self::new(level + 1);
-                    if let (Some(old_flags), Some(old_pa)) = (old.flags(), old.output_address()) {
-                        // Old was a valid block entry, so we need to split it.
-                        // Recreate the entire block in the newly added table.
-                        let a = align_down(chunk.0.start.0, granularity);
-                        let b = align_up(chunk.0.end.0, granularity);
-                        subtable.map_range(&VirtualMemoryRegion::new(a, b), old_pa, old_flags);
-                    }
-                    entry.set(subtable_pa, Attributes::TABLE_OR_PAGE);
-                    subtable
-                };
-                subtable.map_range(&chunk, pa, flags);
+                let mut subtable = Self::subtable_or_split(translation, entry, level, &chunk, tlb);
+                blocks += subtable.map_range(&chunk, pa, flags, max_level, tlb);
             }
             pa.0 += chunk.len();
         }
+
+        blocks
+    }
+
+    /// Unmaps the given virtual address range in this page table, recursing into any subtables as
+    /// necessary, and invalidates the TLB entries in `tlb`, if given, for each descriptor that is
+    /// cleared.
+    ///
+    /// If `free_tables` is set, subtables which are left without any valid entries are freed.
+    ///
+    /// Assumes that the entire range is within the range covered by this page table.
+    fn unmap_range(
+        &mut self,
+        range: &VirtualMemoryRegion,
+        tlb: Option<TlbScope>,
+        free_tables: bool,
+    ) {
+        let level = self.level;
+        let translation = self.translation;
+
+        for chunk in range.split(level) {
+            let entry = self.get_entry_mut(chunk.0.start);
+
+            if !entry.is_valid() {
+                // Nothing is mapped here, but the page may still be reserved.
+                entry.clear();
+                continue;
+            }
+
+            if level == LEAF_LEVEL || (chunk.is_block(level) && !entry.is_table_or_page()) {
+                // Remove the page or block mapping entirely.
+                let contiguous = entry.is_contiguous();
+                entry.clear();
+                invalidate_tlb_entry(chunk.0.start, tlb);
+
+                // if only part of a contiguous group is unmapped, the rest of it must stop
+                // claiming to be contiguous, or the TLB could keep using the whole group
+                let partial_group = contiguous_group(chunk.0.start)
+                    .map_or(true, |group| !range.contains_region(&group));
+                if contiguous && partial_group {
+                    self.clear_contiguous(chunk.0.start, tlb);
+                }
+                continue;
+            }
+
+            // Either the chunk only covers part of a block, which needs to be split so the rest of
+            // it stays mapped, or the chunk is covered by a subtable we need to descend into.
+            let mut subtable = Self::subtable_or_split(translation, entry, level, &chunk, tlb);
+            subtable.unmap_range(&chunk, tlb, free_tables);
+
+            if free_tables && subtable.is_empty() {
+                entry.clear();
+                // Also flushes any walk cache entries referencing the subtable.
+                invalidate_tlb_entry(chunk.0.start, tlb);
+                subtable.free();
+            }
+        }
+    }
+
+    /// Clears the contiguous hint from every page in the contiguous group containing `va`, and
+    /// invalidates the TLB entries in `tlb`, if given, for each page that had it.
+    ///
+    /// Assumes that this is a leaf table.
+    fn clear_contiguous(&mut self, va: VirtualAddress, tlb: Option<TlbScope>) {
+        let group_start = align_down(va.0, CONTIGUOUS_SIZE);
+        for i in 0..CONTIGUOUS_PAGES {
+            let page = VirtualAddress(group_start.wrapping_add(i * PAGE_SIZE));
+            let entry = self.get_entry_mut(page);
+            if let (Some(flags), Some(pa)) = (entry.flags(), entry.output_address()) {
+                if flags.contains(Attributes::CONTIGUOUS) {
+                    // changing the contiguous bit of a live entry needs break-before-make too
+                    entry.clear();
+                    invalidate_tlb_entry(page, tlb);
+                    entry.set(pa, flags - Attributes::CONTIGUOUS);
+                }
+            }
+        }
+    }
+
+    /// Marks every unmapped page in the given virtual address range as reserved, recursing into or
+    /// creating subtables as necessary. Pages that are already mapped are left alone.
+    ///
+    /// Assumes that the entire range is within the range covered by this page table.
+    fn reserve_range(&mut self, range: &VirtualMemoryRegion) {
+        let level = self.level;
+        let translation = self.translation;
+
+        for chunk in range.split(level) {
+            let entry = self.get_entry_mut(chunk.0.start);
+
+            if level == LEAF_LEVEL {
+                if !entry.is_valid() {
+                    entry.set_reserved();
+                }
+            } else if entry.is_valid() && !entry.is_table_or_page() {
+                // Already covered by a block mapping.
+                continue;
+            } else {
+                // reserving never touches valid mappings, so there's nothing to invalidate
+                Self::subtable_or_split(translation, entry, level, &chunk, None)
+                    .reserve_range(&chunk);
+            }
+        }
+    }
+
+    /// Returns whether the page containing `va` is reserved, descending into subtables as
+    /// necessary.
+    ///
+    /// Assumes that the address is within the range covered by this page table.
+    fn is_reserved(&self, va: VirtualAddress) -> bool {
+        let entry = self.get_entry(va);
+        match entry.subtable(self.level, self.translation) {
+            Some(subtable) => subtable.is_reserved(va),
+            None => entry.is_reserved(),
+        }
+    }
+
+    /// Returns the subtable referenced by the given entry, which describes the chunk at `level`.
+    ///
+    /// If the entry is not a table, a new subtable is allocated to replace it. If the entry was a
+    /// valid block mapping, the entire block is recreated in the new subtable, so that it can be
+    /// modified at a finer granularity, and the block's TLB entries are invalidated in `tlb`, if
+    /// given.
+    fn subtable_or_split(
+        translation: &'static dyn Translation,
+        entry: &mut Descriptor,
+        level: usize,
+        chunk: &VirtualMemoryRegion,
+        tlb: Option<TlbScope>,
+    ) -> PageTable {
+        if let Some(subtable) = entry.subtable(level, translation) {
+            return subtable;
+        }
+
+        let granularity = granularity_at_level(level);
+        let old = *entry;
+        let (mut subtable, subtable_pa) = Self::new(level + 1, translation);
+        if let (Some(old_flags), Some(old_pa)) = (old.flags(), old.output_address()) {
+            // Old was a valid block entry, so we need to split it.
+            // Recreate the entire block in the newly added table.
+            let a = align_down(chunk.0.start.0, granularity);
+            let b = align_up(chunk.0.end.0, granularity);
+            subtable.map_range(
+                &VirtualMemoryRegion::new(a, b),
+                old_pa,
+                old_flags,
+                FIRST_BLOCK_LEVEL,
+                // the new subtable isn't reachable yet
+                None,
+            );
+        }
+        entry.set(subtable_pa, Attributes::TABLE_OR_PAGE);
+        if old.is_valid() {
+            invalidate_tlb_entry(chunk.0.start, tlb);
+        }
+        subtable
+    }
+
+    /// Translates the given virtual address, descending into subtables as necessary.
+    ///
+    /// Assumes that the address is within the range covered by this page table.
+    fn translate(&self, va: VirtualAddress) -> Option<(PhysicalAddress, Attributes)> {
+        let level = self.level;
+        let entry = self.get_entry(va);
+
+        if let Some(subtable) = entry.subtable(level, self.translation) {
+            return subtable.translate(va);
+        }
+
+        // This is either a page mapping, or a block mapping at an intermediate level.
+        let (flags, pa) = (entry.flags()?, entry.output_address()?);
+        let offset = va.0 & (granularity_at_level(level) - 1);
+        Some((pa + offset, flags))
+    }
+
+    /// Copies this page table and all of its subtables, for [`RootPageTable::clone_cow`]. Writable
+    /// mappings are made copy-on-write in both copies, and `share` is called for every mapping.
+    ///
+    /// `va_base` is the first virtual address covered by this table, and `tlb` where to invalidate
+    /// the mappings that are made read-only, if anywhere.
+    fn clone_cow(
+        &mut self,
+        va_base: usize,
+        tlb: Option<TlbScope>,
+        share: &mut impl FnMut(PhysicalAddress, usize),
+    ) -> (PageTable, PhysicalAddress) {
+        let level = self.level;
+        let granularity = granularity_at_level(level);
+        let (clone, clone_pa) = Self::new(level, self.translation);
+
+        // Safe because we know that both pointers are properly aligned, dereferenced and
+        // initialised, and nothing else can access the page tables while we hold a mutable
+        // reference to them.
+        let table = unsafe { self.get_mapped_table().as_mut() };
+        let cloned = unsafe { clone.get_mapped_table().as_mut() };
+
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            let va = va_base + i * granularity;
+
+            if let Some(mut subtable) = entry.subtable(level, self.translation) {
+                let (_, subtable_pa) = subtable.clone_cow(va, tlb, share);
+                cloned.entries[i].set(subtable_pa, Attributes::TABLE_OR_PAGE);
+                continue;
+            }
+
+            if entry.is_reserved() {
+                cloned.entries[i] = *entry;
+                continue;
+            }
+
+            let (Some(flags), Some(pa)) = (entry.flags(), entry.output_address()) else {
+                continue;
+            };
+
+            let shared_flags = if flags.contains(Attributes::READ_ONLY) {
+                flags
+            } else {
+                flags | Attributes::READ_ONLY | Attributes::COPY_ON_WRITE
+            };
+
+            if shared_flags != flags {
+                entry.set(pa, shared_flags);
+                invalidate_tlb_entry(VirtualAddress(va), tlb);
+            }
+
+            cloned.entries[i].set(pa, shared_flags);
+            share(pa, granularity);
+        }
+
+        (clone, clone_pa)
+    }
+
+    /// Returns the range covered by the first page or block mapping that ends after `from`,
+    /// descending into subtables as necessary.
+    ///
+    /// `va_base` is the first virtual address covered by this table.
+    fn next_mapping(&self, va_base: usize, from: usize) -> Option<(usize, usize)> {
+        let level = self.level;
+        let granularity = granularity_at_level(level);
+
+        // Safe because we know that the pointer is aligned, initialised and dereferencable, and the
+        // PageTable won't be mutated while we are using it.
+        let table = unsafe { self.get_mapped_table().as_ref() };
+        let first = from.saturating_sub(va_base) / granularity;
+
+        for (i, entry) in table.entries.iter().enumerate().skip(first) {
+            let va = va_base + i * granularity;
+
+            if let Some(subtable) = entry.subtable(level, self.translation) {
+                match subtable.next_mapping(va, from) {
+                    Some(mapping) => return Some(mapping),
+                    None => continue,
+                }
+            }
+
+            if entry.is_valid() || entry.is_reserved() {
+                return Some((va, va + granularity));
+            }
+        }
+
+        None
+    }
+
+    /// Returns the first page or block mapping that ends after `from`, descending into subtables
+    /// as necessary, as its start virtual address, length, physical address and attributes.
+    ///
+    /// `va_base` is the first virtual address covered by this table.
+    fn next_leaf(
+        &self,
+        va_base: usize,
+        from: usize,
+    ) -> Option<(usize, usize, PhysicalAddress, Attributes)> {
+        let level = self.level;
+        let granularity = granularity_at_level(level);
+
+        // Safe because we know that the pointer is aligned, initialised and dereferencable, and the
+        // PageTable won't be mutated while we are using it.
+        let table = unsafe { self.get_mapped_table().as_ref() };
+        let first = from.saturating_sub(va_base) / granularity;
+
+        for (i, entry) in table.entries.iter().enumerate().skip(first) {
+            let va = va_base + i * granularity;
+
+            if let Some(subtable) = entry.subtable(level, self.translation) {
+                match subtable.next_leaf(va, from) {
+                    Some(leaf) => return Some(leaf),
+                    None => continue,
+                }
+            }
+
+            if let (Some(flags), Some(pa)) = (entry.flags(), entry.output_address()) {
+                return Some((va, granularity, pa, flags));
+            }
+        }
+
+        None
+    }
+
+    /// Returns whether this page table has no valid or reserved entries.
+    fn is_empty(&self) -> bool {
+        // Safe because we know that the pointer is aligned, initialised and dereferencable, and the
+        // PageTable won't be mutated while we are using it.
+        let table = unsafe { self.get_mapped_table().as_ref() };
+        table
+            .entries
+            .iter()
+            .all(|entry| !entry.is_valid() && !entry.is_reserved())
     }
 
     fn fmt_indented(&self, f: &mut Formatter, indentation: usize) -> Result<(), fmt::Error> {
@@ -567,7 +1616,7 @@ impl PageTable {
                 }
             } else {
                 writeln!(f, "{:indentation$}{}: {:?}", "", i, table.entries[i])?;
-                if let Some(subtable) = table.entries[i].subtable(self.level) {
+                if let Some(subtable) = table.entries[i].subtable(self.level, self.translation) {
                     subtable.fmt_indented(f, indentation + 2)?;
                 }
                 i += 1;
@@ -583,49 +1632,39 @@ impl PageTable {
         // PageTable won't be mutated while we are freeing it.
         let table = unsafe { self.get_mapped_table().as_ref() };
         for entry in table.entries {
-            if let Some(mut subtable) = entry.subtable(self.level) {
+            if let Some(mut subtable) = entry.subtable(self.level, self.translation) {
                 // Safe because the subtable was allocated by `PageTableWithLevel::new` with the
                 // global allocator and appropriate layout.
                 subtable.free();
             }
         }
-        // Safe because the table was allocated by `PageTableWithLevel::new` with the global
-        // allocator and appropriate layout.
+        // Safe because the table was allocated by `PageTable::new` with the kernel's translation.
         unsafe {
             // Actually free the memory used by the `PageTable`.
-            deallocate(self.get_mapped_table());
+            self.translation.deallocate_table(self.get_mapped_table());
         }
     }
 }
 
 /// A single level of a page table.
-#[repr(C, align(4096))]
+#[cfg_attr(
+    not(any(feature = "granule_16k", feature = "granule_64k")),
+    repr(C, align(4096))
+)]
+#[cfg_attr(feature = "granule_16k", repr(C, align(16384)))]
+#[cfg_attr(feature = "granule_64k", repr(C, align(65536)))]
 pub struct RawPageTable {
-    entries: [Descriptor; 1 << BITS_PER_LEVEL],
+    entries: [Descriptor; ENTRIES_PER_TABLE],
 }
 
 impl RawPageTable {
-    /// Allocates a new zeroed, appropriately-aligned page table on the heap using the global
-    /// allocator and returns a pointer to it.
-    pub fn new() -> NonNull<Self> {
-        // Safe because the pointer has been allocated with the appropriate layout by the global
-        // allocator, and the memory is zeroed which is valid initialisation for a PageTable.
-        unsafe { allocate_zeroed() }
-    }
-
-    /// Returns the physical base address of this page table.
+    /// Returns the physical base address of this page table, wherever it was allocated.
+    #[allow(unused)]
     pub fn get_physical_base(&self) -> PhysicalAddress {
-        let virtual_address = self as *const _ as usize;
-        if virtual_address >= direct_map_virt_offset() && virtual_address < kernel_heap_start() {
-            PhysicalAddress(virtual_address - direct_map_virt_offset())
-        } else {
-            unsafe {
-                // aarch64 is a based architecture, thank you for saving me from writing
-                // page table reverse-parsing code :)
-                asm!("at s1e1r, {}", in(reg) virtual_address);
-            }
-
-            PhysicalAddress((PAR_EL1.read(PAR_EL1::PA) << PAGE_SHIFT) as usize)
+        let va = VirtualAddress(self as *const _ as usize);
+        match KernelTranslation.virtual_to_physical(va) {
+            Some(pa) => pa,
+            None => panic!("page table at {} isn't mapped", va),
         }
     }
 }
@@ -634,6 +1673,8 @@ impl RawPageTable {
 ///
 /// A descriptor may be:
 ///   - Invalid, i.e. the virtual address range is unmapped
+///   - Reserved for demand paging, if it is in the lowest level page table. This is also invalid as
+///     far as the hardware is concerned.
 ///   - A page mapping, if it is in the lowest level page table.
 ///   - A block mapping, if it is not in the lowest level page table.
 ///   - A pointer to a lower level pagetable, if it is not in the lowest level page table.
@@ -664,6 +1705,15 @@ impl Descriptor {
         (self.0 & Attributes::VALID.bits()) != 0
     }
 
+    fn is_reserved(self) -> bool {
+        !self.is_valid() && (self.0 & Attributes::RESERVED.bits()) != 0
+    }
+
+    fn is_contiguous(self) -> bool {
+        self.flags()
+            .map_or(false, |flags| flags.contains(Attributes::CONTIGUOUS))
+    }
+
     fn is_table_or_page(self) -> bool {
         if let Some(flags) = self.flags() {
             flags.contains(Attributes::TABLE_OR_PAGE)
@@ -672,30 +1722,49 @@ impl Descriptor {
         }
     }
 
+    /// Writes the descriptor. No barrier is issued, so a table walk may not see the new value
+    /// until a `dsb ishst`; the public [`RootPageTable`] methods issue one before returning, once
+    /// every descriptor they touch has been written. Replacing a valid descriptor also needs its
+    /// TLB entries invalidated.
     fn set(&mut self, pa: PhysicalAddress, flags: Attributes) {
         self.0 = pa.0 | (flags | Attributes::VALID).bits();
     }
 
-    fn subtable(&self, level: usize) -> Option<PageTable> {
+    /// Returns true if replacing this descriptor with a mapping of `pa` with `flags` changes more
+    /// than its permissions, so it must first be made invalid and its TLB entries invalidated.
+    ///
+    /// The architecture requires this "break-before-make" sequence when the output address, memory
+    /// type, shareability or contiguous hint of a live translation changes, since otherwise the old
+    /// and new translations can both be cached and conflict.
+    fn needs_break_before_make(self, pa: PhysicalAddress, flags: Attributes) -> bool {
+        const MEMORY_TYPE_MASK: usize = 0b111 << 2 | 0b11 << 8;
+
+        let (Some(old_pa), Some(old_flags)) = (self.output_address(), self.flags()) else {
+            return false;
+        };
+        let changed = old_flags.bits() ^ flags.bits();
+        old_pa != pa
+            || changed & MEMORY_TYPE_MASK != 0
+            || changed & Attributes::CONTIGUOUS.bits() != 0
+    }
+
+    fn set_reserved(&mut self) {
+        self.0 = Attributes::RESERVED.bits();
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0;
+    }
+
+    fn subtable(&self, level: usize, translation: &'static dyn Translation) -> Option<PageTable> {
         if level < LEAF_LEVEL && self.is_table_or_page() {
             if let Some(output_address) = self.output_address() {
-                let table = self.physical_to_virtual(output_address);
-                return Some(PageTable::from_pointer(table, level + 1));
+                let table = translation.physical_to_virtual(output_address);
+                return Some(PageTable::from_pointer(table, level + 1, translation));
             }
         }
         None
     }
-
-    // todo
-    fn physical_to_virtual(&self, output_address: PhysicalAddress) -> NonNull<RawPageTable> {
-        if let Some(ptr) =
-            NonNull::new((direct_map_virt_offset() + output_address.0) as *mut RawPageTable)
-        {
-            ptr
-        } else {
-            panic!("Invalid physical address: {:?}", output_address);
-        }
-    }
 }
 
 impl Debug for Descriptor {
@@ -708,37 +1777,117 @@ impl Debug for Descriptor {
     }
 }
 
-/// Allocates appropriately aligned heap space for a `T` and zeroes it.
-///
-/// # Safety
-///
-/// It must be valid to initialise the type `T` by simply zeroing its memory.
-unsafe fn allocate_zeroed<T>() -> NonNull<T> {
-    let layout = Layout::new::<T>();
-    // Safe because we know the layout has non-zero size.
-    let pointer = alloc_zeroed(layout);
-    if pointer.is_null() {
-        handle_alloc_error(layout);
-    }
-    // Safe because we just checked that the pointer is non-null.
-    NonNull::new_unchecked(pointer as *mut T)
+/// Returns the naturally aligned group of pages containing `va` which can share the contiguous
+/// hint, or `None` if the group would run past the end of the address space.
+fn contiguous_group(va: VirtualAddress) -> Option<VirtualMemoryRegion> {
+    let start = align_down(va.0, CONTIGUOUS_SIZE);
+    Some(VirtualMemoryRegion::new(
+        start,
+        start.checked_add(CONTIGUOUS_SIZE)?,
+    ))
 }
 
-/// Deallocates the heap space for a `T` which was previously allocated by `allocate_zeroed`.
-///
-/// # Safety
-///
-/// The memory must have been allocated by the global allocator, with the layout for `T`, and not
-/// yet deallocated.
-pub(crate) unsafe fn deallocate<T>(ptr: NonNull<T>) {
-    let layout = Layout::new::<T>();
-    dealloc(ptr.as_ptr() as *mut u8, layout);
+/// Translates `va` with the calling core's current stage 1 EL1 translation, as a read would be,
+/// returning `None` if it isn't mapped.
+pub(crate) fn translate_current(va: VirtualAddress) -> Option<PhysicalAddress> {
+    // Safe because address translation instructions only update PAR_EL1.
+    unsafe { asm!("at s1e1r, {}", "isb", in(reg) va.0, options(nostack, preserves_flags)) };
+
+    if PAR_EL1.is_set(PAR_EL1::F) {
+        return None;
+    }
+
+    // PAR_EL1.PA always holds PA[47:12], regardless of the translation granule.
+    let page = (PAR_EL1.read(PAR_EL1::PA) << 12) as usize;
+    Some(PhysicalAddress(page | (va.0 & 0xfff)))
 }
 
 pub(crate) const fn is_aligned(value: usize, alignment: usize) -> bool {
     value & (alignment - 1) == 0
 }
 
+/// Which TLB entries a change to an active page table has to be invalidated from.
+#[derive(Copy, Clone)]
+enum TlbScope {
+    /// Entries tagged with the given ASID (and global entries), on the calling core only.
+    Asid(usize),
+    /// Entries for any ASID, on every core in the inner shareable domain.
+    Global,
+}
+
+/// Invalidates all TLB entries (at any level) used to translate the given virtual address in
+/// `scope`, if given, including global entries.
+#[inline(always)]
+fn invalidate_tlb_entry(va: VirtualAddress, scope: Option<TlbScope>) {
+    #[cfg(not(target_arch = "aarch64"))]
+    compile_error!("Add the target_arch to above's check if the following code is safe to use");
+
+    let Some(scope) = scope else {
+        return;
+    };
+
+    // The operand holds VA[55:12] in bits [43:0], and the ASID in bits [63:48]. The VA is always
+    // given in 4 KiB units, regardless of the translation granule.
+    let va = (va.0 >> 12) & ((1 << 44) - 1);
+    barrier::dsb_ishst();
+    unsafe {
+        // Safe because this only discards cached translations, which will be refetched from the
+        // page tables as needed.
+        match scope {
+            TlbScope::Asid(asid) => asm!(
+                "tlbi  vae1, {operand}",
+                operand = in(reg) va | (asid << 48),
+                options(preserves_flags),
+            ),
+            TlbScope::Global => asm!(
+                "tlbi  vaae1is, {operand}",
+                operand = in(reg) va,
+                options(preserves_flags),
+            ),
+        }
+    }
+    match scope {
+        TlbScope::Asid(_) => barrier::dsb_nsh(),
+        TlbScope::Global => barrier::dsb_ish(),
+    }
+    barrier::isb();
+}
+
+/// Invalidates all TLB entries tagged with the given ASID, on every core in the inner shareable
+/// domain. Global entries aren't affected.
+pub fn invalidate_tlb_asid(asid: usize) {
+    #[cfg(not(target_arch = "aarch64"))]
+    compile_error!("Add the target_arch to above's check if the following code is safe to use");
+
+    barrier::dsb_ishst();
+    unsafe {
+        // Safe because this only discards cached translations, which will be refetched from the
+        // page tables as needed.
+        asm!(
+            "tlbi  aside1is, {asid}",
+            asid = in(reg) asid << 48,
+            options(preserves_flags),
+        );
+    }
+    barrier::dsb_ish();
+    barrier::isb();
+}
+
+/// Invalidates every EL1&0 TLB entry, for every ASID, on every core in the inner shareable domain.
+pub fn invalidate_tlb_all() {
+    #[cfg(not(target_arch = "aarch64"))]
+    compile_error!("Add the target_arch to above's check if the following code is safe to use");
+
+    barrier::dsb_ishst();
+    unsafe {
+        // Safe because this only discards cached translations, which will be refetched from the
+        // page tables as needed.
+        asm!("tlbi  vmalle1is", options(preserves_flags));
+    }
+    barrier::dsb_ish();
+    barrier::isb();
+}
+
 //--------------------------------------------------------------------------------------------------
 // Public definitions
 //--------------------------------------------------------------------------------------------------
@@ -754,3 +1903,355 @@ pub(crate) const fn is_aligned(value: usize, alignment: usize) -> bool {
 //--------------------------------------------------------------------------------------------------
 // Private code
 //--------------------------------------------------------------------------------------------------
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use super::{
+        granularity_at_level, Attributes, KernelTranslation, PageTable, PhysicalAddress,
+        RootPageTable, SharedPage, VaRange, VirtualAddress, VirtualMemoryRegion, ENTRIES_PER_TABLE,
+        LEAF_LEVEL, PAGE_SIZE, ROOT_LEVEL, VA_BITS,
+    };
+    use crate::mem::{virtual_memory_manager, MemoryManager};
+    use crate::selftest::SelfTest;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "paging::empty regions",
+            run: empty_regions,
+        },
+        SelfTest {
+            name: "paging::adjacent regions",
+            run: adjacent_regions,
+        },
+        SelfTest {
+            name: "paging::nested regions",
+            run: nested_regions,
+        },
+        SelfTest {
+            name: "paging::partially overlapping regions",
+            run: partially_overlapping_regions,
+        },
+        SelfTest {
+            name: "paging::disjoint regions",
+            run: disjoint_regions,
+        },
+        SelfTest {
+            name: "paging::checked address arithmetic at the ends of the address space",
+            run: checked_arithmetic,
+        },
+        SelfTest {
+            name: "paging::regions at the top of the upper half",
+            run: top_of_upper_half,
+        },
+        SelfTest {
+            name: "paging::translate inside and outside a mapped range",
+            run: translate_mapped_range,
+        },
+        SelfTest {
+            name: "paging::shared page outlives all but its last mapping",
+            run: shared_page_mappings,
+        },
+        SelfTest {
+            name: "paging::iter_mappings coalesces contiguous mappings",
+            run: iter_mappings_coalesces,
+        },
+        SelfTest {
+            name: "paging::table geometry for the configured granule",
+            run: table_geometry,
+        },
+    ];
+
+    /// The ASID given to the page tables the tests build, which are never activated.
+    const TEST_ASID: usize = 1;
+
+    // The expected root level, number of root table entries, and bytes covered by an entry at each
+    // level (0 for levels above the root) for the configured granule.
+    #[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+    const EXPECTED_GEOMETRY: (usize, usize, [usize; 4]) =
+        (0, 512, [512 << 30, 1 << 30, 2 << 20, 4 << 10]);
+    #[cfg(feature = "granule_16k")]
+    const EXPECTED_GEOMETRY: (usize, usize, [usize; 4]) =
+        (0, 2, [128 << 40, 64 << 30, 32 << 20, 16 << 10]);
+    #[cfg(feature = "granule_64k")]
+    const EXPECTED_GEOMETRY: (usize, usize, [usize; 4]) =
+        (1, 64, [0, 4 << 40, 512 << 20, 64 << 10]);
+
+    fn region(start: usize, end: usize) -> VirtualMemoryRegion {
+        VirtualMemoryRegion::new(start, end)
+    }
+
+    /// Checks the relations that hold between any two regions, whichever way round they're asked.
+    fn check_symmetric(a: &VirtualMemoryRegion, b: &VirtualMemoryRegion) {
+        assert_eq!(a.overlaps(b), b.overlaps(a));
+        assert_eq!(a.intersection(b), b.intersection(a));
+        assert_eq!(a.overlaps(b), a.intersection(b).is_some());
+    }
+
+    fn empty_regions() {
+        let empty = region(0x2000, 0x2000);
+        let inverted = region(0x3000, 0x2000);
+        let other = region(0x1000, 0x4000);
+
+        for e in [&empty, &inverted] {
+            assert!(e.is_empty());
+            assert_eq!(e.len(), 0);
+            assert!(!e.contains(e.start()));
+            assert!(!e.contains(e.end()));
+
+            // an empty region is in every region, but doesn't overlap any, not even itself
+            assert!(e.contains_region(e));
+            assert!(other.contains_region(e));
+            assert!(!e.contains_region(&other));
+            assert!(!e.overlaps(e));
+            assert!(!e.overlaps(&other));
+            assert_eq!(e.intersection(&other), None);
+            check_symmetric(e, &other);
+        }
+    }
+
+    fn adjacent_regions() {
+        let a = region(0x1000, 0x2000);
+        let b = region(0x2000, 0x3000);
+
+        assert!(a.contains(a.start()));
+        assert!(a.contains(b.start() - 1));
+        assert!(!a.contains(b.start()));
+        assert!(b.contains(b.start()));
+
+        assert!(!a.contains_region(&b));
+        assert!(!b.contains_region(&a));
+        assert!(!a.overlaps(&b));
+        assert_eq!(a.intersection(&b), None);
+        check_symmetric(&a, &b);
+    }
+
+    fn nested_regions() {
+        let outer = region(0x1000, 0x5000);
+        let inner = region(0x2000, 0x3000);
+        let prefix = region(0x1000, 0x2000);
+
+        assert!(outer.contains_region(&inner));
+        assert!(!inner.contains_region(&outer));
+        assert!(outer.overlaps(&inner));
+        assert_eq!(outer.intersection(&inner), Some(inner.clone()));
+        check_symmetric(&outer, &inner);
+
+        // sharing a start, or being the whole region, still counts as nested
+        assert!(outer.contains_region(&prefix));
+        assert_eq!(outer.intersection(&prefix), Some(prefix.clone()));
+        assert!(outer.contains_region(&outer));
+        assert_eq!(outer.intersection(&outer), Some(outer.clone()));
+        check_symmetric(&outer, &prefix);
+    }
+
+    fn partially_overlapping_regions() {
+        let a = region(0x1000, 0x3000);
+        let b = region(0x2000, 0x4000);
+
+        assert!(!a.contains_region(&b));
+        assert!(!b.contains_region(&a));
+        assert!(a.overlaps(&b));
+        assert_eq!(a.intersection(&b), Some(region(0x2000, 0x3000)));
+        check_symmetric(&a, &b);
+    }
+
+    fn disjoint_regions() {
+        let a = region(0x1000, 0x2000);
+        let b = region(0x3000, 0x4000);
+
+        assert!(!a.contains(b.start()));
+        assert!(!a.contains_region(&b));
+        assert!(!b.contains_region(&a));
+        assert!(!a.overlaps(&b));
+        assert_eq!(a.intersection(&b), None);
+        check_symmetric(&a, &b);
+    }
+
+    fn checked_arithmetic() {
+        let top = VirtualAddress(usize::MAX);
+        assert_eq!(top.checked_add(0), Some(top));
+        assert_eq!(top.checked_add(1), None);
+        assert_eq!(
+            VirtualAddress(usize::MAX - 0xfff).checked_add(0xfff),
+            Some(top)
+        );
+        assert_eq!(VirtualAddress(usize::MAX - 0xfff).checked_add(0x1000), None);
+        assert_eq!(top.checked_sub(usize::MAX), Some(VirtualAddress(0)));
+        assert_eq!(VirtualAddress(0).checked_sub(1), None);
+
+        let top = PhysicalAddress(usize::MAX);
+        assert_eq!(top.checked_add(1), None);
+        assert_eq!(PhysicalAddress(0).checked_sub(1), None);
+        assert_eq!(PhysicalAddress(1).checked_sub(1), Some(PhysicalAddress(0)));
+
+        // the distance between two addresses saturates instead of wrapping
+        assert_eq!(VirtualAddress(0x1000) - VirtualAddress(usize::MAX), 0);
+        assert_eq!(VirtualAddress(usize::MAX) - VirtualAddress(0), usize::MAX);
+        assert_eq!(PhysicalAddress(0) - PhysicalAddress(1), 0);
+    }
+
+    fn top_of_upper_half() {
+        // the last two pages, as close to the top as an exclusive end allows
+        let start = usize::MAX - 2 * PAGE_SIZE + 1;
+        let r = region(start, usize::MAX);
+        assert_eq!(r.len(), 2 * PAGE_SIZE - 1);
+        assert!(r.contains(VirtualAddress(usize::MAX - 1)));
+        assert!(!r.contains(VirtualAddress(usize::MAX)));
+        assert_eq!(region(usize::MAX, 0).len(), 0);
+
+        // splitting into pages stops at the top rather than wrapping around to 0
+        let mut chunks = r.split(LEAF_LEVEL);
+        assert_eq!(chunks.next(), Some(region(start, start + PAGE_SIZE)));
+        assert_eq!(chunks.next(), Some(region(start + PAGE_SIZE, usize::MAX)));
+        assert_eq!(chunks.next(), None);
+    }
+
+    fn translate_mapped_range() {
+        let mut pt = RootPageTable::new(TEST_ASID, VaRange::Lower);
+        let start = 16 * PAGE_SIZE;
+        let range = region(start, start + 3 * PAGE_SIZE);
+        let pa = PhysicalAddress(0x4000_0000);
+        pt.map_range_with(&range, pa, Attributes::user_data(), LEAF_LEVEL)
+            .unwrap();
+
+        for offset in [0, 1, PAGE_SIZE + 0x123, 3 * PAGE_SIZE - 1] {
+            let (translated, flags) = pt
+                .translate(VirtualAddress(start + offset))
+                .expect("mapped address didn't translate");
+            assert_eq!(translated, pa + offset);
+            assert!(flags.contains(Attributes::user_data() | Attributes::VALID));
+            assert!(!flags.contains(Attributes::READ_ONLY));
+        }
+
+        // either side of the range, and beyond what the table covers at all
+        assert_eq!(pt.translate(VirtualAddress(start - 1)), None);
+        assert_eq!(pt.translate(range.end()), None);
+        assert_eq!(pt.translate(VirtualAddress(0)), None);
+        assert_eq!(pt.translate(VirtualAddress(pt.size())), None);
+        assert_eq!(pt.translate(VirtualAddress(usize::MAX)), None);
+    }
+
+    fn shared_page_mappings() {
+        let shared = SharedPage::new().expect("failed to allocate shared page");
+        let pa = shared.physical_address();
+        let range = region(16 * PAGE_SIZE, 17 * PAGE_SIZE);
+
+        let mut a = RootPageTable::new(TEST_ASID, VaRange::Lower);
+        let mut b = RootPageTable::new(TEST_ASID + 1, VaRange::Lower);
+        a.map_shared(&range, &shared, Attributes::user_data())
+            .unwrap();
+        b.map_shared(&range, &shared, Attributes::user_data())
+            .unwrap();
+        assert_eq!(shared.ref_count(), 3);
+        assert_eq!(a.translate(range.start()).map(|(pa, _)| pa), Some(pa));
+        assert_eq!(b.translate(range.start()).map(|(pa, _)| pa), Some(pa));
+
+        // unmapping doesn't free page tables, so from here on only the page itself can be freed
+        let free = virtual_memory_manager().physical_stats().free;
+
+        a.unmap_shared(&range, &shared).unwrap();
+        assert_eq!(a.translate(range.start()), None);
+        assert_eq!(b.translate(range.start()).map(|(pa, _)| pa), Some(pa));
+        assert_eq!(shared.ref_count(), 2);
+        assert_eq!(virtual_memory_manager().physical_stats().free, free);
+
+        // a page that isn't mapped there can't be unmapped again
+        assert!(a.unmap_shared(&range, &shared).is_err());
+        assert_eq!(shared.ref_count(), 2);
+
+        b.unmap_shared(&range, &shared).unwrap();
+        assert_eq!(b.translate(range.start()), None);
+        assert_eq!(shared.ref_count(), 1);
+        assert_eq!(virtual_memory_manager().physical_stats().free, free);
+
+        // with the last mapping gone, dropping the last handle frees the page
+        drop(shared);
+        assert_eq!(
+            virtual_memory_manager().physical_stats().free,
+            free + PAGE_SIZE
+        );
+    }
+
+    fn iter_mappings_coalesces() {
+        let mut pt = RootPageTable::new(TEST_ASID, VaRange::Lower);
+        let data = region(16 * PAGE_SIZE, 18 * PAGE_SIZE);
+        let split = region(32 * PAGE_SIZE, 36 * PAGE_SIZE);
+        let code = region(48 * PAGE_SIZE, 49 * PAGE_SIZE);
+        let data_pa = PhysicalAddress(0x4000_0000);
+        let split_pa = PhysicalAddress(0x5000_0000);
+        let code_pa = PhysicalAddress(0x6000_0000);
+
+        pt.map_range_with(&data, data_pa, Attributes::user_data(), LEAF_LEVEL)
+            .unwrap();
+        // two halves mapped separately, but physically adjacent, so they're reported as one
+        let middle = split.start().0 + 2 * PAGE_SIZE;
+        pt.map_range_with(
+            &region(split.start().0, middle),
+            split_pa,
+            Attributes::user_data(),
+            LEAF_LEVEL,
+        )
+        .unwrap();
+        pt.map_range_with(
+            &region(middle, split.end().0),
+            split_pa + 2 * PAGE_SIZE,
+            Attributes::user_data(),
+            LEAF_LEVEL,
+        )
+        .unwrap();
+        pt.map_range_with(&code, code_pa, Attributes::user_code(), LEAF_LEVEL)
+            .unwrap();
+
+        let data_flags = Attributes::user_data() | Attributes::ACCESSED;
+        let code_flags = Attributes::user_code() | Attributes::ACCESSED;
+        let mut mappings = pt.iter_mappings();
+        assert_eq!(mappings.next(), Some((data, data_pa, data_flags)));
+        assert_eq!(mappings.next(), Some((split, split_pa, data_flags)));
+        assert_eq!(mappings.next(), Some((code, code_pa, code_flags)));
+        assert_eq!(mappings.next(), None);
+    }
+
+    /// Returns the index of the entry for `va` in a table at `level`.
+    fn entry_index(level: usize, va: usize) -> usize {
+        let (mut table, _) = PageTable::new(level, &KernelTranslation);
+        let index = table.entry_index(VirtualAddress(va));
+        table.free();
+        index
+    }
+
+    fn table_geometry() {
+        let (root_level, root_entries, granularity) = EXPECTED_GEOMETRY;
+        assert_eq!(ROOT_LEVEL, root_level);
+        assert_eq!(granularity_at_level(LEAF_LEVEL), PAGE_SIZE);
+        for (level, &size) in granularity.iter().enumerate().skip(ROOT_LEVEL) {
+            assert_eq!(granularity_at_level(level), size);
+        }
+
+        // the root table covers exactly the virtual address bits in use
+        assert_eq!(
+            granularity_at_level(ROOT_LEVEL) * root_entries,
+            1 << VA_BITS
+        );
+        let pt = RootPageTable::new(TEST_ASID, VaRange::Lower);
+        assert_eq!(pt.size(), 1 << VA_BITS);
+
+        for level in ROOT_LEVEL..=LEAF_LEVEL {
+            let entries = if level == ROOT_LEVEL {
+                root_entries
+            } else {
+                ENTRIES_PER_TABLE
+            };
+            let size = granularity_at_level(level);
+
+            assert_eq!(entry_index(level, 0), 0);
+            assert_eq!(entry_index(level, size - 1), 0);
+            assert_eq!(entry_index(level, size), 1);
+            assert_eq!(entry_index(level, (entries - 1) * size), entries - 1);
+            // the next table over starts again at 0, and the upper half's bits above VA_BITS are
+            // ignored
+            assert_eq!(entry_index(level, entries * size), 0);
+            assert_eq!(entry_index(level, (1 << VA_BITS) - 1), entries - 1);
+            assert_eq!(entry_index(level, usize::MAX), entries - 1);
+        }
+    }
+}
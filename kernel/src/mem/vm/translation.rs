@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: MIT
+//! The conversions between physical and virtual addresses used by the kernel's page tables.
+
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use crate::mem::vm::paging::{
+    translate_current, PhysicalAddress, RawPageTable, Translation, VirtualAddress,
+};
+use crate::mem::{direct_map_virt_offset, kernel_mmio_start, virtual_memory_manager};
+use crate::sync::interface::Mutex;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The [`Translation`] used for the kernel's own page tables, and those of processes.
+///
+/// Tables are allocated a page at a time straight from the physical page allocator, rather than
+/// from the kernel heap, and are always accessed through the direct map. That way they can be
+/// allocated before the heap is set up, and their physical addresses never need looking up.
+pub struct KernelTranslation;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+impl Translation for KernelTranslation {
+    fn allocate_table(&self) -> (NonNull<RawPageTable>, PhysicalAddress) {
+        // The physical allocator is reached directly, since tables are often allocated while the
+        // memory manager is already locked to change the kernel's mappings.
+        let Some(pa) = virtual_memory_manager()
+            .inner
+            .lock(|inner| inner.physical_allocator.allocate(TABLE_SIZE))
+        else {
+            panic!("out of physical memory allocating a page table");
+        };
+
+        let table = self.physical_to_virtual(pa);
+        // Safe because the page was just allocated for the table, and is direct mapped. All zeroes
+        // is a table of invalid descriptors.
+        unsafe { ptr::write_bytes(table.as_ptr() as *mut u8, 0, TABLE_SIZE) };
+
+        (table, pa)
+    }
+
+    unsafe fn deallocate_table(&self, page_table: NonNull<RawPageTable>) {
+        let pa = PhysicalAddress(page_table.as_ptr() as usize - direct_map_virt_offset());
+        virtual_memory_manager()
+            .inner
+            .lock(|inner| inner.physical_allocator.deallocate(pa, TABLE_SIZE));
+    }
+
+    fn physical_to_virtual(&self, pa: PhysicalAddress) -> NonNull<RawPageTable> {
+        match NonNull::new((pa.0 + direct_map_virt_offset()) as *mut RawPageTable) {
+            Some(table) => table,
+            None => panic!("invalid page table physical address: {}", pa),
+        }
+    }
+}
+
+impl KernelTranslation {
+    /// Returns the physical address that the kernel virtual address `va` is mapped to, or `None`
+    /// if it isn't mapped.
+    ///
+    /// Direct-map addresses are translated by subtracting the direct map offset. Anything else,
+    /// such as a kernel heap address, is translated by the MMU using the active page tables, since
+    /// the heap isn't guaranteed to be physically contiguous.
+    pub fn virtual_to_physical(&self, va: VirtualAddress) -> Option<PhysicalAddress> {
+        if va.0 >= direct_map_virt_offset() && va.0 < kernel_mmio_start() {
+            return Some(va.from_direct_map());
+        }
+
+        translate_current(va)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// Every level of table fills exactly one page.
+const TABLE_SIZE: usize = mem::size_of::<RawPageTable>();
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: MIT
+//! Files loaded into memory alongside the kernel by the bootloader, i.e. the kernel's ramdisk.
+//!
+//! Each Limine module is either a single file, known by its file name, or a `newc` cpio archive
+//! whose regular files are known by their paths within the archive. Modules live in memory the
+//! bootloader marks as kernel and modules, which is direct mapped and never reclaimed, so their
+//! contents are used in place rather than copied onto the heap.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use limine::LimineModuleRequest;
+
+use crate::sync::OnceCell;
+use crate::{info, warn};
+
+mod cpio;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Finds the files in the modules provided by the bootloader, so they can later be opened with
+/// [`open`].
+///
+/// # Safety
+///
+/// - Must be called once, after the kernel heap is usable, but before bootloader-reclaimable
+///   memory is reclaimed, since the module paths live there.
+pub unsafe fn init() {
+    let mut files = Vec::new();
+    let modules = match BOOTLOADER_MODULE_INFO.get_response().get() {
+        Some(response) => response.modules(),
+        None => &[],
+    };
+
+    for module in modules {
+        let Some(base) = module.base.as_ptr() else {
+            continue;
+        };
+        let data: &'static [u8] = core::slice::from_raw_parts(base, module.length as usize);
+        let path = module
+            .path
+            .to_str()
+            .and_then(|path| path.to_str().ok())
+            .unwrap_or("<unknown>");
+
+        if cpio::is_archive(data) {
+            let count = files.len();
+            for entry in cpio::entries(data) {
+                match entry {
+                    Ok(entry) => files.push(File {
+                        name: entry.name.to_string(),
+                        data: entry.data,
+                    }),
+                    Err(e) => {
+                        warn!("Module {}: malformed archive: {}", path, e);
+                        break;
+                    }
+                }
+            }
+            info!("Module {}: archive of {} files", path, files.len() - count);
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            files.push(File {
+                name: name.to_string(),
+                data,
+            });
+            info!("Module {}: {} bytes", path, data.len());
+        }
+    }
+
+    FILES.set(files);
+}
+
+/// Returns the contents of the file called `name` in the bootloader's modules, if there is one.
+///
+/// Names are paths within an archive, without a leading `/`, or the file name of a module that
+/// isn't an archive. If more than one file has the same name, the first module loaded wins.
+pub fn open(name: &str) -> Option<&'static [u8]> {
+    let name = name.trim_start_matches('/');
+    FILES
+        .get()?
+        .iter()
+        .find(|file| file.name == name)
+        .map(|file| file.data)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+static BOOTLOADER_MODULE_INFO: LimineModuleRequest = LimineModuleRequest::new(0);
+
+static FILES: OnceCell<Vec<File>> = OnceCell::new();
+
+struct File {
+    name: String,
+    data: &'static [u8],
+}
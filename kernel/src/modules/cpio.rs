@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT
+//! A reader for cpio archives in the "new ASCII" (`newc`) format, as written by
+//! `cpio -H newc` and used for Linux initramfs images.
+//!
+//! Each entry is a fixed size header of hex encoded fields, followed by the entry's name and then
+//! its data, both padded to 4 bytes. The archive ends with an entry called `TRAILER!!!`.
+//!
+//! # Resources
+//!
+//! - <https://man.archlinux.org/man/cpio.5#New_ASCII_Format>
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A regular file in an archive.
+#[derive(Copy, Clone, Debug)]
+pub struct Entry<'a> {
+    /// The path of the file within the archive, without any leading `./` or `/`.
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+/// Iterates over the regular files in an archive, skipping directories, links and the like.
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Returns true if `data` starts like a `newc` cpio archive.
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Returns an iterator over the regular files in `archive`.
+pub fn entries(archive: &[u8]) -> Entries {
+    Entries { archive, offset: 0 }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_raw() {
+                Ok(Some((mode, entry))) => {
+                    if mode & MODE_TYPE_MASK == MODE_REGULAR {
+                        return Some(Ok(entry));
+                    }
+                }
+                Ok(None) => return None,
+                Err(e) => {
+                    // stop at the first malformed entry, since the ones after it can't be found
+                    self.offset = self.archive.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER_NAME: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_REGULAR: u32 = 0o100000;
+
+/// The offsets of the header fields used, each of which is 8 hex digits.
+const FIELD_MODE: usize = 14;
+const FIELD_FILE_SIZE: usize = 54;
+const FIELD_NAME_SIZE: usize = 94;
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl<'a> Entries<'a> {
+    /// Reads the entry at the current offset and moves past it, returning it along with its mode,
+    /// or `None` at the end of the archive.
+    fn next_raw(&mut self) -> Result<Option<(u32, Entry<'a>)>, &'static str> {
+        if self.offset >= self.archive.len() {
+            return Ok(None);
+        }
+
+        let header = self
+            .archive
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or("truncated entry header")?;
+        if !header.starts_with(MAGIC) {
+            return Err("bad entry magic");
+        }
+
+        let mode = parse_field(header, FIELD_MODE)?;
+        let file_size = parse_field(header, FIELD_FILE_SIZE)? as usize;
+        let name_size = parse_field(header, FIELD_NAME_SIZE)? as usize;
+
+        // the name size includes its nul terminator
+        let name_start = self.offset + HEADER_SIZE;
+        let name = name_size
+            .checked_sub(1)
+            .and_then(|len| self.archive.get(name_start..name_start + len))
+            .ok_or("truncated entry name")?;
+        let name = core::str::from_utf8(name).map_err(|_| "entry name isn't valid UTF-8")?;
+        if name == TRAILER_NAME {
+            self.offset = self.archive.len();
+            return Ok(None);
+        }
+
+        let data_start = align4(name_start + name_size);
+        let data = self
+            .archive
+            .get(data_start..data_start + file_size)
+            .ok_or("truncated entry data")?;
+        self.offset = align4(data_start + file_size);
+
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        Ok(Some((mode, Entry { name, data })))
+    }
+}
+
+/// Parses the 8 hex digit header field at `offset`.
+fn parse_field(header: &[u8], offset: usize) -> Result<u32, &'static str> {
+    let digits =
+        core::str::from_utf8(&header[offset..offset + 8]).map_err(|_| "bad header field")?;
+    u32::from_str_radix(digits, 16).map_err(|_| "bad header field")
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
@@ -3,7 +3,11 @@
 
 use core::panic::PanicInfo;
 
-use crate::{cpu, println};
+use crate::console::ansi::Style;
+use crate::{boot, cpu, print, println, util};
+
+/// The number of recent log messages replayed when the kernel panics.
+const PANIC_LOG_LINES: usize = 16;
 
 /// Stop immediately if called a second time.
 ///
@@ -39,21 +43,38 @@ fn panic(info: &PanicInfo) -> ! {
     // Protect against panic infinite loops if any of the following code panics itself.
     panic_prevent_reenter();
 
-    let timestamp = crate::time::time_manager().uptime_kernel();
+    let timestamp = crate::time::now_nanos();
     let (location, line, column) = match info.location() {
         Some(loc) => (loc.file(), loc.line(), loc.column()),
         _ => ("<unknown>", 0, 0),
     };
 
+    // the log may have scrolled off the screen already
+    println!("\nRecent log messages:");
+    print::dump_log(PANIC_LOG_LINES);
+
     println!(
-        "\n[  {:>3}.{:06}] Panic! in the Kernel: {}\n    at: {} ({}:{})",
-        timestamp.as_secs(),
-        timestamp.subsec_micros(),
+        "\n{}[  {}] Panic!{} in the Kernel: {}\n    at: {} ({}:{})",
+        Style::RED,
+        crate::print::format_timestamp(timestamp),
+        Style::RESET,
         info.message().unwrap_or(&format_args!("")),
         location,
         line,
         column,
     );
 
+    if let Some(stage) = boot::current_stage() {
+        let elapsed = stage.elapsed();
+        println!(
+            "    during boot stage: {} ({}.{:03} ms in)",
+            stage.name,
+            elapsed / 1_000_000,
+            elapsed % 1_000_000 / 1000
+        );
+    }
+
+    util::backtrace();
+
     cpu::wait_forever()
 }
@@ -1,11 +1,98 @@
 // SPDX-License-Identifier: MIT
 use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::time::Duration;
 
-use crate::console;
+use crate::console::ansi::Style;
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+use crate::{bsp, console, time};
+
+/// How timestamps are formatted in log messages.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum TimestampFormat {
+    /// Seconds and microseconds since the kernel was loaded, e.g. `  1.234567`.
+    SecondsMicros = 0,
+    /// Architectural timer ticks since the kernel was loaded, for high-resolution debugging.
+    Ticks = 1,
+}
+
+/// How important a log message is, from least to most.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Reminders of unfinished code, from `todo_print!`.
+    Todo = 0,
+    /// General information, from `info!`.
+    Info = 1,
+    /// Something went wrong, but the kernel can carry on, from `warn!`.
+    Warn = 2,
+}
+
+/// The number of messages kept in the [`LogBuffer`].
+pub const LOG_BUFFER_LINES: usize = 64;
+
+/// The longest message kept in the [`LogBuffer`], in bytes.
+pub const LOG_LINE_LEN: usize = 128;
+
+/// A fixed-size ring buffer of the most recent log messages, so they can be replayed after they've
+/// scrolled off the console.
+pub struct LogBuffer {
+    entries: [LogEntry; LOG_BUFFER_LINES],
+    /// The slot the next message is written to.
+    next: usize,
+    len: usize,
+}
+
+/// A message in the [`LogBuffer`].
+pub struct LogEntry {
+    level: LogLevel,
+    timestamp: u64,
+    text: [u8; LOG_LINE_LEN],
+    len: usize,
+}
+
+static TIMESTAMP_FORMAT: AtomicU8 = AtomicU8::new(TimestampFormat::SecondsMicros as u8);
+static MIN_LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Todo as u8);
+static LOG_BUFFER: IRQSafeNullLock<LogBuffer> = IRQSafeNullLock::new(LogBuffer::new());
 
 #[doc(hidden)]
 pub fn kprint(args: fmt::Arguments) {
-    console::console().write_fmt(args).unwrap();
+    // early in boot, or in a panic before the drivers are up, go straight to the hardware
+    if !console::is_registered() {
+        bsp::driver::early_console_write(args);
+        return;
+    }
+
+    // strings without any formatting don't need to go through the formatter
+    match args.as_str() {
+        Some(s) => console::console().write_str(s),
+        None => console::console().write_fmt(args).unwrap(),
+    }
+}
+
+/// Returns the format currently used for log timestamps.
+pub fn timestamp_format() -> TimestampFormat {
+    match TIMESTAMP_FORMAT.load(Ordering::Relaxed) {
+        1 => TimestampFormat::Ticks,
+        _ => TimestampFormat::SecondsMicros,
+    }
+}
+
+/// Sets the format used for all subsequent log timestamps.
+#[allow(unused)]
+pub fn set_timestamp_format(format: TimestampFormat) {
+    TIMESTAMP_FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+/// Formats an uptime in nanoseconds, from [`time::now_nanos`], as a log timestamp, according to
+/// the current [`TimestampFormat`].
+pub fn format_timestamp(nanos: u64) -> impl fmt::Display {
+    Timestamp {
+        nanos,
+        format: timestamp_format(),
+    }
 }
 
 /// Prints without a newline.
@@ -31,27 +118,21 @@ macro_rules! println {
 #[macro_export]
 macro_rules! todo_print {
     () => {
-        let timestamp = $crate::time::time_manager().uptime_kernel();
-
-        $crate::println!("[  {:>3}.{:06}] TODO: {}:{}:{}",
-            timestamp.as_secs(),
-            timestamp.subsec_micros(),
-            file!(),
-            line!(),
-            column!()
+        $crate::print::klog(
+            $crate::print::LogLevel::Todo,
+            format_args!("{}:{}:{}", file!(), line!(), column!()),
         );
     };
     ($($arg:tt)*) => {
-        let timestamp = $crate::time::time_manager().uptime_kernel();
-
-        $crate::println!(
-            "[  {:>3}.{:06}] TODO: {}:{}:{}: {}",
-            timestamp.as_secs(),
-            timestamp.subsec_micros(),
-            file!(),
-            line!(),
-            column!(),
-            format_args!($($arg)*)
+        $crate::print::klog(
+            $crate::print::LogLevel::Todo,
+            format_args!(
+                "{}:{}:{}: {}",
+                file!(),
+                line!(),
+                column!(),
+                format_args!($($arg)*)
+            ),
         );
     };
 }
@@ -60,23 +141,13 @@ macro_rules! todo_print {
 #[macro_export]
 macro_rules! info {
     ($string:expr) => ({
-        let timestamp = $crate::time::time_manager().uptime_kernel();
-
-        $crate::print::kprint(format_args_nl!(
-            concat!("[  {:>3}.{:06}] ", $string),
-            timestamp.as_secs(),
-            timestamp.subsec_micros(),
-        ));
+        $crate::print::klog($crate::print::LogLevel::Info, format_args!($string));
     });
     ($format_string:expr, $($arg:tt)*) => ({
-        let timestamp = $crate::time::time_manager().uptime_kernel();
-
-        $crate::print::kprint(format_args_nl!(
-            concat!("[  {:>3}.{:06}] ", $format_string),
-            timestamp.as_secs(),
-            timestamp.subsec_micros(),
-            $($arg)*
-        ));
+        $crate::print::klog(
+            $crate::print::LogLevel::Info,
+            format_args!($format_string, $($arg)*),
+        );
     })
 }
 
@@ -84,22 +155,246 @@ macro_rules! info {
 #[macro_export]
 macro_rules! warn {
     ($string:expr) => ({
-        let timestamp = $crate::time::time_manager().uptime_kernel();
-
-        $crate::print::kprint(format_args_nl!(
-            concat!("[W {:>3}.{:06}] ", $string),
-            timestamp.as_secs(),
-            timestamp.subsec_micros(),
-        ));
+        $crate::print::klog($crate::print::LogLevel::Warn, format_args!($string));
     });
     ($format_string:expr, $($arg:tt)*) => ({
-        let timestamp = $crate::time::time_manager().uptime_kernel();
-
-        $crate::print::kprint(format_args_nl!(
-            concat!("[W {:>3}.{:06}] ", $format_string),
-            timestamp.as_secs(),
-            timestamp.subsec_micros(),
-            $($arg)*
-        ));
+        $crate::print::klog(
+            $crate::print::LogLevel::Warn,
+            format_args!($format_string, $($arg)*),
+        );
     })
 }
+
+/// Logs a message at `level`: it's recorded in the [`LogBuffer`], and printed to the console with
+/// a timestamp, unless `level` is below the minimum set with [`set_min_level`].
+#[doc(hidden)]
+pub fn klog(level: LogLevel, args: fmt::Arguments) {
+    if level < min_level() {
+        return;
+    }
+
+    let timestamp = time::now_nanos();
+    LOG_BUFFER.lock(|buffer| buffer.push(level, timestamp, args));
+
+    let timestamp = format_timestamp(timestamp);
+    match level {
+        LogLevel::Todo => kprint(format_args_nl!("[  {}] TODO: {}", timestamp, args)),
+        LogLevel::Info => kprint(format_args_nl!(
+            "[  {}{}{}] {}",
+            Style::DIM,
+            timestamp,
+            Style::RESET,
+            args
+        )),
+        LogLevel::Warn => kprint(format_args_nl!(
+            "{}[W {}]{} {}",
+            Style::YELLOW,
+            timestamp,
+            Style::RESET,
+            args
+        )),
+    }
+}
+
+/// Returns the minimum level of messages that are logged.
+pub fn min_level() -> LogLevel {
+    match MIN_LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Todo,
+        1 => LogLevel::Info,
+        _ => LogLevel::Warn,
+    }
+}
+
+/// Sets the minimum level of messages that are logged. Anything below it is dropped, and neither
+/// printed nor buffered.
+#[allow(unused)]
+pub fn set_min_level(level: LogLevel) {
+    MIN_LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns the buffer of recent log messages.
+#[allow(unused)]
+pub fn log_buffer() -> &'static IRQSafeNullLock<LogBuffer> {
+    &LOG_BUFFER
+}
+
+/// Prints the last `count` buffered log messages, oldest first, straight to the console.
+pub fn dump_log(count: usize) {
+    LOG_BUFFER.lock(|buffer| {
+        for entry in buffer.iter().skip(buffer.len().saturating_sub(count)) {
+            kprint(format_args_nl!(
+                "[{} {}] {}",
+                entry.level().tag(),
+                format_timestamp(entry.timestamp()),
+                entry.text()
+            ));
+        }
+    });
+}
+
+impl LogLevel {
+    /// A single character identifying the level in a dump of the log.
+    fn tag(self) -> char {
+        match self {
+            LogLevel::Todo => 'T',
+            LogLevel::Info => 'I',
+            LogLevel::Warn => 'W',
+        }
+    }
+}
+
+impl LogBuffer {
+    pub const fn new() -> Self {
+        Self {
+            entries: [LogEntry::EMPTY; LOG_BUFFER_LINES],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Records a message, overwriting the oldest one if the buffer is full. Messages longer than
+    /// [`LOG_LINE_LEN`] bytes are truncated.
+    pub fn push(&mut self, level: LogLevel, timestamp: u64, args: fmt::Arguments) {
+        let entry = &mut self.entries[self.next];
+        entry.level = level;
+        entry.timestamp = timestamp;
+        entry.len = 0;
+
+        // a message that doesn't fit is cut short, which isn't worth reporting
+        let _ = fmt::write(entry, args);
+
+        self.next = (self.next + 1) % LOG_BUFFER_LINES;
+        self.len = (self.len + 1).min(LOG_BUFFER_LINES);
+    }
+
+    /// Returns the number of messages in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the buffered messages, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &LogEntry> {
+        let oldest = (self.next + LOG_BUFFER_LINES - self.len) % LOG_BUFFER_LINES;
+        (0..self.len).map(move |i| &self.entries[(oldest + i) % LOG_BUFFER_LINES])
+    }
+}
+
+impl LogEntry {
+    const EMPTY: Self = Self {
+        level: LogLevel::Info,
+        timestamp: 0,
+        text: [0; LOG_LINE_LEN],
+        len: 0,
+    };
+
+    pub fn level(&self) -> LogLevel {
+        self.level
+    }
+
+    /// The uptime at which the message was logged, in nanoseconds.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn text(&self) -> &str {
+        // Safe because only whole `str`s, or whole characters of them, are ever copied in.
+        unsafe { core::str::from_utf8_unchecked(&self.text[..self.len]) }
+    }
+}
+
+impl fmt::Write for LogEntry {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > LOG_LINE_LEN {
+                return Err(fmt::Error);
+            }
+
+            c.encode_utf8(&mut self.text[self.len..end]);
+            self.len = end;
+        }
+
+        Ok(())
+    }
+}
+
+struct Timestamp {
+    nanos: u64,
+    format: TimestampFormat,
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            TimestampFormat::SecondsMicros => {
+                let mut buf = [0; time::UPTIME_STR_LEN];
+                f.write_str(time::format_nanos(self.nanos, &mut buf))
+            }
+            TimestampFormat::Ticks => write!(
+                f,
+                "{:>10}",
+                time::time_manager().duration_to_ticks(Duration::from_nanos(self.nanos))
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use alloc::format;
+    use alloc::string::{String, ToString};
+    use core::time::Duration;
+
+    use super::{Timestamp, TimestampFormat};
+    use crate::selftest::SelfTest;
+    use crate::time;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "print::seconds and microseconds timestamps",
+            run: seconds_micros_timestamps,
+        },
+        SelfTest {
+            name: "print::tick timestamps",
+            run: tick_timestamps,
+        },
+    ];
+
+    fn timestamp(nanos: u64, format: TimestampFormat) -> String {
+        Timestamp { nanos, format }.to_string()
+    }
+
+    fn seconds_micros_timestamps() {
+        let cases = [
+            (0, "  0.000000"),
+            (999, "  0.000000"),
+            (1_000, "  0.000001"),
+            (1_234_567_890, "  1.234567"),
+            (59_999_999_999, " 59.999999"),
+            (123_456_000_001_000, "123456.000001"),
+            (u64::MAX, "18446744073.709551"),
+        ];
+
+        for (nanos, expected) in cases {
+            assert_eq!(timestamp(nanos, TimestampFormat::SecondsMicros), expected);
+        }
+    }
+
+    fn tick_timestamps() {
+        let per_sec = time::time_manager().duration_to_ticks(Duration::from_secs(1));
+        assert!(per_sec > 0);
+
+        let cases = [
+            (0, 0),
+            (1_000_000_000, per_sec),
+            (3_000_000_000, per_sec * 3),
+        ];
+
+        // right aligned, so columns of timestamps line up
+        for (nanos, ticks) in cases {
+            let formatted = timestamp(nanos, TimestampFormat::Ticks);
+            assert_eq!(formatted, format!("{:>10}", ticks));
+            assert!(formatted.len() >= 10);
+        }
+    }
+}
@@ -0,0 +1,625 @@
+// SPDX-License-Identifier: MIT
+//! Round-robin scheduler for user processes and kernel threads.
+//!
+//! The kernel itself is not preemptible, so tasks are only switched when returning from an
+//! exception taken from a task: a system call or an IRQ taken from EL0, or an IRQ taken from a
+//! kernel thread. Switching swaps the exception context that is about to be restored with the one
+//! saved for the next task, so all tasks share the same kernel exception stack.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+use crate::boot::milestone::{self, Milestone};
+use crate::exception::{self, ExceptionContext};
+use crate::exec::process_manager;
+use crate::mem::allocator::align_down;
+use crate::mem::vm::paging::PAGE_SIZE;
+use crate::mem::{virtual_memory_manager, MemoryManager, OutOfMemory};
+use crate::sched::kthread::KThread;
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+use crate::{cpu, info, time};
+
+pub mod kthread;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// How long a task runs before it's preempted in favour of the next runnable one.
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+/// The address a process returns to when it returns from its entry point. It's never mapped, so
+/// the resulting instruction abort is treated as the process exiting.
+pub use fkk::abi::PROCESS_RETURN_ADDRESS;
+
+/// Something the scheduler can run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Task {
+    /// A user process, by PID.
+    Process(usize),
+
+    /// A kernel thread, by ID.
+    KThread(usize),
+}
+
+pub struct Scheduler {
+    inner: IRQSafeNullLock<SchedulerInner>,
+}
+
+static SCHEDULER: Scheduler = Scheduler::new();
+
+#[inline(always)]
+pub fn scheduler() -> &'static Scheduler {
+    &SCHEDULER
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::Process(pid) => write!(f, "process {}", pid),
+            Task::KThread(id) => write!(f, "kthread {}", id),
+        }
+    }
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Self {
+            inner: IRQSafeNullLock::new(SchedulerInner {
+                run_queue: VecDeque::new(),
+                current: None,
+                need_resched: false,
+                exit_code: None,
+                sleep_until: None,
+                sleep_queue: Vec::new(),
+                kthreads: Vec::new(),
+                next_kthread_id: 1,
+                idle_stack_top: 0,
+                idle_since: None,
+                idle_nanos: 0,
+            }),
+        }
+    }
+
+    /// Adds a process to the back of the run queue.
+    pub fn add(&self, pid: usize) {
+        self.inner
+            .lock(|inner| inner.run_queue.push_back(Task::Process(pid)));
+    }
+
+    /// Asks for the current task to be switched out the next time it's returned to.
+    pub fn request_reschedule(&self) {
+        self.inner.lock(|inner| inner.need_resched = true);
+    }
+
+    /// Asks for the current task to be terminated the next time it's returned to.
+    pub fn request_exit(&self, code: i32) {
+        self.inner.lock(|inner| inner.exit_code = Some(code));
+    }
+
+    /// Asks for the current task to be put to sleep for `nanos` nanoseconds the next time it's
+    /// returned to. It's woken by the first scheduler tick after that, so may sleep for up to a
+    /// [`TIME_SLICE`] longer.
+    pub fn request_sleep(&self, nanos: u64) {
+        let deadline = time::now_nanos().saturating_add(nanos);
+        self.inner.lock(|inner| inner.sleep_until = Some(deadline));
+    }
+
+    /// Returns how long the idle loop has run for, in nanoseconds. Together with the kernel uptime,
+    /// this gives the CPU utilization.
+    #[allow(unused)]
+    pub fn idle_nanos(&self) -> u64 {
+        let now = time::now_nanos();
+        self.inner.lock(|inner| {
+            let current = inner
+                .idle_since
+                .map_or(0, |since| now.saturating_sub(since));
+            inner.idle_nanos + current
+        })
+    }
+
+    /// Starts the scheduler tick, and switches to the first runnable task.
+    /// If there are no runnable tasks, the kernel idles until there are.
+    pub fn start(&self) -> ! {
+        // the idle loop never returns, so its stack is never freed
+        let idle_stack = virtual_memory_manager().kernel_stack_alloc(IDLE_STACK_SIZE);
+        self.inner.lock(|inner| {
+            inner.idle_stack_top = idle_stack.end().0;
+        });
+
+        time::time_manager()
+            .set_periodic_timeout(TIME_SLICE, tick)
+            .expect("failed to set up scheduler tick");
+
+        let mut context = self.idle_context();
+        match self.inner.lock(|inner| inner.run_queue.pop_front()) {
+            Some(next) => self.switch_to(next, &mut context),
+            None => self.enter_idle(&mut context),
+        }
+
+        self.prepare_return(&mut context);
+
+        milestone::print_summary();
+        info!("sched: starting");
+
+        // Safe because the context was either created for idling, or restored from the task.
+        unsafe { exception::enter_context(context) }
+    }
+
+    /// Performs any pending exit or reschedule of the current task. Called right before returning
+    /// to a task, with the context that is about to be restored, which is updated in place if a
+    /// different task (or the idle loop) should run instead.
+    pub fn handle_pending(&self, exc: &mut ExceptionContext) {
+        let (current, exit_code, sleep_until, need_resched) = self.inner.lock(|inner| {
+            let need_resched = core::mem::take(&mut inner.need_resched);
+            (
+                inner.current,
+                inner.exit_code.take(),
+                inner.sleep_until.take(),
+                need_resched,
+            )
+        });
+
+        if let (Some(current), Some(code)) = (current, exit_code) {
+            info!("sched: {} exited with code {}", current, code);
+
+            self.release(current, code);
+            self.inner.lock(|inner| inner.current = None);
+
+            match self.inner.lock(|inner| inner.run_queue.pop_front()) {
+                Some(next) => self.switch_to(next, exc),
+                None => {
+                    info!("sched: no runnable tasks left, idling");
+                    self.enter_idle(exc);
+                }
+            }
+        } else if let (Some(current), Some(deadline)) = (current, sleep_until) {
+            self.save(current, exc);
+            self.inner.lock(|inner| {
+                inner.sleep(current, deadline);
+                inner.current = None;
+            });
+
+            // the idle loop waits for the next interrupt, which at the latest is the next tick
+            match self.inner.lock(|inner| inner.run_queue.pop_front()) {
+                Some(next) => self.switch_to(next, exc),
+                None => self.enter_idle(exc),
+            }
+        } else if need_resched {
+            // keep running the current task if nothing else is runnable
+            if let Some(next) = self.inner.lock(|inner| inner.run_queue.pop_front()) {
+                // nothing to save when coming from the idle loop
+                if let Some(current) = current {
+                    self.save(current, exc);
+                    self.inner.lock(|inner| inner.run_queue.push_back(current));
+                }
+
+                self.switch_to(next, exc);
+            }
+        }
+
+        self.prepare_return(exc);
+    }
+
+    /// Resolves a write fault at `va` on a copy-on-write page of the current process, returning
+    /// false if the current task isn't a process, or `va` isn't in a copy-on-write page.
+    pub fn handle_cow_fault(&self, va: usize) -> Result<bool, OutOfMemory> {
+        match self.inner.lock(|inner| inner.current) {
+            Some(Task::Process(pid)) => process_manager()
+                .with_process(pid, |process| process.handle_cow_fault(va))
+                .unwrap_or(Ok(false)),
+            _ => Ok(false),
+        }
+    }
+
+    /// Resolves a fault at `va` on a page of the current process that's reserved for demand paging,
+    /// returning false if the current task isn't a process, or `va` isn't in such a page.
+    pub fn handle_demand_fault(&self, va: usize) -> Result<bool, OutOfMemory> {
+        match self.inner.lock(|inner| inner.current) {
+            Some(Task::Process(pid)) => process_manager()
+                .with_process(pid, |process| process.handle_demand_fault(va))
+                .unwrap_or(Ok(false)),
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns whether every page of `len` bytes at `va` is mapped into the current process and
+    /// readable from user mode, populating any that are reserved for demand paging along the way.
+    ///
+    /// Returns false if the current task isn't a process, or there's no memory left to populate a
+    /// page.
+    pub fn is_user_range_readable(&self, va: usize, len: usize) -> bool {
+        let Some(Task::Process(pid)) = self.inner.lock(|inner| inner.current) else {
+            return false;
+        };
+        let Some(end) = va.checked_add(len) else {
+            return false;
+        };
+
+        process_manager()
+            .with_process(pid, |process| {
+                let mut page = align_down(va, PAGE_SIZE);
+                while page < end {
+                    if !matches!(process.is_user_readable(page), Ok(true)) {
+                        return false;
+                    }
+                    page += PAGE_SIZE;
+                }
+                true
+            })
+            .unwrap_or(false)
+    }
+
+    /// Reports a completed single-step of the current process, which is re-armed on return if
+    /// there are steps left.
+    #[cfg(debug_assertions)]
+    pub fn handle_single_step(&self, exc: &ExceptionContext) {
+        if let Some(Task::Process(pid)) = self.inner.lock(|inner| inner.current) {
+            let remaining = process_manager()
+                .with_process(pid, |process| process.step_completed())
+                .unwrap_or(0);
+            info!(
+                "sched: process {} stepped to {:#x} ({} steps left)",
+                pid,
+                exc.pc(),
+                remaining
+            );
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// The size of the stack the idle loop runs on. It barely uses any.
+const IDLE_STACK_SIZE: usize = 4096;
+
+struct SchedulerInner {
+    run_queue: VecDeque<Task>,
+    current: Option<Task>,
+    need_resched: bool,
+    exit_code: Option<i32>,
+    /// When the current task asked to sleep until, in nanoseconds of kernel uptime.
+    sleep_until: Option<u64>,
+    /// Sleeping tasks and when to wake them, soonest first.
+    sleep_queue: Vec<(u64, Task)>,
+    kthreads: Vec<KThread>,
+    next_kthread_id: usize,
+    idle_stack_top: usize,
+    /// When the idle loop was last switched to, while it's running.
+    idle_since: Option<u64>,
+    /// The total time spent in the idle loop, up to when it was last switched away from.
+    idle_nanos: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl SchedulerInner {
+    /// Adds `task` to the sleep queue, to be woken once the kernel uptime reaches `deadline`.
+    fn sleep(&mut self, task: Task, deadline: u64) {
+        // after any task with the same deadline, so they're woken in the order they slept
+        let index = self
+            .sleep_queue
+            .partition_point(|&(other, _)| other <= deadline);
+        self.sleep_queue.insert(index, (deadline, task));
+    }
+
+    /// Moves every task whose deadline has passed to the back of the run queue.
+    fn wake_sleepers(&mut self, now: u64) {
+        let count = self
+            .sleep_queue
+            .partition_point(|&(deadline, _)| deadline <= now);
+        for (_, task) in self.sleep_queue.drain(..count) {
+            self.run_queue.push_back(task);
+        }
+    }
+
+    fn kthread_mut(&mut self, id: usize) -> &mut KThread {
+        self.kthreads
+            .iter_mut()
+            .find(|kthread| kthread.id() == id)
+            .expect("scheduled kthread does not exist")
+    }
+}
+
+impl Scheduler {
+    /// Adds a kernel thread to the back of the run queue, returning its ID.
+    fn spawn_kthread(&self, name: &str, entry: fn()) -> usize {
+        self.inner.lock(|inner| {
+            let id = inner.next_kthread_id;
+            inner.next_kthread_id += 1;
+            inner.kthreads.push(KThread::new(id, name, entry));
+            inner.run_queue.push_back(Task::KThread(id));
+            id
+        })
+    }
+
+    /// Makes `next` the current task, replacing `exc` with its saved context. Processes also get
+    /// their address space activated, while kernel threads only use the kernel's.
+    fn switch_to(&self, next: Task, exc: &mut ExceptionContext) {
+        match next {
+            Task::Process(pid) => {
+                process_manager()
+                    .with_process(pid, |process| {
+                        process.activate();
+                        *exc = process.saved_context();
+                    })
+                    .expect("scheduled process does not exist");
+                milestone::record(Milestone::FirstUserProcess);
+            }
+            Task::KThread(id) => {
+                *exc = self
+                    .inner
+                    .lock(|inner| inner.kthread_mut(id).context().clone());
+            }
+        }
+
+        let now = time::now_nanos();
+        self.inner.lock(|inner| {
+            if let Some(since) = inner.idle_since.take() {
+                inner.idle_nanos += now.saturating_sub(since);
+            }
+            inner.current = Some(next);
+        });
+    }
+
+    /// Saves the context of `current`, which is being switched out.
+    fn save(&self, current: Task, exc: &ExceptionContext) {
+        match current {
+            Task::Process(pid) => {
+                process_manager().with_process(pid, |process| {
+                    process.save_context(exc);
+                    process.deactivate();
+                });
+            }
+            Task::KThread(id) => {
+                self.inner
+                    .lock(|inner| inner.kthread_mut(id).save_context(exc));
+            }
+        }
+    }
+
+    /// Tears down `current`, which has exited with `code`.
+    fn release(&self, current: Task, code: i32) {
+        match current {
+            Task::Process(pid) => {
+                process_manager().with_process(pid, |process| process.deactivate());
+                process_manager()
+                    .exit_process(pid, code)
+                    .expect("failed to reap exited process");
+            }
+            Task::KThread(id) => {
+                // we're on the exception stack, so the thread's own stack can go
+                self.inner
+                    .lock(|inner| inner.kthreads.retain(|kthread| kthread.id() != id));
+            }
+        }
+    }
+
+    /// Sets up the debug state for returning to the current task with `exc`.
+    #[allow(unused_variables)]
+    fn prepare_return(&self, exc: &mut ExceptionContext) {
+        #[cfg(debug_assertions)]
+        {
+            let stepping = match self.inner.lock(|inner| inner.current) {
+                Some(Task::Process(pid)) => process_manager()
+                    .with_process(pid, |process| process.is_stepping())
+                    .unwrap_or(false),
+                _ => false,
+            };
+
+            exc.set_software_step(stepping);
+            cpu::set_software_step(stepping);
+        }
+    }
+
+    /// Switches to the idle loop, replacing `exc`, for when there's nothing runnable.
+    fn enter_idle(&self, exc: &mut ExceptionContext) {
+        *exc = self.idle_context();
+
+        let now = time::now_nanos();
+        self.inner.lock(|inner| inner.idle_since = Some(now));
+    }
+
+    /// A context running the idle loop, which can be switched away from like a kernel thread.
+    fn idle_context(&self) -> ExceptionContext {
+        let stack_top = self.inner.lock(|inner| inner.idle_stack_top);
+        ExceptionContext::new_kernel(idle as usize, 0, stack_top)
+    }
+}
+
+/// Called from interrupt context at the end of every time slice. Anything that takes the timer over
+/// for a while has to keep calling this, and hand the timer back to it afterwards.
+pub(crate) fn tick() {
+    let now = time::now_nanos();
+    scheduler().inner.lock(|inner| {
+        inner.wake_sleepers(now);
+        inner.need_resched = true;
+    });
+}
+
+/// Runs when there are no runnable tasks, sleeping the core until the next interrupt, which at the
+/// latest is the next tick. The interrupt may then switch to a task that has become runnable.
+extern "C" fn idle() -> ! {
+    loop {
+        // the idle context starts with interrupts unmasked, but make sure they can wake us
+        exception::asynchronous::local_irq_unmask();
+        cpu::wait_for_interrupt();
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use core::arch::global_asm;
+    use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use core::{ptr, slice};
+
+    use fkk::abi::{SYS_EXIT, SYS_YIELD};
+
+    use super::kthread::spawn_kthread;
+    use super::scheduler;
+    use crate::cpu;
+    use crate::exec::process_manager;
+    use crate::exec::selftest::{is_address_space_active, spawn_code, SHARED_PAGE_ADDRESS};
+    use crate::mem::SharedPage;
+    use crate::selftest::SelfTest;
+
+    pub const TESTS: &[SelfTest] = &[
+        SelfTest {
+            name: "sched::round-robin alternates yielding processes",
+            run: round_robin_alternates,
+        },
+        SelfTest {
+            name: "sched::kthread interleaves with a process",
+            run: kthread_interleaves_with_process,
+        },
+    ];
+
+    /// How many times each task takes a turn.
+    const TURNS: u64 = 50;
+
+    // Takes a ticket from the counter at the start of the shared page in x19, logs the process ID
+    // in x20 in the ticket's slot after it, and yields, x21 times before exiting. The syscalls
+    // clobber x0, so everything is kept in callee-saved registers.
+    global_asm!(
+        ".pushsection .rodata.selftest_take_turns, \"a\"",
+        ".balign 4",
+        "__selftest_take_turns_start:",
+        "1: ldxr x3, [x19]",
+        "   add x4, x3, #1",
+        "   stxr w5, x4, [x19]",
+        "   cbnz w5, 1b",
+        "   add x4, x19, #8",
+        "   str x20, [x4, x3, lsl #3]",
+        "   mov x8, #{yield}",
+        "   svc #0",
+        "   subs x21, x21, #1",
+        "   b.ne 1b",
+        "   mov x0, #0",
+        "   mov x8, #{exit}",
+        "   svc #0",
+        "__selftest_take_turns_end:",
+        ".popsection",
+        yield = const SYS_YIELD,
+        exit = const SYS_EXIT,
+    );
+
+    extern "C" {
+        static __selftest_take_turns_start: u8;
+        static __selftest_take_turns_end: u8;
+    }
+
+    fn take_turns_code() -> &'static [u8] {
+        // Safe because the symbols delimit the code assembled above, which is never written.
+        unsafe {
+            let start = ptr::addr_of!(__selftest_take_turns_start);
+            let end = ptr::addr_of!(__selftest_take_turns_end);
+            slice::from_raw_parts(start, end as usize - start as usize)
+        }
+    }
+
+    /// Returns the turns logged in `shared` by [`take_turns_code`], checking that there are
+    /// `count` of them.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may be taking turns with the page anymore.
+    unsafe fn turns_log(shared: &SharedPage, count: u64) -> &[u64] {
+        let page = shared.physical_address().to_direct_map_virtual().0 as *const u64;
+        assert_eq!(ptr::read_volatile(page), count, "not every turn was logged");
+        slice::from_raw_parts(page.add(1), count as usize)
+    }
+
+    fn assert_alternates(log: &[u64]) {
+        for (turn, pair) in log.windows(2).enumerate() {
+            assert_ne!(
+                pair[0], pair[1],
+                "task {} ran twice in a row at turn {}",
+                pair[0], turn
+            );
+        }
+    }
+
+    fn round_robin_alternates() {
+        let shared = SharedPage::new().expect("failed to allocate shared page");
+        let code = take_turns_code();
+
+        let pids = [1, 2].map(|id| {
+            let registers = [(19, SHARED_PAGE_ADDRESS as u64), (20, id), (21, TURNS)];
+            spawn_code("take_turns", code, &shared, &registers)
+        });
+        for pid in pids {
+            assert_eq!(process_manager().wait(pid), Ok(0));
+            // the process's mapping of the page isn't released when it exits
+            shared.release();
+        }
+
+        // Safe because both processes have exited.
+        assert_alternates(unsafe { turns_log(&shared, 2 * TURNS) });
+    }
+
+    /// The ID [`count_turns`] logs its turns with, which no process is given.
+    const KTHREAD_ID: u64 = 0;
+
+    /// The direct map address of the page [`count_turns`] takes turns with.
+    static KTHREAD_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+    /// The process [`count_turns`] takes turns with.
+    static KTHREAD_PEER: AtomicUsize = AtomicUsize::new(0);
+
+    static KTHREAD_DONE: AtomicBool = AtomicBool::new(false);
+
+    /// Takes turns like [`take_turns_code`] does, from a kernel thread, checking on each turn that
+    /// the address space of the process it's taking turns with isn't active.
+    fn count_turns() {
+        let page = KTHREAD_PAGE.load(Ordering::Acquire) as *mut u64;
+        // Safe because the page outlives this thread, and the counter is only updated atomically.
+        let counter = unsafe { &*(page as *const AtomicU64) };
+        let peer = KTHREAD_PEER.load(Ordering::Relaxed);
+
+        for _ in 0..TURNS {
+            let ticket = counter.fetch_add(1, Ordering::Relaxed);
+            // Safe because the ticket gives this thread the slot to itself.
+            unsafe { page.add(1 + ticket as usize).write_volatile(KTHREAD_ID) };
+
+            assert!(
+                !is_address_space_active(peer),
+                "kthread is running in the address space of process {}",
+                peer
+            );
+
+            scheduler().request_reschedule();
+            cpu::wait_for_interrupt();
+        }
+
+        KTHREAD_DONE.store(true, Ordering::Release);
+        cpu::send_event();
+    }
+
+    fn kthread_interleaves_with_process() {
+        let shared = SharedPage::new().expect("failed to allocate shared page");
+
+        let registers = [(19, SHARED_PAGE_ADDRESS as u64), (20, 1), (21, TURNS)];
+        let pid = spawn_code("take_turns", take_turns_code(), &shared, &registers);
+
+        let page = shared.physical_address().to_direct_map_virtual().0;
+        KTHREAD_PAGE.store(page, Ordering::Release);
+        KTHREAD_PEER.store(pid, Ordering::Relaxed);
+        spawn_kthread("count_turns", count_turns);
+
+        assert_eq!(process_manager().wait(pid), Ok(0));
+        shared.release();
+        while !KTHREAD_DONE.load(Ordering::Acquire) {
+            cpu::wait_for_event();
+        }
+
+        // Safe because the process has exited, and the kthread is done.
+        assert_alternates(unsafe { turns_log(&shared, 2 * TURNS) });
+    }
+}
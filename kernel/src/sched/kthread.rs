@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: MIT
+//! Kernel threads: schedulable contexts that run kernel code on their own stack, in the kernel's
+//! address space.
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+
+use crate::cpu;
+use crate::exception::ExceptionContext;
+use crate::mem::vm::paging::VirtualMemoryRegion;
+use crate::mem::{virtual_memory_manager, MemoryManager};
+use crate::sched::scheduler;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// The size of each kernel thread's stack.
+pub const KTHREAD_STACK_SIZE: usize = 16 * 1024;
+
+pub struct KThread {
+    id: usize,
+    name: String,
+    /// Allocated in the kernel's stack window, with a guard page below it.
+    stack: VirtualMemoryRegion,
+    /// The context of this thread, saved whenever it's switched out by the scheduler.
+    context: ExceptionContext,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Creates a kernel thread which runs `entry`, and adds it to the scheduler's run queue.
+/// The thread exits when `entry` returns. Returns the ID of the new thread.
+#[allow(unused)]
+pub fn spawn_kthread(name: &str, entry: fn()) -> usize {
+    scheduler().spawn_kthread(name, entry)
+}
+
+impl KThread {
+    pub fn new(id: usize, name: &str, entry: fn()) -> Self {
+        let stack = virtual_memory_manager().kernel_stack_alloc(KTHREAD_STACK_SIZE);
+        let stack_top = stack.end().0;
+
+        Self {
+            id,
+            name: name.to_owned(),
+            stack,
+            context: ExceptionContext::new_kernel(
+                kthread_start as usize,
+                entry as usize,
+                stack_top,
+            ),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    #[allow(unused)]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn context(&self) -> &ExceptionContext {
+        &self.context
+    }
+
+    pub fn save_context(&mut self, context: &ExceptionContext) {
+        self.context = context.clone();
+    }
+}
+
+impl Drop for KThread {
+    fn drop(&mut self) {
+        // Safe because threads are only dropped once they've been switched out for good.
+        unsafe { virtual_memory_manager().kernel_stack_free(&self.stack) };
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+/// The first code run by every kernel thread, with the thread's entry point in `x0`.
+extern "C" fn kthread_start(entry: usize) -> ! {
+    // Safe because the address came from a `fn()` in `KThread::new`.
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    entry();
+
+    // the thread is torn down when it's next switched out, at the end of its time slice
+    scheduler().request_exit(0);
+    cpu::wait_forever()
+}
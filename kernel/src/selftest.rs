@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: MIT
+//! In-kernel self-tests, for code that can only be exercised on the target.
+//!
+//! Only built with the `selftest` feature. The tests run in a kernel thread spawned by
+//! `kernel_main` once every driver is loaded, so they can spawn tasks and wait for them, and a
+//! failing test panics.
+
+use crate::info;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A single self-test, which panics if it fails.
+pub struct SelfTest {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Runs every self-test, in the order they're listed in [`SUITES`].
+pub fn run_all() {
+    let mut count = 0;
+    for test in SUITES.iter().flat_map(|suite| suite.iter()) {
+        info!("selftest: {}", test.name);
+        (test.run)();
+        count += 1;
+    }
+
+    info!("selftest: all {} tests passed", count);
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// The self-tests of each module.
+const SUITES: &[&[SelfTest]] = &[
+    crate::boot::milestone::selftest::TESTS,
+    crate::driver::interrupt::gicv2::selftest::TESTS,
+    crate::driver::selftest::TESTS,
+    crate::driver::virtio::selftest::TESTS,
+    crate::dt::selftest::TESTS,
+    #[cfg(debug_assertions)]
+    crate::exception::fault::selftest::TESTS,
+    crate::exec::selftest::TESTS,
+    crate::mem::selftest::TESTS,
+    crate::mem::allocator::selftest::TESTS,
+    crate::mem::allocator::linked_list::selftest::TESTS,
+    crate::mem::allocator::slab::selftest::TESTS,
+    crate::mem::copy::selftest::TESTS,
+    crate::mem::vm::paging::selftest::TESTS,
+    crate::print::selftest::TESTS,
+    crate::sched::selftest::TESTS,
+    crate::sync::selftest::TESTS,
+];
@@ -1,9 +1,17 @@
 // SPDX-License-Identifier: MIT
 use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::sync::interface::ReadWriteEx;
 use crate::{exception, EARLY_INIT_COMPLETE};
 
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// Data which is written during early init, and only read afterwards.
+///
+/// Writing requires an [`EarlyInit`] token, so writing after early init is a build failure rather
+/// than a runtime panic. [`write_checked`](Self::write_checked) is the fallback for the rare
+/// writer that can't be handed the token, and checks the boot phase at runtime instead.
 pub struct InitStateLock<T>
 where
     T: ?Sized,
@@ -11,6 +19,18 @@ where
     data: UnsafeCell<T>,
 }
 
+/// Proof that the kernel is still in early init.
+///
+/// Exactly one is created, by `kernel_init`, and lent out to the code that sets up state guarded by
+/// an [`InitStateLock`]. Early init ends when it's consumed by [`complete`](Self::complete), after
+/// which none of those borrows can be used.
+pub struct EarlyInit {
+    _private: (),
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
 unsafe impl<T> Send for InitStateLock<T> where T: ?Sized + Send {}
 unsafe impl<T> Sync for InitStateLock<T> where T: ?Sized + Send {}
 
@@ -20,28 +40,68 @@ impl<T> InitStateLock<T> {
             data: UnsafeCell::new(data),
         }
     }
-}
 
-impl<T> ReadWriteEx for InitStateLock<T> {
-    type Data = T;
+    /// Grants temporary mutable access to the data, during early init.
+    pub fn write<'a, R>(&'a self, _early_init: &EarlyInit, f: impl FnOnce(&'a mut T) -> R) -> R {
+        // Safe because only the boot core runs during early init, and holds the only token.
+        unsafe { self.write_unchecked(f) }
+    }
 
-    fn write<'a, R>(&'a self, f: impl FnOnce(&'a mut Self::Data) -> R) -> R {
+    /// Like [`write`](Self::write), but for callers that can't be handed the [`EarlyInit`] token.
+    /// Panics if early init is already complete.
+    #[allow(unused)]
+    pub fn write_checked<'a, R>(&'a self, f: impl FnOnce(&'a mut T) -> R) -> R {
         assert!(
-            !EARLY_INIT_COMPLETE.load(core::sync::atomic::Ordering::Relaxed),
+            !EARLY_INIT_COMPLETE.load(Ordering::Relaxed),
             "Attempted to write to init state lock after early init complete"
         );
 
+        // Safe because early init isn't complete yet, so only the boot core is running.
+        unsafe { self.write_unchecked(f) }
+    }
+
+    /// Grants temporary immutable access to the data.
+    pub fn read<'a, R>(&'a self, f: impl FnOnce(&'a T) -> R) -> R {
+        let data = unsafe { &*self.data.get() };
+        f(data)
+    }
+}
+
+impl EarlyInit {
+    /// Creates the early init token.
+    ///
+    /// Panics if called more than once.
+    ///
+    /// # Safety
+    ///
+    /// - Must only be called by the boot core, before any other core is started.
+    pub unsafe fn new() -> Self {
+        static CREATED: AtomicBool = AtomicBool::new(false);
         assert!(
-            !exception::asynchronous::is_local_irq_masked(),
-            "cannot write to InitStateLock while interrupts are unmasked"
+            !CREATED.swap(true, Ordering::Relaxed),
+            "early init token created more than once"
         );
 
-        let data = unsafe { &mut *self.data.get() };
-        f(data)
+        Self { _private: () }
     }
 
-    fn read<'a, R>(&'a self, f: impl FnOnce(&'a Self::Data) -> R) -> R {
-        let data = unsafe { &*self.data.get() };
+    /// Ends early init, after which [`InitStateLock`]s can no longer be written to.
+    pub fn complete(self) {
+        EARLY_INIT_COMPLETE.store(true, Ordering::Relaxed);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl<T> InitStateLock<T> {
+    unsafe fn write_unchecked<'a, R>(&'a self, f: impl FnOnce(&'a mut T) -> R) -> R {
+        assert!(
+            !exception::asynchronous::is_local_irq_masked(),
+            "cannot write to InitStateLock while interrupts are unmasked"
+        );
+
+        let data = &mut *self.data.get();
         f(data)
     }
 }
@@ -27,7 +27,7 @@ impl<T> Mutex for IRQSafeNullLock<T> {
 
     fn lock<'a, R>(&'a self, f: impl FnOnce(&'a mut Self::Data) -> R) -> R {
         // note: this is very obviously not thread safe
-        // todo: implement concurrency later once we get to SMP/interrupts
+        // this stays around for single-core paths; data shared between cores should use Spinlock
         let data = unsafe { &mut *self.data.get() };
 
         exception::asynchronous::exec_with_masked_irqs(|| f(data))
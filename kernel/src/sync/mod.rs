@@ -2,9 +2,13 @@
 pub use self::init::*;
 pub use self::irq_safe_null::*;
 pub use self::once_cell::*;
+pub use self::rwlock::*;
+pub use self::spinlock::*;
 
 mod init;
 mod irq_safe_null;
 mod once_cell;
+mod rwlock;
+mod spinlock;
 
 pub mod interface;
@@ -1,11 +1,16 @@
 // SPDX-License-Identifier: MIT
 use core::cell::UnsafeCell;
 use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::cpu;
 
 //--------------------------------------------------------------------------------------------------
 // Public definitions
 //--------------------------------------------------------------------------------------------------
+/// A cell which can be written to only once, and read from any core once it's been written.
 pub struct OnceCell<T> {
+    state: AtomicU8,
     data: UnsafeCell<Option<T>>,
 }
 
@@ -18,20 +23,51 @@ unsafe impl<T> Sync for OnceCell<T> where T: Send {}
 impl<T> OnceCell<T> {
     pub const fn new() -> Self {
         Self {
+            state: AtomicU8::new(UNINIT),
             data: UnsafeCell::new(None),
         }
     }
 
+    /// Initialises the cell with `value`.
+    ///
+    /// Panics if the cell has already been initialised, or is being initialised by another core.
     pub fn set(&self, value: T) {
-        let data = unsafe { &mut *self.data.get() };
-        assert!(data.is_none(), "OnceCell already initialized");
-        *data = Some(value);
+        assert!(self.try_claim(), "OnceCell already initialized");
+
+        // Safe because claiming the cell gives us exclusive access until it's published.
+        unsafe { self.publish(value) };
     }
 
+    /// Returns the value of the cell, or `None` if it hasn't been fully initialised yet.
     pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != INIT {
+            return None;
+        }
+
+        // Safe because the value is never written again once published.
         let data = unsafe { &*self.data.get() };
         data.as_ref()
     }
+
+    /// Returns the value of the cell, initialising it with `f` first if it's uninitialised.
+    ///
+    /// `f` is run exactly once, even if several cores race to initialise the cell; the losers wait
+    /// for the winner to finish. Calling this again from within `f` will deadlock.
+    #[allow(unused)]
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if self.try_claim() {
+            // Safe because claiming the cell gives us exclusive access until it's published.
+            unsafe { self.publish(f()) };
+        }
+
+        loop {
+            if let Some(value) = self.get() {
+                return value;
+            }
+
+            cpu::nop();
+        }
+    }
 }
 
 impl<T> Deref for OnceCell<T> {
@@ -42,3 +78,33 @@ impl<T> Deref for OnceCell<T> {
             .unwrap_or_else(|| panic!("OnceCell not initialized"))
     }
 }
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const INIT: u8 = 2;
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl<T> OnceCell<T> {
+    /// Attempts to move the cell from uninitialised to initialising, returning whether this call
+    /// won the right to write the value.
+    fn try_claim(&self) -> bool {
+        self.state
+            .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Writes the value, then makes it visible to `get`.
+    ///
+    /// # Safety
+    ///
+    /// - The caller must have claimed the cell with `try_claim`.
+    unsafe fn publish(&self, value: T) {
+        *self.data.get() = Some(value);
+        self.state.store(INIT, Ordering::Release);
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: MIT
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::interface::ReadWriteEx;
+use crate::{cpu, exception};
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A reader-writer spinlock, safe to share between cores.
+///
+/// Any number of cores may read the data at once, while writing is exclusive. Once a writer is
+/// waiting, new readers hold off until it's been through, so a steady stream of readers can't
+/// starve writers. As with `Spinlock`, IRQs are masked on the local core while the lock is held,
+/// and waiting cores sleep in `wfe` between checks.
+///
+/// The lock is not re-entrant: taking it again from within `read` or `write` may deadlock.
+pub struct RwSpinlock<T>
+where
+    T: ?Sized,
+{
+    /// The writer bits, and the number of readers above them.
+    state: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Send for RwSpinlock<T> where T: ?Sized + Send {}
+unsafe impl<T> Sync for RwSpinlock<T> where T: ?Sized + Send + Sync {}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+#[allow(unused)]
+impl<T> RwSpinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T> ReadWriteEx for RwSpinlock<T> {
+    type Data = T;
+
+    fn write<'a, R>(&'a self, f: impl FnOnce(&'a mut Self::Data) -> R) -> R {
+        exception::asynchronous::exec_with_masked_irqs(|| {
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                // taking the lock clears the waiting bit; any other waiting writers set it again
+                if state & (WRITER | READERS_MASK) == 0 {
+                    match self.state.compare_exchange_weak(
+                        state,
+                        WRITER,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break,
+                        Err(actual) => {
+                            state = actual;
+                            continue;
+                        }
+                    }
+                }
+
+                if state & WRITER_WAITING == 0 {
+                    self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+                }
+
+                cpu::wait_for_event();
+                state = self.state.load(Ordering::Relaxed);
+            }
+
+            // Safe because holding the writer bit grants exclusive access to the data.
+            let data = unsafe { &mut *self.data.get() };
+            let result = f(data);
+
+            self.state.fetch_and(!WRITER, Ordering::Release);
+            cpu::send_event();
+
+            result
+        })
+    }
+
+    fn read<'a, R>(&'a self, f: impl FnOnce(&'a Self::Data) -> R) -> R {
+        exception::asynchronous::exec_with_masked_irqs(|| {
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                if state & (WRITER | WRITER_WAITING) != 0 {
+                    cpu::wait_for_event();
+                    state = self.state.load(Ordering::Relaxed);
+                    continue;
+                }
+
+                assert!(state & READERS_MASK != READERS_MASK, "too many readers");
+                match self.state.compare_exchange_weak(
+                    state,
+                    state + READER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => state = actual,
+                }
+            }
+
+            // Safe because no writer can hold the lock while there are readers.
+            let data = unsafe { &*self.data.get() };
+            let result = f(data);
+
+            // only wake anyone up once the last reader is out
+            if self.state.fetch_sub(READER, Ordering::Release) & READERS_MASK == READER {
+                cpu::send_event();
+            }
+
+            result
+        })
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+/// Set while a writer holds the lock.
+const WRITER: usize = 1 << 0;
+
+/// Set while a writer is waiting for the lock, which keeps new readers out.
+const WRITER_WAITING: usize = 1 << 1;
+
+/// A single reader, counted in the bits above the writer bits.
+const READER: usize = 1 << 2;
+
+const READERS_MASK: usize = !(WRITER | WRITER_WAITING);
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: MIT
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::interface::Mutex;
+use crate::{cpu, exception};
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+/// A ticket spinlock, safe to share between cores.
+///
+/// Like `IRQSafeNullLock`, IRQs are masked on the local core while the lock is held. Waiting cores
+/// are served in the order they arrived, and sleep in `wfe` between checks rather than hammering
+/// the bus.
+///
+/// The lock is not re-entrant: locking it again from within `lock` will deadlock.
+pub struct Spinlock<T>
+where
+    T: ?Sized,
+{
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Send for Spinlock<T> where T: ?Sized + Send {}
+unsafe impl<T> Sync for Spinlock<T> where T: ?Sized + Send {}
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+#[allow(unused)]
+impl<T> Spinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T> Mutex for Spinlock<T> {
+    type Data = T;
+
+    fn lock<'a, R>(&'a self, f: impl FnOnce(&'a mut Self::Data) -> R) -> R {
+        exception::asynchronous::exec_with_masked_irqs(|| {
+            let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+            while self.now_serving.load(Ordering::Acquire) != ticket {
+                cpu::wait_for_event();
+            }
+
+            // Safe because holding the current ticket grants exclusive access to the data.
+            let data = unsafe { &mut *self.data.get() };
+            let result = f(data);
+
+            // only the lock holder ever writes to now_serving, so a plain store is enough
+            self.now_serving
+                .store(ticket.wrapping_add(1), Ordering::Release);
+            cpu::send_event();
+
+            result
+        })
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftest {
+    use core::hint;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use core::time::Duration;
+
+    use super::Spinlock;
+    use crate::sched::{self, TIME_SLICE};
+    use crate::selftest::SelfTest;
+    use crate::sync::interface::Mutex;
+    use crate::time;
+
+    pub const TESTS: &[SelfTest] = &[SelfTest {
+        name: "spinlock::increments from the timer IRQ and a thread aren't lost",
+        run: irq_and_thread_increments,
+    }];
+
+    /// How many increments the timer makes. The thread keeps going until it's done.
+    const INCREMENTS: u64 = 500;
+
+    /// How often the timer fires while the test has it, so it races the thread many times over.
+    const INTERVAL: Duration = Duration::from_micros(200);
+
+    /// How long the timer may take to make its increments, which is far longer than it should need.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    static COUNTER: Spinlock<u64> = Spinlock::new(0);
+    static IRQ_INCREMENTS: AtomicU64 = AtomicU64::new(0);
+
+    /// Increments the counter non-atomically, spinning in between to widen the window for a lost
+    /// update if the lock didn't keep the other side out.
+    fn increment() {
+        COUNTER.lock(|count| {
+            let value = *count;
+            for _ in 0..100 {
+                hint::spin_loop();
+            }
+            *count = value + 1;
+        });
+    }
+
+    fn timer_increment() {
+        if IRQ_INCREMENTS.load(Ordering::Relaxed) < INCREMENTS {
+            increment();
+            IRQ_INCREMENTS.fetch_add(1, Ordering::Release);
+        }
+
+        // the scheduler still needs its tick while the test has the timer
+        sched::tick();
+    }
+
+    fn irq_and_thread_increments() {
+        COUNTER.lock(|count| *count = 0);
+        IRQ_INCREMENTS.store(0, Ordering::Relaxed);
+
+        time::time_manager()
+            .set_periodic_timeout(INTERVAL, timer_increment)
+            .expect("failed to take over the timer");
+
+        // keep contending for the lock for as long as the timer is still incrementing
+        let deadline = time::time_manager().uptime_kernel() + TIMEOUT;
+        let mut thread_increments = 0;
+        let mut finished = false;
+        while !finished && time::time_manager().uptime_kernel() < deadline {
+            increment();
+            thread_increments += 1;
+            finished = IRQ_INCREMENTS.load(Ordering::Acquire) == INCREMENTS;
+        }
+
+        time::time_manager()
+            .set_periodic_timeout(TIME_SLICE, sched::tick)
+            .expect("failed to give the timer back to the scheduler");
+
+        assert!(
+            finished,
+            "timer only made {} increments",
+            IRQ_INCREMENTS.load(Ordering::Relaxed)
+        );
+        assert_eq!(COUNTER.lock(|count| *count), thread_increments + INCREMENTS);
+    }
+}
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: MIT
+//! System call dispatch.
+//!
+//! The system call numbers, calling convention and error codes are defined in [`fkk::abi`], which
+//! is shared with user programs.
+
+use fkk::abi::{
+    EBADF, EFAULT, EINVAL, ENOSYS, STDERR, STDOUT, SYSCALLS, SYSCALL_ARG_COUNT, SYSCALL_COUNT,
+    SYS_EXIT, SYS_NANOSLEEP, SYS_WRITE, SYS_YIELD,
+};
+
+use crate::console;
+use crate::mem::vm::paging::VA_BITS;
+use crate::sched;
+
+//--------------------------------------------------------------------------------------------------
+// Public definitions
+//--------------------------------------------------------------------------------------------------
+pub type SyscallArgs = [u64; SYSCALL_ARG_COUNT];
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Calls the handler for system call `nr`, returning its result, or `-ENOSYS` if there is no such
+/// system call.
+pub fn dispatch(nr: usize, args: SyscallArgs) -> isize {
+    match SYSCALL_TABLE.get(nr) {
+        Some(handler) => handler(&args),
+        None => -ENOSYS,
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+type SyscallHandler = fn(&SyscallArgs) -> isize;
+
+/// The handler for each system call. There must be exactly one for every number in the ABI, which
+/// is checked when [`SYSCALL_TABLE`] is built.
+const SYSCALL_HANDLERS: [(usize, SyscallHandler); SYSCALL_COUNT] = [
+    (SYS_EXIT, sys_exit),
+    (SYS_WRITE, sys_write),
+    (SYS_YIELD, sys_yield),
+    (SYS_NANOSLEEP, sys_nanosleep),
+];
+
+/// Indexed by system call number.
+static SYSCALL_TABLE: [SyscallHandler; SYSCALL_COUNT] = {
+    let mut table: [SyscallHandler; SYSCALL_COUNT] = [SYSCALL_HANDLERS[0].1; SYSCALL_COUNT];
+    let mut seen = [false; SYSCALL_COUNT];
+
+    // SYSCALL_COUNT handlers, each for a distinct number below SYSCALL_COUNT, cover every number
+    let mut i = 0;
+    while i < SYSCALL_COUNT {
+        let (nr, handler) = SYSCALL_HANDLERS[i];
+        assert!(nr < SYSCALL_COUNT, "system call number out of range");
+        assert!(!seen[nr], "system call has more than one handler");
+        seen[nr] = true;
+        table[nr] = handler;
+        i += 1;
+    }
+
+    // and every number the ABI lists has one
+    let mut i = 0;
+    while i < SYSCALL_COUNT {
+        assert!(seen[SYSCALLS[i]], "system call has no handler");
+        i += 1;
+    }
+    table
+};
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+fn sys_exit(args: &SyscallArgs) -> isize {
+    // the process is torn down on the way back to EL0, so it never sees the return value
+    sched::scheduler().request_exit(args[0] as i32);
+    0
+}
+
+fn sys_yield(_args: &SyscallArgs) -> isize {
+    sched::scheduler().request_reschedule();
+    0
+}
+
+fn sys_nanosleep(args: &SyscallArgs) -> isize {
+    let nanos = args[0];
+    if nanos == 0 {
+        return sys_yield(args);
+    }
+
+    // the process is put to sleep on the way back to EL0, and nothing wakes it early yet, so the
+    // time left is always 0
+    sched::scheduler().request_sleep(nanos);
+    0
+}
+
+fn sys_write(args: &SyscallArgs) -> isize {
+    let (fd, buf, len) = (args[0], args[1] as usize, args[2] as usize);
+    if fd != STDOUT && fd != STDERR {
+        return -EBADF;
+    }
+
+    // the buffer must lie entirely within the lower (user) half of the address space
+    match buf.checked_add(len) {
+        Some(end) if end <= 1 << VA_BITS => {}
+        _ => return -EFAULT,
+    }
+
+    if !sched::scheduler().is_user_range_readable(buf, len) {
+        return -EFAULT;
+    }
+
+    // Safe because every page of the range was checked to be mapped and readable in the user half,
+    // which is the calling process's address space while it's running.
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+    match core::str::from_utf8(bytes) {
+        Ok(s) => {
+            if console::console().write_fmt(format_args!("{}", s)).is_err() {
+                return -EINVAL;
+            }
+
+            len as isize
+        }
+        Err(_) => -EINVAL,
+    }
+}
@@ -1,6 +1,10 @@
 // SPDX-License-Identifier: MIT
 use core::time::Duration;
 
+use crate::sync::interface::Mutex;
+use crate::sync::IRQSafeNullLock;
+use crate::warn;
+
 pub(crate) use arch_time::KernelTimerData;
 pub(crate) use arch_time::KERNEL_TIMER_DATA;
 
@@ -8,7 +12,12 @@ pub(crate) use arch_time::KERNEL_TIMER_DATA;
 #[path = "arch/aarch64/time.rs"]
 mod arch_time;
 
-pub struct TimeManager;
+/// A function to be called from interrupt context when a timeout expires.
+pub type TimeoutCallback = fn();
+
+pub struct TimeManager {
+    timeout: IRQSafeNullLock<TimeoutState>,
+}
 
 static TIME_MANAGER: TimeManager = TimeManager::new();
 
@@ -16,10 +25,86 @@ pub fn time_manager() -> &'static TimeManager {
     &TIME_MANAGER
 }
 
+/// The length of the longest string produced by [`format_uptime`] and [`format_nanos`].
+pub const UPTIME_STR_LEN: usize = 27;
+
+/// The time since the kernel was loaded, in nanoseconds.
+///
+/// This is monotonic, and cheaper than [`TimeManager::uptime_kernel`], for timestamps that are
+/// taken often.
+pub fn now_nanos() -> u64 {
+    arch_time::uptime_kernel_nanos()
+}
+
+/// The raw value of the architectural timer's counter.
+///
+/// This is as cheap as reading the time gets, for intervals measured on hot paths. Differences
+/// between two readings are converted with [`ticks_to_nanos`].
+#[inline(always)]
+pub fn now_ticks() -> u64 {
+    arch_time::counter_ticks()
+}
+
+/// Converts a number of architectural timer ticks into nanoseconds.
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    arch_time::ticks_to_nanos(ticks)
+}
+
+/// Writes the current uptime into `buf` as seconds and microseconds, e.g. `  1.234567`, as used
+/// in log messages, and returns the written part of `buf`.
+///
+/// Panics if `buf` is shorter than [`UPTIME_STR_LEN`].
+#[allow(unused)]
+pub fn format_uptime(buf: &mut [u8]) -> &str {
+    format_nanos(now_nanos(), buf)
+}
+
+/// Like [`format_uptime`], but formats the given number of nanoseconds.
+pub fn format_nanos(nanos: u64, buf: &mut [u8]) -> &str {
+    assert!(buf.len() >= UPTIME_STR_LEN, "uptime buffer too small");
+
+    let mut secs = nanos / 1_000_000_000;
+    let mut micros = nanos % 1_000_000_000 / 1000;
+
+    // written backwards from the end, with the seconds padded to at least three characters
+    let mut pos = UPTIME_STR_LEN;
+    for _ in 0..6 {
+        pos -= 1;
+        buf[pos] = b'0' + (micros % 10) as u8;
+        micros /= 10;
+    }
+
+    pos -= 1;
+    buf[pos] = b'.';
+
+    let secs_end = pos;
+    loop {
+        pos -= 1;
+        buf[pos] = b'0' + (secs % 10) as u8;
+        secs /= 10;
+        if secs == 0 {
+            break;
+        }
+    }
+
+    while secs_end - pos < 3 {
+        pos -= 1;
+        buf[pos] = b' ';
+    }
+
+    // Safe because only ASCII has been written.
+    unsafe { core::str::from_utf8_unchecked(&buf[pos..UPTIME_STR_LEN]) }
+}
+
 #[allow(unused)]
 impl TimeManager {
     pub const fn new() -> Self {
-        Self
+        Self {
+            timeout: IRQSafeNullLock::new(TimeoutState {
+                callback: None,
+                interval: None,
+            }),
+        }
     }
 
     /// The timer resolution.
@@ -38,8 +123,108 @@ impl TimeManager {
         arch_time::uptime_kernel()
     }
 
+    /// The number of architectural timer ticks in the given duration.
+    pub fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        arch_time::duration_to_ticks(duration)
+    }
+
     /// Spin for the given duration.
     pub fn spin_for(&self, duration: Duration) {
         arch_time::spin_for(duration)
     }
+
+    /// Spin until the kernel uptime, as returned by [`uptime_kernel`](Self::uptime_kernel), reaches
+    /// `deadline`. Returns `false` if it already had.
+    ///
+    /// Unlike calling [`spin_for`](Self::spin_for) in a loop, waits against an absolute deadline
+    /// don't drift by however long each iteration took.
+    pub fn spin_until(&self, deadline: Duration) -> bool {
+        arch_time::spin_until(deadline)
+    }
+
+    /// Spin until `condition` returns `true`, or the kernel uptime reaches `deadline`. Returns
+    /// `false` on timeout.
+    ///
+    /// This is meant for polling device registers with a bounded wait.
+    pub fn poll_until(&self, deadline: Duration, condition: impl FnMut() -> bool) -> bool {
+        arch_time::poll_until(deadline, condition)
+    }
+
+    /// Calls `callback` from interrupt context once `duration` has elapsed.
+    ///
+    /// Only one timeout can be pending at a time; setting a new one replaces the previous one.
+    pub fn set_timeout(
+        &self,
+        duration: Duration,
+        callback: TimeoutCallback,
+    ) -> Result<(), &'static str> {
+        self.arm_timeout(duration, None, callback)
+    }
+
+    /// Calls `callback` from interrupt context every `interval`, until cancelled.
+    ///
+    /// Only one timeout can be pending at a time; setting a new one replaces the previous one.
+    pub fn set_periodic_timeout(
+        &self,
+        interval: Duration,
+        callback: TimeoutCallback,
+    ) -> Result<(), &'static str> {
+        self.arm_timeout(interval, Some(interval), callback)
+    }
+
+    /// Cancels any pending timeout.
+    pub fn cancel_timeout(&self) {
+        self.timeout.lock(|state| {
+            arch_time::cancel_timeout();
+            state.callback = None;
+            state.interval = None;
+        });
+    }
+
+    /// Handles an expired timeout. This is called from the timer's interrupt handler.
+    pub(crate) fn handle_timeout(&self) {
+        let callback = self.timeout.lock(|state| {
+            let callback = state.callback;
+
+            match state.interval {
+                Some(interval) => {
+                    if let Err(x) = arch_time::rearm_timeout(interval) {
+                        warn!("failed to re-arm periodic timeout: {}", x);
+                        arch_time::cancel_timeout();
+                    }
+                }
+                None => {
+                    arch_time::cancel_timeout();
+                    state.callback = None;
+                }
+            }
+
+            callback
+        });
+
+        // call outside the lock, so that the callback is free to set a new timeout
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    fn arm_timeout(
+        &self,
+        duration: Duration,
+        interval: Option<Duration>,
+        callback: TimeoutCallback,
+    ) -> Result<(), &'static str> {
+        self.timeout.lock(|state| {
+            arch_time::set_timeout(duration)?;
+            state.callback = Some(callback);
+            state.interval = interval;
+
+            Ok(())
+        })
+    }
+}
+
+struct TimeoutState {
+    callback: Option<TimeoutCallback>,
+    interval: Option<Duration>,
 }
@@ -1,6 +1,15 @@
 // SPDX-License-Identifier: MIT
 //! General purpose code.
 
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64/backtrace.rs"]
+mod arch_backtrace;
+
+pub mod symbols;
+
+pub use arch_backtrace::{backtrace, backtrace_from};
+pub use symbols::symbolize;
+
 /// Convert a size into human readable format.
 pub const fn size_human_readable_ceil(size: usize) -> (usize, &'static str) {
     const KIB: usize = 1024;
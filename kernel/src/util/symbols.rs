@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: MIT
+//! Resolution of kernel addresses to the functions they're in, e.g. for backtraces.
+//!
+//! The symbol table can't be generated by the build script, since that runs before the kernel is
+//! linked. Instead, it's built once during init from the kernel ELF the bootloader loaded, and kept
+//! sorted by address. Looking an address up is then a binary search, which doesn't allocate, so it
+//! can be done while panicking.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use limine::LimineKernelFileRequest;
+use object::{File, Object, ObjectSymbol, SymbolKind};
+
+use crate::sync::OnceCell;
+use crate::warn;
+
+//--------------------------------------------------------------------------------------------------
+// Public code
+//--------------------------------------------------------------------------------------------------
+/// Builds the kernel's symbol table from the kernel ELF provided by the bootloader.
+///
+/// If the bootloader didn't provide the kernel file, or it has no symbols, addresses are left
+/// unresolved.
+///
+/// # Safety
+///
+/// - Must be called once, after the kernel heap is usable, but before bootloader-reclaimable
+///   memory is reclaimed.
+pub unsafe fn init() {
+    let Some(file) = BOOTLOADER_KERNEL_FILE_INFO
+        .get_response()
+        .get()
+        .and_then(|response| response.kernel_file.get())
+    else {
+        warn!("Bootloader didn't provide the kernel file; backtraces won't be symbolized");
+        return;
+    };
+
+    let Some(base) = file.base.as_ptr() else {
+        return;
+    };
+
+    let data = core::slice::from_raw_parts(base as *const u8, file.length as usize);
+    match SymbolTable::parse(data) {
+        Ok(table) => SYMBOLS.set(table),
+        Err(e) => warn!("Failed to read the kernel symbol table: {}", e),
+    }
+}
+
+/// Returns the name of the function containing `address`, and the offset of `address` into it.
+pub fn symbolize(address: usize) -> Option<(&'static str, usize)> {
+    let table = SYMBOLS.get()?;
+
+    // the last symbol starting at or before the address
+    let index = table
+        .symbols
+        .partition_point(|symbol| symbol.address <= address)
+        .checked_sub(1)?;
+    let symbol = &table.symbols[index];
+
+    let offset = address - symbol.address;
+    if symbol.size != 0 && offset >= symbol.size {
+        return None;
+    }
+
+    Some((&table.names[symbol.name.clone()], offset))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private definitions
+//--------------------------------------------------------------------------------------------------
+static BOOTLOADER_KERNEL_FILE_INFO: LimineKernelFileRequest = LimineKernelFileRequest::new(0);
+
+static SYMBOLS: OnceCell<SymbolTable> = OnceCell::new();
+
+struct SymbolTable {
+    /// Sorted by address.
+    symbols: Vec<Symbol>,
+    /// Every symbol's demangled name, one after the other.
+    names: String,
+}
+
+struct Symbol {
+    address: usize,
+    size: usize,
+    /// The range of `SymbolTable::names` holding the name.
+    name: Range<usize>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private code
+//--------------------------------------------------------------------------------------------------
+impl SymbolTable {
+    fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        let file = File::parse(data).map_err(|_| "kernel file isn't a valid ELF")?;
+
+        let mut symbols = Vec::new();
+        let mut names = String::new();
+        for symbol in file.symbols() {
+            if symbol.kind() != SymbolKind::Text || symbol.address() == 0 {
+                continue;
+            }
+
+            let Ok(name) = symbol.name() else {
+                continue;
+            };
+
+            let start = names.len();
+            demangle(name, &mut names);
+            symbols.push(Symbol {
+                address: symbol.address() as usize,
+                size: symbol.size() as usize,
+                name: start..names.len(),
+            });
+        }
+
+        if symbols.is_empty() {
+            return Err("kernel file has no function symbols");
+        }
+
+        symbols.sort_unstable_by_key(|symbol| symbol.address);
+        symbols.shrink_to_fit();
+        names.shrink_to_fit();
+
+        Ok(Self { symbols, names })
+    }
+}
+
+/// Appends the demangled form of a legacy Rust symbol name to `out`, e.g.
+/// `_ZN4flow5panic5panic17h0123456789abcdefE` becomes `flow::panic::panic`. Any other name is
+/// appended as it is.
+fn demangle(name: &str, out: &mut String) {
+    let Some(mut rest) = name
+        .strip_prefix("_ZN")
+        .and_then(|name| name.strip_suffix('E'))
+    else {
+        out.push_str(name);
+        return;
+    };
+
+    let start = out.len();
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Some(len) = rest[..digits].parse::<usize>().ok().filter(|&len| len > 0) else {
+            break;
+        };
+
+        let Some(component) = rest.get(digits..digits + len) else {
+            break;
+        };
+        rest = &rest[digits + len..];
+
+        // the last component is a hash of the symbol, which isn't worth printing
+        if rest.is_empty() && is_hash(component) {
+            break;
+        }
+
+        if out.len() > start {
+            out.push_str("::");
+        }
+        unescape(component, out);
+    }
+
+    // not a name we understand after all
+    if !rest.is_empty() {
+        out.truncate(start);
+        out.push_str(name);
+    }
+}
+
+/// Whether a path component is the `h` followed by 16 hex digits that ends legacy symbol names.
+fn is_hash(component: &str) -> bool {
+    component.len() == 17
+        && component.starts_with('h')
+        && component[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Appends a path component to `out`, replacing the escapes used for characters that can't appear
+/// in symbol names.
+fn unescape(component: &str, out: &mut String) {
+    // components starting with an escape get a leading underscore, so they don't start with `$`
+    let mut rest = match component.starts_with("_$") {
+        true => &component[1..],
+        false => component,
+    };
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = after;
+            continue;
+        }
+
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                let escape = &rest[1..end + 1];
+                let replacement = match escape {
+                    "SP" => Some("@"),
+                    "BP" => Some("*"),
+                    "RF" => Some("&"),
+                    "LT" => Some("<"),
+                    "GT" => Some(">"),
+                    "LP" => Some("("),
+                    "RP" => Some(")"),
+                    "C" => Some(","),
+                    "u20" => Some(" "),
+                    "u27" => Some("'"),
+                    "u5b" => Some("["),
+                    "u5d" => Some("]"),
+                    "u7b" => Some("{"),
+                    "u7d" => Some("}"),
+                    "u7e" => Some("~"),
+                    _ => None,
+                };
+
+                if let Some(replacement) = replacement {
+                    out.push_str(replacement);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+
+        let c = rest.chars().next().unwrap();
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+}